@@ -0,0 +1,152 @@
+//! Measures how well small writes are coalesced into packets.
+//!
+//! A chatty client sends bursts of 10 to 100 byte messages, hundreds per
+//! second, to a server over the loopback interface. The same workload is run
+//! with Nagle's algorithm and with `nodelay`. With Nagle, the first message of
+//! a burst is sent right away and the rest, written while it is in flight,
+//! share the next packet. The run fails if more than two packets are sent per
+//! burst, or more than with `nodelay`, where each write is its own packet.
+//!
+//! Run with `cargo bench --bench coalesce`.
+
+extern crate utp2;
+extern crate mio;
+
+use mio::Ready;
+use utp2::{UtpSocket, UtpStream};
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of bursts written
+const BURSTS: usize = 200;
+
+/// Messages written per burst
+const MESSAGES_PER_BURST: usize = 5;
+
+/// Time between two bursts. Together with `MESSAGES_PER_BURST`, this is 500
+/// messages per second.
+const BURST_INTERVAL_MS: u64 = 10;
+
+/// Outcome of a run
+struct Run {
+    elapsed: Duration,
+    bytes: usize,
+    packets_sent: u64,
+    acks_sent: u64,
+}
+
+/// Processes pending packets on both sockets and reads everything the server
+/// stream has buffered, returning the number of bytes read
+fn pump(sockets: &[&UtpSocket], server: &UtpStream, buf: &mut [u8]) -> usize {
+    for socket in sockets {
+        socket.ready(Ready::readable() | Ready::writable()).unwrap();
+        socket.tick().unwrap();
+    }
+
+    let mut total = 0;
+
+    loop {
+        match server.read(buf) {
+            Ok(0) => panic!("unexpected EOF"),
+            Ok(n) => total += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return total,
+            Err(e) => panic!("read failed; err={:?}", e),
+        }
+    }
+}
+
+fn run(nodelay: bool) -> Run {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let (server_socket, listener) = UtpSocket::bind(&addr).unwrap();
+    let (client_socket, _) = UtpSocket::bind(&addr).unwrap();
+    let sockets = [&server_socket, &client_socket];
+
+    let client = client_socket.connect(server_socket.local_addr().unwrap()).unwrap();
+    client.set_nodelay(nodelay).unwrap();
+
+    let server = loop {
+        for socket in &sockets {
+            socket.ready(Ready::readable() | Ready::writable()).unwrap();
+        }
+
+        if let Ok(stream) = listener.accept() {
+            break stream;
+        }
+    };
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut written = 0;
+    let mut received = 0;
+    let interval = Duration::from_millis(BURST_INTERVAL_MS);
+    let start = Instant::now();
+
+    for burst in 0..BURSTS {
+        for i in 0..MESSAGES_PER_BURST {
+            let len = 10 + ((burst * MESSAGES_PER_BURST + i) * 37) % 91;
+            let msg = vec![0xAB; len];
+            let mut pos = 0;
+
+            while pos < len {
+                match client.write(&msg[pos..]) {
+                    Ok(n) => pos += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        received += pump(&sockets, &server, &mut buf);
+                    }
+                    Err(e) => panic!("write failed; err={:?}", e),
+                }
+            }
+
+            written += len;
+        }
+
+        // Keep both sides going until the next burst is due
+        let next = start + interval * (burst as u32 + 1);
+
+        while Instant::now() < next {
+            received += pump(&sockets, &server, &mut buf);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Send whatever Nagle still holds back
+    client.flush().unwrap();
+
+    while received < written {
+        received += pump(&sockets, &server, &mut buf);
+    }
+
+    Run {
+        elapsed: start.elapsed(),
+        bytes: written,
+        packets_sent: client.stats().packets_sent(),
+        acks_sent: server.stats().acks_sent(),
+    }
+}
+
+fn main() {
+    let messages = BURSTS * MESSAGES_PER_BURST;
+    let mut packets = vec![];
+
+    for &nodelay in &[false, true] {
+        let run = run(nodelay);
+
+        println!("nodelay={:<5}; messages={}; bytes={}; packets={}; bytes/packet={:.1}; \
+                  acks={}; elapsed={:?}",
+                 nodelay, messages, run.bytes, run.packets_sent,
+                 run.bytes as f64 / run.packets_sent as f64, run.acks_sent, run.elapsed);
+
+        packets.push(run.packets_sent);
+    }
+
+    // Two packets per burst, plus the SYN
+    let optimal = 2 * BURSTS as u64 + 1;
+
+    assert!(packets[0] <= optimal,
+            "small writes were not coalesced; packets={}; optimal={}", packets[0], optimal);
+
+    assert!(packets[0] <= packets[1],
+            "coalescing sent more packets than nodelay; nagle={}; nodelay={}",
+            packets[0], packets[1]);
+}
@@ -0,0 +1,226 @@
+//! Measures the receive path: parsing inbound packets, routing them to their
+//! connection and queuing the payload for the application.
+//!
+//! A synthetic generator opens connections from a plain UDP socket and sends
+//! them pre-encoded DATA packets round-robin. Only the time spent by the
+//! socket processing the packets and the streams handing the data out is
+//! measured.
+//!
+//! Run with `cargo bench --bench recv`.
+
+extern crate utp2;
+extern crate mio;
+
+use mio::Ready;
+use utp2::{UtpSocket, UtpStream};
+
+use std::{cmp, io};
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Number of DATA packets sent for each run
+const PACKETS: usize = 200_000;
+
+/// Payload carried by each DATA packet
+const PAYLOAD_LEN: usize = 100;
+
+/// Number of packets sent before the socket processes them. This keeps the
+/// batch well within the kernel's receive buffer.
+const BATCH: usize = 256;
+
+const HEADER_LEN: usize = 20;
+const TYPE_DATA: u8 = 0;
+const TYPE_STATE: u8 = 2;
+const TYPE_SYN: u8 = 4;
+const VERSION: u8 = 1;
+
+/// A connection opened by the generator
+struct Conn {
+    // Connection ID used by the generator's DATA packets
+    id: u16,
+    // Next sequence number
+    seq_nr: u16,
+    // Sequence number of the socket's SYN-ACK
+    ack_nr: u16,
+}
+
+/// Opens connections to a socket and generates inbound DATA packets for them
+struct Generator {
+    socket: UdpSocket,
+    target: SocketAddr,
+    conns: Vec<Conn>,
+}
+
+impl Generator {
+    fn new(target: SocketAddr) -> Generator {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        Generator {
+            socket,
+            target,
+            conns: vec![],
+        }
+    }
+
+    /// Sends `n` SYNs, returning the IDs they were sent with
+    fn send_syns(&mut self, n: usize) -> Vec<u16> {
+        let start = self.conns.len();
+
+        (start..start + n).map(|i| {
+            // The socket's receive ID is one more than the SYN's
+            let id = (2 * i) as u16;
+            let syn = encode(TYPE_SYN, id, 1, 0, &[]);
+            self.socket.send_to(&syn, self.target).unwrap();
+            id
+        }).collect()
+    }
+
+    /// Waits for the SYN-ACKs of the connections opened with `ids`. The
+    /// connections are tracked in the order of `ids`, which is the order in
+    /// which the socket accepts them.
+    fn recv_syn_acks(&mut self, ids: &[u16], server: &UtpSocket) {
+        let mut acks = HashMap::new();
+        let mut buf = [0; 1500];
+
+        while acks.len() < ids.len() {
+            server.ready(Ready::readable() | Ready::writable()).unwrap();
+
+            let n = match self.socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => panic!("recv failed; err={:?}", e),
+            };
+
+            if n < HEADER_LEN || buf[0] >> 4 != TYPE_STATE {
+                continue;
+            }
+
+            acks.insert(read_u16(&buf[2..4]), read_u16(&buf[16..18]));
+        }
+
+        for &id in ids {
+            self.conns.push(Conn {
+                id: id + 1,
+                seq_nr: 2,
+                ack_nr: acks[&id],
+            });
+        }
+    }
+
+    /// Encodes `n` DATA packets, spread round-robin over the connections
+    fn data_packets(&mut self, n: usize) -> Vec<Vec<u8>> {
+        let payload = [0xAB; PAYLOAD_LEN];
+        let num_conns = self.conns.len();
+
+        (0..n).map(|i| {
+            let conn = &mut self.conns[i % num_conns];
+            let p = encode(TYPE_DATA, conn.id, conn.seq_nr, conn.ack_nr, &payload);
+            conn.seq_nr = conn.seq_nr.wrapping_add(1);
+            p
+        }).collect()
+    }
+}
+
+fn encode(ty: u8, id: u16, seq_nr: u16, ack_nr: u16, payload: &[u8]) -> Vec<u8> {
+    let mut p = vec![0; HEADER_LEN];
+
+    p[0] = ty << 4 | VERSION;
+    write_u16(&mut p[2..4], id);
+    // Window of 1MB
+    p[12..16].copy_from_slice(&[0, 0x10, 0, 0]);
+    write_u16(&mut p[16..18], seq_nr);
+    write_u16(&mut p[18..20], ack_nr);
+
+    p.extend_from_slice(payload);
+    p
+}
+
+fn read_u16(src: &[u8]) -> u16 {
+    (src[0] as u16) << 8 | src[1] as u16
+}
+
+fn write_u16(dst: &mut [u8], val: u16) {
+    dst[0] = (val >> 8) as u8;
+    dst[1] = val as u8;
+}
+
+/// Reads everything buffered by the streams, returning the number of bytes
+fn drain<'a, I>(streams: I, buf: &mut [u8]) -> usize
+    where I: IntoIterator<Item = &'a UtpStream>,
+{
+    let mut total = 0;
+
+    for stream in streams {
+        loop {
+            match stream.read(buf) {
+                Ok(0) => panic!("unexpected EOF"),
+                Ok(n) => total += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("read failed; err={:?}", e),
+            }
+        }
+    }
+
+    total
+}
+
+fn run(num_conns: usize) {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let (server, listener) = UtpSocket::bind(&addr).unwrap();
+    let mut gen = Generator::new(server.local_addr().unwrap());
+
+    // Open the connections in batches
+    let mut streams = Vec::with_capacity(num_conns);
+
+    while streams.len() < num_conns {
+        let ids = gen.send_syns(cmp::min(BATCH, num_conns - streams.len()));
+        gen.recv_syn_acks(&ids, &server);
+
+        while let Ok(stream) = listener.accept() {
+            streams.push(stream);
+        }
+    }
+
+    let packets = gen.data_packets(PACKETS);
+    let mut buf = vec![0; 64 * 1024];
+    let mut elapsed = Duration::from_secs(0);
+    let mut received = 0;
+
+    for (i, batch) in packets.chunks(BATCH).enumerate() {
+        for p in batch {
+            gen.socket.send_to(p, gen.target).unwrap();
+        }
+
+        let expect = server.driver_stats().packets_received() + batch.len() as u64;
+        let start = Instant::now();
+
+        // Process until the whole batch went through the socket
+        while server.driver_stats().packets_received() < expect {
+            server.ready(Ready::readable() | Ready::writable()).unwrap();
+        }
+
+        // Packets are spread round-robin, only read the streams they went to
+        let first = i * BATCH;
+        let touched = (first..first + cmp::min(batch.len(), num_conns))
+            .map(|j| &streams[j % num_conns]);
+
+        received += drain(touched, &mut buf);
+        elapsed += start.elapsed();
+    }
+
+    assert_eq!(received, PACKETS * PAYLOAD_LEN);
+
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+    println!("connections={:>6}; packets={}; elapsed={:?}; packets/sec={:.0}",
+             num_conns, PACKETS, elapsed, PACKETS as f64 / secs);
+}
+
+fn main() {
+    for &num_conns in &[1, 100, 10_000] {
+        run(num_conns);
+    }
+}
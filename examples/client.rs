@@ -16,7 +16,7 @@ pub fn main() {
     let (socket, _) = UtpSocket::bind(&local_addr).unwrap();
 
     // Connect to the remote
-    let stream = socket.connect(&remote_addr).unwrap();
+    let stream = socket.connect(remote_addr).unwrap();
 
     let poll = Poll::new().unwrap();
     let mut events = Events::with_capacity(1024);
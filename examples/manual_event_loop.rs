@@ -0,0 +1,139 @@
+//! Drives a transfer between two sockets from a hand-rolled mio event loop.
+//!
+//! Everything the sockets need is done explicitly:
+//!
+//! * `UtpSocket::ready` is called with the readiness of the UDP socket, which
+//!   processes inbound packets and flushes outbound ones.
+//! * `UtpSocket::tick` is called at least every 500ms, and earlier when
+//!   `UtpSocket::next_timeout` asks for it. This drives retransmissions,
+//!   delayed ACKs and paced packets.
+//! * Streams are read from and written to when their own readiness changes.
+//!
+//! The client sends 1MB to the server, then shuts down its write half. The
+//! server reads until EOF and checks the data.
+
+extern crate utp2;
+extern crate mio;
+extern crate env_logger;
+
+use mio::*;
+use utp2::*;
+
+use std::{cmp, io};
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+const SERVER_SOCKET: Token = Token(0);
+const CLIENT_SOCKET: Token = Token(1);
+const LISTENER: Token = Token(2);
+const CLIENT: Token = Token(3);
+const SERVER: Token = Token(4);
+
+const LEN: usize = 1024 * 1024;
+
+/// How often `UtpSocket::tick` must be called
+const TICK_INTERVAL_MS: u64 = 500;
+
+pub fn main() {
+    let _ = ::env_logger::init();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let (server_socket, listener) = UtpSocket::bind(&addr).unwrap();
+    let (client_socket, _) = UtpSocket::bind(&addr).unwrap();
+
+    let client = client_socket.connect(server_socket.local_addr().unwrap()).unwrap();
+
+    let poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(1024);
+
+    poll.register(&server_socket, SERVER_SOCKET, Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+    poll.register(&client_socket, CLIENT_SOCKET, Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge()).unwrap();
+    poll.register(&client, CLIENT, Ready::writable(), PollOpt::edge()).unwrap();
+
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut written = 0;
+    let mut received = Vec::with_capacity(LEN);
+    let mut server = None;
+    let mut eof = false;
+    let mut buf = [0; 4096];
+
+    let start = Instant::now();
+    let tick_interval = Duration::from_millis(TICK_INTERVAL_MS);
+    let mut next_tick = start + tick_interval;
+
+    while !eof {
+        // Sleep until the next event, the next periodic tick or the time the
+        // sockets asked to be ticked at, whichever comes first.
+        let now = Instant::now();
+        let mut timeout = if next_tick > now { next_tick - now } else { Duration::from_secs(0) };
+
+        for socket in &[&server_socket, &client_socket] {
+            if let Some(t) = socket.next_timeout() {
+                timeout = cmp::min(timeout, t);
+            }
+        }
+
+        poll.poll(&mut events, Some(timeout)).unwrap();
+
+        for event in &events {
+            match event.token() {
+                SERVER_SOCKET => server_socket.ready(event.readiness()).unwrap(),
+                CLIENT_SOCKET => client_socket.ready(event.readiness()).unwrap(),
+                LISTENER => {
+                    let stream = listener.accept().unwrap();
+                    poll.register(&stream, SERVER, Ready::readable(), PollOpt::edge()).unwrap();
+                    server = Some(stream);
+                }
+                CLIENT => {
+                    while written < LEN {
+                        match client.write(&data[written..]) {
+                            Ok(n) => written += n,
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => panic!("write failed; err={:?}", e),
+                        }
+                    }
+
+                    if written == LEN {
+                        client.shutdown(Shutdown::Write).unwrap();
+                    }
+                }
+                SERVER => {
+                    let stream = server.as_ref().unwrap();
+
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) => {
+                                eof = true;
+                                break;
+                            }
+                            Ok(n) => received.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => panic!("read failed; err={:?}", e),
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        // Tick both sockets when due, either periodically or because a timer
+        // requested by `next_timeout` fired. Ticking early is harmless.
+        let now = Instant::now();
+        let timer_due = [&server_socket, &client_socket].iter()
+            .any(|s| s.next_timeout() == Some(Duration::from_secs(0)));
+
+        if now >= next_tick || timer_due {
+            server_socket.tick().unwrap();
+            client_socket.tick().unwrap();
+
+            if now >= next_tick {
+                next_tick = now + tick_interval;
+            }
+        }
+    }
+
+    assert!(received == data, "data corrupted");
+
+    println!("transferred {} bytes in {:?}", LEN, start.elapsed());
+}
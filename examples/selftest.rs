@@ -0,0 +1,14 @@
+extern crate utp2;
+
+use std::process;
+
+/// Validates the wire format and a loopback transfer on this platform
+pub fn main() {
+    let report = utp2::selftest();
+
+    println!("{}", report);
+
+    if !report.is_ok() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,203 @@
+//! Establishes a TLS session over a uTP connection.
+//!
+//! Both ends run in the same process. The client sends a message, the server
+//! echoes it back, then both ends exchange `close_notify` alerts before the
+//! streams are dropped.
+
+extern crate utp2;
+extern crate mio;
+extern crate env_logger;
+extern crate rustls;
+extern crate rcgen;
+
+use mio::*;
+use utp2::*;
+
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MESSAGE: &[u8] = b"hello over uTP";
+
+/// One end of the TLS session
+struct Peer {
+    stream: UtpStream,
+    tls: Connection,
+    eof: bool,
+}
+
+impl Peer {
+    fn new(stream: UtpStream, tls: Connection) -> Peer {
+        Peer {
+            stream,
+            tls,
+            eof: false,
+        }
+    }
+
+    /// Moves TLS records between the session and the stream
+    fn pump(&mut self) -> io::Result<()> {
+        while !self.eof && self.tls.wants_read() {
+            match self.tls.read_tls(&mut &self.stream) {
+                Ok(0) => {
+                    self.eof = true;
+                }
+                Ok(_) => {
+                    self.tls.process_new_packets()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while self.tls.wants_write() {
+            match self.tls.write_tls(&mut &self.stream) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // TLS records are often smaller than a packet, don't let Nagle's
+        // algorithm hold them back.
+        self.stream.flush()
+    }
+
+    /// Reads decrypted data. Returns `None` if no data is available yet.
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.tls.reader().read(dst) {
+            Ok(n) => Ok(Some(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn main() {
+    ::env_logger::init().unwrap();
+
+    let (client_config, server_config) = tls_configs();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    let (server_socket, listener) = UtpSocket::bind(&addr).unwrap();
+    let (client_socket, _) = UtpSocket::bind(&addr).unwrap();
+
+    let poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(1024);
+
+    poll.register(&server_socket, Token(0), Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+    poll.register(&client_socket, Token(1), Ready::readable() | Ready::writable(), PollOpt::edge()).unwrap();
+    poll.register(&listener, Token(2), Ready::readable(), PollOpt::edge()).unwrap();
+
+    // Reading and writing may start before the uTP connection is established,
+    // the TLS handshake is held back until then.
+    let server_addr = server_socket.local_addr().unwrap();
+    let stream = client_socket.connect(server_addr).unwrap();
+
+    let name = ServerName::try_from("localhost").unwrap();
+    let tls = ClientConnection::new(client_config, name).unwrap();
+
+    let mut client = Peer::new(stream, tls.into());
+    let mut server: Option<Peer> = None;
+
+    let mut sent = false;
+    let mut echo = vec![];
+    let mut buf = [0; 1024];
+
+    loop {
+        poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+
+        for event in &events {
+            match event.token() {
+                Token(0) => server_socket.ready(event.readiness()).unwrap(),
+                Token(1) => client_socket.ready(event.readiness()).unwrap(),
+                Token(2) => {
+                    while let Ok(stream) = listener.accept() {
+                        let tls = ServerConnection::new(server_config.clone()).unwrap();
+                        server = Some(Peer::new(stream, tls.into()));
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        server_socket.tick().unwrap();
+        client_socket.tick().unwrap();
+
+        client.pump().unwrap();
+
+        if !client.tls.is_handshaking() && !sent {
+            println!("handshake complete; version={:?}", client.tls.protocol_version());
+
+            client.tls.writer().write_all(MESSAGE).unwrap();
+            sent = true;
+        }
+
+        if let Some(server) = server.as_mut() {
+            server.pump().unwrap();
+
+            // Echo until the client sends `close_notify`
+            while let Some(n) = server.read(&mut buf).unwrap() {
+                if n == 0 {
+                    server.tls.send_close_notify();
+                    break;
+                }
+
+                server.tls.writer().write_all(&buf[..n]).unwrap();
+            }
+
+            server.pump().unwrap();
+        }
+
+        let mut closed = false;
+
+        while let Some(n) = client.read(&mut buf).unwrap() {
+            if n == 0 {
+                closed = true;
+                break;
+            }
+
+            echo.extend_from_slice(&buf[..n]);
+
+            if echo == MESSAGE {
+                println!("echo; message={:?}", String::from_utf8_lossy(&echo));
+                client.tls.send_close_notify();
+            }
+        }
+
+        client.pump().unwrap();
+
+        if closed {
+            println!("TLS session closed");
+            return;
+        }
+    }
+}
+
+/// Returns client and server configurations using a self-signed certificate
+fn tls_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert = certified.cert.der().clone();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone()).unwrap();
+
+    let client = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let server = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], PrivateKeyDer::Pkcs8(key))
+        .unwrap();
+
+    (Arc::new(client), Arc::new(server))
+}
@@ -0,0 +1,82 @@
+//! Feeds arbitrary datagrams to the packet parser and the extension chain
+//! decoder. Every inbound datagram goes through these first, so they must not
+//! panic, and must not allocate more than the datagram itself, whatever the
+//! input.
+//!
+//! Run with `cargo fuzz run packet` from the repository root.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use utp2::packet::{Packet, PacketRef, SelectiveAck, HEADER_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    // Parsing in place, as the receive path does
+    let mut buf = BytesMut::from(data);
+
+    if let Ok(p) = PacketRef::parse(&mut buf) {
+        let _ = p.validate_strict();
+        let _ = p.unknown_extension();
+        let _ = p.extension_bits();
+
+        check_extensions(data.len(), p.extensions());
+
+        if let Some(sack) = p.selective_ack() {
+            check_selective_ack(data.len(), sack);
+        }
+
+        let payload_len = p.payload().len();
+        let p = p.into_packet();
+
+        assert_eq!(payload_len, p.payload().len());
+        assert_eq!(data.len(), p.encoded_len());
+    }
+
+    // Parsing into an owned packet, which must encode back to the input
+    match Packet::parse(BytesMut::from(data)) {
+        Ok(p) => {
+            let _ = p.unknown_extension();
+            let _ = p.extension_bits();
+
+            check_extensions(data.len(), p.extensions());
+
+            if let Some(sack) = p.selective_ack() {
+                check_selective_ack(data.len(), sack);
+            }
+
+            assert_eq!(data.len(), p.encoded_len());
+            assert_eq!(data, &p.to_vec()[..]);
+        }
+        Err(_) => {
+            // Both parsers agree on what is malformed
+            let mut buf = BytesMut::from(data);
+            assert!(PacketRef::parse(&mut buf).is_err());
+        }
+    }
+});
+
+/// Each extension takes at least two bytes of the datagram, so the chain is
+/// bounded by the datagram's length.
+fn check_extensions<'a, I>(len: usize, extensions: I)
+    where I: Iterator<Item = (u8, &'a [u8])>,
+{
+    let mut total = 0;
+
+    for (i, (ty, ext)) in extensions.enumerate() {
+        assert!(ty != 0);
+        assert!(i < (len - HEADER_LEN) / 2);
+
+        total += 2 + ext.len();
+        assert!(HEADER_LEN + total <= len);
+    }
+}
+
+/// Every bit of the bitfield can be queried
+fn check_selective_ack(len: usize, sack: SelectiveAck) {
+    assert!(sack.len() <= len * 8);
+
+    for offset in 0..sack.len() + 8 {
+        let _ = sack.is_acked(offset);
+    }
+}
@@ -0,0 +1,70 @@
+//! Allocation counters for the packet and queue hot paths.
+//!
+//! Counting is enabled with the `alloc-stats` feature and reported by
+//! `DriverStats`. Buffers are instrumented where they are created or grown,
+//! instead of hooking the global allocator. Counts are kept per thread and
+//! shared by the sockets it drives.
+
+use bytes::BytesMut;
+
+use std::mem;
+use std::collections::VecDeque;
+
+/// Allocation counts, by hot path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub packet: u64,
+    pub queue: u64,
+}
+
+/// `BytesMut` stores buffers up to this size inline, without allocating
+const INLINE_CAP: usize = 4 * mem::size_of::<usize>() - 1;
+
+#[cfg(feature = "alloc-stats")]
+thread_local!(static COUNTS: ::std::cell::Cell<Counts> = ::std::cell::Cell::new(Counts::default()));
+
+/// Returns the counts for the current thread. These are always zero without
+/// the `alloc-stats` feature.
+#[cfg(feature = "alloc-stats")]
+pub fn counts() -> Counts {
+    COUNTS.with(|c| c.get())
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+pub fn counts() -> Counts {
+    Counts::default()
+}
+
+#[cfg(feature = "alloc-stats")]
+fn count<F: FnOnce(&mut Counts)>(f: F) {
+    COUNTS.with(|c| {
+        let mut counts = c.get();
+        f(&mut counts);
+        c.set(counts);
+    });
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+fn count<F: FnOnce(&mut Counts)>(_: F) {
+}
+
+/// Records a packet buffer of `cap` bytes being created
+pub fn new_buffer(cap: usize) {
+    if cap > INLINE_CAP {
+        count(|c| c.packet += 1);
+    }
+}
+
+/// Records `additional` bytes being reserved in a packet buffer
+pub fn grow_buffer(buf: &BytesMut, additional: usize) {
+    if buf.capacity() - buf.len() < additional {
+        count(|c| c.packet += 1);
+    }
+}
+
+/// Records an item being pushed onto a queue
+pub fn push_queue<T>(queue: &VecDeque<T>) {
+    if queue.len() == queue.capacity() {
+        count(|c| c.queue += 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! `futures-io` integration
+//!
+//! `UtpStream` implements `AsyncRead` and `AsyncWrite` when the `futures-io`
+//! feature is enabled, `Incoming` implements `Stream`, and
+//! `UtpListener::poll_accept` and `UtpStream::poll_connect` wait for connections from a task. The
+//! `UtpSocket` must still be driven by the application by calling `ready` and
+//! `tick`; tasks blocked on a stream or listener are woken as the socket
+//! makes progress.
+//!
+//! The read and write timeouts of a stream apply to tasks as well: a task
+//! blocked past the timeout is woken and its poll completes with `TimedOut`.
+//!
+//! Tokio based applications can use the `tokio` module instead, which also
+//! drives the socket.
+
+use socket::{Incoming, UtpListener, UtpStream};
+use split::{ReadHalf, WriteHalf, OwnedReadHalf, OwnedWriteHalf};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use std::io::{self, IoSlice, IoSliceMut};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+impl UtpListener {
+    /// Attempts to accept an inbound connection, registering the task to wake
+    /// once one is ready otherwise.
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<UtpStream>> {
+        match self.accept() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.register_accept_waker(cx.waker());
+                Poll::Pending
+            }
+            ret => Poll::Ready(ret),
+        }
+    }
+}
+
+/// The stream of connections never ends. `Incoming` is an `Iterator` as well,
+/// so `StreamExt::next` has to be called as `StreamExt::next(&mut incoming)`.
+impl<'a> Stream for Incoming<'a> {
+    type Item = io::Result<UtpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<io::Result<UtpStream>>>
+    {
+        self.listener().poll_accept(cx).map(Some)
+    }
+}
+
+impl UtpStream {
+    /// Waits for the handshake of a stream returned by `UtpSocket::connect`.
+    ///
+    /// Resolves to `Ok(())` once connected, or to the error that ended the
+    /// connection, such as `TimedOut` when the peer never answered. Reads and
+    /// writes before then would block.
+    pub fn poll_connect(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.connect_result() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // The stream becomes writable once connected, and both
+                // wakers are woken if the connection fails.
+                self.register_write_waker(cx.waker());
+                Poll::Pending
+            }
+            ret => Poll::Ready(ret),
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(&self, cx, |stream| stream.read(buf))
+    }
+
+    fn poll_read_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &mut [IoSliceMut])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(&self, cx, |stream| stream.read_vectored(bufs))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(&self, cx, |stream| stream.write(buf))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &[IoSlice])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(&self, cx, |stream| stream.write_vectored(bufs))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        // Written data is handed to the socket immediately, flushing only
+        // needs to release data held back by Nagle's algorithm.
+        Poll::Ready(self.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        poll_close(&self, cx, self.close())
+    }
+}
+
+impl<'a> AsyncRead for ReadHalf<'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(self.stream(), cx, |stream| stream.read(buf))
+    }
+
+    fn poll_read_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &mut [IoSliceMut])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(self.stream(), cx, |stream| stream.read_vectored(bufs))
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(self.stream(), cx, |stream| stream.read(buf))
+    }
+
+    fn poll_read_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &mut [IoSliceMut])
+        -> Poll<io::Result<usize>>
+    {
+        poll_read(self.stream(), cx, |stream| stream.read_vectored(bufs))
+    }
+}
+
+// Closing a write half only closes the write half of the stream, the read
+// half keeps receiving data until the peer closes its own.
+
+impl<'a> AsyncWrite for WriteHalf<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(self.stream(), cx, |stream| stream.write(buf))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &[IoSlice])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(self.stream(), cx, |stream| stream.write_vectored(bufs))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let stream = self.stream();
+        poll_close(stream, cx, stream.close_write())
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(self.stream(), cx, |stream| stream.write(buf))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &[IoSlice])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(self.stream(), cx, |stream| stream.write_vectored(bufs))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let stream = self.stream();
+        poll_close(stream, cx, stream.close_write())
+    }
+}
+
+/// Completes once `read`, reading from the stream, no longer blocks
+fn poll_read<F>(stream: &UtpStream, cx: &mut Context, read: F) -> Poll<io::Result<usize>>
+    where F: FnOnce(&UtpStream) -> io::Result<usize>,
+{
+    match read(stream) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            stream.register_read_waker(cx.waker());
+            Poll::Pending
+        }
+        ret => Poll::Ready(ret),
+    }
+}
+
+/// Completes once `write`, writing to the stream, no longer blocks
+fn poll_write<F>(stream: &UtpStream, cx: &mut Context, write: F) -> Poll<io::Result<usize>>
+    where F: FnOnce(&UtpStream) -> io::Result<usize>,
+{
+    match write(stream) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            stream.register_write_waker(cx.waker());
+            Poll::Pending
+        }
+        ret => Poll::Ready(ret),
+    }
+}
+
+/// Completes once `close`, the result of closing the stream or its write
+/// half, no longer blocks
+fn poll_close(stream: &UtpStream, cx: &mut Context, close: io::Result<()>) -> Poll<io::Result<()>> {
+    match close {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            stream.register_write_waker(cx.waker());
+            Poll::Pending
+        }
+        ret => Poll::Ready(ret),
+    }
+}
@@ -0,0 +1,639 @@
+//! Blocking API
+//!
+//! `UtpListener` and `UtpStream` in this module mirror `TcpListener` and
+//! `TcpStream`: calls block until they complete, and streams implement
+//! `Read` and `Write`. Each bound socket is driven by a background thread,
+//! which owns the non-blocking `UtpSocket` and its streams and carries out
+//! the requests of the handles. Programs that do not run an event loop can
+//! use uTP without one.
+//!
+//! The driver thread exits once every handle of its socket has been dropped
+//! and the connections have been closed.
+
+use config::UtpConfig;
+use driver::UtpDriver;
+use {socket, util};
+
+use mio::{Events, PollOpt, Ready, Registration, SetReadiness, Token};
+use slab::Slab;
+
+use std::{cmp, io, thread};
+use std::io::{IoSlice, IoSliceMut};
+use std::mem::MaybeUninit;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+/// A uTP socket accepting inbound connections, driven by a background
+/// thread.
+pub struct UtpListener {
+    handle: Handle,
+    local_addr: SocketAddr,
+}
+
+/// A uTP connection, driven by a background thread.
+///
+/// Dropping the stream closes the connection gracefully, the driver thread
+/// delivers any data still queued.
+pub struct UtpStream {
+    handle: Handle,
+    id: usize,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    connection_id: u16,
+}
+
+/// Sends requests to a driver thread
+#[derive(Clone)]
+struct Handle {
+    tx: Sender<Request>,
+    wake: SetReadiness,
+}
+
+/// Requests carried out by the driver thread. Streams are identified by their
+/// index in the driver's slab.
+enum Request {
+    Accept(Sender<io::Result<Opened>>),
+    Connect(SocketAddr, Sender<io::Result<Opened>>),
+    Read(usize, usize, Sender<io::Result<Vec<u8>>>),
+    Peek(usize, usize, Sender<io::Result<Vec<u8>>>),
+    Write(usize, Vec<u8>, Sender<io::Result<usize>>),
+    Flush(usize, Sender<io::Result<()>>),
+    Shutdown(usize, Shutdown, Sender<io::Result<()>>),
+    SetReadTimeout(usize, Option<Duration>, Sender<io::Result<()>>),
+    SetWriteTimeout(usize, Option<Duration>, Sender<io::Result<()>>),
+    ReadTimeout(usize, Sender<io::Result<Option<Duration>>>),
+    WriteTimeout(usize, Sender<io::Result<Option<Duration>>>),
+    Close(usize),
+    CloseListener,
+}
+
+/// A request that is waiting for the socket to make progress
+enum Pending {
+    Request(Request),
+    // The stream is connecting, it is handed out once connected
+    Connecting(usize, Sender<io::Result<Opened>>),
+}
+
+/// A stream handed out by the driver thread
+struct Opened {
+    id: usize,
+    peer_addr: SocketAddr,
+    connection_id: u16,
+}
+
+/// State owned by the driver thread
+struct Driver {
+    listener: Option<socket::UtpListener>,
+    streams: Slab<socket::UtpStream>,
+    pending: Vec<Pending>,
+}
+
+const SOCKET: Token = Token(0);
+const WAKE: Token = Token(1);
+
+impl UtpListener {
+    /// Binds a new socket to `addr` and starts its driver thread.
+    pub fn bind(addr: &SocketAddr) -> io::Result<UtpListener> {
+        UtpListener::bind_with_config(addr, UtpConfig::new())
+    }
+
+    /// Binds a new socket to `addr` using the provided configuration and
+    /// starts its driver thread.
+    pub fn bind_with_config(addr: &SocketAddr, config: UtpConfig) -> io::Result<UtpListener> {
+        let (handle, local_addr) = spawn(*addr, config, true)?;
+
+        Ok(UtpListener {
+            handle,
+            local_addr,
+        })
+    }
+
+    /// Blocks until a new inbound connection is established.
+    pub fn accept(&self) -> io::Result<UtpStream> {
+        let opened = self.handle.call(Request::Accept)?;
+        Ok(UtpStream::new(self.handle.clone(), self.local_addr, opened))
+    }
+
+    /// Opens a connection to `addr` from the listener's socket, blocking until
+    /// it is established. The connection shares the listener's port.
+    ///
+    /// Each address `addr` resolves to is tried in turn, as by
+    /// `UtpStream::connect`.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UtpStream> {
+        let local_addr = self.local_addr;
+
+        let opened = util::each_addr(addr, |addr| {
+            if addr.is_ipv4() != local_addr.is_ipv4() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "address family differs from the socket's"));
+            }
+
+            self.handle.call(|tx| Request::Connect(*addr, tx))
+        })?;
+
+        Ok(UtpStream::new(self.handle.clone(), self.local_addr, opened))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for UtpListener {
+    fn drop(&mut self) {
+        let _ = self.handle.send(Request::CloseListener);
+    }
+}
+
+impl UtpStream {
+    /// Opens a connection to `addr`, blocking until it is established.
+    ///
+    /// The connection uses a new socket, bound to an ephemeral port, which
+    /// does not accept inbound connections. `addr` is resolved as by
+    /// `TcpStream::connect`, and each address is tried in turn until a
+    /// handshake completes. The error of the last attempt is returned if none
+    /// does.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<UtpStream> {
+        UtpStream::connect_with_config(addr, UtpConfig::new())
+    }
+
+    /// Opens a connection to `addr` using the provided configuration,
+    /// blocking until it is established.
+    pub fn connect_with_config<A: ToSocketAddrs>(addr: A, config: UtpConfig)
+        -> io::Result<UtpStream>
+    {
+        util::each_addr(addr, |addr| {
+            let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let (handle, local_addr) = spawn(local.parse().unwrap(), config.clone(), false)?;
+            let opened = handle.call(|tx| Request::Connect(*addr, tx))?;
+            Ok(UtpStream::new(handle, local_addr, opened))
+        })
+    }
+
+    fn new(handle: Handle, local_addr: SocketAddr, opened: Opened) -> UtpStream {
+        UtpStream {
+            handle,
+            id: opened.id,
+            local_addr,
+            peer_addr: opened.peer_addr,
+            connection_id: opened.connection_id,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    /// Returns the ID identifying the connection in the packets sent by the
+    /// peer, see `socket::UtpStream::connection_id`.
+    pub fn connection_id(&self) -> u16 {
+        self.connection_id
+    }
+
+    /// Blocks until data is received, then reads it into `dst`. Returns 0 once
+    /// the peer has closed its write half and all of the data was read.
+    pub fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+
+        let data = self.handle.call(|tx| Request::Read(self.id, dst.len(), tx))?;
+        dst[..data.len()].copy_from_slice(&data);
+
+        Ok(data.len())
+    }
+
+    /// Blocks until data is received, then reads it into `dst`, which does
+    /// not need to be initialized. Returns the number of bytes read, which
+    /// are initialized, see `socket::UtpStream::read_uninit`.
+    pub fn read_uninit(&self, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+
+        let data = self.handle.call(|tx| Request::Read(self.id, dst.len(), tx))?;
+
+        for (dst, src) in dst.iter_mut().zip(&data) {
+            dst.write(*src);
+        }
+
+        Ok(data.len())
+    }
+
+    /// Blocks until data is received, then reads it into each buffer of
+    /// `dsts` in turn.
+    pub fn read_vectored(&self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        let len = dsts.iter().map(|dst| dst.len()).sum();
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let data = self.handle.call(|tx| Request::Read(self.id, len, tx))?;
+        let mut rem = &data[..];
+
+        for dst in dsts.iter_mut() {
+            let n = cmp::min(dst.len(), rem.len());
+            dst[..n].copy_from_slice(&rem[..n]);
+            rem = &rem[n..];
+        }
+
+        Ok(data.len())
+    }
+
+    /// Blocks until data is received, then copies it into `dst` without
+    /// consuming it, see `socket::UtpStream::peek`.
+    pub fn peek(&self, dst: &mut [u8]) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+
+        let data = self.handle.call(|tx| Request::Peek(self.id, dst.len(), tx))?;
+        dst[..data.len()].copy_from_slice(&data);
+
+        Ok(data.len())
+    }
+
+    /// Blocks until some of `src` can be queued, returning the number of bytes
+    /// written.
+    pub fn write(&self, src: &[u8]) -> io::Result<usize> {
+        if src.is_empty() {
+            return Ok(0);
+        }
+
+        self.handle.call(|tx| Request::Write(self.id, src.to_vec(), tx))
+    }
+
+    /// Blocks until some of the data of `srcs` can be queued, returning the
+    /// number of bytes written. The buffers are packetized as a single one.
+    pub fn write_vectored(&self, srcs: &[IoSlice]) -> io::Result<usize> {
+        let data: Vec<u8> = srcs.iter().flat_map(|src| src.iter().cloned()).collect();
+
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        self.handle.call(|tx| Request::Write(self.id, data, tx))
+    }
+
+    /// Sends any data held back by Nagle's algorithm, see
+    /// `socket::UtpStream::flush`.
+    pub fn flush(&self) -> io::Result<()> {
+        self.handle.call(|tx| Request::Flush(self.id, tx))
+    }
+
+    /// Shuts down the read half, the write half, or both halves of the
+    /// connection, as `TcpStream::shutdown` does.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.handle.call(|tx| Request::Shutdown(self.id, how, tx))
+    }
+
+    /// Sets the read timeout. Reads, including peeks, that block for longer
+    /// fail with `TimedOut`. `None`, the default, blocks indefinitely.
+    ///
+    /// Returns `InvalidInput` if `timeout` is zero, as `TcpStream` does.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.handle.call(|tx| Request::SetReadTimeout(self.id, timeout, tx))
+    }
+
+    /// Returns the read timeout.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.handle.call(|tx| Request::ReadTimeout(self.id, tx))
+    }
+
+    /// Sets the write timeout. Writes that block for longer, such as when the
+    /// peer stopped acking data, fail with `TimedOut`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.handle.call(|tx| Request::SetWriteTimeout(self.id, timeout, tx))
+    }
+
+    /// Returns the write timeout.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.handle.call(|tx| Request::WriteTimeout(self.id, tx))
+    }
+}
+
+impl io::Read for UtpStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        UtpStream::read(self, dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        UtpStream::read_vectored(self, dsts)
+    }
+}
+
+impl io::Read for &UtpStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        UtpStream::read(self, dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        UtpStream::read_vectored(self, dsts)
+    }
+}
+
+impl io::Write for UtpStream {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        UtpStream::write(self, src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        UtpStream::write_vectored(self, srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        UtpStream::flush(self)
+    }
+}
+
+impl io::Write for &UtpStream {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        UtpStream::write(self, src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        UtpStream::write_vectored(self, srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        UtpStream::flush(self)
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let _ = self.handle.send(Request::Close(self.id));
+    }
+}
+
+impl Handle {
+    fn send(&self, request: Request) -> io::Result<()> {
+        self.tx.send(request).map_err(|_| stopped())?;
+        self.wake.set_readiness(Ready::readable())
+    }
+
+    /// Sends the request built by `f` and blocks until the driver replies
+    fn call<T, F>(&self, f: F) -> io::Result<T>
+        where F: FnOnce(Sender<io::Result<T>>) -> Request,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.send(f(tx))?;
+
+        match rx.recv() {
+            Ok(ret) => ret,
+            Err(_) => Err(stopped()),
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("uTP driver thread stopped")
+}
+
+/// Starts a driver thread for a socket bound to `addr`, returning once the
+/// socket is bound.
+fn spawn(addr: SocketAddr, config: UtpConfig, listen: bool)
+    -> io::Result<(Handle, SocketAddr)>
+{
+    let (tx, rx) = mpsc::channel();
+    let (bound_tx, bound_rx) = mpsc::channel();
+
+    // Sockets are not `Send`, the socket is bound by the driver thread
+    thread::Builder::new()
+        .name("utp-driver".to_string())
+        .spawn(move || {
+            let (registration, wake) = Registration::new2();
+
+            let socket = socket::UtpSocket::bind_with_config(&addr, config)
+                .and_then(|(socket, listener)| {
+                    let local_addr = socket.local_addr()?;
+                    Ok((socket, listener, local_addr))
+                });
+
+            let (socket, listener) = match socket {
+                Ok((socket, listener, local_addr)) => {
+                    let handle = Handle { tx, wake: wake.clone() };
+                    let _ = bound_tx.send(Ok((handle, local_addr)));
+                    (socket, listener)
+                }
+                Err(e) => {
+                    let _ = bound_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            let driver = Driver {
+                listener: if listen { Some(listener) } else { None },
+                streams: Slab::new(),
+                pending: vec![],
+            };
+
+            if let Err(e) = driver.run(socket, registration, wake, rx) {
+                error!("uTP driver thread failed; err={}", e);
+            }
+        })?;
+
+    match bound_rx.recv() {
+        Ok(ret) => ret,
+        Err(_) => Err(stopped()),
+    }
+}
+
+impl Driver {
+    fn run(mut self,
+           socket: socket::UtpSocket,
+           registration: Registration,
+           wake: SetReadiness,
+           rx: Receiver<Request>) -> io::Result<()>
+    {
+        let mut driver = UtpDriver::new()?;
+        let mut events = Events::with_capacity(256);
+        let mut connected = true;
+
+        driver.poll().register(&registration, WAKE, Ready::readable(), PollOpt::edge())?;
+        driver.add_socket(socket, SOCKET)?;
+
+        loop {
+            // Cleared before draining the requests, so that a request sent
+            // after the channel is drained wakes the next turn.
+            wake.set_readiness(Ready::empty())?;
+
+            // Every handle is gone once the channel disconnects, the
+            // listener is dropped to refuse new connections.
+            while connected {
+                match rx.try_recv() {
+                    Ok(request) => self.pending.push(Pending::Request(request)),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        connected = false;
+                        self.listener = None;
+                    }
+                }
+            }
+
+            {
+                let socket = driver.socket(SOCKET).unwrap();
+                self.process(socket);
+
+                if !connected && socket.is_idle() {
+                    return Ok(());
+                }
+            }
+
+            driver.turn(&mut events, None)?;
+        }
+    }
+
+    /// Attempts every pending request, keeping those that would block
+    fn process(&mut self, socket: &socket::UtpSocket) {
+        let pending = ::std::mem::take(&mut self.pending);
+
+        for request in pending {
+            if let Some(request) = self.attempt(socket, request) {
+                self.pending.push(request);
+            }
+        }
+    }
+
+    fn opened(&self, id: usize) -> Opened {
+        let stream = &self.streams[id];
+
+        Opened {
+            id,
+            // The peer's address is always known
+            peer_addr: stream.peer_addr().unwrap(),
+            connection_id: stream.connection_id(),
+        }
+    }
+
+    /// Carries out the request, returning it if it would block
+    fn attempt(&mut self, socket: &socket::UtpSocket, pending: Pending) -> Option<Pending> {
+        let request = match pending {
+            Pending::Request(request) => request,
+            Pending::Connecting(id, tx) => {
+                return match self.streams[id].connect_result() {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        Some(Pending::Connecting(id, tx))
+                    }
+                    Ok(()) => {
+                        let _ = tx.send(Ok(self.opened(id)));
+                        None
+                    }
+                    Err(e) => {
+                        self.streams.remove(id);
+                        let _ = tx.send(Err(e));
+                        None
+                    }
+                };
+            }
+        };
+
+        match request {
+            Request::Accept(tx) => {
+                let ret = match self.listener {
+                    Some(ref listener) => listener.accept(),
+                    None => Err(io::ErrorKind::NotConnected.into()),
+                };
+
+                match ret {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Some(Pending::Request(Request::Accept(tx)));
+                    }
+                    ret => {
+                        let _ = tx.send(ret.map(|stream| {
+                            let id = self.streams.insert(stream);
+                            self.opened(id)
+                        }));
+                    }
+                }
+            }
+            Request::Connect(addr, tx) => {
+                match socket.connect(addr) {
+                    Ok(stream) => {
+                        let id = self.streams.insert(stream);
+                        return Some(Pending::Connecting(id, tx));
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                    }
+                }
+            }
+            Request::Read(id, len, tx) => {
+                // The buffer is not zeroed, only the data read is initialized
+                let mut buf = Vec::with_capacity(len);
+
+                match self.streams[id].read_uninit(&mut buf.spare_capacity_mut()[..len]) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Some(Pending::Request(Request::Read(id, len, tx)));
+                    }
+                    ret => {
+                        let _ = tx.send(ret.map(|n| {
+                            unsafe { buf.set_len(n) };
+                            buf
+                        }));
+                    }
+                }
+            }
+            Request::Peek(id, len, tx) => {
+                let mut buf = vec![0; len];
+
+                match self.streams[id].peek(&mut buf) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Some(Pending::Request(Request::Peek(id, len, tx)));
+                    }
+                    ret => {
+                        let _ = tx.send(ret.map(|n| {
+                            buf.truncate(n);
+                            buf
+                        }));
+                    }
+                }
+            }
+            Request::Write(id, data, tx) => {
+                match self.streams[id].write(&data) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Some(Pending::Request(Request::Write(id, data, tx)));
+                    }
+                    ret => {
+                        let _ = tx.send(ret);
+                    }
+                }
+            }
+            Request::Flush(id, tx) => {
+                let _ = tx.send(self.streams[id].flush());
+            }
+            Request::Shutdown(id, how, tx) => {
+                let _ = tx.send(self.streams[id].shutdown(how));
+            }
+            Request::SetReadTimeout(id, timeout, tx) => {
+                let _ = tx.send(self.streams[id].set_read_timeout(timeout));
+            }
+            Request::SetWriteTimeout(id, timeout, tx) => {
+                let _ = tx.send(self.streams[id].set_write_timeout(timeout));
+            }
+            Request::ReadTimeout(id, tx) => {
+                let _ = tx.send(self.streams[id].read_timeout());
+            }
+            Request::WriteTimeout(id, tx) => {
+                let _ = tx.send(self.streams[id].write_timeout());
+            }
+            Request::Close(id) => {
+                // Dropping the stream closes the connection gracefully
+                self.streams.remove(id);
+            }
+            Request::CloseListener => {
+                // Inbound connections are refused from now on
+                self.listener = None;
+            }
+        }
+
+        None
+    }
+}
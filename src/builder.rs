@@ -0,0 +1,72 @@
+//! Configures a `UtpSocket` before it is bound
+//!
+//! The builder gathers the `UtpConfig` applied to the socket's connections
+//! and the options of the underlying UDP socket, so that all of them are in
+//! effect before the first packet is received.
+
+use config::UtpConfig;
+use socket::{UtpSocket, UtpListener};
+use sockopt;
+
+use mio::net::UdpSocket;
+
+use std::io;
+use std::net::SocketAddr;
+
+/// Builds a `UtpSocket`, see `UtpSocket::builder`.
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    config: UtpConfig,
+    ttl: Option<u32>,
+    tos: Option<u8>,
+}
+
+impl Builder {
+    /// Returns a new `Builder` using the default configuration.
+    pub fn new() -> Builder {
+        Builder::default()
+    }
+
+    /// Sets the configuration of the socket, replacing any made through
+    /// `config_mut`.
+    pub fn config(&mut self, config: UtpConfig) -> &mut Self {
+        self.config = config;
+        self
+    }
+
+    /// Returns the configuration of the socket, to update it in place.
+    pub fn config_mut(&mut self) -> &mut UtpConfig {
+        &mut self.config
+    }
+
+    /// Sets the time-to-live of the IP packets sent by the socket. Defaults to
+    /// the system default.
+    pub fn ttl(&mut self, ttl: u32) -> &mut Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the TOS byte, or the traffic class, of the packets sent by the
+    /// socket, see `UtpSocket::set_tos`. Defaults to the system default.
+    pub fn tos(&mut self, tos: u8) -> &mut Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// Binds a new `UtpSocket` to the given socket address.
+    ///
+    /// Fails if the address cannot be bound or an option cannot be applied.
+    pub fn bind(&self, addr: &SocketAddr) -> io::Result<(UtpSocket, UtpListener)> {
+        let socket = UdpSocket::bind(addr)?;
+
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+
+        if let Some(tos) = self.tos {
+            sockopt::set_tos(&socket, tos)?;
+        }
+
+        Ok(UtpSocket::from_mio_socket(socket, self.config.clone()))
+    }
+}
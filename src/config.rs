@@ -0,0 +1,985 @@
+use congestion::{CongestionControl, Ledbat};
+use gate::TransmitGate;
+use policy::{PeerPolicy, UnknownConnection};
+use stats::{Stall, DropReason};
+use timestamp::{TimestampSource, InstantTimestamps};
+use {packet, sockopt, tuning, util};
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Configures a `UtpSocket` and the connections it manages.
+///
+/// The defaults are defined in the `tuning` module.
+#[derive(Clone)]
+pub struct UtpConfig {
+    // Builds the congestion controller for each new connection. When unset,
+    // `Ledbat` is used with the configured target delay and gain.
+    congestion_control: Option<CongestionControlFactory>,
+
+    // Builds the slow peer policy for each new connection
+    peer_policy: Option<PeerPolicyFactory>,
+
+    // Builds the transmit gate of each socket
+    transmit_gate: Option<TransmitGateFactory>,
+
+    // Builds the packet timestamp source for each new connection. When unset,
+    // timestamps are derived from `Instant`.
+    timestamp_source: Option<TimestampSourceFactory>,
+
+    // Handling of packets from known peers with an unknown connection ID
+    unknown_connection: UnknownConnection,
+    unknown_connection_hook: Option<UnknownConnectionHook>,
+    silent_drop: bool,
+
+    strict_extensions: bool,
+
+    advertise_extensions: bool,
+
+    strict_validation: bool,
+
+    drop_hook: Option<DropHook>,
+
+    max_window_size: usize,
+
+    max_packet_size: usize,
+
+    max_recv_payload_size: usize,
+
+    min_packet_size: usize,
+
+    max_packets_in_flight: usize,
+
+    max_burst: usize,
+
+    reorder_buffer_size: usize,
+
+    recv_buffer_size: usize,
+
+    max_initial_window: usize,
+
+    max_connections: usize,
+
+    accept_backlog: usize,
+
+    path_cache_size: usize,
+
+    path_cache_ttl: Duration,
+
+    initial_timeout: Duration,
+
+    min_timeout: Duration,
+
+    target_delay: Duration,
+
+    max_cwnd_increase_bytes_per_rtt: usize,
+
+    jitter_filter: bool,
+
+    max_send_window: Option<usize>,
+
+    pacing: bool,
+
+    mtu_discovery: bool,
+
+    max_ack_delay: Duration,
+
+    delayed_ack: bool,
+
+    keepalive: Option<Duration>,
+
+    idle_timeout: Option<Duration>,
+
+    max_retransmits: u32,
+
+    max_lifetime: Option<Duration>,
+
+    reset_on_read_shutdown: bool,
+
+    // Stalled connection detection, in retransmission timeouts of silence
+    watchdog: Option<u32>,
+    watchdog_reset: bool,
+    stall_hook: Option<StallHook>,
+}
+
+type CongestionControlFactory = Arc<dyn Fn() -> Box<dyn CongestionControl> + Send + Sync>;
+type PeerPolicyFactory = Arc<dyn Fn() -> Box<dyn PeerPolicy> + Send + Sync>;
+type TransmitGateFactory = Arc<dyn Fn() -> Box<dyn TransmitGate> + Send + Sync>;
+type TimestampSourceFactory = Arc<dyn Fn() -> Box<dyn TimestampSource> + Send + Sync>;
+type UnknownConnectionHook = Arc<dyn Fn(&SocketAddr, u16) + Send + Sync>;
+type StallHook = Arc<dyn Fn(&Stall) + Send + Sync>;
+pub(crate) type DropHook = Arc<dyn Fn(&SocketAddr, DropReason) + Send + Sync>;
+
+impl UtpConfig {
+    /// Returns a new `UtpConfig` with default values.
+    pub fn new() -> UtpConfig {
+        UtpConfig {
+            congestion_control: None,
+            timestamp_source: None,
+            peer_policy: None,
+            transmit_gate: None,
+            unknown_connection: UnknownConnection::Reset,
+            unknown_connection_hook: None,
+            silent_drop: false,
+            strict_extensions: false,
+            advertise_extensions: true,
+            strict_validation: false,
+            drop_hook: None,
+            max_window_size: tuning::MAX_WINDOW_SIZE,
+            max_packet_size: tuning::MAX_PACKET_SIZE,
+            max_recv_payload_size: tuning::MAX_RECV_PAYLOAD_SIZE,
+            min_packet_size: tuning::MIN_PACKET_SIZE,
+            max_packets_in_flight: tuning::MAX_PACKETS_IN_FLIGHT,
+            max_burst: tuning::MAX_BURST_PACKETS,
+            reorder_buffer_size: tuning::REORDER_BUFFER_SIZE,
+            recv_buffer_size: tuning::MAX_WINDOW_SIZE,
+            max_initial_window: tuning::MAX_INITIAL_WINDOW_SIZE,
+            max_connections: tuning::MAX_CONNECTIONS_PER_SOCKET,
+            accept_backlog: tuning::ACCEPT_BACKLOG,
+            path_cache_size: tuning::PATH_CACHE_SIZE,
+            path_cache_ttl: Duration::from_secs(tuning::PATH_CACHE_TTL_SECS),
+            initial_timeout: Duration::from_millis(tuning::INITIAL_TIMEOUT_MS),
+            min_timeout: Duration::from_millis(tuning::MIN_TIMEOUT_MS),
+            target_delay: util::from_micros(tuning::TARGET_DELAY_MICROS as u64),
+            max_cwnd_increase_bytes_per_rtt: tuning::MAX_CWND_INCREASE_BYTES_PER_RTT,
+            jitter_filter: false,
+            max_send_window: None,
+            pacing: true,
+            mtu_discovery: sockopt::DONT_FRAGMENT,
+            max_ack_delay: Duration::from_millis(tuning::MAX_ACK_DELAY_MS),
+            delayed_ack: true,
+            keepalive: None,
+            idle_timeout: None,
+            max_retransmits: tuning::MAX_RETRANSMITS,
+            max_lifetime: None,
+            reset_on_read_shutdown: false,
+            watchdog: None,
+            watchdog_reset: false,
+            stall_hook: None,
+        }
+    }
+
+    /// Sets the function used to create the congestion controller of each
+    /// connection.
+    ///
+    /// Defaults to `Ledbat`, using `target_delay`,
+    /// `max_cwnd_increase_bytes_per_rtt` and `jitter_filter`.
+    pub fn set_congestion_control<F>(&mut self, f: F) -> &mut Self
+        where F: Fn() -> Box<dyn CongestionControl> + Send + Sync + 'static,
+    {
+        self.congestion_control = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn new_congestion_control(&self) -> Box<dyn CongestionControl> {
+        match self.congestion_control {
+            Some(ref f) => f(),
+            None => {
+                let mut ledbat = Ledbat::new();
+                ledbat.set_packet_size(self.max_packet_size)
+                    .set_target_delay(self.target_delay)
+                    .set_max_cwnd_increase_bytes_per_rtt(self.max_cwnd_increase_bytes_per_rtt)
+                    .set_min_window(self.min_packet_size)
+                    .set_jitter_filter(self.jitter_filter);
+
+                if let Some(max) = self.max_send_window {
+                    ledbat.set_max_cwnd(max);
+                }
+
+                Box::new(ledbat)
+            }
+        }
+    }
+
+    /// Sets the function used to create the policy that monitors the peer of
+    /// each connection.
+    ///
+    /// By default, peers are not monitored.
+    pub fn set_peer_policy<F>(&mut self, f: F) -> &mut Self
+        where F: Fn() -> Box<dyn PeerPolicy> + Send + Sync + 'static,
+    {
+        self.peer_policy = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn new_peer_policy(&self) -> Option<Box<dyn PeerPolicy>> {
+        self.peer_policy.as_ref().map(|f| f())
+    }
+
+    /// Sets the function used to create the gate consulted before each packet
+    /// is sent.
+    ///
+    /// The function is called once per socket; the gate sees the packets of
+    /// all of its connections. Gates of several sockets may share state to
+    /// schedule application-wide. By default, packets are not gated.
+    pub fn set_transmit_gate<F>(&mut self, f: F) -> &mut Self
+        where F: Fn() -> Box<dyn TransmitGate> + Send + Sync + 'static,
+    {
+        self.transmit_gate = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn new_transmit_gate(&self) -> Option<Box<dyn TransmitGate>> {
+        self.transmit_gate.as_ref().map(|f| f())
+    }
+
+    /// Sets the function used to create the source of the timestamps sent
+    /// with the packets of each connection.
+    ///
+    /// Defaults to `InstantTimestamps`. Deployments sending at high packet
+    /// rates may use a cheaper clock.
+    pub fn set_timestamp_source<F>(&mut self, f: F) -> &mut Self
+        where F: Fn() -> Box<dyn TimestampSource> + Send + Sync + 'static,
+    {
+        self.timestamp_source = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn new_timestamp_source(&self, now: Instant) -> Box<dyn TimestampSource> {
+        match self.timestamp_source {
+            Some(ref f) => f(),
+            None => Box::new(InstantTimestamps::new(now)),
+        }
+    }
+
+    /// How packets from a known peer with an unknown connection ID are
+    /// handled.
+    pub fn unknown_connection(&self) -> UnknownConnection {
+        self.unknown_connection
+    }
+
+    /// Sets how packets from a peer with connections on the socket, but with
+    /// an unknown connection ID, are handled. Defaults to
+    /// `UnknownConnection::Reset`.
+    pub fn set_unknown_connection(&mut self, val: UnknownConnection) -> &mut Self {
+        self.unknown_connection = val;
+        self
+    }
+
+    /// Sets the function called with the peer's address and the connection ID
+    /// of the packet when `UnknownConnection::Notify` is selected.
+    ///
+    /// The hook is called while the socket processes inbound packets and must
+    /// not call back into the socket or its streams.
+    pub fn set_unknown_connection_hook<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&SocketAddr, u16) + Send + Sync + 'static,
+    {
+        self.unknown_connection_hook = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn notify_unknown_connection(&self, addr: &SocketAddr, connection_id: u16) {
+        if let Some(ref f) = self.unknown_connection_hook {
+            f(addr, connection_id);
+        }
+    }
+
+    /// Whether packets that match no connection are dropped without a reply.
+    pub fn silent_drop(&self) -> bool {
+        self.silent_drop
+    }
+
+    /// Sets whether packets that match no connection, including SYNs when the
+    /// listener is closed, are dropped without answering with a RESET.
+    ///
+    /// A scanner then cannot tell the port apart from one that is filtered.
+    /// Peers of connections that were forgotten, e.g. after a restart, find
+    /// out by timing out instead. Defaults to `false`.
+    pub fn set_silent_drop(&mut self, val: bool) -> &mut Self {
+        self.silent_drop = val;
+        self
+    }
+
+    /// Whether packets carrying unknown extensions are dropped.
+    pub fn strict_extensions(&self) -> bool {
+        self.strict_extensions
+    }
+
+    /// Sets whether packets carrying an extension type that is not understood
+    /// are dropped.
+    ///
+    /// By default, unknown extensions are skipped and the rest of the packet
+    /// is processed as usual. Defaults to `false`.
+    pub fn set_strict_extensions(&mut self, val: bool) -> &mut Self {
+        self.strict_extensions = val;
+        self
+    }
+
+    /// Whether outbound SYNs include the extension bits.
+    pub fn advertise_extensions(&self) -> bool {
+        self.advertise_extensions
+    }
+
+    /// Sets whether outbound SYNs include the extension bits, advertising the
+    /// extensions supported beyond selective ACKs.
+    ///
+    /// libutp, and the BitTorrent clients built on it, send the extension bits
+    /// with every SYN, and the handshake looks the same on the wire when this
+    /// is set. Extension bits received from the peer are accepted either way.
+    /// Defaults to `true`.
+    pub fn set_advertise_extensions(&mut self, val: bool) -> &mut Self {
+        self.advertise_extensions = val;
+        self
+    }
+
+    /// Whether packets that no conforming peer would send are dropped.
+    pub fn strict_validation(&self) -> bool {
+        self.strict_validation
+    }
+
+    /// Sets whether inbound packets are checked beyond what is needed to parse
+    /// them.
+    ///
+    /// Datagrams that are too short, have an unsupported version or type, or
+    /// whose extensions run past the end of the datagram are always dropped
+    /// and counted by `DriverStats::malformed_packets`. In strict mode, a
+    /// payload on a packet other than `ST_DATA` and a selective ACK that is
+    /// not a non-zero multiple of 4 bytes are rejected as well, before they
+    /// reach the connection. These are counted by
+    /// `DriverStats::rejected_packets`. Defaults to `false`.
+    pub fn set_strict_validation(&mut self, val: bool) -> &mut Self {
+        self.strict_validation = val;
+        self
+    }
+
+    /// Sets the function called with the sender's address and the reason
+    /// whenever a received packet is discarded.
+    ///
+    /// Every discarded packet is counted by `DriverStats::dropped`, the hook
+    /// helps tracing data that never arrives to the exact point it was
+    /// dropped. It is called while the socket processes inbound packets and
+    /// must not call back into the socket or its streams.
+    pub fn set_drop_hook<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&SocketAddr, DropReason) + Send + Sync + 'static,
+    {
+        self.drop_hook = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn drop_hook(&self) -> Option<DropHook> {
+        self.drop_hook.clone()
+    }
+
+    /// Max number of bytes buffered for a connection in each direction.
+    ///
+    /// This bounds the data queued for sending and the receive buffer, and is
+    /// the window assumed for the peer until it advertises its own.
+    pub fn max_window_size(&self) -> usize {
+        self.max_window_size
+    }
+
+    /// Sets the max number of bytes buffered for a connection in each
+    /// direction.
+    ///
+    /// Writes block once this much data is waiting to be acknowledged, and
+    /// the receive buffer is capped to it, whatever `recv_buffer_size` is.
+    /// Defaults to `tuning::MAX_WINDOW_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` does not leave room for a payload after the header, or
+    /// does not fit the 32 bit window field of the header.
+    pub fn set_max_window_size(&mut self, val: usize) -> &mut Self {
+        assert!(val > packet::HEADER_LEN && val as u64 <= u32::MAX as u64,
+                "invalid max window size; val={}", val);
+        self.max_window_size = val;
+        self
+    }
+
+    /// Max size of a packet, including the header.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Sets the max size of a packet, including the header.
+    ///
+    /// Networks with jumbo frames can use larger packets, while tunnels may
+    /// require smaller ones. Path MTU discovery only probes above this size.
+    /// LEDBAT starts out with a window of a single packet of this size.
+    /// Defaults to `tuning::MAX_PACKET_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is smaller than `min_packet_size`, which must be lowered
+    /// first.
+    pub fn set_max_packet_size(&mut self, val: usize) -> &mut Self {
+        assert!(val >= self.min_packet_size, "max packet size too small; val={}", val);
+        self.max_packet_size = val;
+        self
+    }
+
+    /// Largest payload accepted in a single received packet.
+    pub fn max_recv_payload_size(&self) -> usize {
+        self.max_recv_payload_size
+    }
+
+    /// Sets the largest payload accepted in a single received packet.
+    ///
+    /// Packets carrying a larger payload are dropped before they reach the
+    /// connection, so that a peer cannot make the socket hold on to buffers
+    /// of arbitrary size. They are counted by `DriverStats::oversized_packets`.
+    /// The limit must leave room for the packets sent by peers, which use
+    /// their own max packet size. Defaults to
+    /// `tuning::MAX_RECV_PAYLOAD_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn set_max_recv_payload_size(&mut self, val: usize) -> &mut Self {
+        assert!(val > 0, "max receive payload size must be positive");
+        self.max_recv_payload_size = val;
+        self
+    }
+
+    /// Smallest packet, including the header, that a write is split into to
+    /// fill the window. This is also the size of the congestion window after a
+    /// connection times out.
+    pub fn min_packet_size(&self) -> usize {
+        self.min_packet_size
+    }
+
+    /// Sets the smallest packet, including the header, that a write is split
+    /// into to fill the window.
+    ///
+    /// This is also the size of the congestion window after a timeout, unless
+    /// a custom congestion controller is set. Defaults to
+    /// `tuning::MIN_PACKET_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` does not leave room for a payload after the header, or
+    /// is larger than `max_packet_size`.
+    pub fn set_min_packet_size(&mut self, val: usize) -> &mut Self {
+        assert!(val > packet::HEADER_LEN && val <= self.max_packet_size,
+                "invalid min packet size; val={}", val);
+        self.min_packet_size = val;
+        self
+    }
+
+    /// Max number of packets a connection tracks for sending, whether
+    /// in-flight or waiting to be sent.
+    pub fn max_packets_in_flight(&self) -> usize {
+        self.max_packets_in_flight
+    }
+
+    /// Sets the max number of packets a connection tracks for sending.
+    ///
+    /// Applications making many small writes with `nodelay` set would
+    /// otherwise fill the byte window with tiny packets, each of which is
+    /// tracked and scanned when processing ACKs. Once the limit is reached,
+    /// writes wait for packets to be acked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn set_max_packets_in_flight(&mut self, val: usize) -> &mut Self {
+        assert!(val > 0, "max packets in flight must be positive");
+        self.max_packets_in_flight = val;
+        self
+    }
+
+    /// Max number of packets a connection sends at once.
+    pub fn max_burst(&self) -> usize {
+        self.max_burst
+    }
+
+    /// Sets the max number of packets a connection sends at once.
+    ///
+    /// A connection with a large window would otherwise send it all in one
+    /// event loop iteration, holding up the other connections on the socket.
+    /// Once the limit is reached, `UtpSocket::next_timeout` returns zero and
+    /// the remaining packets are sent by the following call to
+    /// `UtpSocket::tick`. Defaults to `tuning::MAX_BURST_PACKETS`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn set_max_burst(&mut self, val: usize) -> &mut Self {
+        assert!(val > 0, "max burst must be positive");
+        self.max_burst = val;
+        self
+    }
+
+    /// Max number of out of order packets a connection holds.
+    pub fn reorder_buffer_size(&self) -> usize {
+        self.reorder_buffer_size
+    }
+
+    /// Sets the max number of packets a connection holds when they arrive
+    /// ahead of a missing one.
+    ///
+    /// Held packets are delivered once the gap fills. Packets beyond the limit
+    /// are dropped and the peer has to retransmit them, which bounds the
+    /// memory a lossy or reordering path can tie up. Defaults to
+    /// `tuning::REORDER_BUFFER_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is larger than `tuning::REORDER_BUFFER_SIZE`.
+    pub fn set_reorder_buffer_size(&mut self, val: usize) -> &mut Self {
+        assert!(val <= tuning::REORDER_BUFFER_SIZE,
+                "reorder buffer size too large; val={}", val);
+        self.reorder_buffer_size = val;
+        self
+    }
+
+    /// Max number of bytes a connection buffers for the application to read.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.recv_buffer_size
+    }
+
+    /// Sets the max number of bytes a connection buffers for the application
+    /// to read.
+    ///
+    /// The space left in the buffer, counting data held until a missing packet
+    /// arrives, is the window advertised to the peer. A reader that falls
+    /// behind shrinks the window and slows the sender down. Defaults to
+    /// `tuning::MAX_WINDOW_SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is larger than `tuning::MAX_WINDOW_SIZE`.
+    pub fn set_recv_buffer_size(&mut self, val: usize) -> &mut Self {
+        assert!(val <= tuning::MAX_WINDOW_SIZE,
+                "receive buffer size too large; val={}", val);
+        self.recv_buffer_size = val;
+        self
+    }
+
+    /// Largest initial congestion window that `UtpStream::set_initial_window`
+    /// may request.
+    pub fn max_initial_window(&self) -> usize {
+        self.max_initial_window
+    }
+
+    /// Sets the largest initial congestion window that
+    /// `UtpStream::set_initial_window` may request. Larger requests are
+    /// reduced to this value. Defaults to ten packets.
+    pub fn set_max_initial_window(&mut self, val: usize) -> &mut Self {
+        self.max_initial_window = val;
+        self
+    }
+
+    /// Max number of connections managed by the socket.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Sets the max number of connections managed by the socket.
+    ///
+    /// Outbound connections beyond the limit fail to open, inbound ones are
+    /// reset. Defaults to 2048.
+    pub fn set_max_connections(&mut self, val: usize) -> &mut Self {
+        self.max_connections = val;
+        self
+    }
+
+    /// Max number of inbound connections waiting to be accepted.
+    pub fn accept_backlog(&self) -> usize {
+        self.accept_backlog
+    }
+
+    /// Sets the max number of inbound connections waiting to be accepted.
+    ///
+    /// When the listener falls behind, further SYNs are answered with a
+    /// RESET until a connection is accepted. Defaults to 128.
+    pub fn set_accept_backlog(&mut self, val: usize) -> &mut Self {
+        self.accept_backlog = val;
+        self
+    }
+
+    /// Max number of peers whose path characteristics are cached.
+    pub fn path_cache_size(&self) -> usize {
+        self.path_cache_size
+    }
+
+    /// Sets the max number of peers whose path characteristics are cached.
+    ///
+    /// When a connection is removed, its round trip time, packet size and
+    /// loss rate are stored by peer IP address. New connections to the same
+    /// host start out with the cached round trip time and packet size. Zero
+    /// disables the cache. Defaults to 256.
+    pub fn set_path_cache_size(&mut self, val: usize) -> &mut Self {
+        self.path_cache_size = val;
+        self
+    }
+
+    /// Time that cached path characteristics are used for.
+    pub fn path_cache_ttl(&self) -> Duration {
+        self.path_cache_ttl
+    }
+
+    /// Sets the time that cached path characteristics are used for. Defaults
+    /// to 10 minutes.
+    pub fn set_path_cache_ttl(&mut self, val: Duration) -> &mut Self {
+        self.path_cache_ttl = val;
+        self
+    }
+
+    /// Timeout used until a round trip time has been measured. This bounds
+    /// each attempt of the handshake.
+    pub fn initial_timeout(&self) -> Duration {
+        self.initial_timeout
+    }
+
+    /// Sets the timeout used until a round trip time has been measured.
+    ///
+    /// Lower values retransmit lost SYNs sooner on fast networks, at the cost
+    /// of spurious retransmits on slow ones. Defaults to
+    /// `tuning::INITIAL_TIMEOUT_MS`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is shorter than a millisecond or longer than
+    /// `tuning::MAX_TIMEOUT_MS`.
+    pub fn set_initial_timeout(&mut self, val: Duration) -> &mut Self {
+        let ms = util::as_micros(val) / 1_000;
+        assert!(ms > 0 && ms <= tuning::MAX_TIMEOUT_MS,
+                "invalid initial timeout; val={:?}", val);
+        self.initial_timeout = val;
+        self
+    }
+
+    /// Lower bound of the retransmission timeout computed from the round
+    /// trip time.
+    pub fn min_timeout(&self) -> Duration {
+        self.min_timeout
+    }
+
+    /// Sets the lower bound of the retransmission timeout computed from the
+    /// round trip time.
+    ///
+    /// A lower bound recovers from losses faster on low latency paths, but
+    /// retransmits needlessly when the round trip time varies. Defaults to
+    /// `tuning::MIN_TIMEOUT_MS`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is shorter than a millisecond or longer than
+    /// `tuning::MAX_TIMEOUT_MS`.
+    pub fn set_min_timeout(&mut self, val: Duration) -> &mut Self {
+        let ms = util::as_micros(val) / 1_000;
+        assert!(ms > 0 && ms <= tuning::MAX_TIMEOUT_MS,
+                "invalid min timeout; val={:?}", val);
+        self.min_timeout = val;
+        self
+    }
+
+    /// LEDBAT target queuing delay.
+    pub fn target_delay(&self) -> Duration {
+        self.target_delay
+    }
+
+    /// Sets the LEDBAT target queuing delay.
+    ///
+    /// Latency sensitive applications may want a lower target, such as 25ms,
+    /// at the cost of yielding more bandwidth to competing traffic. Ignored
+    /// when a custom congestion controller is set.
+    pub fn set_target_delay(&mut self, val: Duration) -> &mut Self {
+        self.target_delay = val;
+        self
+    }
+
+    /// Max number of bytes LEDBAT grows the congestion window by per round
+    /// trip.
+    pub fn max_cwnd_increase_bytes_per_rtt(&self) -> usize {
+        self.max_cwnd_increase_bytes_per_rtt
+    }
+
+    /// Sets the max number of bytes LEDBAT grows the congestion window by per
+    /// round trip. Ignored when a custom congestion controller is set.
+    pub fn set_max_cwnd_increase_bytes_per_rtt(&mut self, val: usize) -> &mut Self {
+        self.max_cwnd_increase_bytes_per_rtt = val;
+        self
+    }
+
+    /// Whether LEDBAT ignores delay within the jitter of the path.
+    pub fn jitter_filter(&self) -> bool {
+        self.jitter_filter
+    }
+
+    /// Sets whether LEDBAT ignores delay within the jitter of the path, see
+    /// `Ledbat::set_jitter_filter`.
+    ///
+    /// This helps on noisy links, such as Wi-Fi, where the measured delay
+    /// varies without a queue building up. Ignored when a custom congestion
+    /// controller is set. Defaults to `false`.
+    pub fn set_jitter_filter(&mut self, val: bool) -> &mut Self {
+        self.jitter_filter = val;
+        self
+    }
+
+    /// Largest congestion window LEDBAT grows to.
+    pub fn max_send_window(&self) -> Option<usize> {
+        self.max_send_window
+    }
+
+    /// Sets the largest congestion window LEDBAT grows to, in bytes.
+    ///
+    /// This bounds how much each connection sends per round trip regardless
+    /// of the window the peer advertises, for example to share an uplink
+    /// fairly among many peers. Defaults to `None`, the window grows as long
+    /// as the path allows. Ignored when a custom congestion controller is
+    /// set.
+    pub fn set_max_send_window(&mut self, val: Option<usize>) -> &mut Self {
+        self.max_send_window = val;
+        self
+    }
+
+    /// Whether packets are paced over the round trip time.
+    pub fn pacing(&self) -> bool {
+        self.pacing
+    }
+
+    /// Sets whether packets are paced.
+    ///
+    /// When enabled, packets are spaced out so that a full congestion window
+    /// is sent over one round trip instead of in a single burst. This keeps
+    /// queuing delay, and thus LEDBAT's delay signal, accurate. Use
+    /// `UtpSocket::next_timeout` to know when paced packets are due. Defaults
+    /// to `true`.
+    pub fn set_pacing(&mut self, val: bool) -> &mut Self {
+        self.pacing = val;
+        self
+    }
+
+    /// Whether connections probe the path for packets larger than
+    /// `max_packet_size`.
+    pub fn mtu_discovery(&self) -> bool {
+        self.mtu_discovery
+    }
+
+    /// Sets whether connections probe the path for packets larger than
+    /// `max_packet_size`, up to `tuning::MAX_PROBE_PACKET_SIZE`.
+    ///
+    /// Probes are sent with the "don't fragment" bit set, which is only
+    /// supported on Linux, Android, FreeBSD and Apple platforms. Elsewhere,
+    /// probes may be fragmented and get through, so that later packets no
+    /// longer fit the path. Defaults to `true` on the supported platforms.
+    pub fn set_mtu_discovery(&mut self, val: bool) -> &mut Self {
+        self.mtu_discovery = val;
+        self
+    }
+
+    /// Max time that an ACK is delayed by.
+    pub fn max_ack_delay(&self) -> Duration {
+        self.max_ack_delay
+    }
+
+    /// Sets the max time that an ACK is delayed by.
+    ///
+    /// STATE packets are delayed by a quarter of the measured round trip time,
+    /// up to this value, giving outbound data a chance to carry the ACK
+    /// instead. ACKs are sent right away until a round trip time has been
+    /// measured. Defaults to 100ms.
+    pub fn set_max_ack_delay(&mut self, val: Duration) -> &mut Self {
+        self.max_ack_delay = val;
+        self
+    }
+
+    /// Whether ACKs are delayed.
+    pub fn delayed_ack(&self) -> bool {
+        self.delayed_ack
+    }
+
+    /// Sets whether ACKs are delayed.
+    ///
+    /// When enabled, an ACK is held back for up to `max_ack_delay` until
+    /// every second data packet is received. Disable it for latency sensitive
+    /// traffic, at the cost of sending a STATE packet for each data packet
+    /// received. Defaults to `true`.
+    pub fn set_delayed_ack(&mut self, val: bool) -> &mut Self {
+        self.delayed_ack = val;
+        self
+    }
+
+    /// Interval at which idle connections send keep-alive packets.
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Sets the interval at which idle connections send keep-alive packets.
+    ///
+    /// A connection that has not sent anything for `val` sends a STATE packet
+    /// that the peer ignores. This keeps NAT mappings along the path open on
+    /// long-lived idle connections. libutp uses 29 seconds. Defaults to
+    /// `None`, disabling keep-alives.
+    pub fn set_keepalive(&mut self, val: Option<Duration>) -> &mut Self {
+        self.keepalive = val;
+        self
+    }
+
+    /// How long a connection waits to hear from the peer before failing.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Sets how long a connection waits to hear from the peer before failing.
+    ///
+    /// When no packet is received for `val`, the connection is reset and
+    /// pending reads and writes fail with `TimedOut`. An idle peer may not
+    /// send anything, so this should exceed the peer's keep-alive interval.
+    /// Defaults to `None`, connections never time out.
+    pub fn set_idle_timeout(&mut self, val: Option<Duration>) -> &mut Self {
+        self.idle_timeout = val;
+        self
+    }
+
+    /// Number of times packets are retransmitted after timing out before the
+    /// connection fails.
+    pub fn max_retransmits(&self) -> u32 {
+        self.max_retransmits
+    }
+
+    /// Sets the number of times packets are retransmitted after timing out
+    /// before the connection fails.
+    ///
+    /// The timeout doubles each time it expires without the peer acking
+    /// anything, up to `tuning::MAX_TIMEOUT_MS`. Once the last retransmission
+    /// also times out, the connection is reset and pending reads and writes
+    /// fail with `TimedOut`. Defaults to `tuning::MAX_RETRANSMITS`.
+    pub fn set_max_retransmits(&mut self, val: u32) -> &mut Self {
+        self.max_retransmits = val;
+        self
+    }
+
+    /// How long a connection may stay open.
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+    }
+
+    /// Sets how long a connection may stay open, regardless of activity.
+    ///
+    /// Once `val` has elapsed since the connection was created, it is closed
+    /// gracefully: pending data is delivered, followed by a FIN. Reads fail
+    /// with `ConnectionAborted` once buffered data is consumed, as do writes,
+    /// which tells an expired connection apart from one the peer closed.
+    /// Defaults to `None`, connections are never closed.
+    pub fn set_max_lifetime(&mut self, val: Option<Duration>) -> &mut Self {
+        self.max_lifetime = val;
+        self
+    }
+
+    /// Whether data received after the read half was shut down resets the
+    /// connection.
+    pub fn reset_on_read_shutdown(&self) -> bool {
+        self.reset_on_read_shutdown
+    }
+
+    /// Sets whether data received after the read half was shut down resets
+    /// the connection.
+    ///
+    /// Such data is discarded without being acked, so the peer's writes
+    /// eventually stall. Resetting lets the peer know right away that nobody
+    /// is reading, as TCP does when data arrives for a closed socket.
+    /// Defaults to `false`.
+    pub fn set_reset_on_read_shutdown(&mut self, val: bool) -> &mut Self {
+        self.reset_on_read_shutdown = val;
+        self
+    }
+
+    /// Number of retransmission timeouts a connection with unacked data may
+    /// go without sending or receiving a packet before it is flagged as
+    /// stalled.
+    pub fn watchdog(&self) -> Option<u32> {
+        self.watchdog
+    }
+
+    /// Sets the number of retransmission timeouts a connection with unacked
+    /// data may go without sending or receiving a packet before it is flagged
+    /// as stalled.
+    ///
+    /// Retransmissions normally keep such a connection busy, so silence
+    /// points at a bug or at a path that drops everything. Stalled connections
+    /// are reported to the hook set with `set_stall_hook`, and
+    /// `UtpStream::is_stalled` returns `true` until a packet is sent or
+    /// received. Defaults to `None`, connections are not watched.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` is zero.
+    pub fn set_watchdog(&mut self, val: Option<u32>) -> &mut Self {
+        assert!(val != Some(0), "watchdog must be at least one timeout");
+        self.watchdog = val;
+        self
+    }
+
+    /// Whether stalled connections are reset.
+    pub fn watchdog_reset(&self) -> bool {
+        self.watchdog_reset
+    }
+
+    /// Sets whether connections flagged by the watchdog are reset, failing
+    /// pending reads and writes with `TimedOut`. Defaults to `false`.
+    pub fn set_watchdog_reset(&mut self, val: bool) -> &mut Self {
+        self.watchdog_reset = val;
+        self
+    }
+
+    /// Sets the function called with a snapshot of each connection flagged
+    /// by the watchdog.
+    ///
+    /// The hook is called while the socket is ticked and must not call back
+    /// into the socket or its streams.
+    pub fn set_stall_hook<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&Stall) + Send + Sync + 'static,
+    {
+        self.stall_hook = Some(Arc::new(f));
+        self
+    }
+
+    pub(crate) fn notify_stall(&self, stall: &Stall) {
+        if let Some(ref f) = self.stall_hook {
+            f(stall);
+        }
+    }
+}
+
+impl Default for UtpConfig {
+    fn default() -> UtpConfig {
+        UtpConfig::new()
+    }
+}
+
+impl fmt::Debug for UtpConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UtpConfig")
+            .field("max_window_size", &self.max_window_size)
+            .field("max_packet_size", &self.max_packet_size)
+            .field("max_recv_payload_size", &self.max_recv_payload_size)
+            .field("min_packet_size", &self.min_packet_size)
+            .field("max_packets_in_flight", &self.max_packets_in_flight)
+            .field("max_burst", &self.max_burst)
+            .field("reorder_buffer_size", &self.reorder_buffer_size)
+            .field("recv_buffer_size", &self.recv_buffer_size)
+            .field("unknown_connection", &self.unknown_connection)
+            .field("silent_drop", &self.silent_drop)
+            .field("strict_extensions", &self.strict_extensions)
+            .field("advertise_extensions", &self.advertise_extensions)
+            .field("strict_validation", &self.strict_validation)
+            .field("max_initial_window", &self.max_initial_window)
+            .field("max_connections", &self.max_connections)
+            .field("accept_backlog", &self.accept_backlog)
+            .field("path_cache_size", &self.path_cache_size)
+            .field("path_cache_ttl", &self.path_cache_ttl)
+            .field("initial_timeout", &self.initial_timeout)
+            .field("min_timeout", &self.min_timeout)
+            .field("target_delay", &self.target_delay)
+            .field("max_cwnd_increase_bytes_per_rtt", &self.max_cwnd_increase_bytes_per_rtt)
+            .field("jitter_filter", &self.jitter_filter)
+            .field("max_send_window", &self.max_send_window)
+            .field("pacing", &self.pacing)
+            .field("mtu_discovery", &self.mtu_discovery)
+            .field("max_ack_delay", &self.max_ack_delay)
+            .field("delayed_ack", &self.delayed_ack)
+            .field("keepalive", &self.keepalive)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_retransmits", &self.max_retransmits)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("watchdog", &self.watchdog)
+            .field("watchdog_reset", &self.watchdog_reset)
+            .finish()
+    }
+}
@@ -0,0 +1,343 @@
+//! Congestion control
+//!
+//! The congestion controller decides how many bytes a connection may have
+//! in-flight. Connections use LEDBAT by default, but any implementation of
+//! `CongestionControl` can be selected with `UtpConfig`.
+
+use tuning::{
+    MAX_WINDOW_SIZE,
+    MAX_PACKET_SIZE,
+    MIN_PACKET_SIZE,
+    TARGET_DELAY_MICROS,
+    MAX_CWND_INCREASE_BYTES_PER_RTT,
+};
+use util;
+
+use std::{cmp, fmt};
+use std::time::{Duration, Instant};
+
+/// Determines the size of a connection's congestion window.
+pub trait CongestionControl: fmt::Debug {
+    /// Called when the peer acknowledges data.
+    fn on_ack(&mut self, ack: &Ack);
+
+    /// Called when packets are determined to be lost.
+    fn on_loss(&mut self);
+
+    /// Called when the connection times out waiting for an acknowledgement.
+    fn on_timeout(&mut self);
+
+    /// Returns the max number of bytes that may be in-flight.
+    fn cwnd(&self) -> usize;
+
+    /// Called before any data is sent when the application knows that the
+    /// path supports a window of `window` bytes, for example because it just
+    /// transferred data to the same peer.
+    ///
+    /// The default implementation ignores the hint.
+    fn set_initial_window(&mut self, window: usize) {
+        let _ = window;
+    }
+
+    /// Called before sending data after nothing was sent for `periods`
+    /// retransmission timeouts. The window was measured before the connection
+    /// went idle and sending a full window at once may burst into a path that
+    /// changed since.
+    ///
+    /// The default implementation ignores the idle period.
+    fn on_idle(&mut self, periods: u32) {
+        let _ = periods;
+    }
+}
+
+/// An acknowledgement received from the peer.
+#[derive(Debug, Clone)]
+pub struct Ack {
+    bytes_acked: usize,
+    delay: Option<Duration>,
+    min_rtt: Duration,
+    jitter: Duration,
+    app_limited: bool,
+    now: Instant,
+}
+
+/// Low Extra Delay Background Transport congestion control, as specified by
+/// BEP-29.
+///
+/// The window grows as long as the measured queuing delay is below the target
+/// and shrinks once it goes above.
+///
+/// Connections start in slow start, where the window doubles every round trip.
+/// Slow start ends on the first loss, timeout, or once the delay approaches
+/// the target, at which point LEDBAT takes over.
+#[derive(Debug)]
+pub struct Ledbat {
+    max_window: usize,
+    slow_start: bool,
+    // Target queuing delay, in microseconds
+    target: i64,
+    max_cwnd_increase: usize,
+    // Size of a full packet. The window starts at a single packet and does
+    // not decay below it while idle.
+    packet_size: usize,
+    // Window after a timeout
+    min_window: usize,
+    // The window never grows past this
+    max_cwnd: usize,
+    // Whether delay within the jitter is ignored
+    jitter_filter: bool,
+}
+
+/// Congestion control using a fixed window.
+///
+/// This is mostly useful for testing.
+#[derive(Debug)]
+pub struct FixedWindow {
+    window: usize,
+}
+
+const MIN_WINDOW_SIZE: usize = 10;
+const SLOW_START_THRESHOLD: usize = MAX_WINDOW_SIZE;
+
+impl Ack {
+    pub(crate) fn new(bytes_acked: usize,
+                      delay: Option<Duration>,
+                      min_rtt: Duration,
+                      jitter: Duration,
+                      app_limited: bool,
+                      now: Instant) -> Ack
+    {
+        Ack {
+            bytes_acked,
+            delay,
+            min_rtt,
+            jitter,
+            app_limited,
+            now,
+        }
+    }
+
+    /// Number of payload bytes acknowledged
+    pub fn bytes_acked(&self) -> usize {
+        self.bytes_acked
+    }
+
+    /// Estimated one-way queuing delay. `None` if the peer did not provide a
+    /// timestamp.
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
+
+    /// The smallest round trip time of the acked packets
+    pub fn min_rtt(&self) -> Duration {
+        self.min_rtt
+    }
+
+    /// Jitter of the arrival of the peer's packets, a measure of how noisy
+    /// `delay` is. Zero until two packets with a timestamp were received.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// True when the application has not been filling the window, in which
+    /// case the window should not grow.
+    pub fn is_app_limited(&self) -> bool {
+        self.app_limited
+    }
+
+    /// The instant at which the ACK was received
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+}
+
+impl Ledbat {
+    /// Returns a new `Ledbat` starting with a window of a single packet.
+    pub fn new() -> Ledbat {
+        Ledbat {
+            max_window: MAX_PACKET_SIZE,
+            slow_start: true,
+            target: TARGET_DELAY_MICROS as i64,
+            max_cwnd_increase: MAX_CWND_INCREASE_BYTES_PER_RTT,
+            packet_size: MAX_PACKET_SIZE,
+            min_window: MIN_PACKET_SIZE,
+            max_cwnd: usize::MAX,
+            jitter_filter: false,
+        }
+    }
+
+    /// Sets the queuing delay LEDBAT aims for.
+    ///
+    /// Lower values keep latency down at the cost of throughput when
+    /// competing with other traffic. Defaults to 100ms.
+    pub fn set_target_delay(&mut self, target: Duration) -> &mut Self {
+        self.target = cmp::max(util::as_micros(target) as i64, 1);
+        self
+    }
+
+    /// Sets the max number of bytes the window grows by per round trip.
+    pub fn set_max_cwnd_increase_bytes_per_rtt(&mut self, n: usize) -> &mut Self {
+        self.max_cwnd_increase = n;
+        self
+    }
+
+    /// Sets the size of a full packet, including the header, which is the
+    /// initial window. Defaults to `tuning::MAX_PACKET_SIZE`.
+    pub fn set_packet_size(&mut self, n: usize) -> &mut Self {
+        self.packet_size = n;
+        self.max_window = cmp::min(n, self.max_cwnd);
+        self
+    }
+
+    /// Sets the size of the window after a timeout.
+    pub fn set_min_window(&mut self, n: usize) -> &mut Self {
+        self.min_window = n;
+        self
+    }
+
+    /// Sets the largest the window grows to, in bytes.
+    pub fn set_max_cwnd(&mut self, n: usize) -> &mut Self {
+        self.max_cwnd = n;
+        self.max_window = cmp::min(self.max_window, n);
+        self
+    }
+
+    /// Sets whether the measured delay is reduced by the jitter of the path,
+    /// up to half the target delay.
+    ///
+    /// On noisy links, such as Wi-Fi, delay samples vary without any queue
+    /// building up, and the window shrinks needlessly. With the filter, only
+    /// delay beyond the noise is treated as queuing delay. Defaults to `false`.
+    pub fn set_jitter_filter(&mut self, val: bool) -> &mut Self {
+        self.jitter_filter = val;
+        self
+    }
+}
+
+impl Default for Ledbat {
+    fn default() -> Ledbat {
+        Ledbat::new()
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn on_ack(&mut self, ack: &Ack) {
+        // Without a delay sample, there is no signal to act on
+        let delay = match ack.delay() {
+            Some(delay) => delay,
+            None => return,
+        };
+
+        let bytes_acked = ack.bytes_acked();
+
+        trace!("applying congestion control; bytes_acked={}; delay={:?}; min_rtt={:?}",
+               bytes_acked, delay, ack.min_rtt());
+
+        // The computation is done using signed integers as our delay may be
+        // above the target, in which case the window shrinks.
+        let target = self.target;
+        let mut our_delay = util::as_micros(delay) as i64;
+
+        if self.jitter_filter {
+            let noise = cmp::min(util::as_micros(ack.jitter()) as i64, target / 2);
+            our_delay = cmp::max(our_delay - noise, 0);
+        }
+
+        let max_window = self.max_window;
+
+        let off_target = (target - our_delay) as f64;
+        let window_factor =
+            cmp::min(bytes_acked, max_window) as f64 /
+            cmp::max(max_window, bytes_acked) as f64;
+
+        let delay_factor = off_target / target as f64;
+        let mut scaled_gain = self.max_cwnd_increase as f64 *
+            window_factor * delay_factor;
+
+        if scaled_gain > 0.0 && ack.is_app_limited() {
+            // We're most likely rate limited by the application, which
+            // prevents us from ever hitting the window size. If this is the
+            // case, we cannot let the max_window grow indefinitely.
+            scaled_gain = 0.0;
+        }
+
+        let ledbat_cwnd = cmp::max(
+            MIN_WINDOW_SIZE as i64,
+            max_window as i64 + scaled_gain as i64) as usize;
+
+        trace!("ledbat; our_delay={}; off_target={}; gain={}; cwnd={}",
+               our_delay, off_target, scaled_gain, ledbat_cwnd);
+
+        if self.slow_start {
+            // Grow the window by the number of bytes acked, doubling it every
+            // round trip. Unless the application does not fill the window, in
+            // which case it has not been validated.
+            let ss_cwnd = if ack.is_app_limited() {
+                max_window
+            } else {
+                max_window + bytes_acked
+            };
+
+            if ss_cwnd > SLOW_START_THRESHOLD {
+                self.slow_start = false;
+            } else if our_delay > target * 9 / 10 {
+                // Even if we're a little under the target delay, we
+                // conservatively discontinue the slow start phase
+                self.slow_start = false;
+            } else {
+                self.max_window = cmp::min(cmp::max(ss_cwnd, ledbat_cwnd), self.max_cwnd);
+                return;
+            }
+        }
+
+        self.max_window = cmp::min(ledbat_cwnd, self.max_cwnd);
+    }
+
+    fn on_loss(&mut self) {
+        self.max_window = cmp::max(self.max_window / 2, MIN_WINDOW_SIZE);
+        self.slow_start = false;
+    }
+
+    fn on_timeout(&mut self) {
+        self.max_window = self.min_window;
+        self.slow_start = false;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.max_window
+    }
+
+    fn set_initial_window(&mut self, window: usize) {
+        // Slow start continues from the larger window
+        self.max_window = cmp::min(cmp::max(self.max_window, window), self.max_cwnd);
+    }
+
+    fn on_idle(&mut self, periods: u32) {
+        // Halve the window for each idle period, as in RFC 2861, down to the
+        // initial window.
+        let decayed = cmp::max(self.max_window >> cmp::min(periods, 31), self.packet_size);
+        self.max_window = cmp::min(self.max_window, decayed);
+    }
+}
+
+impl FixedWindow {
+    /// Returns a new `FixedWindow` allowing `window` bytes in-flight.
+    pub fn new(window: usize) -> FixedWindow {
+        FixedWindow { window }
+    }
+}
+
+impl CongestionControl for FixedWindow {
+    fn on_ack(&mut self, _: &Ack) {
+    }
+
+    fn on_loss(&mut self) {
+    }
+
+    fn on_timeout(&mut self) {
+    }
+
+    fn cwnd(&self) -> usize {
+        self.window
+    }
+}
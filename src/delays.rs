@@ -1,5 +1,6 @@
 use util;
 use super::TIMESTAMP_MASK;
+use std::cmp;
 use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Default)]
@@ -15,9 +16,42 @@ pub struct Delays {
     last_step: Option<Instant>,
 }
 
+/// Estimates how fast the peer's clock drifts from ours, from the one way delay
+/// samples the peer reports.
+///
+/// Samples are averaged over fixed periods, relative to a base that follows
+/// the average. The drift is the smoothed change of the average between two
+/// periods, in microseconds per period.
+#[derive(Debug, Clone)]
+pub struct ClockDrift {
+    average_delay: i32,
+    current_delay_sum: i64,
+    current_delay_samples: i64,
+    average_delay_base: u32,
+    average_sample_time: Instant,
+    drift: i32,
+}
+
+/// Estimates the variation of the one way delay from the arrival of packets,
+/// as the interarrival jitter of RFC 3550.
+///
+/// The time between two arrivals minus the time between their timestamps is
+/// the change in one way delay. The jitter is the mean of its absolute value,
+/// smoothed with a gain of 1/16.
+#[derive(Debug, Clone, Default)]
+pub struct Jitter {
+    // Timestamp and arrival time of the previous packet
+    last: Option<(u32, Instant)>,
+    // Jitter in microseconds, scaled by 16
+    jitter: u64,
+}
+
 const CURR_DELAY_LEN: usize = 3;
 const BASE_DELAY_LEN: usize = 13;
 
+/// Length of the periods over which delay samples are averaged
+const DRIFT_PERIOD_SECS: u64 = 5;
+
 impl Delays {
     pub fn new() -> Delays {
         Delays::default()
@@ -88,3 +122,102 @@ impl Delays {
         *self = Delays::new();
     }
 }
+
+impl ClockDrift {
+    pub fn new(now: Instant) -> ClockDrift {
+        ClockDrift {
+            average_delay: 0,
+            current_delay_sum: 0,
+            current_delay_samples: 0,
+            average_delay_base: 0,
+            average_sample_time: now,
+            drift: 0,
+        }
+    }
+
+    /// Returns the drift estimate, in microseconds per period. Positive when
+    /// the delays grow, e.g. because the peer's clock runs faster than ours.
+    pub fn get(&self) -> i32 {
+        self.drift
+    }
+
+    /// Adds a delay sample. Returns the updated estimate when a period ends.
+    pub fn add_sample(&mut self, sample: u32, now: Instant) -> Option<i32> {
+        if self.average_delay_base == 0 {
+            self.average_delay_base = sample;
+        }
+
+        let dist_down = self.average_delay_base.wrapping_sub(sample);
+        let dist_up = sample.wrapping_sub(self.average_delay_base);
+
+        let average_delay_sample = if dist_down > dist_up {
+            dist_up as i64
+        } else {
+            -(dist_down as i64)
+        };
+
+        self.current_delay_sum = self.current_delay_sum.wrapping_add(average_delay_sample);
+        self.current_delay_samples += 1;
+
+        if now <= self.average_sample_time {
+            return None;
+        }
+
+        let mut prev_average_delay = self.average_delay;
+        self.average_delay = (self.current_delay_sum / self.current_delay_samples) as i32;
+        self.average_sample_time = now + Duration::from_secs(DRIFT_PERIOD_SECS);
+
+        self.current_delay_sum = 0;
+        self.current_delay_samples = 0;
+
+        // Keep the average close to zero by moving the base
+        let min_sample = cmp::min(prev_average_delay, self.average_delay);
+        let max_sample = cmp::max(prev_average_delay, self.average_delay);
+
+        if min_sample > 0 {
+            self.average_delay_base = self.average_delay_base.wrapping_add(min_sample as u32);
+            self.average_delay -= min_sample;
+            prev_average_delay -= min_sample;
+        } else if max_sample < 0 {
+            let adjust = -max_sample;
+
+            self.average_delay_base = self.average_delay_base.wrapping_sub(adjust as u32);
+            self.average_delay += adjust;
+            prev_average_delay += adjust;
+        }
+
+        let drift = self.average_delay as i64 - prev_average_delay as i64;
+        self.drift = ((self.drift as i64 * 7 + drift) / 8) as i32;
+
+        Some(self.drift)
+    }
+}
+
+impl Jitter {
+    pub fn new() -> Jitter {
+        Jitter::default()
+    }
+
+    /// Returns the current jitter, zero until two packets were received
+    pub fn get(&self) -> Duration {
+        util::from_micros(self.jitter / 16)
+    }
+
+    /// Adds a packet carrying `timestamp`, in microseconds, received at `now`
+    pub fn add_sample(&mut self, timestamp: u32, now: Instant) {
+        if let Some((last_timestamp, last_at)) = self.last {
+            if now < last_at {
+                return;
+            }
+
+            let arrival = util::as_micros(now - last_at) as i64;
+            // Reordered packets have an earlier timestamp
+            let sent = timestamp.wrapping_sub(last_timestamp) as i32 as i64;
+            let diff = (arrival - sent).unsigned_abs();
+
+            self.jitter = self.jitter + diff - self.jitter / 16;
+        }
+
+        self.last = Some((timestamp, now));
+    }
+}
@@ -0,0 +1,153 @@
+//! Driving several sockets from one event loop
+//!
+//! Each `UtpSocket` needs its UDP socket polled and its timers ticked. An
+//! application listening on several ports can hand all of its sockets to a
+//! single `UtpDriver`, which owns one `Poll`, computes one deadline for the
+//! timers of every socket, and dispatches readiness to the right socket. The
+//! application registers its streams and listeners with the same `Poll` and
+//! handles their events after each turn.
+//!
+//! Sockets are not `Send`, a driver runs on the thread that created them.
+
+use socket::UtpSocket;
+use tuning::TICK_INTERVAL_MS;
+
+use mio::{Events, Poll, PollOpt, Ready, Token};
+
+use std::{cmp, fmt, io};
+use std::time::{Duration, Instant};
+
+/// Drives any number of `UtpSocket`s from a single `Poll`.
+///
+/// Each call to `turn` waits for events, hands the readiness of the UDP
+/// sockets to the matching `UtpSocket`, and ticks the sockets whose timers are
+/// due. Every socket is also ticked periodically, as `UtpSocket::tick`
+/// requires.
+pub struct UtpDriver {
+    poll: Poll,
+    sockets: Vec<(Token, UtpSocket)>,
+    // Sockets are all ticked at least this often
+    tick_interval: Duration,
+    next_tick: Instant,
+}
+
+impl UtpDriver {
+    /// Returns a new driver without any sockets.
+    pub fn new() -> io::Result<UtpDriver> {
+        let poll = Poll::new()?;
+        let tick_interval = Duration::from_millis(TICK_INTERVAL_MS);
+
+        Ok(UtpDriver {
+            poll,
+            sockets: vec![],
+            tick_interval,
+            next_tick: Instant::now() + tick_interval,
+        })
+    }
+
+    /// The `Poll` the sockets are registered with. Streams and listeners of
+    /// the driven sockets are registered with it as well, using tokens that
+    /// are distinct from those of the sockets.
+    pub fn poll(&self) -> &Poll {
+        &self.poll
+    }
+
+    /// Starts driving `socket`, registering it with `token`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token` is already used by another socket of the driver.
+    pub fn add_socket(&mut self, socket: UtpSocket, token: Token) -> io::Result<()> {
+        assert!(self.socket(token).is_none(), "token already in use; token={:?}", token);
+
+        self.poll.register(&socket, token,
+                           Ready::readable() | Ready::writable(),
+                           PollOpt::edge())?;
+
+        self.sockets.push((token, socket));
+        Ok(())
+    }
+
+    /// Stops driving the socket registered with `token` and returns it.
+    pub fn remove_socket(&mut self, token: Token) -> io::Result<Option<UtpSocket>> {
+        let pos = match self.sockets.iter().position(|&(t, _)| t == token) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+
+        let (_, socket) = self.sockets.remove(pos);
+        self.poll.deregister(&socket)?;
+
+        Ok(Some(socket))
+    }
+
+    /// Returns the socket registered with `token`, if any.
+    pub fn socket(&self, token: Token) -> Option<&UtpSocket> {
+        self.sockets.iter()
+            .find(|&&(t, _)| t == token)
+            .map(|(_, socket)| socket)
+    }
+
+    /// Returns the amount of time until a socket must be ticked, either
+    /// periodically or for a timer it requested.
+    pub fn next_timeout(&self) -> Duration {
+        let now = Instant::now();
+        let tick = if self.next_tick > now { self.next_tick - now } else { Duration::from_secs(0) };
+
+        self.sockets.iter()
+            .filter_map(|(_, socket)| socket.next_timeout())
+            .fold(tick, cmp::min)
+    }
+
+    /// Waits for events, at most until the next timer of a socket is due or
+    /// `timeout` elapses, then processes the readiness of the sockets and
+    /// ticks those whose timers are due.
+    ///
+    /// `events` holds every event of the turn once this returns. Events for
+    /// the sockets' tokens have been handled, the others are left to the
+    /// application.
+    pub fn turn(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let mut wait = self.next_timeout();
+
+        if let Some(timeout) = timeout {
+            wait = cmp::min(wait, timeout);
+        }
+
+        self.poll.poll(events, Some(wait))?;
+
+        for event in events.iter() {
+            if let Some(socket) = self.socket(event.token()) {
+                socket.ready(event.readiness())?;
+            }
+        }
+
+        // Ticking early is harmless, only sockets with a timer due are
+        // ticked between periodic ticks.
+        let now = Instant::now();
+        let periodic = now >= self.next_tick;
+
+        for (_, socket) in &self.sockets {
+            if periodic || socket.next_timeout() == Some(Duration::from_secs(0)) {
+                socket.tick()?;
+            }
+        }
+
+        if periodic {
+            self.next_tick = now + self.tick_interval;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UtpDriver {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let tokens: Vec<_> = self.sockets.iter().map(|&(token, _)| token).collect();
+
+        fmt.debug_struct("UtpDriver")
+            .field("sockets", &tokens)
+            .field("tick_interval", &self.tick_interval)
+            .field("next_tick", &self.next_tick)
+            .finish()
+    }
+}
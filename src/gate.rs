@@ -0,0 +1,82 @@
+//! Transmission scheduling
+//!
+//! A `TransmitGate` is consulted before each packet is sent, letting an
+//! external scheduler, e.g. an application-wide bandwidth manager, delay or
+//! deny transmissions. Applications select a gate with
+//! `UtpConfig::set_transmit_gate`. By default, packets are sent as soon as
+//! congestion control allows.
+
+use packet::Type;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Decides whether a packet may be sent now.
+///
+/// The gate is called while the socket flushes connections and must not call
+/// back into the socket or its streams. It should be cheap, it is consulted
+/// for every packet, including ACKs.
+pub trait TransmitGate: fmt::Debug {
+    /// Called before `transmit` is sent.
+    fn check(&mut self, transmit: &Transmit) -> Admission;
+}
+
+/// The outcome of a `TransmitGate` check.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Admission {
+    /// The packet is sent.
+    Allow,
+
+    /// The connection stops sending for the given duration. The packet, and
+    /// any queued after it, are offered to the gate again once it elapses.
+    Delay(Duration),
+
+    /// The packet is dropped, as if it was lost on the way. Data is
+    /// retransmitted once the connection times out, and congestion control
+    /// reacts as it would to a loss.
+    Deny,
+}
+
+/// A packet about to be sent.
+#[derive(Debug, Clone)]
+pub struct Transmit {
+    pub(crate) addr: SocketAddr,
+    pub(crate) connection_id: u16,
+    pub(crate) ty: Type,
+    pub(crate) size: usize,
+    pub(crate) priority: u8,
+    pub(crate) retransmit: bool,
+}
+
+impl Transmit {
+    /// Address of the peer
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// ID the packet is sent with
+    pub fn connection_id(&self) -> u16 {
+        self.connection_id
+    }
+
+    /// Type of the packet
+    pub fn ty(&self) -> Type {
+        self.ty
+    }
+
+    /// Size of the packet on the wire, excluding the IP and UDP headers
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Priority of the connection, see `UtpStream::set_priority`
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// True if the packet was sent before
+    pub fn is_retransmit(&self) -> bool {
+        self.retransmit
+    }
+}
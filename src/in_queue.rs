@@ -1,10 +1,13 @@
-use {MAX_WINDOW_SIZE, MAX_DELTA_SEQ};
+use {allocs, seq, MAX_DELTA_SEQ};
+use tuning::MAX_WINDOW_SIZE;
 use packet::{self, Packet};
+use stats::DropReason;
 
-use bytes::{BytesMut, Buf};
+use bytes::{Bytes, Buf, BufMut};
 
-use std::{mem, u16};
-use std::io::{self, Read, Cursor};
+use std::{cmp, mem, u16};
+use std::mem::MaybeUninit;
+use std::io::{self, Read, Cursor, IoSliceMut};
 use std::collections::VecDeque;
 
 #[derive(Debug)]
@@ -14,10 +17,19 @@ pub struct InQueue {
     // packets: VecDeque<Option<packet::Inbound>>,
 
     // Sequenced data packets for reading
-    data: VecDeque<Cursor<BytesMut>>,
+    data: VecDeque<Cursor<Bytes>>,
 
     // Ignore all packets lower than this seq_nr
     ack_nr: Option<u16>,
+
+    // Number of payload bytes sequenced for reading so far
+    offset: u64,
+
+    // Max number of packets held while waiting for a gap to fill
+    max_held: usize,
+
+    // Max number of bytes buffered, whether held or waiting to be read
+    capacity: usize,
 }
 
 impl InQueue {
@@ -30,10 +42,25 @@ impl InQueue {
         InQueue {
             packets: Default::default(),
             data: VecDeque::new(),
-            ack_nr: ack_nr,
+            ack_nr,
+            offset: 0,
+            max_held: MAX_DELTA_SEQ,
+            capacity: MAX_WINDOW_SIZE,
         }
     }
 
+    /// Sets the max number of bytes buffered for the application. This bounds
+    /// the window advertised to the peer.
+    pub fn set_capacity(&mut self, val: usize) {
+        self.capacity = val;
+    }
+
+    /// Sets the max number of packets received ahead of a gap that are held
+    /// until the gap fills. Further packets are dropped, to be retransmitted.
+    pub fn set_max_held(&mut self, val: usize) {
+        self.max_held = cmp::min(val, MAX_DELTA_SEQ);
+    }
+
     /// Returns the seq number of the last remote packet to ack
     pub fn ack_nr(&self) -> u16 {
         self.ack_nr.unwrap_or(0)
@@ -44,20 +71,20 @@ impl InQueue {
     pub fn poll(&mut self) -> Option<Packet> {
         trace!("poll; ack_nr={:?}", self.ack_nr);
 
-        // Get the current position, if none then no packets can be read
-        let pos = match self.ack_nr {
-            Some(ack_nr) => (ack_nr as usize) + 1,
-            None => return None,
-        };
-
         loop {
+            // Get the current position, if none then no packets can be read
+            let seq_nr = match self.ack_nr {
+                Some(ack_nr) => ack_nr.wrapping_add(1),
+                None => return None,
+            };
+
             // Take the next packet
-            let slot = pos % MAX_DELTA_SEQ;
+            let slot = seq_nr as usize % MAX_DELTA_SEQ;
             let p = mem::replace(&mut self.packets[slot], None);
 
             let p = match p {
                 Some(p) => {
-                    trace!("slot has packet; slot={:?}; packet={:?}", slot, p);
+                    trace!("slot has packet; slot={:?}; packet={}", slot, p);
                     p
                 }
                 None => {
@@ -67,12 +94,15 @@ impl InQueue {
             };
 
             // Update ack_nr
-            self.ack_nr = Some((pos % u16::MAX as usize) as u16);
+            self.ack_nr = Some(seq_nr);
 
             if p.ty() == packet::Type::Data {
                 trace!(" -> got data");
                 if !p.payload().is_empty() {
+                    self.offset += p.payload().len() as u64;
+
                     let buf = Cursor::new(p.into_payload());
+                    allocs::push_queue(&self.data);
                     self.data.push_back(buf);
                 }
             } else {
@@ -81,47 +111,69 @@ impl InQueue {
         }
     }
 
-    pub fn push(&mut self, packet: Packet) -> bool {
-        trace!("InQueue::push; packet={:?}; ack_nr={:?}", packet, self.ack_nr);
+    /// Queues `packet` until it can be processed in order. Returns why the
+    /// packet was dropped otherwise.
+    pub fn push(&mut self, packet: Packet) -> Result<(), DropReason> {
+        trace!("InQueue::push; packet={}; ack_nr={:?}", packet, self.ack_nr);
 
         // State packets are handled outside of this queue
         assert!(packet.ty() != packet::Type::State);
 
-        // Just drop if our window is full
-        if self.bytes_pending() >= MAX_WINDOW_SIZE as usize {
+        let seq_nr = packet.seq_nr();
+
+        // The packet following `ack_nr` is always accepted, it is what fills
+        // the gap.
+        let next = self.ack_nr.map(|ack_nr| ack_nr.wrapping_add(1));
+
+        // Just drop if our window is full. Held packets don't count against
+        // the packet filling the gap, otherwise the gap would never fill.
+        let buffered = if next == Some(seq_nr) {
+            self.bytes_pending()
+        } else {
+            self.bytes_buffered()
+        };
+
+        if buffered >= self.capacity {
             trace!("    -> window full; dropping packet");
-            return false;
+            return Err(DropReason::WindowFull);
         }
 
-        let seq_nr = packet.seq_nr();
-
         if let Some(ack_nr) = self.ack_nr {
             if !in_range(ack_nr, seq_nr) {
                 trace!("    -> not in range -- dropping");
-                // Drop the packet
-                return false;
+
+                if seq::wrapping_le(seq_nr, ack_nr) {
+                    return Err(DropReason::Duplicate);
+                }
+
+                return Err(DropReason::OutOfWindow);
             }
         }
 
+        if next != Some(seq_nr) && self.num_held() >= self.max_held {
+            trace!("    -> reorder buffer full -- dropping");
+            return Err(DropReason::ReorderBufferFull);
+        }
+
         // Track the packet
         let slot = seq_nr as usize % MAX_DELTA_SEQ;
 
         if self.packets[slot].is_some() {
             trace!("    -> slot occupied -- dropping");
             // Slot already occupied, ignore the packet
-            return false;
+            return Err(DropReason::Duplicate);
         }
 
         trace!("    -> tracking packet; seq_nr={:?}; slot={:?}", seq_nr, slot);
 
         self.packets[slot] = Some(packet);
-        true
+        Ok(())
     }
 
     pub fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
         let n = match self.data.front_mut() {
             Some(buf) => {
-                let n = try!(buf.read(dst));
+                let n = buf.read(dst)?;
 
                 if buf.has_remaining() {
                     return Ok(n);
@@ -139,26 +191,207 @@ impl InQueue {
         Ok(n)
     }
 
+    /// Reads sequenced data into each buffer of `dsts` in turn
+    pub fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = 0;
+
+        for dst in dsts.iter_mut() {
+            let mut pos = 0;
+
+            while pos < dst.len() && self.is_readable() {
+                pos += self.read(&mut dst[pos..])?;
+            }
+
+            n += pos;
+
+            if pos < dst.len() {
+                break;
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Reads sequenced data into `dst`, which does not need to be
+    /// initialized. Returns the number of bytes read, which are initialized.
+    pub fn read_uninit(&mut self, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = 0;
+
+        while n < dst.len() {
+            let buf = match self.data.front_mut() {
+                Some(buf) => buf,
+                None => break,
+            };
+
+            let len = {
+                let src = &buf.get_ref()[buf.position() as usize..];
+                let len = cmp::min(src.len(), dst.len() - n);
+
+                for (dst, src) in dst[n..n + len].iter_mut().zip(src) {
+                    dst.write(*src);
+                }
+
+                len
+            };
+
+            buf.advance(len);
+            n += len;
+
+            if !buf.has_remaining() {
+                let _ = self.data.pop_front();
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Reads sequenced data into `dst`, advancing it past the data read
+    pub fn read_buf<B: BufMut>(&mut self, dst: &mut B) -> io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = 0;
+
+        while dst.has_remaining_mut() && self.is_readable() {
+            // The chunk may not be initialized, it is only written through
+            // `MaybeUninit`
+            let chunk = unsafe { dst.bytes_mut() as *mut [u8] as *mut [MaybeUninit<u8>] };
+            let len = self.read_uninit(unsafe { &mut *chunk })?;
+
+            // `read_uninit` initialized the first `len` bytes
+            unsafe { dst.advance_mut(len) };
+            n += len;
+        }
+
+        Ok(n)
+    }
+
+    /// Copies sequenced data into `dst` without consuming it
+    pub fn peek(&self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let mut n = 0;
+
+        for buf in &self.data {
+            let src = &buf.get_ref()[buf.position() as usize..];
+            let len = cmp::min(src.len(), dst.len() - n);
+
+            dst[n..n + len].copy_from_slice(&src[..len]);
+            n += len;
+
+            if n == dst.len() {
+                break;
+            }
+        }
+
+        Ok(n)
+    }
+
+    /// Discards the data waiting to be read and the packets held until a gap
+    /// fills. The ack_nr is left as is.
+    pub fn discard(&mut self) {
+        self.data.clear();
+
+        for slot in self.packets.iter_mut() {
+            *slot = None;
+        }
+    }
+
     pub fn is_readable(&self) -> bool {
         !self.data.is_empty()
     }
 
+    /// Returns the number of bytes the peer may send, the space left in the
+    /// buffer.
     pub fn local_window(&self) -> usize {
-        let pending = self.bytes_pending();
+        let buffered = self.bytes_buffered();
 
-        if pending >= MAX_WINDOW_SIZE {
+        if buffered >= self.capacity {
             0
         } else {
-            MAX_WINDOW_SIZE - pending
+            self.capacity - buffered
         }
     }
 
+    /// Returns the number of bytes buffered, whether waiting to be read or
+    /// held until a gap fills.
+    pub fn bytes_buffered(&self) -> usize {
+        let held: usize = self.packets.iter()
+            .filter_map(|p| p.as_ref())
+            .map(|p| p.payload().len())
+            .sum();
+
+        self.bytes_pending() + held
+    }
+
     pub fn bytes_pending(&self) -> usize {
         self.data.iter()
-            .map(|p| p.get_ref().len())
+            .map(|p| p.get_ref().len() - p.position() as usize)
             .sum()
     }
 
+    /// Returns true if `seq_nr` was already consumed, the peer retransmitted
+    /// it.
+    pub fn is_consumed(&self, seq_nr: u16) -> bool {
+        match self.ack_nr {
+            Some(ack_nr) => seq::wrapping_le(seq_nr, ack_nr),
+            None => false,
+        }
+    }
+
+    /// Returns the number of payload bytes received in order, counted from
+    /// the start of the stream
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the number of contiguous runs of packets held until a gap
+    /// fills, along with the number of packets missing before the first run.
+    pub fn gaps(&self) -> (usize, usize) {
+        let ack_nr = match self.ack_nr {
+            Some(ack_nr) => ack_nr,
+            None => return (0, 0),
+        };
+
+        let mut ranges = 0;
+        let mut first_gap = 0;
+        let mut prev_held = false;
+
+        for i in 1..(MAX_DELTA_SEQ + 1) {
+            let seq_nr = ack_nr.wrapping_add(i as u16);
+            let held = self.packets[seq_nr as usize % MAX_DELTA_SEQ].as_ref()
+                .map(|p| p.seq_nr() == seq_nr)
+                .unwrap_or(false);
+
+            if held && !prev_held {
+                if ranges == 0 {
+                    first_gap = i - 1;
+                }
+
+                ranges += 1;
+            }
+
+            prev_held = held;
+        }
+
+        (ranges, first_gap)
+    }
+
+    fn num_held(&self) -> usize {
+        self.packets.iter().filter(|p| p.is_some()).count()
+    }
+
     pub fn set_initial_ack_nr(&mut self, ack_nr: u16) {
         // This is the starting point
         self.ack_nr = Some(ack_nr);
@@ -176,14 +409,8 @@ impl InQueue {
     }
 }
 
+/// Returns true if `seq_nr` is one of the `MAX_DELTA_SEQ` packets following
+/// `ack_nr`. Packets at or before `ack_nr` have already been processed.
 fn in_range(ack_nr: u16, seq_nr: u16) -> bool {
-    let upper = ack_nr.wrapping_add(MAX_DELTA_SEQ as u16);
-
-    if upper > ack_nr {
-        // Non wrapping case
-        seq_nr >= ack_nr && seq_nr < upper
-    } else {
-        // Wrapping case
-        seq_nr >= ack_nr || seq_nr < upper
-    }
+    seq::in_window(ack_nr.wrapping_add(1), MAX_DELTA_SEQ, seq_nr)
 }
@@ -0,0 +1,72 @@
+//! Read and write timeouts
+//!
+//! A stream's reads and writes never block, a timeout bounds how long they
+//! keep returning `WouldBlock`. The deadline is armed by the first attempt
+//! that would block and cleared once an attempt makes progress. When the
+//! socket is ticked past the deadline, the task or poll waiting on the stream
+//! is woken and the next attempt fails with `TimedOut`.
+
+use std::time::{Duration, Instant};
+
+/// Tracks the timeout of one direction of a stream
+#[derive(Debug, Default)]
+pub struct IoTimeout {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    expired: bool,
+}
+
+impl IoTimeout {
+    pub fn get(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Sets the timeout, which applies from the next attempt that would block
+    pub fn set(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.clear();
+    }
+
+    /// Called when an attempt would block. Returns `true` if the attempt must
+    /// fail with `TimedOut`, arming the deadline otherwise.
+    pub fn would_block(&mut self, now: Instant) -> bool {
+        if self.poll_expired(now) || self.expired {
+            self.clear();
+            return true;
+        }
+
+        if self.deadline.is_none() {
+            self.deadline = self.timeout.map(|timeout| now + timeout);
+        }
+
+        false
+    }
+
+    /// Called when an attempt made progress
+    pub fn clear(&mut self) {
+        self.deadline = None;
+        self.expired = false;
+    }
+
+    /// Returns `true` if the deadline passed since the last call. The blocked
+    /// task must then be woken to observe the error.
+    pub fn poll_expired(&mut self, now: Instant) -> bool {
+        match self.deadline {
+            Some(at) if now >= at => {
+                self.deadline = None;
+                self.expired = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns `true` once the deadline passed, until an attempt observes it
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
@@ -6,25 +6,82 @@ extern crate slab;
 extern crate rand;
 extern crate byteorder;
 
+#[cfg(unix)]
+extern crate libc;
+
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "futures-io")]
+extern crate futures_io;
+
+#[cfg(feature = "futures-io")]
+extern crate futures_core;
+
+#[cfg(feature = "futures-io")]
+mod async_io;
+
+#[cfg(feature = "tokio")]
+extern crate tokio as tokio_rt;
+
+#[cfg(all(feature = "tokio", unix))]
+pub mod tokio;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "serde")]
+mod serialize;
+
+mod allocs;
+mod builder;
+mod config;
+mod congestion;
 mod delays;
+mod driver;
+mod gate;
 mod in_queue;
+mod io_timeout;
+mod mtu;
 mod out_queue;
-mod packet;
+mod path_cache;
+mod policy;
+mod selftest;
+mod seq;
 mod socket;
+mod sockopt;
+mod split;
+mod state;
+mod stats;
+mod timestamp;
 mod util;
+mod vectors;
+
+pub mod blocking;
+pub mod packet;
+pub mod tuning;
 
 #[cfg(test)]
 extern crate env_logger;
 
+#[cfg(all(test, feature = "futures-io"))]
+extern crate futures_util;
+
 #[cfg(test)]
 mod test;
 
-pub use socket::{UtpSocket, UtpStream, UtpListener};
+pub use builder::Builder;
+pub use config::UtpConfig;
+pub use congestion::{CongestionControl, Ack, Ledbat, FixedWindow};
+pub use driver::UtpDriver;
+pub use gate::{TransmitGate, Transmit, Admission};
+pub use path_cache::PathInfo;
+pub use policy::{PeerPolicy, Verdict, SlowPeerPolicy, UnknownConnection};
+pub use selftest::{selftest, SelfTest, Check};
+pub use socket::{UtpSocket, UtpStream, UtpListener, Incoming};
+pub use split::{ReadHalf, WriteHalf, OwnedReadHalf, OwnedWriteHalf};
+pub use stats::{Stats, Summary, Stall, DriverStats, DropReason};
+pub use timestamp::{TimestampSource, InstantTimestamps};
 
-// max window size
-const MAX_WINDOW_SIZE: usize = 64 * 1_024;
-const MAX_DELTA_SEQ: usize = 32;
+const MAX_DELTA_SEQ: usize = tuning::REORDER_BUFFER_SIZE;
 const TIMESTAMP_MASK: u32 = 0xFFFFFFFF;
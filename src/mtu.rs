@@ -0,0 +1,123 @@
+//! Path MTU discovery
+//!
+//! Packets start out limited to the configured max packet size. Once
+//! connected, a single larger DATA packet at a time is sent as a probe. The
+//! probe size is picked by binary search between the largest packet size known
+//! to get through (the floor) and the smallest size known not to (the
+//! ceiling). An acked probe raises the floor, a lost probe lowers the ceiling.
+//!
+//! If packets at the floor size repeatedly time out, the floor drops to
+//! `MIN_MTU_PACKET_SIZE` and the search starts over.
+//!
+//! Probes are sent with the "don't fragment" bit set, so that oversized probes
+//! are dropped instead of getting through as fragments. Other packets,
+//! including retransmitted probes, may be fragmented.
+//!
+//! A probe the OS refuses to send as too large never left the host, and its
+//! payload is split into packets of the floor size. A probe lost on the way
+//! is retransmitted as is: the peer may have received it after all, and as
+//! uTP numbers packets rather than bytes, splitting it would duplicate data.
+
+use tuning::MIN_MTU_PACKET_SIZE;
+
+#[derive(Debug)]
+pub struct Mtu {
+    // Largest packet size known to get through
+    floor: usize,
+
+    // Smallest packet size known not to get through, minus one
+    ceiling: usize,
+
+    // Outstanding probe
+    probe: Option<Probe>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    seq_nr: u16,
+    size: usize,
+}
+
+// The search stops once the floor and ceiling are this close
+const SEARCH_GRANULARITY: usize = 16;
+
+impl Mtu {
+    pub fn new(floor: usize, ceiling: usize) -> Mtu {
+        Mtu {
+            floor,
+            ceiling,
+            probe: None,
+        }
+    }
+
+    /// Size of regular packets, including the header
+    pub fn packet_size(&self) -> usize {
+        self.floor
+    }
+
+    /// Smallest packet size known not to get through, minus one
+    pub fn ceiling(&self) -> usize {
+        self.ceiling
+    }
+
+    /// Size of the next probe, if one should be sent
+    pub fn probe_size(&self) -> Option<usize> {
+        if self.probe.is_some() || self.ceiling < self.floor + SEARCH_GRANULARITY {
+            return None;
+        }
+
+        Some((self.floor + self.ceiling).div_ceil(2))
+    }
+
+    /// Track a packet of `size` bytes that was queued with `seq_nr`
+    pub fn push(&mut self, seq_nr: u16, size: usize) {
+        if size > self.floor && self.probe.is_none() {
+            trace!("mtu probe; seq_nr={}; size={}", seq_nr, size);
+
+            self.probe = Some(Probe {
+                seq_nr,
+                size,
+            });
+        }
+    }
+
+    /// Returns true if the packet is the outstanding probe
+    pub fn is_probe(&self, seq_nr: u16) -> bool {
+        self.probe.as_ref().map(|p| p.seq_nr == seq_nr).unwrap_or(false)
+    }
+
+    /// The packet has been acked
+    pub fn acked(&mut self, seq_nr: u16) {
+        if self.is_probe(seq_nr) {
+            let probe = self.probe.take().unwrap();
+            self.floor = probe.size;
+
+            trace!("mtu probe acked; floor={}; ceiling={}", self.floor, self.ceiling);
+        }
+    }
+
+    /// The packet has been lost. Returns true if it was the probe.
+    pub fn lost(&mut self, seq_nr: u16) -> bool {
+        if !self.is_probe(seq_nr) {
+            return false;
+        }
+
+        let probe = self.probe.take().unwrap();
+        self.ceiling = probe.size - 1;
+
+        trace!("mtu probe lost; floor={}; ceiling={}", self.floor, self.ceiling);
+
+        true
+    }
+
+    /// A packet of `size` bytes has timed out after being sent `num_sends`
+    /// times.
+    pub fn timed_out(&mut self, size: usize, num_sends: u32) {
+        if num_sends >= 2 && size <= self.floor && self.floor > MIN_MTU_PACKET_SIZE {
+            self.ceiling = self.floor - 1;
+            self.floor = MIN_MTU_PACKET_SIZE;
+
+            trace!("mtu reset; floor={}; ceiling={}", self.floor, self.ceiling);
+        }
+    }
+}
@@ -1,17 +1,35 @@
 //! Queue of outgoing packets.
 
-use {util, MAX_WINDOW_SIZE};
-use packet::{self, Packet, HEADER_LEN};
+use {allocs, seq, util};
+use congestion::{Ack, CongestionControl};
+use mtu::Mtu;
+use path_cache::PathInfo;
+use packet::{self, Packet, SelectiveAck, HEADER_LEN};
+use stats::Stats;
+use timestamp::{TimestampSource, InstantTimestamps};
+use tuning::{
+    MAX_WINDOW_SIZE,
+    MAX_PACKET_SIZE,
+    MAX_PROBE_PACKET_SIZE,
+    MIN_PACKET_SIZE,
+    MAX_PACKETS_IN_FLIGHT,
+    INITIAL_TIMEOUT_MS,
+    MAX_ACK_DELAY_MS,
+    ACK_EVERY_PACKETS,
+    MIN_TIMEOUT_MS,
+    MAX_TIMEOUT_MS,
+    MAX_WINDOW_PROBE_INTERVAL_MS,
+    DUPLICATE_ACKS_BEFORE_RESEND,
+    ACK_NR_ALLOWED_WINDOW,
+};
+
+use bytes::{Buf, Bytes};
 
 use std::{cmp, io};
+use std::io::IoSlice;
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
-// TODO:
-//
-// * Nagle check, don't flush the last data packet if there is in-flight data
-//   and it is too small.
-
 #[derive(Debug)]
 pub struct OutQueue {
     // queued packets
@@ -23,13 +41,82 @@ pub struct OutQueue {
     rtt: u64,
     rtt_variance: i64,
 
-    // Max number of bytes that we can have in-flight to the peer w/o acking.
-    // This number dynamically changes to handle control flow.
-    max_window: u32,
+    // Determines the max number of bytes that we can have in-flight to the
+    // peer w/o acking.
+    congestion: Box<dyn CongestionControl>,
+
+    // Timestamps sent with each packet
+    timestamps: Box<dyn TimestampSource>,
 
     // Peer's window. This is the number of bytes that it has locally but not
     // acked
     peer_window: u32,
+
+    // True when the last call to `next` held back data because of the peer's
+    // window rather than the congestion window
+    peer_window_limited: bool,
+
+    // While the peer's window is too small for the next packet and nothing is
+    // in-flight, no ACK will tell us when it reopens. The packet is sent
+    // anyway at this instant, as a window probe.
+    window_probe_at: Option<Instant>,
+
+    // Number of window probes sent since the peer's window last grew
+    window_probes: u32,
+
+    // When false, small writes are coalesced and the last data packet is held
+    // back while it is smaller than a full packet and other data is in-flight
+    // (Nagle's algorithm).
+    nodelay: bool,
+
+    // When true, packets are spread over the round trip time instead of being
+    // sent in a burst.
+    pacing: bool,
+
+    // Path MTU discovery, determines the size of outbound packets
+    mtu: Mtu,
+    mtu_probing: bool,
+
+    // Smallest packet that writes are split into to fill the window
+    min_packet_size: usize,
+
+    // Max number of packets in the queue, regardless of their size
+    max_packets: usize,
+
+    // Max number of bytes in the queue
+    max_window_size: usize,
+
+    // Timeout used until a packet is received from the peer, and the lower
+    // bound of the one computed from the round trip time, in milliseconds
+    initial_timeout: u64,
+    min_timeout: u64,
+
+    // Upper bound of the adaptive ACK delay
+    max_ack_delay: Duration,
+
+    // When false, every received packet is acked right away
+    delayed_ack: bool,
+
+    // When set, a keep-alive is sent after the connection has not sent
+    // anything for this long
+    keepalive: Option<Duration>,
+
+    // Number of bytes written to the queue that were cumulatively acked. Unlike
+    // `bytes_acked`, this excludes selectively acked data.
+    ack_offset: u64,
+
+    // Counters reported by `stats`
+    bytes_acked: u64,
+    packets_lost: u64,
+    timeouts: u64,
+
+    // Number of times the timeout expired since the peer last acked a packet
+    consecutive_timeouts: u32,
+
+    // Exponent the timeout is backed off by. Following Karn's algorithm, it is
+    // only reset once a packet that was not retransmitted is acked, as acks
+    // for retransmitted packets do not give a valid RTT sample.
+    backoff: u32,
 }
 
 #[derive(Debug)]
@@ -39,6 +126,10 @@ struct State {
     // Sequence number for the next packet
     seq_nr: u16,
 
+    // Sequence number of the last packet sent, not counting retransmissions.
+    // The peer cannot ack anything past it.
+    last_sent_seq_nr: u16,
+
     // Sequence number of the last locally acked packet (aka, read)
     local_ack: Option<u16>,
 
@@ -55,6 +146,39 @@ struct State {
     // Difference between the `timestamp` specified by the last incoming packet
     // and the current time.
     their_delay: u32,
+
+    // Number of packets sent, and how many of those were retransmissions
+    packets_sent: u64,
+    packets_resent: u64,
+
+    // Number of STATE packets sent, and how many ACKs were carried by DATA or
+    // FIN packets instead
+    acks_sent: u64,
+    acks_piggybacked: u64,
+
+    // When pacing, the next packet may not be sent before this instant
+    next_send_at: Option<Instant>,
+
+    // A STATE packet acking the received data is due at this instant
+    ack_due_at: Option<Instant>,
+
+    // The local window reopened after being too small for the peer to send a
+    // full packet. The peer must be told, even when there is nothing to ack.
+    window_update: bool,
+
+    // The peer retransmitted a packet that was already acked, the ACK must
+    // have been lost. A STATE is sent right away, even if the ACK did not
+    // change.
+    ack_required: bool,
+
+    // The last time any packet was sent
+    last_sent_at: Option<Instant>,
+
+    // The last time a packet from the queue was sent
+    data_sent_at: Option<Instant>,
+
+    // Number of bytes sent and neither acked nor considered lost
+    in_flight: usize,
 }
 
 #[derive(Debug)]
@@ -63,11 +187,16 @@ struct Entry {
     num_sends: u32,
     last_sent_at: Option<Instant>,
     acked: bool,
+    // Sent without waiting for more data to coalesce
+    pushed: bool,
 }
 
 pub struct Next<'a> {
     item: Item<'a>,
     state: &'a mut State,
+    now: Instant,
+    pace: Option<Duration>,
+    probe: bool,
 }
 
 enum Item<'a> {
@@ -75,14 +204,6 @@ enum Item<'a> {
     State(Packet),
 }
 
-// Max size of a UDP packet... ideally this will be dynamically discovered using
-// MTU.
-const MAX_PACKET_SIZE: usize = 1_400;
-const MIN_PACKET_SIZE: usize = 150;
-
-const MAX_DATA_SIZE: usize = MAX_PACKET_SIZE - HEADER_LEN;
-const MIN_DATA_SIZE: usize = MIN_PACKET_SIZE - HEADER_LEN;
-
 const MICROS_PER_SEC: u32 = 1_000_000;
 const NANOS_PER_MS: u32 = 1_000_000;
 const NANOS_PER_MICRO: u32 = 1_000;
@@ -91,24 +212,59 @@ impl OutQueue {
     /// Create a new `OutQueue` with the specified `seq_nr` and `ack_nr`
     pub fn new(connection_id: u16,
                seq_nr: u16,
-               local_ack: Option<u16>) -> OutQueue
+               local_ack: Option<u16>,
+               congestion: Box<dyn CongestionControl>,
+               now: Instant) -> OutQueue
     {
         OutQueue {
             packets: VecDeque::new(),
             state: State {
-                connection_id: connection_id,
-                seq_nr: seq_nr,
-                local_ack: local_ack,
+                connection_id,
+                seq_nr,
+                last_sent_seq_nr: seq_nr,
+                local_ack,
                 last_ack: None,
                 local_window: MAX_WINDOW_SIZE as u32,
-                created_at: Instant::now(),
+                created_at: now,
                 their_delay: 0,
+                packets_sent: 0,
+                packets_resent: 0,
+                acks_sent: 0,
+                acks_piggybacked: 0,
+                next_send_at: None,
+                ack_due_at: None,
+                window_update: false,
+                ack_required: false,
+                last_sent_at: None,
+                data_sent_at: None,
+                in_flight: 0,
             },
             rtt: 0,
             rtt_variance: 0,
-            // Start the max window at the packet size
-            max_window: MAX_PACKET_SIZE as u32,
+            congestion,
+            timestamps: Box::new(InstantTimestamps::new(now)),
             peer_window: MAX_WINDOW_SIZE as u32,
+            peer_window_limited: false,
+            window_probe_at: None,
+            window_probes: 0,
+            nodelay: false,
+            pacing: false,
+            mtu: Mtu::new(MAX_PACKET_SIZE, MAX_PROBE_PACKET_SIZE),
+            mtu_probing: false,
+            min_packet_size: MIN_PACKET_SIZE,
+            max_ack_delay: Duration::from_millis(MAX_ACK_DELAY_MS),
+            delayed_ack: true,
+            keepalive: None,
+            max_packets: MAX_PACKETS_IN_FLIGHT,
+            max_window_size: MAX_WINDOW_SIZE,
+            initial_timeout: INITIAL_TIMEOUT_MS,
+            min_timeout: MIN_TIMEOUT_MS,
+            ack_offset: 0,
+            bytes_acked: 0,
+            packets_lost: 0,
+            timeouts: 0,
+            consecutive_timeouts: 0,
+            backoff: 0,
         }
     }
 
@@ -116,6 +272,20 @@ impl OutQueue {
         self.state.connection_id
     }
 
+    /// Starts the congestion window at `val` bytes. Returns `false` if data
+    /// has already been sent, in which case the window is left as is.
+    pub fn set_initial_window(&mut self, val: usize) -> bool {
+        let sent = self.bytes_acked > 0 || self.packets.iter()
+            .any(|e| e.num_sends > 0 && e.packet.ty() != packet::Type::Syn);
+
+        if sent {
+            return false;
+        }
+
+        self.congestion.set_initial_window(val);
+        true
+    }
+
     /// Returns true if the out queue is fully flushed and all packets have been
     /// ACKed.
     pub fn is_empty(&self) -> bool {
@@ -124,77 +294,462 @@ impl OutQueue {
     }
 
     /// Whenever a packet is received, the included timestamp is passed in here.
-    pub fn update_their_delay(&mut self, their_timestamp: u32) -> u32 {
-        let our_timestamp = util::as_wrapping_micros(self.state.created_at.elapsed());
+    pub fn update_their_delay(&mut self, their_timestamp: u32, now: Instant) -> u32 {
+        let our_timestamp = self.timestamp(now);
         self.state.their_delay = our_timestamp.wrapping_sub(their_timestamp);
         self.state.their_delay
     }
 
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub fn set_nodelay(&mut self, val: bool) {
+        self.nodelay = val;
+    }
+
+    /// Returns the number of bytes, counted from the start of the stream, that
+    /// the peer acked in order
+    pub fn ack_offset(&self) -> u64 {
+        self.ack_offset
+    }
+
+    /// Returns the payload of the data packets that the peer has not acked,
+    /// in order. Selectively acked packets are included, the peer may not
+    /// have delivered them yet.
+    pub fn unacked_data(&self) -> Vec<u8> {
+        let mut ret = vec![];
+
+        for entry in &self.packets {
+            if entry.packet.ty() == packet::Type::Data {
+                ret.extend_from_slice(entry.packet.payload());
+            }
+        }
+
+        ret
+    }
+
+    /// Send the last packet without waiting for it to fill up
+    pub fn push_pending(&mut self) {
+        if let Some(entry) = self.packets.back_mut() {
+            entry.pushed = true;
+        }
+    }
+
+    pub fn set_pacing(&mut self, val: bool) {
+        self.pacing = val;
+    }
+
+    /// Returns the instant at which the next paced packet may be sent, if a
+    /// packet is waiting on it.
+    pub fn next_send_at(&self) -> Option<Instant> {
+        if !self.pacing {
+            return None;
+        }
+
+        let waiting = self.packets.iter()
+            .any(|e| e.last_sent_at.is_none() && !e.acked);
+
+        if waiting {
+            self.state.next_send_at
+        } else {
+            None
+        }
+    }
+
+    /// Sets the max size of regular packets, including the header. Path MTU
+    /// discovery probes above it.
+    pub fn set_max_packet_size(&mut self, val: usize) {
+        self.mtu = Mtu::new(val, cmp::max(val, MAX_PROBE_PACKET_SIZE));
+    }
+
+    /// Sets the smallest packet, including the header, that writes are split
+    /// into to fill the window.
+    pub fn set_min_packet_size(&mut self, val: usize) {
+        self.min_packet_size = val;
+    }
+
+    /// Sets the max number of packets in the queue. Once reached, writes wait
+    /// for packets to be acked, even when the window has room.
+    pub fn set_max_packets_in_flight(&mut self, val: usize) {
+        self.max_packets = val;
+    }
+
+    /// Sets the max number of bytes in the queue. This is also the window
+    /// assumed for the peer until it advertises its own.
+    pub fn set_max_window_size(&mut self, val: usize) {
+        self.max_window_size = val;
+        self.peer_window = cmp::min(val, u32::MAX as usize) as u32;
+    }
+
+    /// Sets the timeout used until a packet is received from the peer
+    pub fn set_initial_timeout(&mut self, val: Duration) {
+        self.initial_timeout = util::as_micros(val) / 1_000;
+    }
+
+    /// Sets the lower bound of the timeout computed from the round trip time
+    pub fn set_min_timeout(&mut self, val: Duration) {
+        self.min_timeout = util::as_micros(val) / 1_000;
+    }
+
+    pub fn set_max_ack_delay(&mut self, val: Duration) {
+        self.max_ack_delay = val;
+    }
+
+    pub fn set_delayed_ack(&mut self, val: bool) {
+        self.delayed_ack = val;
+    }
+
+    /// Time that a STATE packet may be delayed by, waiting for a DATA packet
+    /// to carry the ACK instead. This is a quarter of the round trip time, so
+    /// that ACKs on fast links are not held back disproportionately.
+    fn ack_delay(&self) -> Duration {
+        cmp::min(Duration::from_millis(self.rtt / 4), self.max_ack_delay)
+    }
+
+    /// True when the pending ACK may be held back. Once `ACK_EVERY_PACKETS`
+    /// packets are waiting on it, the ACK is sent right away.
+    fn may_delay_ack(&self) -> bool {
+        if !self.delayed_ack || self.state.window_update || self.state.ack_required {
+            return false;
+        }
+
+        match (self.state.local_ack, self.state.last_ack) {
+            (Some(local), Some(last)) => seq::distance(last, local) < ACK_EVERY_PACKETS,
+            _ => true,
+        }
+    }
+
+    /// Returns the instant at which a delayed ACK is due, if any.
+    pub fn ack_due_at(&self) -> Option<Instant> {
+        if self.state.local_ack != self.state.last_ack {
+            self.state.ack_due_at
+        } else {
+            None
+        }
+    }
+
+    pub fn set_keepalive(&mut self, val: Option<Duration>) {
+        self.keepalive = val;
+    }
+
+    /// Returns the instant at which a keep-alive is due, if any.
+    ///
+    /// Only idle connections send keep-alives. While packets are in the queue,
+    /// the peer hears from us anyway.
+    pub fn keepalive_at(&self) -> Option<Instant> {
+        if !self.packets.is_empty() || self.state.local_ack.is_none() {
+            return None;
+        }
+
+        match (self.keepalive, self.state.last_sent_at) {
+            (Some(interval), Some(at)) => Some(at + interval),
+            _ => None,
+        }
+    }
+
+    /// Returns what was learned about the path, `None` if the peer never
+    /// responded.
+    pub fn path_info(&self) -> Option<PathInfo> {
+        self.state.local_ack.map(|_| PathInfo {
+            rtt: self.rtt,
+            rtt_variance: self.rtt_variance,
+            packet_size: self.mtu.packet_size(),
+            mtu_ceiling: self.mtu.ceiling(),
+            packets_sent: self.state.packets_sent,
+            packets_resent: self.state.packets_resent,
+        })
+    }
+
+    /// Starts out with the path learned by a previous connection
+    pub fn set_path_info(&mut self, info: &PathInfo) {
+        self.rtt = info.rtt;
+        self.rtt_variance = info.rtt_variance;
+        self.mtu = Mtu::new(info.packet_size, info.mtu_ceiling);
+    }
+
+    pub fn set_mtu_probing(&mut self, val: bool) {
+        self.mtu_probing = val;
+    }
+
+    /// Sets the source of the timestamps sent with each packet
+    pub fn set_timestamp_source(&mut self, val: Box<dyn TimestampSource>) {
+        self.timestamps = val;
+    }
+
+    /// Max number of payload bytes in a regular packet
+    pub fn max_data_size(&self) -> usize {
+        self.mtu.packet_size() - HEADER_LEN
+    }
+
+    /// Update the peer's advertised window. The peer may shrink its window
+    /// below the number of bytes in-flight, in which case no new data is sent
+    /// until enough of it has been acked.
     pub fn set_peer_window(&mut self, val: u32) {
+        if (val as usize) < self.in_flight() {
+            trace!("peer window below in-flight; window={}; in_flight={}", val, self.in_flight());
+        }
+
+        // The window reopened, probes are no longer needed
+        if val > self.peer_window {
+            self.window_probe_at = None;
+            self.window_probes = 0;
+        }
+
         self.peer_window = val;
     }
 
-    pub fn set_their_ack(&mut self, ack_nr: u16, now: Instant) -> Option<(usize, Duration)> {
+    /// Returns the instant at which a window probe is due, if any.
+    pub fn window_probe_at(&self) -> Option<Instant> {
+        if self.packets.is_empty() {
+            return None;
+        }
+
+        self.window_probe_at
+    }
+
+    /// Time between window probes, starting at the regular timeout and
+    /// doubling with each probe.
+    fn window_probe_interval(&self) -> Duration {
+        let timeout = self.rto();
+        let interval = timeout << cmp::min(self.window_probes, 16);
+
+        Duration::from_millis(cmp::min(interval, MAX_WINDOW_PROBE_INTERVAL_MS))
+    }
+
+    /// True when sending is held back by the peer's window. The congestion
+    /// window should not grow while this is the case, the network is not
+    /// what is limiting the connection.
+    pub fn is_peer_window_limited(&self) -> bool {
+        self.peer_window_limited
+    }
+
+    /// Returns true if `ack_nr` acks a packet that was sent, and lags at most
+    /// `ACK_NR_ALLOWED_WINDOW` packets behind the last packet acked.
+    pub fn is_valid_ack(&self, ack_nr: u16) -> bool {
+        // Last packet cumulatively acked by the peer
+        let acked = match self.packets.front() {
+            Some(entry) => entry.packet.seq_nr().wrapping_sub(1),
+            None => self.state.seq_nr,
+        };
+
+        let start = acked.wrapping_sub(ACK_NR_ALLOWED_WINDOW);
+        let len = seq::distance(start, self.state.last_sent_seq_nr) as usize + 1;
+
+        seq::in_window(start, len, ack_nr)
+    }
+
+    /// Applies an ACK from the peer, which must pass `is_valid_ack`
+    pub fn set_their_ack(&mut self,
+                         ack_nr: u16,
+                         selective_ack: Option<SelectiveAck>,
+                         now: Instant) -> Option<(usize, Duration)>
+    {
         let mut acked_bytes = 0;
         let mut min_rtt = None;
 
         loop {
             let pop = self.packets.front()
-                .map(|entry| {
-                    let seq_nr = entry.packet.seq_nr();
-
-                    let lower = ack_nr.wrapping_sub(ack_nr);
-
-                    if lower < ack_nr {
-                        seq_nr > lower && seq_nr <= ack_nr
-                    } else {
-                        seq_nr > lower || seq_nr <= ack_nr
-                    }
-                })
+                // The packet is acked if its seq_nr is at or before ack_nr
+                .map(|entry| seq::wrapping_le(entry.packet.seq_nr(), ack_nr))
                 .unwrap_or(false);
 
             if !pop {
-                return min_rtt.map(|rtt| (acked_bytes, rtt));
+                break;
             }
 
             // The packet has been acked..
             let p = self.packets.pop_front().unwrap();
 
+            self.ack_offset += p.packet.payload().len() as u64;
+
+            if p.acked {
+                // Already accounted for by a selective ACK
+                continue;
+            }
+
+            self.consecutive_timeouts = 0;
+
+            self.mtu.acked(p.packet.seq_nr());
+
             // If the packet has a payload, track the number of bytes sent
             acked_bytes += p.packet.payload().len();
 
-            if p.last_sent_at.is_none() {
-                // We timed out, but the ack arrived after the timeout... the
-                // packet is ACKed but don't use it for congestion control
+            match p.last_sent_at {
+                Some(last_sent_at) => {
+                    self.state.in_flight -= p.packet.encoded_len();
+                    self.update_rtt(last_sent_at, p.num_sends, now, &mut min_rtt);
+                }
+                None => {
+                    // We timed out, but the ack arrived after the timeout...
+                    // the packet is ACKed but don't use it for congestion
+                    // control
+                }
+            }
+        }
+
+        if let Some(selective_ack) = selective_ack {
+            self.process_selective_ack(ack_nr, selective_ack, now, &mut acked_bytes, &mut min_rtt);
+        }
+
+        self.bytes_acked += acked_bytes as u64;
+
+        min_rtt.map(|rtt| (acked_bytes, rtt))
+    }
+
+    /// Mark packets included in the selective ACK as acked and schedule the
+    /// gaps for retransmission.
+    fn process_selective_ack(&mut self,
+                             ack_nr: u16,
+                             selective_ack: SelectiveAck,
+                             now: Instant,
+                             acked_bytes: &mut usize,
+                             min_rtt: &mut Option<Duration>)
+    {
+        let first_seq_nr = match self.packets.front() {
+            Some(entry) => entry.packet.seq_nr(),
+            None => return,
+        };
+
+        // The first bit in the bitfield represents `ack_nr + 2`
+        let base = ack_nr.wrapping_add(2);
+
+        for offset in 0..selective_ack.len() {
+            if !selective_ack.is_acked(offset) {
                 continue;
             }
 
-            // Calculate the RTT for the packet.
-            let packet_rtt = now.duration_since(p.last_sent_at.unwrap());
+            // Queued packets have consecutive sequence numbers, as in the
+            // `InQueue` slots
+            let seq_nr = base.wrapping_add(offset as u16);
+            let i = seq::distance(first_seq_nr, seq_nr) as usize;
 
-            min_rtt = Some(min_rtt
-                .map(|curr| cmp::min(curr, packet_rtt))
-                .unwrap_or(packet_rtt));
+            if i >= self.packets.len() {
+                continue;
+            }
+
+            let (last_sent_at, num_sends) = {
+                let entry = &mut self.packets[i];
+                debug_assert_eq!(entry.packet.seq_nr(), seq_nr);
 
-            if p.num_sends == 1 {
-                // Use the packet to update rtt & rtt_variance
-                let packet_rtt = util::as_ms(now.duration_since(p.last_sent_at.unwrap()));
-                let delta = (self.rtt as i64 - packet_rtt as i64).abs();
+                if entry.acked {
+                    continue;
+                }
 
-                self.rtt_variance += (delta - self.rtt_variance) / 4;
+                entry.acked = true;
+                *acked_bytes += entry.packet.payload().len();
+                self.consecutive_timeouts = 0;
 
-                if self.rtt >= packet_rtt {
-                    self.rtt -= (self.rtt - packet_rtt) / 8;
-                } else {
-                    self.rtt += (packet_rtt - self.rtt) / 8;
+                if entry.last_sent_at.is_some() {
+                    self.state.in_flight -= entry.packet.encoded_len();
                 }
+
+                self.mtu.acked(entry.packet.seq_nr());
+
+                (entry.last_sent_at, entry.num_sends)
+            };
+
+            if let Some(last_sent_at) = last_sent_at {
+                self.update_rtt(last_sent_at, num_sends, now, min_rtt);
+            }
+        }
+
+        // A packet is considered lost once enough packets sent after it have
+        // been acked, i.e. it was sent before the acked packet with the Nth
+        // latest send time. Lost packets are scheduled for retransmission by
+        // clearing `last_sent_at`.
+        let mut latest = [None; DUPLICATE_ACKS_BEFORE_RESEND];
+
+        for entry in self.packets.iter().filter(|e| e.acked) {
+            let mut sent_at = match entry.last_sent_at {
+                Some(sent_at) => sent_at,
+                None => continue,
+            };
+
+            // Keep `latest` sorted, latest first
+            for slot in latest.iter_mut() {
+                if slot.map(|s| sent_at > s).unwrap_or(true) {
+                    sent_at = match slot.replace(sent_at) {
+                        Some(prev) => prev,
+                        None => break,
+                    };
+                }
+            }
+        }
+
+        let threshold = match latest[DUPLICATE_ACKS_BEFORE_RESEND - 1] {
+            Some(threshold) => threshold,
+            None => return,
+        };
+
+        let mut lost = false;
+
+        for entry in self.packets.iter_mut() {
+            match entry.last_sent_at {
+                Some(sent_at) if !entry.acked && sent_at < threshold => {}
+                _ => continue,
+            }
+
+            trace!("packet lost; seq_nr={:?}", entry.packet.seq_nr());
+            entry.last_sent_at = None;
+            self.state.in_flight -= entry.packet.encoded_len();
+            self.packets_lost += 1;
+
+            // A lost MTU probe is not a sign of congestion
+            if !self.mtu.lost(entry.packet.seq_nr()) {
+                lost = true;
+            }
+        }
+
+        if lost {
+            self.congestion.on_loss();
+        }
+    }
+
+    fn update_rtt(&mut self,
+                  last_sent_at: Instant,
+                  num_sends: u32,
+                  now: Instant,
+                  min_rtt: &mut Option<Duration>)
+    {
+        // Calculate the RTT for the packet.
+        let packet_rtt = now.duration_since(last_sent_at);
+
+        *min_rtt = Some(min_rtt
+            .map(|curr| cmp::min(curr, packet_rtt))
+            .unwrap_or(packet_rtt));
+
+        if num_sends == 1 {
+            self.backoff = 0;
+
+            // Use the packet to update rtt & rtt_variance
+            let packet_rtt = util::as_ms(packet_rtt);
+            let delta = (self.rtt as i64 - packet_rtt as i64).abs();
+
+            self.rtt_variance += (delta - self.rtt_variance) / 4;
+
+            if self.rtt >= packet_rtt {
+                self.rtt -= (self.rtt - packet_rtt) / 8;
+            } else {
+                self.rtt += (packet_rtt - self.rtt) / 8;
             }
         }
     }
 
     pub fn set_local_window(&mut self, val: usize) {
         assert!(val <= ::std::u32::MAX as usize);
-        self.state.local_window = val as u32;
+
+        let threshold = self.mtu.packet_size() as u32;
+        let val = val as u32;
+
+        // A peer that saw a window smaller than a packet stops sending, so
+        // let it know as soon as there is room again.
+        if self.state.local_window < threshold && val >= threshold {
+            trace!("window reopened; prev={}; wnd={}", self.state.local_window, val);
+            self.state.window_update = true;
+        }
+
+        self.state.local_window = val;
     }
 
     /// Update peer ack
@@ -212,6 +767,12 @@ impl OutQueue {
         self.state.local_ack = Some(val);
     }
 
+    /// Sends a STATE right away, repeating the current ACK. The peer
+    /// retransmitted a packet because the previous one was lost.
+    pub fn resend_ack(&mut self) {
+        self.state.ack_required = true;
+    }
+
     /// Returns the socket timeout based on an aggregate of packet round trip
     /// times.
     pub fn socket_timeout(&self) -> Option<Duration> {
@@ -219,18 +780,33 @@ impl OutQueue {
             return None;
         }
 
-        // Until a packet is received from the peer, the timeout is 1 second.
-        if self.state.local_ack.is_none() {
-            return Some(Duration::from_secs(1));
-        }
+        // Until a packet is received from the peer, use the initial timeout.
+        let timeout = if self.state.local_ack.is_none() {
+            self.initial_timeout
+        } else {
+            self.rto()
+        };
 
-        let timeout = self.rtt as i64 + self.rtt_variance;
+        // Back off until a new RTT sample is taken
+        let timeout = timeout << self.backoff;
 
-        if timeout > 500 {
-            Some(Duration::from_millis(timeout as u64))
-        } else {
-            Some(Duration::from_millis(500))
-        }
+        Some(Duration::from_millis(cmp::min(timeout, MAX_TIMEOUT_MS)))
+    }
+
+    /// Returns the smoothed round trip time, in milliseconds
+    pub fn rtt(&self) -> u64 {
+        self.rtt
+    }
+
+    /// Returns the mean deviation of the round trip time, in milliseconds
+    pub fn rtt_variance(&self) -> u64 {
+        self.rtt_variance as u64
+    }
+
+    /// Returns the number of times the timeout expired since the peer last
+    /// acked a packet.
+    pub fn consecutive_timeouts(&self) -> u32 {
+        self.consecutive_timeouts
     }
 
     /// Push an outbound packet into the queue
@@ -249,56 +825,139 @@ impl OutQueue {
         // Set the sequence number
         packet.set_seq_nr(self.state.seq_nr);
 
+        allocs::push_queue(&self.packets);
         self.packets.push_back(Entry {
-            packet: packet,
+            packet,
             num_sends: 0,
             last_sent_at: None,
             acked: false,
+            pushed: false,
         });
     }
 
-    pub fn next(&mut self) -> Option<Next> {
-        let ts = self.timestamp();
+    pub fn next(&mut self, now: Instant) -> Option<Next<'_>> {
+        self.decay_idle_window(now);
+
+        let ts = self.timestamp(now);
         let diff = self.state.their_delay;
         let ack = self.state.local_ack.unwrap_or(0);
         let wnd_size = self.state.local_window;
+        let ack_delay = self.ack_delay();
+        let may_delay_ack = self.may_delay_ack();
+        let keepalive_due = self.keepalive_at().map(|at| now >= at).unwrap_or(false);
+        let window_probe_interval = self.window_probe_interval();
 
         // Number of bytes in-flight
         let in_flight = self.in_flight();
+        let max_window = self.max_window();
+        let max_data_size = self.max_data_size();
+        let num_packets = self.packets.len();
+
+        // Wait for the pacing interval to elapse, pending ACKs can still be
+        // sent.
+        let paced = self.pacing && self.state.next_send_at
+            .map(|at| now < at)
+            .unwrap_or(false);
+
+        // Round trip time used for pacing, zero when disabled
+        let pacing_rtt = if self.pacing { self.rtt } else { 0 };
 
-        for entry in &mut self.packets {
-            // The packet has been sent
-            if entry.last_sent_at.is_some() {
+        // Set when pacing holds back a packet that could otherwise be sent
+        let mut paced_data = false;
+
+        self.peer_window_limited = false;
+
+        for (i, entry) in self.packets.iter_mut().enumerate() {
+            // The packet has been sent or the peer already has it
+            if entry.last_sent_at.is_some() || entry.acked {
                 continue;
             }
 
+            // Nagle: hold back the last packet while it is not full and data
+            // is in-flight, more data may be coalesced into it.
+            if !self.nodelay && !entry.pushed && in_flight > 0 && i == num_packets - 1 &&
+                entry.packet.ty() == packet::Type::Data &&
+                entry.packet.payload().len() < max_data_size
+            {
+                break;
+            }
+
+            if paced {
+                paced_data = true;
+                break;
+            }
+
+            let peer_window = self.peer_window as usize;
+
             if in_flight > 0 {
-                let max = cmp::min(self.max_window, self.peer_window) as usize;
+                let max = cmp::min(max_window, peer_window);
 
-                // Don't send more data than the window allows
-                if in_flight + entry.packet.len() > max {
-                    return None;
+                // Don't send more data than the window allows. Pending ACKs
+                // must still go out, or a peer waiting on them stalls too.
+                if in_flight + entry.packet.encoded_len() > max {
+                    self.peer_window_limited = peer_window < max_window;
+                    break;
                 }
-            } else {
-                // Don't send more data than the window allows
-                if in_flight + entry.packet.len() > self.peer_window as usize {
-                    return None;
+            } else if entry.packet.encoded_len() > peer_window {
+                // Don't send more data than the window allows, unless a window
+                // probe is due.
+                let probe_at = *self.window_probe_at.get_or_insert(now + window_probe_interval);
+
+                if now < probe_at {
+                    self.peer_window_limited = true;
+                    break;
                 }
+
+                trace!("window probe; seq_nr={:?}; peer_window={:?}",
+                       entry.packet.seq_nr(), peer_window);
+
+                self.window_probe_at = None;
+                self.window_probes += 1;
             }
 
-            // Update timestamp
+            // Refresh the header fields. Retransmitted packets must not carry
+            // the stale values from their first transmission.
             entry.packet.set_timestamp(ts);
             entry.packet.set_timestamp_diff(diff);
             entry.packet.set_ack_nr(ack);
             entry.packet.set_wnd_size(wnd_size);
 
+            let pace = pacing_interval(pacing_rtt, max_window, entry.packet.encoded_len());
+            let probe = self.mtu.is_probe(entry.packet.seq_nr());
+
             return Some(Next {
                 item: Item::Entry(entry),
                 state: &mut self.state,
+                now,
+                pace,
+                probe,
             });
         }
 
-        if self.state.local_ack != self.state.last_ack {
+        if self.state.local_ack != self.state.last_ack ||
+            self.state.window_update ||
+            self.state.ack_required
+        {
+            // Data released by pacing before the ACK would be due carries it,
+            // even when the ACK may not be delayed otherwise.
+            if paced_data && !self.state.window_update && !self.state.ack_required {
+                let at = self.state.next_send_at.expect("paced without next_send_at");
+
+                if at <= now + ack_delay {
+                    return None;
+                }
+            }
+
+            // Give outbound data a chance to carry the ACK. Window updates
+            // are sent right away.
+            if may_delay_ack {
+                let due = *self.state.ack_due_at.get_or_insert(now + ack_delay);
+
+                if now < due {
+                    return None;
+                }
+            }
+
             trace!("ack_required; local={:?}; last={:?}; seq_nr={:?}",
                    self.state.local_ack,
                    self.state.last_ack,
@@ -316,32 +975,259 @@ impl OutQueue {
             return Some(Next {
                 item: Item::State(packet),
                 state: &mut self.state,
+                now,
+                pace: None,
+                probe: false,
+            });
+        }
+
+        if keepalive_due {
+            trace!("keepalive; seq_nr={:?}", self.state.seq_nr);
+
+            // As in libutp, the keep-alive acks the packet before the last one
+            // received. The peer sees a duplicate ACK, which is otherwise
+            // ignored, so the packet is safe to send at any time.
+            let mut packet = Packet::state();
+
+            packet.set_connection_id(self.state.connection_id);
+            packet.set_seq_nr(self.state.seq_nr);
+            packet.set_timestamp(ts);
+            packet.set_timestamp_diff(diff);
+            packet.set_ack_nr(ack.wrapping_sub(1));
+            packet.set_wnd_size(wnd_size);
+
+            return Some(Next {
+                item: Item::State(packet),
+                state: &mut self.state,
+                now,
+                pace: None,
+                probe: false,
             });
         }
 
         None
     }
 
-    pub fn max_window(&self) -> u32 {
-        self.max_window
+    /// Max number of bytes that may be in-flight, as determined by congestion
+    /// control.
+    pub fn max_window(&self) -> usize {
+        self.congestion.cwnd()
+    }
+
+    /// Apply an ACK to the congestion controller
+    pub fn on_ack(&mut self, ack: &Ack) {
+        let prev = self.max_window();
+        self.congestion.on_ack(ack);
+
+        trace!("max_window; old={:?}; new={:?}", prev, self.max_window());
+    }
+
+    /// A window measured before the connection went idle no longer reflects
+    /// the path. Nothing being in-flight, let congestion control know how many
+    /// timeouts elapsed since data was last sent.
+    fn decay_idle_window(&mut self, now: Instant) {
+        let pending = self.packets.iter()
+            .any(|e| e.last_sent_at.is_none() && !e.acked);
+
+        if self.in_flight() > 0 || !pending {
+            return;
+        }
+
+        let data_sent_at = match self.state.data_sent_at {
+            Some(data_sent_at) => data_sent_at,
+            None => return,
+        };
+
+        let periods = util::as_ms(now.duration_since(data_sent_at)) / self.rto();
+
+        if periods > 0 {
+            trace!("connection idle; periods={}", periods);
+            self.congestion.on_idle(cmp::min(periods, u32::MAX as u64) as u32);
+
+            // The idle time is accounted for
+            self.state.data_sent_at = Some(now);
+        }
+    }
+
+    /// Retransmission timeout, before backing off
+    pub fn retransmit_timeout(&self) -> Duration {
+        Duration::from_millis(self.rto())
     }
 
-    pub fn set_max_window(&mut self, val: u32) {
-        trace!("set_max_window; old={:?}; new={:?}", self.max_window, val);
-        self.max_window = val;
+    /// Returns the last time a packet was sent, if any
+    pub fn last_sent_at(&self) -> Option<Instant> {
+        self.state.last_sent_at
+    }
+
+    /// Retransmission timeout in milliseconds, before backing off
+    fn rto(&self) -> u64 {
+        cmp::max(self.rtt as i64 + self.rtt_variance, self.min_timeout as i64) as u64
     }
 
     /// The peer timed out, consider all the packets lost
     pub fn timed_out(&mut self) {
-        for entry in &mut self.packets {
+        // Nothing was in-flight, e.g. sending is held back by the peer's
+        // window. There is nothing to time out.
+        if self.in_flight() == 0 {
+            return;
+        }
+
+        // Selectively acked packets have been received and do not need to be
+        // sent again.
+        for entry in self.packets.iter_mut().filter(|e| !e.acked) {
             entry.last_sent_at = None;
+            self.mtu.lost(entry.packet.seq_nr());
+        }
+
+        self.state.in_flight = 0;
+
+        // Packets that repeatedly time out may be too large for the path
+        if let Some(entry) = self.packets.iter().find(|e| !e.acked) {
+            self.mtu.timed_out(entry.packet.encoded_len(), entry.num_sends);
         }
 
-        self.max_window = MIN_PACKET_SIZE as u32;
+        self.timeouts += 1;
+        self.consecutive_timeouts += 1;
+        self.backoff = cmp::min(self.backoff + 1, 16);
+        self.congestion.on_timeout();
+    }
+
+    /// The MTU probe could not be sent as it is larger than the path MTU known
+    /// locally. The probe never left the host, so its payload is split into
+    /// packets of the regular size, renumbering the packets queued after it.
+    /// Packets are sent in order, none of those were sent either.
+    pub fn mtu_probe_refused(&mut self) {
+        let i = {
+            let mtu = &self.mtu;
+
+            match self.packets.iter().position(|e| mtu.is_probe(e.packet.seq_nr())) {
+                Some(i) => i,
+                None => return,
+            }
+        };
+
+        let seq_nr = self.packets[i].packet.seq_nr();
+        debug_assert_eq!(self.packets[i].num_sends, 0);
+
+        self.mtu.lost(seq_nr);
+
+        let max = self.max_data_size();
+        let payload = self.packets[i].packet.clone().into_payload();
+        let extra = (payload.len().div_ceil(max) - 1) as u16;
+
+        for entry in self.packets.iter_mut().skip(i + 1) {
+            debug_assert_eq!(entry.num_sends, 0);
+
+            let seq_nr = entry.packet.seq_nr().wrapping_add(extra);
+            entry.packet.set_seq_nr(seq_nr);
+        }
+
+        self.state.seq_nr = self.state.seq_nr.wrapping_add(extra);
+
+        for n in 0..=(extra as usize) {
+            let end = cmp::min(payload.len(), (n + 1) * max);
+
+            let mut packet = Packet::data_bytes(payload.slice(n * max, end));
+            packet.set_connection_id(self.state.connection_id);
+            packet.set_seq_nr(seq_nr.wrapping_add(n as u16));
+
+            if n == 0 {
+                self.packets[i].packet = packet;
+                continue;
+            }
+
+            let pushed = self.packets[i].pushed;
+
+            allocs::push_queue(&self.packets);
+            self.packets.insert(i + n, Entry {
+                packet,
+                num_sends: 0,
+                last_sent_at: None,
+                acked: false,
+                pushed,
+            });
+        }
     }
 
     /// Push data into the outbound queue
-    pub fn write(&mut self, mut src: &[u8]) -> io::Result<usize> {
+    pub fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        let coalesce = !self.nodelay;
+        self.write_payload(src, None, coalesce)
+    }
+
+    /// Push data into the outbound queue. Packets reference segments of `src`
+    /// instead of copying them.
+    pub fn write_bytes(&mut self, src: &Bytes) -> io::Result<usize> {
+        let coalesce = !self.nodelay;
+        self.write_payload(src, Some(src), coalesce)
+    }
+
+    /// Push the data of `bufs` into the outbound queue as if it was a single
+    /// buffer. Packets span the boundaries between buffers, even when
+    /// `nodelay` is set.
+    pub fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let mut len = 0;
+
+        for buf in bufs {
+            if buf.is_empty() {
+                continue;
+            }
+
+            // The last packet holds the end of the previous buffer and has
+            // not been sent yet.
+            let coalesce = !self.nodelay || len > 0;
+
+            match self.write_payload(buf, None, coalesce) {
+                Ok(n) => {
+                    len += n;
+
+                    if n < buf.len() {
+                        break;
+                    }
+                }
+                Err(ref e) if len > 0 && e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Push the data of `src` into the outbound queue, advancing it past the
+    /// data queued. Its chunks are packetized as by `write_vectored`.
+    pub fn write_buf<B: Buf>(&mut self, src: &mut B) -> io::Result<usize> {
+        let mut len = 0;
+
+        while src.has_remaining() {
+            let coalesce = !self.nodelay || len > 0;
+
+            let (n, chunk_len) = {
+                let chunk = src.bytes();
+
+                match self.write_payload(chunk, None, coalesce) {
+                    Ok(n) => (n, chunk.len()),
+                    Err(ref e) if len > 0 && e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            };
+
+            src.advance(n);
+            len += n;
+
+            if n < chunk_len {
+                break;
+            }
+        }
+
+        Ok(len)
+    }
+
+    /// Pushes `src` into the queue, slicing payloads out of `shared` when it
+    /// holds the same data. When `coalesce` is set, `src` is first appended
+    /// to the last packet if it has not been sent yet.
+    fn write_payload(&mut self, mut src: &[u8], shared: Option<&Bytes>, coalesce: bool)
+        -> io::Result<usize>
+    {
         if src.len() == 0 {
             return Ok(0);
         }
@@ -355,18 +1241,65 @@ impl OutQueue {
 
         trace!("write; remaining={:?}; src={:?}", rem, src.len());
 
-        while rem > HEADER_LEN {
+        if coalesce {
+            // Coalesce into the last packet if it has not been sent yet
+            if let Some(entry) = self.packets.back_mut() {
+                if entry.num_sends == 0 && entry.packet.ty() == packet::Type::Data {
+                    let n = cmp::min(
+                        self.mtu.packet_size().saturating_sub(entry.packet.encoded_len()),
+                        cmp::min(src.len(), rem));
+
+                    entry.packet.extend_payload(&src[..n]);
+
+                    len += n;
+                    rem -= n;
+
+                    src = &src[n..];
+                }
+            }
+        }
+
+        // Probe for a larger MTU once connected
+        let mut probe_size = if self.mtu_probing && self.state.local_ack.is_some() {
+            self.mtu.probe_size()
+        } else {
+            None
+        };
+
+        while rem > HEADER_LEN && self.packets.len() < self.max_packets {
+            let max_size = match probe_size {
+                Some(size) if src.len() >= size - HEADER_LEN => size,
+                _ => self.mtu.packet_size(),
+            };
+
             let packet_len = cmp::min(
-                MAX_PACKET_SIZE,
+                max_size - HEADER_LEN,
                 cmp::min(src.len(), rem - HEADER_LEN));
 
             if packet_len == 0 {
                 break;
             }
 
-            let packet = Packet::data(&src[..packet_len]);
+            // Leave the rest of the window for a larger packet
+            if len > 0 && packet_len < src.len() && packet_len + HEADER_LEN < self.min_packet_size {
+                break;
+            }
+
+            let packet = match shared {
+                Some(shared) => Packet::data_bytes(shared.slice(len, len + packet_len)),
+                None => Packet::data(&src[..packet_len]),
+            };
+
             self.push(packet);
 
+            let seq_nr = self.state.seq_nr;
+            self.mtu.push(seq_nr, packet_len + HEADER_LEN);
+
+            // Only a single probe at a time
+            if self.mtu.is_probe(seq_nr) {
+                probe_size = None;
+            }
+
             len += packet_len;
             rem -= packet_len + HEADER_LEN;
 
@@ -377,8 +1310,27 @@ impl OutQueue {
     }
 
     fn remaining_capacity(&self) -> usize {
+        if self.packets.len() >= self.max_packets {
+            return 0;
+        }
+
         let cur_window = self.buffered();
-        let max = cmp::min(self.max_window, self.peer_window) as usize;
+        let mut max = cmp::min(self.max_window(), self.peer_window as usize);
+        max = cmp::min(max, self.max_window_size);
+
+        if cur_window == 0 {
+            // Congestion control may shrink the window below a single packet.
+            // Always allow one packet to be queued, otherwise the connection
+            // would stall.
+            // While the peer's window is too small for a packet, a single byte
+            // is queued. It is sent as a window probe.
+            max = cmp::max(max, cmp::min(self.mtu.packet_size(), self.peer_window as usize));
+            max = cmp::max(max, HEADER_LEN + 1);
+        } else if cur_window + self.min_packet_size > max {
+            // Wait for the window to open up instead of filling the gap with a
+            // tiny packet.
+            return 0;
+        }
 
         if cur_window >= max {
             return 0;
@@ -391,23 +1343,65 @@ impl OutQueue {
         self.remaining_capacity() > 0
     }
 
-    fn in_flight(&self) -> usize {
-        // TODO: Don't iterate each time
-        self.packets.iter()
-            .filter(|p| p.last_sent_at.is_some() && !p.acked)
-            .map(|p| p.packet.len())
-            .sum()
+    /// Returns the number of bytes, including headers, sent and neither acked
+    /// nor considered lost. This is what the congestion and peer windows
+    /// limit.
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight
     }
 
     fn buffered(&self) -> usize {
         // TODO: Don't iterate each time
         self.packets.iter()
-            .map(|p| p.packet.len())
+            .map(|p| p.packet.encoded_len())
             .sum()
     }
 
-    fn timestamp(&self) -> u32 {
-        util::as_wrapping_micros(self.state.created_at.elapsed())
+    /// Returns a snapshot of the queue's statistics
+    pub fn stats(&self, now: Instant) -> Stats {
+        Stats {
+            elapsed: now.duration_since(self.state.created_at),
+            rtt: Duration::from_millis(self.rtt),
+            rtt_variance: Duration::from_millis(self.rtt_variance as u64),
+            cwnd: self.max_window(),
+            bytes_pending: self.buffered(),
+            bytes_acked: self.bytes_acked,
+            packets_sent: self.state.packets_sent,
+            packets_resent: self.state.packets_resent,
+            acks_sent: self.state.acks_sent,
+            acks_piggybacked: self.state.acks_piggybacked,
+            packets_lost: self.packets_lost,
+            timeouts: self.timeouts,
+            quality: 100,
+            // Filled in by the connection from the inbound queue
+            recv_offset: 0,
+            recv_ranges: 0,
+            recv_gap: 0,
+            jitter: Duration::from_millis(0),
+        }
+    }
+
+    fn timestamp(&mut self, now: Instant) -> u32 {
+        self.timestamps.timestamp(now)
+    }
+}
+
+/// Time to wait between two packets of `len` bytes so that a full window is
+/// spread over the round trip time `rtt`, given in milliseconds. `None` until
+/// the round trip time has been measured.
+fn pacing_interval(rtt: u64, max_window: usize, len: usize) -> Option<Duration> {
+    if rtt == 0 || max_window == 0 {
+        return None;
+    }
+
+    let micros = rtt * 1_000 * len as u64 / max_window as u64;
+    Some(util::from_micros(micros))
+}
+
+#[cfg(test)]
+impl OutQueue {
+    pub fn len(&self) -> usize {
+        self.packets.len()
     }
 }
 
@@ -419,15 +1413,55 @@ impl<'a> Next<'a> {
         }
     }
 
+    /// True if the packet was sent before
+    pub fn is_retransmit(&self) -> bool {
+        match self.item {
+            Item::Entry(ref e) => e.num_sends > 0,
+            Item::State(_) => false,
+        }
+    }
+
+    /// True if the packet is an MTU probe, which must not be fragmented
+    pub fn is_mtu_probe(&self) -> bool {
+        self.probe
+    }
+
     pub fn sent(mut self) {
+        match self.item {
+            Item::Entry(_) if self.state.local_ack != self.state.last_ack => {
+                self.state.acks_piggybacked += 1;
+            }
+            Item::State(_) => self.state.acks_sent += 1,
+            _ => {}
+        }
+
         if let Item::Entry(ref mut e) = self.item {
             // Increment the number of sends
             e.num_sends += 1;
+            self.state.packets_sent += 1;
+
+            if e.num_sends > 1 {
+                self.state.packets_resent += 1;
+            } else {
+                // Packets are first sent in sequence
+                self.state.last_sent_seq_nr = e.packet.seq_nr();
+            }
+
+            let now = self.now;
+            self.state.next_send_at = self.pace.map(|pace| now + pace);
+
+            // Only packets that are not in-flight are yielded by `next`
+            self.state.in_flight += e.packet.encoded_len();
 
             // Track the time
-            e.last_sent_at = Some(Instant::now());
+            e.last_sent_at = Some(self.now);
+            self.state.data_sent_at = Some(self.now);
         }
 
         self.state.last_ack = self.state.local_ack;
+        self.state.ack_due_at = None;
+        self.state.window_update = false;
+        self.state.ack_required = false;
+        self.state.last_sent_at = Some(self.now);
     }
 }
@@ -1,7 +1,15 @@
-use bytes::{BytesMut, BufMut};
+//! uTP packets, as defined by BEP-29.
+//!
+//! These types are not needed to use the protocol. They are exposed so that
+//! tooling, such as packet dumps and conformance tests, can inspect and build
+//! packets.
+
+use allocs;
+
+use bytes::{Bytes, BytesMut, BufMut};
 use byteorder::{ByteOrder, BigEndian};
 
-use std::{fmt, io};
+use std::{error, fmt, io, mem};
 
 /// Packet header
 ///
@@ -19,11 +27,28 @@ use std::{fmt, io};
 /// | seq_nr                        | ack_nr                        |
 /// +---------------+---------------+---------------+---------------+
 /// ```
+///
+/// The header and extensions are stored apart from the payload. The payload is
+/// a `Bytes` handle, so it can share memory with the datagram it was received
+/// in or with the buffer the application wrote it from.
 #[derive(Clone)]
 pub struct Packet {
+    // Header and extensions
     data: BytesMut,
+    payload: Bytes,
 }
 
+/// A packet parsed in place, out of the buffer it was received into.
+///
+/// Reading the header and the extensions does not copy anything. Packets that
+/// must be kept around, e.g. in the inbound queue, are split off the buffer
+/// with `into_packet`, without copying either. The buffer space of any other
+/// packet is reused by the next read.
+pub struct PacketRef<'a> {
+    buf: &'a mut BytesMut,
+}
+
+/// Packet type
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Type {
@@ -34,6 +59,29 @@ pub enum Type {
     Syn = 4,
 }
 
+/// Reason a datagram could not be parsed as a packet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// Shorter than the header
+    TooShort(usize),
+    /// The version is not 1
+    UnsupportedVersion(u8),
+    /// The type is not one defined by BEP-29
+    UnknownType(u8),
+    /// The extension chain runs past the end of the datagram
+    BadExtension,
+    /// A packet other than `ST_DATA` carries a payload. Only reported by
+    /// `PacketRef::validate_strict`.
+    UnexpectedPayload(Type),
+    /// The selective ACK bitfield is empty or not a multiple of 4 bytes.
+    /// Only reported by `PacketRef::validate_strict`.
+    BadSelectiveAck(usize),
+    /// The extension bits are not 8 bytes long. Only reported by
+    /// `PacketRef::validate_strict`.
+    BadExtensionBits(usize),
+}
+
+/// Length of the fixed header, which is followed by the extensions
 pub const HEADER_LEN: usize = 20;
 
 const DEFAULT: [u8; 20] = [
@@ -45,24 +93,62 @@ const DEFAULT: [u8; 20] = [
 
 const VERSION_MASK: u8 = 0b1111;
 
-impl Packet {
-    pub fn parse(packet: BytesMut) -> io::Result<Packet> {
-        let ret = Packet::new(packet);
+/// Extension identifier for selective ACKs
+const EXT_SELECTIVE_ACK: u8 = 1;
 
-        if ret.version() != 1 {
-            return Err(io::Error::new(io::ErrorKind::Other, "invalid packet version"));
-        }
+/// Extension identifier for the bitmask of supported extensions, which libutp
+/// includes with every SYN
+const EXT_BITS: u8 = 2;
 
-        if ret.ty_raw() >= 5 {
-            return Err(io::Error::new(io::ErrorKind::Other, "invalid packet type"));
-        }
+/// Length of the extension bits
+pub const EXTENSION_BITS_LEN: usize = 8;
 
-        Ok(ret)
+/// Iterates the extension chain of a packet, yielding the type and the data of
+/// each extension.
+///
+/// ```text
+/// 0               8               16
+/// +---------------+---------------+---------------+---------------+
+/// | extension     | len           | data ...                      |
+/// +---------------+---------------+---------------+---------------+
+/// ```
+///
+/// `extension` is the type of the next extension in the chain, zero for the
+/// last one. The type of the first extension is in the packet header.
+#[derive(Debug, Clone)]
+pub struct Extensions<'a> {
+    data: &'a [u8],
+    ty: u8,
+    pos: usize,
+}
+
+/// Selective ACK extension.
+///
+/// Each bit represents a packet in the send window. The first bit maps to
+/// `ack_nr + 2`, as `ack_nr + 1` is assumed to have been lost.
+#[derive(Debug, Copy, Clone)]
+pub struct SelectiveAck<'a> {
+    bitfield: &'a [u8],
+}
+
+impl Packet {
+    pub fn parse(packet: BytesMut) -> Result<Packet, ParseError> {
+        validate(&packet)?;
+        Ok(Packet::new(packet))
     }
 
-    pub fn new(packet: BytesMut) -> Packet {
+    /// Splits `packet` into its header and its payload without validating it.
+    /// If the extension chain is malformed, the packet has no payload.
+    ///
+    /// Only for buffers that went through `validate`, or tests of malformed
+    /// packets; the accessors panic on a short header or an unknown type.
+    pub(crate) fn new(mut packet: BytesMut) -> Packet {
+        let offset = payload_offset(&packet).unwrap_or(packet.len());
+        let payload = packet.split_off(offset).freeze();
+
         Packet {
             data: packet,
+            payload,
         }
     }
 
@@ -85,13 +171,15 @@ impl Packet {
     }
 
     pub fn data(src: &[u8]) -> Packet {
-        let mut data = BytesMut::with_capacity(HEADER_LEN + src.len());
-
-        data.put_slice(&DEFAULT);
-        data.put_slice(src);
+        allocs::new_buffer(src.len());
+        Packet::data_bytes(Bytes::from(src))
+    }
 
-        let mut p = Packet::new(data);
+    /// Returns a data packet carrying `payload` without copying it
+    pub fn data_bytes(payload: Bytes) -> Packet {
+        let mut p = Packet::default();
         p.set_ty(Type::Data);
+        p.payload = payload;
         p
     }
 
@@ -101,19 +189,8 @@ impl Packet {
         p
     }
 
-    fn ty_raw(&self) -> u8 {
-        self.data[0] >> 4
-    }
-
     pub fn ty(&self) -> Type {
-        match self.ty_raw() {
-            0 => Type::Data,
-            1 => Type::Fin,
-            2 => Type::State,
-            3 => Type::Reset,
-            4 => Type::Syn,
-            _ => unreachable!(),
-        }
+        ty(&self.data)
     }
 
     pub fn set_ty(&mut self, ty: Type) {
@@ -124,6 +201,30 @@ impl Packet {
         self.data[0] & VERSION_MASK
     }
 
+    /// Sets the protocol version. Packets with a version other than 1 are
+    /// rejected by `parse`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `val` does not fit in four bits.
+    pub fn set_version(&mut self, val: u8) {
+        assert!(val <= VERSION_MASK, "version out of range; version={}", val);
+        self.data[0] = self.data[0] & !VERSION_MASK | val
+    }
+
+    /// Returns the type of the first extension, zero if there is none
+    pub fn extension(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// Sets the type of the first extension.
+    ///
+    /// The extension data is left as is, so this is only useful to build
+    /// malformed packets. Use `add_extension` to append an extension.
+    pub fn set_extension(&mut self, val: u8) {
+        self.data[1] = val
+    }
+
     pub fn connection_id(&self) -> u16 {
         BigEndian::read_u16(&self.data[2..4])
     }
@@ -172,27 +273,465 @@ impl Packet {
         BigEndian::write_u16(&mut self.data[18..20], val);
     }
 
+    /// Returns the selective ACK extension, if the packet includes one.
+    pub fn selective_ack(&self) -> Option<SelectiveAck<'_>> {
+        self.extension_data(EXT_SELECTIVE_ACK)
+            .map(|bitfield| SelectiveAck { bitfield })
+    }
+
+    /// Include a selective ACK extension with the packet.
+    ///
+    /// The packet must not already contain a selective ACK.
+    pub fn set_selective_ack(&mut self, bitfield: &[u8]) {
+        assert!(self.selective_ack().is_none(), "packet already has a selective ACK");
+        self.add_extension(EXT_SELECTIVE_ACK, bitfield);
+    }
+
+    /// Returns the extension bits advertised by the peer, if the packet
+    /// includes them.
+    pub fn extension_bits(&self) -> Option<&[u8]> {
+        self.extension_data(EXT_BITS)
+    }
+
+    /// Include the extension bits with the packet, advertising the extensions
+    /// supported beyond selective ACKs.
+    ///
+    /// The packet must not already contain extension bits.
+    pub fn set_extension_bits(&mut self, bits: &[u8; EXTENSION_BITS_LEN]) {
+        assert!(self.extension_bits().is_none(), "packet already has extension bits");
+        self.add_extension(EXT_BITS, bits);
+    }
+
+    /// Returns the type of the first extension that is not understood, if
+    /// any.
+    ///
+    /// Unknown extensions are otherwise skipped, the rest of the chain and the
+    /// payload are still available.
+    pub fn unknown_extension(&self) -> Option<u8> {
+        self.extensions()
+            .map(|(ty, _)| ty)
+            .find(|&ty| !is_known_extension(ty))
+    }
+
+    /// Returns an iterator over the packet's extensions
+    pub fn extensions(&self) -> Extensions<'_> {
+        Extensions::new(&self.data)
+    }
+
+    /// Appends an extension of type `ty` to the end of the extension chain,
+    /// ahead of the payload.
+    pub fn add_extension(&mut self, ty: u8, ext: &[u8]) {
+        assert!(ty != 0, "extension type must not be zero");
+        assert!(ext.len() <= 255, "extension too long; len={}", ext.len());
+
+        let offset = self.data.len();
+
+        // Position of the byte holding the type of the new extension, either
+        // in the header or in the last extension of the chain
+        let mut link = 1;
+        let mut pos = HEADER_LEN;
+
+        while self.data[link] != 0 {
+            link = pos;
+            pos += 2 + self.data[pos + 1] as usize;
+        }
+
+        allocs::new_buffer(self.data.len() + 2 + ext.len());
+        let mut data = BytesMut::with_capacity(self.data.len() + 2 + ext.len());
+
+        data.put_slice(&self.data[..offset]);
+        data.put_u8(0);
+        data.put_u8(ext.len() as u8);
+        data.put_slice(ext);
+
+        data[link] = ty;
+
+        self.data = data;
+    }
+
     pub fn payload(&self) -> &[u8] {
-        &self.data[HEADER_LEN..]
+        &self.payload[..]
+    }
+
+    /// Append data to the end of the payload.
+    ///
+    /// The payload is copied into a new buffer unless this packet is the
+    /// only reference to it.
+    pub fn extend_payload(&mut self, src: &[u8]) {
+        let payload = mem::replace(&mut self.payload, Bytes::new());
+
+        let mut buf = match payload.try_mut() {
+            Ok(buf) => {
+                allocs::grow_buffer(&buf, src.len());
+                buf
+            }
+            Err(payload) => {
+                allocs::new_buffer(payload.len() + src.len());
+                let mut buf = BytesMut::with_capacity(payload.len() + src.len());
+                buf.put_slice(&payload);
+                buf
+            }
+        };
+
+        buf.extend_from_slice(src);
+        self.payload = buf.freeze();
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+
+    /// Returns the encoded packet
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut dst = Vec::with_capacity(self.encoded_len());
+        self.encode_to(&mut dst);
+        dst
+    }
+
+    /// Writes the encoded packet to the start of `dst`, returning the number
+    /// of bytes written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dst` is shorter than the packet.
+    pub fn encode_into(&self, dst: &mut [u8]) -> usize {
+        let len = self.encoded_len();
+        assert!(dst.len() >= len, "buffer too short; len={}; packet={}", dst.len(), len);
+
+        let (header, payload) = dst[..len].split_at_mut(self.data.len());
+        header.copy_from_slice(&self.data);
+        payload.copy_from_slice(&self.payload);
+        len
+    }
+
+    /// Appends the encoded packet to `dst`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dst` does not have enough remaining capacity.
+    pub fn encode_to<B: BufMut>(&self, dst: &mut B) {
+        dst.put_slice(&self.data);
+        dst.put_slice(&self.payload);
+    }
+
+    /// Returns the length of the encoded packet, including the header
+    pub fn encoded_len(&self) -> usize {
+        self.data.len() + self.payload.len()
+    }
+
+    /// Returns the data of the first extension of type `ty`
+    fn extension_data(&self, ty: u8) -> Option<&[u8]> {
+        self.extensions()
+            .find(|&(ext, _)| ext == ty)
+            .map(|(_, data)| data)
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parses the packet held by `buf`, which must contain nothing else
+    pub fn parse(buf: &'a mut BytesMut) -> Result<PacketRef<'a>, ParseError> {
+        validate(buf)?;
+        Ok(PacketRef { buf })
+    }
+
+    pub fn ty(&self) -> Type {
+        ty(self.buf)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buf[0] & VERSION_MASK
+    }
+
+    pub fn extension(&self) -> u8 {
+        self.buf[1]
+    }
+
+    pub fn connection_id(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[2..4])
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[4..8])
+    }
+
+    pub fn timestamp_diff(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[8..12])
+    }
+
+    pub fn wnd_size(&self) -> u32 {
+        BigEndian::read_u32(&self.buf[12..16])
+    }
+
+    pub fn seq_nr(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[16..18])
+    }
+
+    pub fn ack_nr(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[18..20])
+    }
+
+    /// Returns the selective ACK extension, if the packet includes one.
+    pub fn selective_ack(&self) -> Option<SelectiveAck<'_>> {
+        self.extensions()
+            .find(|&(ext, _)| ext == EXT_SELECTIVE_ACK)
+            .map(|(_, bitfield)| SelectiveAck { bitfield })
+    }
+
+    /// Returns the extension bits advertised by the peer, if the packet
+    /// includes them.
+    pub fn extension_bits(&self) -> Option<&[u8]> {
+        self.extensions()
+            .find(|&(ext, _)| ext == EXT_BITS)
+            .map(|(_, bits)| bits)
     }
 
-    pub fn into_payload(mut self) -> BytesMut {
-        self.data.split_to(HEADER_LEN);
-        self.data
+    /// Returns the type of the first extension that is not understood, if
+    /// any.
+    pub fn unknown_extension(&self) -> Option<u8> {
+        self.extensions()
+            .map(|(ty, _)| ty)
+            .find(|&ty| !is_known_extension(ty))
     }
 
-    pub fn as_slice(&self) -> &[u8] {
-        &self.data[..]
+    /// Returns an iterator over the packet's extensions
+    pub fn extensions(&self) -> Extensions<'_> {
+        Extensions::new(self.buf)
     }
 
+    /// Checks the packet for header combinations that a conforming peer never
+    /// sends, but that `parse` accepts: a payload on a packet other than
+    /// `ST_DATA`, a selective ACK whose length is not a non-zero multiple of 4
+    /// bytes, or extension bits that are not 8 bytes long.
+    pub fn validate_strict(&self) -> Result<(), ParseError> {
+        let ty = self.ty();
+
+        if ty != Type::Data && !self.payload().is_empty() {
+            return Err(ParseError::UnexpectedPayload(ty));
+        }
+
+        if let Some(sack) = self.selective_ack() {
+            let len = sack.bitfield.len();
+
+            if len == 0 || len % 4 != 0 {
+                return Err(ParseError::BadSelectiveAck(len));
+            }
+        }
+
+        if let Some(bits) = self.extension_bits() {
+            if bits.len() != EXTENSION_BITS_LEN {
+                return Err(ParseError::BadExtensionBits(bits.len()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        let offset = payload_offset(self.buf).unwrap();
+        &self.buf[offset..]
+    }
+
+    /// Splits the packet off the buffer it was received into
+    pub fn into_packet(self) -> Packet {
+        Packet::new(self.buf.take())
+    }
+}
+
+/// Returns true for the extensions this implementation understands
+fn is_known_extension(ty: u8) -> bool {
+    ty == EXT_SELECTIVE_ACK || ty == EXT_BITS
+}
+
+/// Checks that `data` holds a well formed packet
+fn validate(data: &[u8]) -> Result<(), ParseError> {
+    if data.len() < HEADER_LEN {
+        return Err(ParseError::TooShort(data.len()));
+    }
+
+    if data[0] & VERSION_MASK != 1 {
+        return Err(ParseError::UnsupportedVersion(data[0] & VERSION_MASK));
+    }
+
+    if data[0] >> 4 >= 5 {
+        return Err(ParseError::UnknownType(data[0] >> 4));
+    }
+
+    if payload_offset(data).is_none() {
+        return Err(ParseError::BadExtension);
+    }
+
+    Ok(())
+}
+
+fn ty(data: &[u8]) -> Type {
+    match data[0] >> 4 {
+        0 => Type::Data,
+        1 => Type::Fin,
+        2 => Type::State,
+        3 => Type::Reset,
+        4 => Type::Syn,
+        _ => unreachable!(),
+    }
+}
+
+/// Walks the extension chain, returning the offset at which the payload
+/// starts. Returns `None` if the chain is malformed.
+fn payload_offset(data: &[u8]) -> Option<usize> {
+    let mut ext = data[1];
+    let mut pos = HEADER_LEN;
+
+    while ext != 0 {
+        if pos + 2 > data.len() {
+            return None;
+        }
+
+        let len = data[pos + 1] as usize;
+
+        if pos + 2 + len > data.len() {
+            return None;
+        }
+
+        ext = data[pos];
+        pos += 2 + len;
+    }
+
+    Some(pos)
+}
+
+impl<'a> Extensions<'a> {
+    fn new(data: &'a [u8]) -> Extensions<'a> {
+        Extensions {
+            data,
+            ty: data[1],
+            pos: HEADER_LEN,
+        }
+    }
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        if self.ty == 0 {
+            return None;
+        }
+
+        let pos = self.pos;
+
+        // A malformed chain ends the iteration
+        if pos + 2 > self.data.len() || pos + 2 + self.data[pos + 1] as usize > self.data.len() {
+            self.ty = 0;
+            return None;
+        }
+
+        let ty = self.ty;
+        let len = self.data[pos + 1] as usize;
+
+        self.ty = self.data[pos];
+        self.pos = pos + 2 + len;
+
+        Some((ty, &self.data[pos + 2..pos + 2 + len]))
+    }
+}
+
+impl<'a> SelectiveAck<'a> {
+    /// Returns true if the packet `offset` positions past `ack_nr + 2` has
+    /// been received by the peer.
+    pub fn is_acked(&self, offset: usize) -> bool {
+        self.bitfield.get(offset / 8)
+            .map(|byte| byte & (1 << (offset % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Number of packets represented by the bitfield
     pub fn len(&self) -> usize {
-        self.as_slice().len()
+        self.bitfield.len() * 8
+    }
+
+    /// Returns true if the bitfield is empty
+    pub fn is_empty(&self) -> bool {
+        self.bitfield.is_empty()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::TooShort(len) => write!(fmt, "packet too short; len={}", len),
+            ParseError::UnsupportedVersion(v) => write!(fmt, "unsupported packet version; version={}", v),
+            ParseError::UnknownType(ty) => write!(fmt, "unknown packet type; type={}", ty),
+            ParseError::BadExtension => write!(fmt, "malformed extension chain"),
+            ParseError::UnexpectedPayload(ty) => write!(fmt, "unexpected payload; type={}", ty),
+            ParseError::BadSelectiveAck(len) => write!(fmt, "invalid selective ACK; len={}", len),
+            ParseError::BadExtensionBits(len) => write!(fmt, "invalid extension bits; len={}", len),
+        }
+    }
+}
+
+impl error::Error for ParseError {
+}
+
+impl From<ParseError> for io::Error {
+    fn from(src: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, src)
     }
 }
 
 impl Default for Packet {
     fn default() -> Packet {
-        Packet { data: BytesMut::from(&DEFAULT[..]) }
+        allocs::new_buffer(DEFAULT.len());
+        Packet {
+            data: BytesMut::from(&DEFAULT[..]),
+            payload: Bytes::new(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Type::Data => "ST_DATA",
+            Type::Fin => "ST_FIN",
+            Type::State => "ST_STATE",
+            Type::Reset => "ST_RESET",
+            Type::Syn => "ST_SYN",
+        };
+
+        fmt.write_str(name)
+    }
+}
+
+/// Formats the header in `data` on a single line, e.g.
+///
+/// ```text
+/// ST_DATA id=25103 seq=2 ack=1 wnd=65536 ts=1000 ts_diff=200 len=1380
+/// ```
+fn fmt_header(data: &[u8], payload: usize, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "{} id={} seq={} ack={} wnd={} ts={} ts_diff={}",
+           ty(data),
+           BigEndian::read_u16(&data[2..4]),
+           BigEndian::read_u16(&data[16..18]),
+           BigEndian::read_u16(&data[18..20]),
+           BigEndian::read_u32(&data[12..16]),
+           BigEndian::read_u32(&data[4..8]),
+           BigEndian::read_u32(&data[8..12]))?;
+
+    if data[1] != 0 {
+        write!(fmt, " ext={}", data[1])?;
+    }
+
+    write!(fmt, " len={}", payload)
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt_header(&self.data, self.payload.len(), fmt)
+    }
+}
+
+impl<'a> fmt::Display for PacketRef<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt_header(self.buf, self.payload().len(), fmt)
     }
 }
 
@@ -201,7 +740,24 @@ impl fmt::Debug for Packet {
         fmt.debug_struct("Packet")
             .field("type", &self.ty())
             .field("version", &self.version())
-            // .field("extension", &self.extension())
+            .field("extension", &self.extension())
+            .field("connection_id", &self.connection_id())
+            .field("timestamp", &self.timestamp())
+            .field("timestamp_diff", &self.timestamp_diff())
+            .field("wnd_size", &self.wnd_size())
+            .field("seq_nr", &self.seq_nr())
+            .field("ack_nr", &self.ack_nr())
+            .field("payload", &self.payload().len())
+            .finish()
+    }
+}
+
+impl<'a> fmt::Debug for PacketRef<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("PacketRef")
+            .field("type", &self.ty())
+            .field("version", &self.version())
+            .field("extension", &self.extension())
             .field("connection_id", &self.connection_id())
             .field("timestamp", &self.timestamp())
             .field("timestamp_diff", &self.timestamp_diff())
@@ -0,0 +1,124 @@
+//! Path characteristics cache
+//!
+//! When a connection is removed, what it learned about the path to the peer is
+//! stored, keyed by the peer's IP address. New connections to the same host
+//! start out with the cached round trip time and packet size instead of
+//! learning them from scratch. The port is ignored as reconnecting peers
+//! usually pick a new one.
+//!
+//! Entries expire after a while since paths change, and the least recently
+//! updated entry is evicted when the cache is full.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub(crate) struct PathCache {
+    entries: HashMap<IpAddr, Entry>,
+
+    // Max number of entries, zero disables the cache
+    capacity: usize,
+
+    // How long an entry is used for after being stored
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: PathInfo,
+    updated_at: Instant,
+}
+
+/// What a connection learned about the path to a peer.
+///
+/// Returned by `UtpSocket::path_info`. Applications may use this to tune new
+/// connections, for example with `UtpStream::set_initial_window`.
+#[derive(Debug, Clone)]
+pub struct PathInfo {
+    // Smoothed round trip time and its variance, in milliseconds
+    pub(crate) rtt: u64,
+    pub(crate) rtt_variance: i64,
+
+    // Packet size found by path MTU discovery, and the smallest size known
+    // not to get through, minus one
+    pub(crate) packet_size: usize,
+    pub(crate) mtu_ceiling: usize,
+
+    pub(crate) packets_sent: u64,
+    pub(crate) packets_resent: u64,
+}
+
+impl PathCache {
+    pub fn new(capacity: usize, ttl: Duration) -> PathCache {
+        PathCache {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Returns the cached path to `addr`, unless it expired
+    pub fn get(&self, addr: &IpAddr, now: Instant) -> Option<&PathInfo> {
+        self.entries.get(addr)
+            .filter(|entry| now < entry.updated_at + self.ttl)
+            .map(|entry| &entry.info)
+    }
+
+    /// Stores the path to `addr`, replacing any previous entry
+    pub fn insert(&mut self, addr: IpAddr, info: PathInfo, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| now < entry.updated_at + ttl);
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&addr) {
+            let oldest = self.entries.iter()
+                .min_by_key(|&(_, entry)| entry.updated_at)
+                .map(|(addr, _)| *addr);
+
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        trace!("caching path; addr={}; info={:?}", addr, info);
+
+        self.entries.insert(addr, Entry {
+            info,
+            updated_at: now,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl PathInfo {
+    /// Smoothed round trip time
+    pub fn rtt(&self) -> Duration {
+        Duration::from_millis(self.rtt)
+    }
+
+    /// Variance of the round trip time
+    pub fn rtt_variance(&self) -> Duration {
+        Duration::from_millis(self.rtt_variance as u64)
+    }
+
+    /// Size of packets, including the header, known to get through
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Fraction of sent packets that had to be retransmitted
+    pub fn loss_rate(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+
+        self.packets_resent as f64 / self.packets_sent as f64
+    }
+}
@@ -0,0 +1,236 @@
+//! Slow peer detection
+//!
+//! A `PeerPolicy` is consulted on every socket tick with the connection's
+//! current `Stats` and decides whether the peer is keeping up. Applications
+//! select a policy with `UtpConfig::set_peer_policy`. By default, connections
+//! are not monitored.
+
+use stats::Stats;
+use util;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Decides whether a connection's peer is performing acceptably.
+pub trait PeerPolicy: fmt::Debug {
+    /// Called periodically with the connection's statistics.
+    fn check(&mut self, stats: &Stats) -> Verdict;
+}
+
+/// The outcome of a `PeerPolicy` check.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Verdict {
+    /// The peer is performing acceptably.
+    Healthy,
+
+    /// The peer is slow. The connection is kept open, but
+    /// `UtpStream::is_slow_peer` will return `true` so that the application
+    /// can decide what to do.
+    Slow,
+
+    /// The connection is reset.
+    Disconnect,
+}
+
+/// What a socket does with a packet from a peer it has connections with, when
+/// the packet's connection ID matches none of them.
+///
+/// This usually means that the peer restarted and lost track of its
+/// connections. Packets from peers without any connection on the socket are
+/// always answered with a RESET. Selected with
+/// `UtpConfig::set_unknown_connection`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UnknownConnection {
+    /// The packet is dropped.
+    Ignore,
+
+    /// A RESET is sent back. This is the default.
+    Reset,
+
+    /// The packet is dropped and the hook set with
+    /// `UtpConfig::set_unknown_connection_hook` is called, letting the
+    /// application reconnect without waiting for its connections to time
+    /// out.
+    Notify,
+}
+
+/// Flags peers that persistently exceed a max round trip time or loss rate,
+/// or that fall below a min throughput while data is pending.
+///
+/// A peer is only flagged once it has failed `strikes` consecutive checks.
+/// Flagged peers are reported as `Verdict::Slow` unless auto disconnect is
+/// enabled, in which case they are disconnected unless the veto hook objects.
+#[derive(Clone)]
+pub struct SlowPeerPolicy {
+    grace_period: Duration,
+    max_rtt: Duration,
+    max_loss_rate: f64,
+    min_throughput: u64,
+    strikes: u32,
+    auto_disconnect: bool,
+    veto: Option<Veto>,
+
+    // Consecutive failed checks
+    failed: u32,
+
+    // Stats at the previous check, used to compute the throughput
+    last_elapsed: Duration,
+    last_bytes_acked: u64,
+}
+
+type Veto = Arc<dyn Fn(&Stats) -> bool + Send + Sync>;
+
+impl SlowPeerPolicy {
+    /// Returns a new `SlowPeerPolicy` with default thresholds.
+    pub fn new() -> SlowPeerPolicy {
+        SlowPeerPolicy {
+            grace_period: Duration::from_secs(10),
+            max_rtt: Duration::from_secs(5),
+            max_loss_rate: 0.5,
+            min_throughput: 0,
+            strikes: 10,
+            auto_disconnect: false,
+            veto: None,
+            failed: 0,
+            last_elapsed: Duration::from_secs(0),
+            last_bytes_acked: 0,
+        }
+    }
+
+    /// Connections are not judged until they are at least this old.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn set_grace_period(&mut self, val: Duration) -> &mut Self {
+        self.grace_period = val;
+        self
+    }
+
+    /// Sets the max acceptable round trip time.
+    ///
+    /// Defaults to 5 seconds.
+    pub fn set_max_rtt(&mut self, val: Duration) -> &mut Self {
+        self.max_rtt = val;
+        self
+    }
+
+    /// Sets the max acceptable fraction of retransmitted packets.
+    ///
+    /// Defaults to 0.5.
+    pub fn set_max_loss_rate(&mut self, val: f64) -> &mut Self {
+        self.max_loss_rate = val;
+        self
+    }
+
+    /// Sets the min acceptable number of bytes acked per second while data is
+    /// pending.
+    ///
+    /// Defaults to 0, which disables the check.
+    pub fn set_min_throughput(&mut self, val: u64) -> &mut Self {
+        self.min_throughput = val;
+        self
+    }
+
+    /// Sets the number of consecutive failed checks before a peer is flagged.
+    ///
+    /// Defaults to 10.
+    pub fn set_strikes(&mut self, val: u32) -> &mut Self {
+        self.strikes = val;
+        self
+    }
+
+    /// When set, flagged peers are disconnected instead of reported as slow.
+    ///
+    /// Defaults to `false`.
+    pub fn set_auto_disconnect(&mut self, val: bool) -> &mut Self {
+        self.auto_disconnect = val;
+        self
+    }
+
+    /// Sets a hook that is called before a flagged peer is disconnected.
+    /// Returning `true` vetoes the disconnect, in which case the peer is
+    /// reported as slow instead.
+    pub fn set_veto<F>(&mut self, f: F) -> &mut Self
+        where F: Fn(&Stats) -> bool + Send + Sync + 'static,
+    {
+        self.veto = Some(Arc::new(f));
+        self
+    }
+
+    fn is_failing(&mut self, stats: &Stats) -> bool {
+        let elapsed = stats.elapsed() - self.last_elapsed;
+        let acked = stats.bytes_acked() - self.last_bytes_acked;
+
+        self.last_elapsed = stats.elapsed();
+        self.last_bytes_acked = stats.bytes_acked();
+
+        if stats.rtt() > self.max_rtt {
+            return true;
+        }
+
+        if stats.loss_rate() > self.max_loss_rate {
+            return true;
+        }
+
+        // Throughput is only meaningful when there is data to send
+        if self.min_throughput > 0 && stats.bytes_pending() > 0 {
+            let micros = util::as_micros(elapsed);
+
+            if micros > 0 && acked * 1_000_000 / micros < self.min_throughput {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+impl PeerPolicy for SlowPeerPolicy {
+    fn check(&mut self, stats: &Stats) -> Verdict {
+        if stats.elapsed() < self.grace_period {
+            self.last_elapsed = stats.elapsed();
+            self.last_bytes_acked = stats.bytes_acked();
+            return Verdict::Healthy;
+        }
+
+        if !self.is_failing(stats) {
+            self.failed = 0;
+            return Verdict::Healthy;
+        }
+
+        self.failed = self.failed.saturating_add(1);
+
+        if self.failed < self.strikes {
+            return Verdict::Healthy;
+        }
+
+        if !self.auto_disconnect {
+            return Verdict::Slow;
+        }
+
+        match self.veto {
+            Some(ref veto) if veto(stats) => Verdict::Slow,
+            _ => Verdict::Disconnect,
+        }
+    }
+}
+
+impl Default for SlowPeerPolicy {
+    fn default() -> SlowPeerPolicy {
+        SlowPeerPolicy::new()
+    }
+}
+
+impl fmt::Debug for SlowPeerPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SlowPeerPolicy")
+            .field("grace_period", &self.grace_period)
+            .field("max_rtt", &self.max_rtt)
+            .field("max_loss_rate", &self.max_loss_rate)
+            .field("min_throughput", &self.min_throughput)
+            .field("strikes", &self.strikes)
+            .field("auto_disconnect", &self.auto_disconnect)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
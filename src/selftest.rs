@@ -0,0 +1,276 @@
+//! Built-in self test.
+//!
+//! Checks that packets are encoded and decoded as specified by BEP-29 and that
+//! a transfer over the loopback interface completes. This is meant to validate
+//! platforms the crate is not regularly tested on, such as big-endian or
+//! 32-bit targets, before deploying to them.
+
+use packet::{self, Packet};
+use socket::UtpSocket;
+use vectors;
+
+use bytes::BytesMut;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+
+use std::{fmt, io, mem};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Outcome of `selftest`
+#[derive(Debug)]
+pub struct SelfTest {
+    checks: Vec<Check>,
+}
+
+/// A single check run by `selftest`
+#[derive(Debug)]
+pub struct Check {
+    name: &'static str,
+    result: Result<(), String>,
+}
+
+/// Number of bytes sent over the loopback connection
+const LOOPBACK_LEN: usize = 256 * 1024;
+
+/// Time allowed for the loopback transfer to complete
+const LOOPBACK_TIMEOUT_MS: u64 = 10_000;
+
+/// Runs the self test and returns a report of each check.
+///
+/// This binds two sockets to ephemeral ports on the loopback interface and
+/// blocks until the transfer between them completes or times out.
+pub fn selftest() -> SelfTest {
+    let checks = vec![
+        Check::run("header encoding", encode_header),
+        Check::run("header decoding", decode_header),
+        Check::run("selective ack extension", selective_ack),
+        Check::run("malformed packets rejected", malformed),
+        Check::run("loopback transfer", loopback),
+    ];
+
+    SelfTest { checks }
+}
+
+impl SelfTest {
+    /// Returns `true` if every check passed
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(Check::is_ok)
+    }
+
+    /// Returns the checks that were run
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+}
+
+impl fmt::Display for SelfTest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let endian = if cfg!(target_endian = "big") { "big" } else { "little" };
+
+        writeln!(fmt, "utp2 self test; endian={}; pointer_width={}",
+                 endian, mem::size_of::<usize>() * 8)?;
+
+        for check in &self.checks {
+            writeln!(fmt, "{}", check)?;
+        }
+
+        write!(fmt, "{}", if self.is_ok() { "passed" } else { "FAILED" })
+    }
+}
+
+impl Check {
+    fn run(name: &'static str, f: fn() -> Result<(), String>) -> Check {
+        Check {
+            name,
+            result: f(),
+        }
+    }
+
+    /// Name of the check
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// Returns `true` if the check passed
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// Describes why the check failed
+    pub fn error(&self) -> Option<&str> {
+        self.result.as_ref().err().map(|e| &e[..])
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.result {
+            Ok(()) => write!(fmt, "ok    {}", self.name),
+            Err(ref e) => write!(fmt, "FAIL  {}: {}", self.name, e),
+        }
+    }
+}
+
+macro_rules! ensure_eq {
+    ($what:expr, $actual:expr, $expected:expr) => {{
+        let actual = $actual;
+        let expected = $expected;
+
+        if actual != expected {
+            return Err(format!("{} mismatch; actual={:?}; expected={:?}",
+                               $what, actual, expected));
+        }
+    }}
+}
+
+fn encode_header() -> Result<(), String> {
+    let mut p = Packet::state();
+    p.set_connection_id(0x1234);
+    p.set_timestamp(0x01020304);
+    p.set_timestamp_diff(0x05060708);
+    p.set_wnd_size(0x090A0B0C);
+    p.set_seq_nr(0x0D0E);
+    p.set_ack_nr(0x0F10);
+
+    ensure_eq!("encoded header", &p.to_vec()[..], &vectors::STATE[..]);
+
+    let syn = Packet::syn();
+    ensure_eq!("default header", &syn.to_vec()[..], &vectors::SYN[..]);
+    Ok(())
+}
+
+fn decode_header() -> Result<(), String> {
+    let p = Packet::parse(BytesMut::from(&vectors::DATA[..]))
+            .map_err(|e| format!("parse failed; err={}", e))?;
+
+    ensure_eq!("type", p.ty(), packet::Type::Data);
+    ensure_eq!("version", p.version(), 1);
+    ensure_eq!("connection_id", p.connection_id(), 0x1234);
+    ensure_eq!("timestamp", p.timestamp(), 0x01020304);
+    ensure_eq!("timestamp_diff", p.timestamp_diff(), 0x05060708);
+    ensure_eq!("wnd_size", p.wnd_size(), 0x090A0B0C);
+    ensure_eq!("seq_nr", p.seq_nr(), 0x0D0E);
+    ensure_eq!("ack_nr", p.ack_nr(), 0x0F10);
+    ensure_eq!("payload", p.payload(), &b"utp"[..]);
+    Ok(())
+}
+
+fn selective_ack() -> Result<(), String> {
+    let mut p = Packet::data(b"utp");
+    p.set_selective_ack(&[0b1000_0001, 0, 0, 0b0100_0000]);
+
+    ensure_eq!("encoded packet", &p.to_vec()[..], &vectors::SELECTIVE_ACK[..]);
+
+    let p = Packet::parse(BytesMut::from(&p.to_vec()[..]))
+            .map_err(|e| format!("parse failed; err={}", e))?;
+
+    let sack = match p.selective_ack() {
+        Some(sack) => sack,
+        None => return Err("selective ack missing after decoding".to_string()),
+    };
+
+    let acked: Vec<usize> = (0..sack.len()).filter(|&i| sack.is_acked(i)).collect();
+
+    ensure_eq!("acked packets", acked, vec![0, 7, 30]);
+    ensure_eq!("payload", p.payload(), &b"utp"[..]);
+    Ok(())
+}
+
+fn malformed() -> Result<(), String> {
+    for &(name, data) in &vectors::MALFORMED {
+        if Packet::parse(BytesMut::from(data)).is_ok() {
+            return Err(format!("{} was accepted", name));
+        }
+    }
+
+    Ok(())
+}
+
+fn loopback() -> Result<(), String> {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+    let (server, listener) = io_check("bind", UtpSocket::bind(&addr))?;
+    let (client, _) = io_check("bind", UtpSocket::bind(&addr))?;
+
+    let server_addr = io_check("local_addr", server.local_addr())?;
+    let stream = io_check("connect", client.connect(server_addr))?;
+
+    let poll = io_check("poll", Poll::new())?;
+    let mut events = Events::with_capacity(16);
+
+    for (i, socket) in [&server, &client].iter().enumerate() {
+        io_check("register", poll.register(*socket, Token(i),
+                                           Ready::readable() | Ready::writable(),
+                                           PollOpt::edge()))?;
+    }
+
+    let data: Vec<u8> = (0..LOOPBACK_LEN).map(|i| (i % 251) as u8).collect();
+    let mut written = 0;
+    let mut received = vec![];
+    let mut accepted = None;
+    let mut buf = [0; 4096];
+
+    let deadline = Instant::now() + Duration::from_millis(LOOPBACK_TIMEOUT_MS);
+    let mut ticked_at = Instant::now();
+
+    while received.len() < data.len() {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(format!("timed out; written={}; received={}; stats={:?}",
+                               written, received.len(), stream.stats()));
+        }
+
+        io_check("poll", poll.poll(&mut events, Some(Duration::from_millis(10))))?;
+
+        for socket in &[&server, &client] {
+            io_check("ready", socket.ready(Ready::readable() | Ready::writable()))?;
+        }
+
+        if now - ticked_at >= Duration::from_millis(100) {
+            io_check("tick", server.tick())?;
+            io_check("tick", client.tick())?;
+            ticked_at = now;
+        }
+
+        if accepted.is_none() {
+            accepted = io_check("accept", would_block(listener.accept()))?;
+        }
+
+        while written < data.len() {
+            match io_check("write", would_block(stream.write(&data[written..])))? {
+                Some(n) => written += n,
+                None => break,
+            }
+        }
+
+        if let Some(ref accepted) = accepted {
+            while let Some(n) = io_check("read", would_block(accepted.read(&mut buf)))? {
+                if n == 0 {
+                    return Err(format!("unexpected EOF; received={}", received.len()));
+                }
+
+                received.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+
+    if let Some(pos) = data.iter().zip(&received).position(|(a, b)| a != b) {
+        return Err(format!("data corrupted; offset={}", pos));
+    }
+
+    ensure_eq!("received bytes", received.len(), data.len());
+    Ok(())
+}
+
+fn io_check<T>(what: &str, res: io::Result<T>) -> Result<T, String> {
+    res.map_err(|e| format!("{} failed; err={}", what, e))
+}
+
+fn would_block<T>(res: io::Result<T>) -> io::Result<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,29 @@
+//! Sequence number arithmetic
+//!
+//! Sequence and ACK numbers are 16 bits and wrap around. A number comes before
+//! another if it is less than half the sequence space behind it, which holds
+//! as long as fewer than 32768 packets are in flight.
+
+/// Half the sequence space
+const HALF: u16 = 0x8000;
+
+/// Returns the number of steps from `from` forward to `to`
+pub fn distance(from: u16, to: u16) -> u16 {
+    to.wrapping_sub(from)
+}
+
+/// Returns true if `lhs` comes before `rhs`
+pub fn wrapping_lt(lhs: u16, rhs: u16) -> bool {
+    let dist = distance(lhs, rhs);
+    dist != 0 && dist < HALF
+}
+
+/// Returns true if `lhs` is `rhs` or comes before it
+pub fn wrapping_le(lhs: u16, rhs: u16) -> bool {
+    distance(lhs, rhs) < HALF
+}
+
+/// Returns true if `seq_nr` is one of the `len` numbers starting at `start`
+pub fn in_window(start: u16, len: usize, seq_nr: u16) -> bool {
+    (distance(start, seq_nr) as usize) < len
+}
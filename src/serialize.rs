@@ -0,0 +1,213 @@
+//! `serde` integration
+//!
+//! `Packet` and the statistics types implement `Serialize` and `Deserialize`
+//! when the `serde` feature is enabled, so that tools can dump and reload
+//! captured packets and connection state, e.g. as JSON. `Stall` only
+//! implements `Serialize`.
+//!
+//! Structs are serialized as maps. When deserializing, unknown fields are
+//! ignored and missing ones take their default value, so dumps remain
+//! readable as fields are added.
+
+use packet::{Packet, Type};
+use stats::{Stats, Summary, Stall, DriverStats};
+
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use std::fmt;
+
+/// Implements `Serialize` for a struct by serializing the listed fields, and
+/// `Deserialize`, starting from the struct's default value, unless
+/// `serialize_only` is given.
+macro_rules! impl_serde {
+    ($ty:ident { $($field:ident),* }) => {
+        impl_serde!(serialize_only $ty { $($field),* });
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$ty, D::Error> {
+                struct StructVisitor;
+
+                impl<'de> Visitor<'de> for StructVisitor {
+                    type Value = $ty;
+
+                    fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                        write!(fmt, "struct {}", stringify!($ty))
+                    }
+
+                    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<$ty, A::Error> {
+                        let mut val = $ty::default();
+
+                        while let Some(key) = map.next_key::<String>()? {
+                            match &key[..] {
+                                $(stringify!($field) => val.$field = map.next_value()?,)*
+                                _ => {
+                                    map.next_value::<IgnoredAny>()?;
+                                }
+                            }
+                        }
+
+                        Ok(val)
+                    }
+                }
+
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+                deserializer.deserialize_struct(stringify!($ty), FIELDS, StructVisitor)
+            }
+        }
+    };
+    (serialize_only $ty:ident { $($field:ident),* }) => {
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let len = [$(stringify!($field)),*].len();
+                let mut state = serializer.serialize_struct(stringify!($ty), len)?;
+                $(state.serialize_field(stringify!($field), &self.$field)?;)*
+                state.end()
+            }
+        }
+    };
+}
+
+impl_serde!(Stats {
+    elapsed, rtt, rtt_variance, cwnd, bytes_pending, bytes_acked, packets_sent,
+    packets_resent, acks_sent, acks_piggybacked, packets_lost, timeouts, quality,
+    recv_offset, recv_ranges, recv_gap, jitter
+});
+
+impl_serde!(Summary {
+    elapsed, bytes_acked, packets_sent, packets_resent, timeouts
+});
+
+impl_serde!(DriverStats {
+    elapsed, wakeups, packets_received, drops, packets_sent,
+    max_packets_per_wakeup, ticks, tick_time, congestion_time, max_connections,
+    max_accept_backlog, packet_allocations, queue_allocations
+});
+
+impl_serde!(serialize_only Stall {
+    addr, connection_id, idle, timeout, stats
+});
+
+/// Types are serialized by their BEP-29 name, e.g. `ST_DATA`
+impl Serialize for Type {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Type {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Type, D::Error> {
+        struct TypeVisitor;
+
+        impl<'de> Visitor<'de> for TypeVisitor {
+            type Value = Type;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("a packet type, e.g. ST_DATA")
+            }
+
+            fn visit_str<E: de::Error>(self, val: &str) -> Result<Type, E> {
+                match val {
+                    "ST_DATA" => Ok(Type::Data),
+                    "ST_FIN" => Ok(Type::Fin),
+                    "ST_STATE" => Ok(Type::State),
+                    "ST_RESET" => Ok(Type::Reset),
+                    "ST_SYN" => Ok(Type::Syn),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(val), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+const PACKET_FIELDS: &[&str] = &[
+    "ty", "version", "connection_id", "timestamp", "timestamp_diff", "wnd_size",
+    "seq_nr", "ack_nr", "extensions", "payload",
+];
+
+/// Packets are serialized field by field. Extensions are a list of
+/// `(type, data)` pairs, in the order of the chain.
+impl Serialize for Packet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let extensions: Vec<_> = self.extensions().collect();
+
+        let mut state = serializer.serialize_struct("Packet", PACKET_FIELDS.len())?;
+        state.serialize_field("ty", &self.ty())?;
+        state.serialize_field("version", &self.version())?;
+        state.serialize_field("connection_id", &self.connection_id())?;
+        state.serialize_field("timestamp", &self.timestamp())?;
+        state.serialize_field("timestamp_diff", &self.timestamp_diff())?;
+        state.serialize_field("wnd_size", &self.wnd_size())?;
+        state.serialize_field("seq_nr", &self.seq_nr())?;
+        state.serialize_field("ack_nr", &self.ack_nr())?;
+        state.serialize_field("extensions", &extensions)?;
+        state.serialize_field("payload", self.payload())?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Packet, D::Error> {
+        struct PacketVisitor;
+
+        impl<'de> Visitor<'de> for PacketVisitor {
+            type Value = Packet;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("struct Packet")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Packet, A::Error> {
+                let mut p = Packet::default();
+                let mut extensions: Vec<(u8, Vec<u8>)> = vec![];
+                let mut payload: Vec<u8> = vec![];
+
+                while let Some(key) = map.next_key::<String>()? {
+                    match &key[..] {
+                        "ty" => p.set_ty(map.next_value()?),
+                        "version" => {
+                            let version: u8 = map.next_value()?;
+
+                            if version > 0b1111 {
+                                return Err(de::Error::invalid_value(
+                                    de::Unexpected::Unsigned(version as u64), &"a 4 bit version"));
+                            }
+
+                            p.set_version(version);
+                        }
+                        "connection_id" => p.set_connection_id(map.next_value()?),
+                        "timestamp" => p.set_timestamp(map.next_value()?),
+                        "timestamp_diff" => p.set_timestamp_diff(map.next_value()?),
+                        "wnd_size" => p.set_wnd_size(map.next_value()?),
+                        "seq_nr" => p.set_seq_nr(map.next_value()?),
+                        "ack_nr" => p.set_ack_nr(map.next_value()?),
+                        "extensions" => extensions = map.next_value()?,
+                        "payload" => payload = map.next_value()?,
+                        _ => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+
+                for (ty, data) in extensions {
+                    if ty == 0 || data.len() > 255 {
+                        return Err(de::Error::custom(
+                            format!("invalid extension; type={}; len={}", ty, data.len())));
+                    }
+
+                    p.add_extension(ty, &data);
+                }
+
+                if !payload.is_empty() {
+                    p.extend_payload(&payload);
+                }
+
+                Ok(p)
+            }
+        }
+
+        deserializer.deserialize_struct("Packet", PACKET_FIELDS, PacketVisitor)
+    }
+}
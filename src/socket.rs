@@ -1,20 +1,33 @@
-use {util, TIMESTAMP_MASK};
-use delays::Delays;
+use {allocs, sockopt, util, TIMESTAMP_MASK};
+use builder::Builder;
+use config::{UtpConfig, DropHook};
+use gate::{TransmitGate, Transmit, Admission};
+use congestion::Ack;
+use delays::{Delays, ClockDrift, Jitter};
 use in_queue::InQueue;
+use io_timeout::IoTimeout;
 use out_queue::OutQueue;
-use packet::{self, Packet};
+use packet::{self, Packet, PacketRef, HEADER_LEN};
+use path_cache::{PathCache, PathInfo};
+use policy::{PeerPolicy, Verdict, UnknownConnection};
+use split::{self, ReadHalf, WriteHalf, OwnedReadHalf, OwnedWriteHalf};
+use state::{self, State, Action};
+use stats::{Stats, Summary, Stall, DriverStats, DropReason, QualityMeter};
 
 use mio::net::UdpSocket;
 use mio::{Evented, Registration, SetReadiness, Ready, Poll, PollOpt, Token};
 
-use bytes::{BytesMut, BufMut};
+use bytes::{Buf, Bytes, BytesMut, BufMut};
 use slab::Slab;
 
-use std::{cmp, io, u32};
+use std::{cmp, io, mem, u32};
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::net::SocketAddr;
+use std::io::{IoSlice, IoSliceMut};
+use std::mem::MaybeUninit;
+use std::net::{self, SocketAddr, Shutdown, ToSocketAddrs};
 use std::collections::{HashMap, VecDeque};
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
 pub struct UtpSocket {
@@ -42,12 +55,21 @@ pub struct UtpListener {
     registration: Registration,
 }
 
+/// An iterator over the inbound connections of a `UtpListener`, see
+/// `UtpListener::incoming`.
+pub struct Incoming<'a> {
+    listener: &'a UtpListener,
+}
+
 // Shared between the UtpSocket and each UtpStream
 struct Inner {
     // State that needs to be passed to `Connection`. This is broken out to make
     // the borrow checker happy.
     shared: Shared,
 
+    // Socket configuration
+    config: UtpConfig,
+
     // Connection specific state
     connections: Slab<Connection>,
 
@@ -62,6 +84,18 @@ struct Inner {
     listener: SetReadiness,
 
     listener_open: bool,
+
+    // Task waiting for an inbound connection
+    accept_waker: Option<Waker>,
+
+    // What connections learned about the path to their peer
+    path_cache: PathCache,
+
+    // The instant at which the socket was created
+    created_at: Instant,
+
+    // Allocation counts when the socket was created
+    created_allocs: allocs::Counts,
 }
 
 struct Shared {
@@ -72,11 +106,25 @@ struct Shared {
     // readiness of each connection.
     ready: Ready,
 
-    // Buffer used for out-bound data, this does not need to be share-able
+    // Buffer used for out-bound data, this does not need to be share-able.
+    // Packets are encoded into it right before being sent.
     out_buf: Vec<u8>,
 
     // where to write the out_buf to
     out_buf_dst: Option<SocketAddr>,
+
+    // Consulted before each packet is sent, if configured
+    gate: Option<Box<dyn TransmitGate>>,
+
+    // Notified of each discarded packet, if configured
+    drop_hook: Option<DropHook>,
+
+    // True while packets are sent with the "don't fragment" bit, which is only
+    // set for MTU probes
+    dont_fragment: bool,
+
+    // Driver statistics, `elapsed` is only set on snapshots
+    driver: DriverStats,
 }
 
 // Owned by UtpSocket
@@ -105,19 +153,76 @@ struct Connection {
     // Activity deadline
     deadline: Option<Instant>,
 
+    // The last time a packet was received from the peer, and how long the
+    // peer may stay silent before the connection is failed.
+    last_recv_at: Instant,
+    idle_timeout: Option<Duration>,
+
+    // Number of times packets are retransmitted after timing out before the
+    // connection is failed
+    max_retransmits: u32,
+
+    // Max number of packets sent per flush. When the last flush stopped at the
+    // limit, the connection is `burst_limited` until the next one.
+    max_burst: usize,
+    burst_limited: bool,
+
+    // Priority reported to the transmit gate, and the instant until which the
+    // gate delayed sending.
+    priority: u8,
+    gated_until: Option<Instant>,
+
+    // The connection is closed at this instant, if set. Once it has expired,
+    // reads and writes fail with `ConnectionAborted`.
+    expires_at: Option<Instant>,
+    expired: bool,
+
+    // Error returned by reads and writes once the connection is reset
+    reset_error: io::ErrorKind,
+
     // Tracks delays for the congestion control algorithm
     our_delays: Delays,
 
     their_delays: Delays,
 
     last_maxed_out_window: Instant,
-    average_delay: i32,
-    current_delay_sum: i64,
-    current_delay_samples: i64,
-    average_delay_base: u32,
-    average_sample_time: Instant,
-    clock_drift: i32,
-    slow_start: bool,
+    clock_drift: ClockDrift,
+
+    // Variation of the arrival times of the peer's packets
+    jitter: Jitter,
+
+    // Monitors the peer, if configured
+    peer_policy: Option<Box<dyn PeerPolicy>>,
+
+    // Set when the peer policy reports the peer as slow
+    slow_peer: bool,
+
+    // Number of retransmission timeouts without any packet exchanged after
+    // which a connection with unacked data is flagged, and whether it
+    // currently is.
+    watchdog: Option<u32>,
+    stalled: bool,
+
+    // True once the peer's FIN has been received, or the read half has been
+    // shut down. Reads return EOF once buffered data is consumed.
+    read_closed: bool,
+
+    // True once the read half has been shut down. Data received since is
+    // discarded without being acked, or resets the connection if
+    // `reset_on_read_shutdown` is set.
+    read_shutdown: bool,
+    reset_on_read_shutdown: bool,
+
+    // Connection quality score
+    quality: QualityMeter,
+
+    // Tasks waiting for the stream to become readable or writable
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+
+    // Bound how long reads and writes may keep blocking
+    read_timeout: IoTimeout,
+    write_timeout: IoTimeout,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -126,85 +231,191 @@ struct Key {
     addr: SocketAddr,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-enum State {
-    // Establishing a new connection, waiting for the peer to respond with a
-    // STATE.
-    SynSent,
-    // Received Syn, the state packet is sent immediately, but the connection is
-    // not transitioned to `Connected` until it has been accepted.
-    SynRecv,
-    // Fully connected state
-    Connected,
-    // A SYN has been sent and we are currently waiting for an ACK before
-    // closing the connection.
-    FinSent,
-    // The connection has been reset by the remote.
-    Reset,
-}
-
 type InnerCell = Rc<RefCell<Inner>>;
 
 const MIN_BUFFER_SIZE: usize = 4 * 1_024;
 const MAX_BUFFER_SIZE: usize = 64 * 1_024;
 const DEFAULT_IN_BUFFER_SIZE: usize = 64 * 1024;
 const DEFAULT_OUT_BUFFER_SIZE: usize = 4 * 1024;
-const MAX_CONNECTIONS_PER_SOCKET: usize = 2 * 1024;
-const DEFAULT_TIMEOUT_MS: u64 = 1_000;
-const TARGET_DELAY: u32 = 100_000; // 100ms in micros
 
-const SLOW_START_THRESHOLD: usize = DEFAULT_IN_BUFFER_SIZE;
-const MAX_CWND_INCREASE_BYTES_PER_RTT: usize = 3000;
-const MIN_WINDOW_SIZE: usize = 10;
-const MAX_DATA_SIZE: usize = 1_400 - 20;
+// Largest delay drift per averaging period that is attributed to clock skew.
+// Crystal oscillators drift by up to 100ppm, 500us over the 5 second period.
+const MAX_CLOCK_DRIFT_MICROS: i32 = 500;
 
 impl UtpSocket {
     /// Bind a new `UtpSocket` to the given socket address
     pub fn bind(addr: &SocketAddr) -> io::Result<(UtpSocket, UtpListener)> {
-        UdpSocket::bind(addr).map(UtpSocket::from_socket)
+        UtpSocket::bind_with_config(addr, UtpConfig::new())
+    }
+
+    /// Returns a `Builder` to configure a `UtpSocket` before binding it.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Bind a new `UtpSocket` to the given socket address using the provided
+    /// configuration.
+    pub fn bind_with_config(addr: &SocketAddr, config: UtpConfig)
+        -> io::Result<(UtpSocket, UtpListener)>
+    {
+        UdpSocket::bind(addr)
+            .map(|socket| UtpSocket::from_mio_socket(socket, config))
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.inner.borrow().shared.socket.local_addr()
     }
 
-    /// Create a new `Utpsocket` backed by the provided `UdpSocket`.
-    pub fn from_socket(socket: UdpSocket) -> (UtpSocket, UtpListener) {
+    /// Sets the time-to-live of the IP packets sent by the socket.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.borrow().shared.socket.set_ttl(ttl)
+    }
+
+    /// Returns the time-to-live of the IP packets sent by the socket.
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.borrow().shared.socket.ttl()
+    }
+
+    /// Sets the TOS byte of the IPv4 packets sent by the socket, or their
+    /// traffic class for IPv6.
+    ///
+    /// The DSCP is held in the upper 6 bits, e.g. `0x20` marks the traffic as
+    /// the CS1 scavenger class and `0x04` as Lower Effort (RFC 8622), which
+    /// suits the background transfers LEDBAT is designed for. The lower 2
+    /// bits are the ECN field. Only supported on Unix.
+    pub fn set_tos(&self, tos: u8) -> io::Result<()> {
+        sockopt::set_tos(&self.inner.borrow().shared.socket, tos)
+    }
+
+    /// Returns the TOS byte, or the traffic class, of the packets sent by the
+    /// socket.
+    pub fn tos(&self) -> io::Result<u8> {
+        sockopt::tos(&self.inner.borrow().shared.socket)
+    }
+
+    /// Create a new `UtpSocket` backed by the provided, already bound,
+    /// `UdpSocket`.
+    ///
+    /// This allows options that `UtpSocket` does not expose, such as
+    /// `SO_REUSEADDR` or the bound device, to be set before binding. The
+    /// socket is switched to non-blocking mode.
+    pub fn from_socket(socket: net::UdpSocket) -> io::Result<(UtpSocket, UtpListener)> {
+        UtpSocket::from_socket_with_config(socket, UtpConfig::new())
+    }
+
+    /// Create a new `UtpSocket` backed by the provided, already bound,
+    /// `UdpSocket` using the provided configuration.
+    pub fn from_socket_with_config(socket: net::UdpSocket, config: UtpConfig)
+        -> io::Result<(UtpSocket, UtpListener)>
+    {
+        UdpSocket::from_socket(socket)
+            .map(|socket| UtpSocket::from_mio_socket(socket, config))
+    }
+
+    pub(crate) fn from_mio_socket(socket: UdpSocket, config: UtpConfig)
+        -> (UtpSocket, UtpListener)
+    {
         let (registration, set_readiness) = Registration::new2();
+        let path_cache = PathCache::new(config.path_cache_size(), config.path_cache_ttl());
 
         let inner = Rc::new(RefCell::new(Inner {
             shared: Shared {
-                socket: socket,
+                socket,
                 ready: Ready::empty(),
                 out_buf: Vec::with_capacity(DEFAULT_OUT_BUFFER_SIZE),
                 out_buf_dst: None,
+                gate: config.new_transmit_gate(),
+                drop_hook: config.drop_hook(),
+                dont_fragment: false,
+                driver: DriverStats::default(),
             },
+            config,
             connections: Slab::new(),
             connection_lookup: HashMap::new(),
             in_buf: BytesMut::with_capacity(DEFAULT_IN_BUFFER_SIZE),
             accept_buf: VecDeque::new(),
             listener: set_readiness,
             listener_open: true,
+            accept_waker: None,
+            path_cache,
+            created_at: Instant::now(),
+            created_allocs: allocs::counts(),
         }));
 
         let listener = UtpListener {
             inner: inner.clone(),
-            registration: registration,
+            registration,
         };
 
         let socket = UtpSocket {
-            inner: inner,
+            inner,
         };
 
         (socket, listener)
     }
 
-    /// Connect a new `UtpSocket` to the given remote socket address
-    pub fn connect(&self, addr: &SocketAddr) -> io::Result<UtpStream> {
-        self.inner.borrow_mut().connect(addr, &self.inner)
+    /// Connect a new `UtpSocket` to the given remote socket address.
+    ///
+    /// `addr` is resolved as by `TcpStream::connect`. The first address of the
+    /// socket's address family for which a connection can be opened is used,
+    /// the error of the last attempt is returned if there is none. As the
+    /// handshake completes in the background, a peer that does not answer at
+    /// that address is only reported by the stream.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UtpStream> {
+        let local_addr = self.local_addr()?;
+
+        util::each_addr(addr, |addr| {
+            // The socket only reaches peers of its own address family
+            if addr.is_ipv4() != local_addr.is_ipv4() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "address family differs from the socket's"));
+            }
+
+            self.inner.borrow_mut().connect(addr, &self.inner)
+        })
     }
 
-    /// Called whenever the socket readiness changes
+    /// Replaces `stream` with a new connection to the same peer.
+    ///
+    /// A peer that restarted no longer knows the stream's connection ID. Its
+    /// packets show up under an unknown ID, see
+    /// `UtpConfig::set_unknown_connection_hook`, or the stream fails with
+    /// `ConnectionReset`. The stale connection is reset, discarding pending
+    /// data, and a new one is established.
+    ///
+    /// Returns `InvalidInput` if `stream` belongs to another socket.
+    pub fn reconnect(&self, stream: UtpStream) -> io::Result<UtpStream> {
+        if !Rc::ptr_eq(&self.inner, &stream.inner) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "stream belongs to another socket"));
+        }
+
+        let addr = {
+            let mut inner = self.inner.borrow_mut();
+            let inner = &mut *inner;
+            let conn = &mut inner.connections[stream.token];
+
+            if conn.state != State::Reset {
+                // The RESET is best effort, the peer may not know the
+                // connection anymore.
+                let _ = conn.reset(&mut inner.shared);
+            }
+
+            conn.key.addr
+        };
+
+        // Releases the stale connection
+        drop(stream);
+
+        self.connect(addr)
+    }
+
+    /// Called whenever the socket readiness changes.
+    ///
+    /// `ready` is the readiness reported by the event loop. Inbound packets
+    /// are processed, and outbound packets are only sent once the socket has
+    /// been reported writable. See `examples/manual_event_loop.rs` for a
+    /// complete loop driving the socket.
     pub fn ready(&self, ready: Ready) -> io::Result<()> {
         self.inner.borrow_mut().ready(ready, &self.inner)
     }
@@ -213,6 +424,56 @@ impl UtpSocket {
     pub fn tick(&self) -> io::Result<()> {
         self.inner.borrow_mut().tick()
     }
+
+    /// Returns the amount of time until `tick` must be called for paced
+    /// packets, delayed ACKs, keep-alives, retransmissions and packets held
+    /// back by the burst limit to be sent on time.
+    ///
+    /// `tick` must still be called every 500ms. Returns `None` when no packets
+    /// are waiting.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.inner.borrow().next_timeout(Instant::now())
+    }
+
+    /// Returns what previous connections learned about the path to the host
+    /// of `addr`. The port is ignored.
+    pub fn path_info(&self, addr: &SocketAddr) -> Option<PathInfo> {
+        let inner = self.inner.borrow();
+        inner.path_cache.get(&addr.ip(), Instant::now()).cloned()
+    }
+
+    /// Returns a snapshot of the socket driver's statistics.
+    pub fn driver_stats(&self) -> DriverStats {
+        let inner = self.inner.borrow();
+
+        let mut stats = inner.shared.driver.clone();
+        stats.elapsed = inner.created_at.elapsed();
+
+        let allocs = allocs::counts();
+        stats.packet_allocations = allocs.packet - inner.created_allocs.packet;
+        stats.queue_allocations = allocs.queue - inner.created_allocs.queue;
+
+        stats
+    }
+
+    /// Returns true once every connection of the socket has been closed and
+    /// finalized.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.inner.borrow().connections.is_empty()
+    }
+
+    /// Returns false once sending would block, until `ready` is called with
+    /// writable readiness.
+    pub(crate) fn is_writable(&self) -> bool {
+        self.inner.borrow().shared.is_writable()
+    }
+
+    /// Returns the file descriptor of the UDP socket
+    #[cfg(unix)]
+    pub(crate) fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.inner.borrow().shared.socket.as_raw_fd()
+    }
 }
 
 impl Evented for UtpSocket {
@@ -240,15 +501,93 @@ impl UtpListener {
     pub fn accept(&self) -> io::Result<UtpStream> {
         self.inner.borrow_mut().accept()
     }
+
+    /// Returns an iterator over the inbound connections.
+    ///
+    /// Each item is the result of `accept`, so the iterator never ends and
+    /// yields `WouldBlock` errors while no connection is ready, as
+    /// `TcpListener::incoming` does for a non-blocking listener.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    /// Registers a task to wake once an inbound connection is ready.
+    pub(crate) fn register_accept_waker(&self, waker: &Waker) {
+        let mut inner = self.inner.borrow_mut();
+        register_waker(&mut inner.accept_waker, waker);
+    }
+}
+
+impl<'a> Incoming<'a> {
+    pub(crate) fn listener(&self) -> &'a UtpListener {
+        self.listener
+    }
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<UtpStream>;
+
+    fn next(&mut self) -> Option<io::Result<UtpStream>> {
+        Some(self.listener.accept())
+    }
 }
 
 impl Drop for UtpListener {
     fn drop(&mut self) {
-        let mut inner = self.inner.borrow_mut();
-        inner.listener_open = false;
+        let mut pending = vec![];
+
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.listener_open = false;
+
+            // Empty the connection queue
+            while let Ok(stream) = inner.accept() {
+                pending.push(stream);
+            }
+        }
+
+        // Dropping the streams closes their connections, which borrows the
+        // socket again
+        drop(pending);
+    }
+}
+
+#[cfg(test)]
+impl UtpSocket {
+    /// Panics if the socket holds state that can no longer be reclaimed.
+    ///
+    /// Released connections that are still closing are fine, their deadline
+    /// eventually finalizes them.
+    pub fn assert_no_leaks(&self) {
+        // Streams, the listener and accepted connections that were never
+        // handed out all hold on to the shared state.
+        assert_eq!(1, Rc::strong_count(&self.inner), "socket handles outlived the socket");
+
+        let inner = self.inner.borrow();
+
+        assert_eq!(inner.connections.len(), inner.connection_lookup.len(),
+                   "connection lookup out of sync");
+
+        for (key, &token) in &inner.connection_lookup {
+            let conn = &inner.connections[token];
+            assert_eq!(*key, conn.key, "connection lookup out of sync; token={}", token);
+        }
 
-        // Empty the connection queue
-        while let Ok(_) = inner.accept() {}
+        for (token, conn) in inner.connections.iter() {
+            assert!(!conn.is_finalized(),
+                    "finalized connection not removed; token={}; state={:?}",
+                    token, conn.state);
+
+            assert!(conn.deadline.is_some(),
+                    "closing connection has no deadline; token={}; state={:?}",
+                    token, conn.state);
+        }
+    }
+
+    /// Returns the state of each connection on the socket
+    pub fn connection_states(&self) -> Vec<State> {
+        let inner = self.inner.borrow();
+        inner.connections.iter().map(|(_, conn)| conn.state).collect()
     }
 }
 
@@ -284,39 +623,444 @@ impl UtpStream {
         inner.shared.socket.local_addr()
     }
 
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let inner = self.inner.borrow();
+        Ok(inner.connections[self.token].key.addr)
+    }
+
+    /// Returns the ID identifying the connection in the packets sent by the
+    /// peer. Packets sent to the peer carry the ID that follows it for
+    /// connections that were opened by `connect`, and the one preceding it
+    /// for accepted connections.
+    pub fn connection_id(&self) -> u16 {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].key.receive_id
+    }
+
     pub fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.read_with(|in_queue| in_queue.read(dst))
+    }
+
+    /// Reads data into each buffer of `dsts` in turn, filling one before
+    /// moving on to the next. Returns the total number of bytes read, see
+    /// `read`.
+    pub fn read_vectored(&self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.read_with(|in_queue| in_queue.read_vectored(dsts))
+    }
+
+    /// Reads data into `dst`, which does not need to be initialized.
+    ///
+    /// Returns the number of bytes read, see `read`; that many bytes at the
+    /// start of `dst` are initialized. Unlike `read`, data of several packets
+    /// is read at once. Large receive buffers can be reused without zeroing
+    /// them first.
+    pub fn read_uninit(&self, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.read_with(|in_queue| in_queue.read_uninit(dst))
+    }
+
+    /// Reads data into `dst`, advancing it past the data read. Returns the
+    /// number of bytes read, see `read`.
+    ///
+    /// Data is copied straight into the spare capacity of buffers such as
+    /// `BytesMut`, without going through a temporary slice.
+    pub fn read_buf<B: BufMut>(&self, dst: &mut B) -> io::Result<usize> {
+        self.read_with(|in_queue| in_queue.read_buf(dst))
+    }
+
+    /// Reads from the connection with `read`, which is passed the
+    /// connection's `InQueue`. Once data is consumed, the window is reopened
+    /// to the peer.
+    fn read_with<F>(&self, read: F) -> io::Result<usize>
+        where F: FnOnce(&mut InQueue) -> io::Result<usize>,
+    {
         let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
         let connection = &mut inner.connections[self.token];
 
-        match connection.in_queue.read(dst) {
+        match read(&mut connection.in_queue) {
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if connection.state == State::Connected {
-                    try!(connection.update_readiness());
-                    Err(io::ErrorKind::WouldBlock.into())
-                } else if connection.state.is_closed() {
-                    if connection.state == State::Reset {
-                        Err(io::ErrorKind::ConnectionReset.into())
-                    } else {
-                        Ok(0)
-                    }
-                } else {
-                    unreachable!();
-                }
+                connection.read_unavailable()
             }
             ret => {
+                connection.read_timeout.clear();
                 connection.update_local_window();
+                connection.flush(&mut inner.shared);
+                ret
+            }
+        }
+    }
+
+    /// Copies data received from the peer into `dst` without consuming it.
+    ///
+    /// The next `read` returns the same bytes. This allows inspecting the
+    /// start of a stream before picking a parser for it. Errors and EOF are
+    /// reported as by `read`.
+    pub fn peek(&self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+        let connection = &mut inner.connections[self.token];
+
+        match connection.in_queue.peek(dst) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                connection.read_unavailable()
+            }
+            ret => {
+                connection.read_timeout.clear();
                 ret
             }
         }
     }
 
     pub fn write(&self, src: &[u8]) -> io::Result<usize> {
-        self.inner.borrow_mut().write(self.token, src)
+        self.inner.borrow_mut().write(self.token, |q| q.write(src))
+    }
+
+    /// Writes the data of each buffer of `srcs` in turn, returning the total
+    /// number of bytes queued.
+    ///
+    /// The buffers are packetized as if they were a single buffer, so that
+    /// small buffers, such as a frame header followed by its body, do not
+    /// each take up a packet.
+    pub fn write_vectored(&self, srcs: &[IoSlice]) -> io::Result<usize> {
+        self.inner.borrow_mut().write(self.token, |q| q.write_vectored(srcs))
+    }
+
+    /// Writes data from `src`, advancing it past the data queued. Returns the
+    /// number of bytes queued.
+    ///
+    /// The chunks of `src` are packetized as a single buffer, see
+    /// `write_vectored`.
+    pub fn write_buf<B: Buf>(&self, src: &mut B) -> io::Result<usize> {
+        self.inner.borrow_mut().write(self.token, |q| q.write_buf(src))
+    }
+
+    /// Writes data from `src` without copying it.
+    ///
+    /// Queued packets hold references to segments of `src` until the peer
+    /// acknowledges them. Returns the number of bytes queued, which may be
+    /// less than `src.len()`; the rest can be written by slicing `src`.
+    pub fn write_bytes(&self, src: &Bytes) -> io::Result<usize> {
+        self.inner.borrow_mut().write(self.token, |q| q.write_bytes(src))
+    }
+
+    /// Sends any data held back by Nagle's algorithm.
+    ///
+    /// Written data is handed to the socket immediately, so this does not wait
+    /// for the peer to acknowledge it. Layered protocols, such as TLS, flush
+    /// after each handshake message, which would otherwise be delayed by a
+    /// round trip.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        conn.out_queue.push_pending();
+        conn.flush(&mut inner.shared);
+
+        Ok(())
+    }
+
+    /// Gracefully closes the connection.
+    ///
+    /// The first call shuts down both halves of the connection, see
+    /// `shutdown`. The peer's reads return EOF once it has received all of the
+    /// data.
+    ///
+    /// Returns `Ok(())` once the peer has acknowledged the data and the FIN,
+    /// and `WouldBlock` until then. The stream becomes writable once the close
+    /// completes.
+    pub fn close(&self) -> io::Result<()> {
+        self.close_with(Shutdown::Both)
+    }
+
+    /// Closes the write half as `close` does, leaving the read half open.
+    pub(crate) fn close_write(&self) -> io::Result<()> {
+        self.close_with(Shutdown::Write)
+    }
+
+    fn close_with(&self, how: Shutdown) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        // The FIN can only be sent once connected
+        if conn.state == State::SynSent {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        conn.shutdown(how, &mut inner.shared)?;
+
+        if conn.out_queue.is_empty() {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// Gracefully closes the connection, returning a summary of the delivery.
+    ///
+    /// This behaves as `close`, returning `WouldBlock` until the peer has
+    /// acknowledged the data and the FIN. Batch transfers can log the summary
+    /// per file or peer.
+    pub fn finish(&self) -> io::Result<Summary> {
+        self.close()?;
+        Ok(Summary::new(&self.stats()))
+    }
+
+    /// Splits the stream into a read half and a write half that borrow it.
+    pub fn split(&self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        split::split(self)
+    }
+
+    /// Splits the stream into a read half and a write half that can be used
+    /// independently, e.g. by two tasks. The connection is closed once both
+    /// halves are dropped.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        split::into_split(self)
+    }
+
+    /// Shuts down the read half, the write half, or both halves of the
+    /// connection, following the contract of `TcpStream::shutdown`.
+    ///
+    /// Shutting down the write half queues a FIN after any pending data, after
+    /// which writes fail with `BrokenPipe`. Data sent by the peer is still
+    /// received and acked until the peer closes its own write half, which
+    /// allows request / response protocols to signal the end of a request.
+    ///
+    /// Shutting down the read half discards buffered data and makes reads
+    /// return EOF. Data the peer sends afterwards is dropped without being
+    /// acked, or resets the connection, see
+    /// `UtpConfig::set_reset_on_read_shutdown`.
+    ///
+    /// Shutting down both halves does both, fully closing the connection.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        if conn.state == State::SynSent {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        conn.shutdown(how, &mut inner.shared)
+    }
+
+    /// Aborts the connection.
+    ///
+    /// Pending data is discarded and a RESET is sent to the peer, whose reads
+    /// and writes then fail with `ConnectionReset`, as do those on this
+    /// stream.
+    pub fn abort(&self) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        if conn.state == State::Reset {
+            return Ok(());
+        }
+
+        conn.reset(&mut inner.shared)
+    }
+
+    /// Sets the value of the `nodelay` option.
+    ///
+    /// When `false`, the default, small writes are coalesced and a packet
+    /// smaller than the max packet size is not sent while other data is
+    /// in-flight (Nagle's algorithm). Setting it to `true` sends data as soon
+    /// as the window allows, which suits interactive traffic.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        conn.out_queue.set_nodelay(nodelay);
+        conn.flush(&mut inner.shared);
+
+        Ok(())
+    }
+
+    /// Starts the congestion window at `window` bytes instead of a single
+    /// packet.
+    ///
+    /// Applications that have prior knowledge of the path, such as when
+    /// reconnecting to a peer they just transferred data with, can skip part
+    /// of the ramp up. The window is capped by `UtpConfig::max_initial_window`
+    /// and the congestion controller may ignore it. This must be called before
+    /// any data is sent, otherwise `InvalidInput` is returned.
+    pub fn set_initial_window(&self, window: usize) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        let conn = &mut inner.connections[self.token];
+
+        let window = cmp::min(window, inner.config.max_initial_window());
+
+        if !conn.out_queue.set_initial_window(window) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "data has already been sent"));
+        }
+
+        Ok(())
+    }
+
+    /// Gets the value of the `nodelay` option.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        let inner = self.inner.borrow();
+        Ok(inner.connections[self.token].out_queue.nodelay())
+    }
+
+    /// Sets the read timeout.
+    ///
+    /// Once reads have been returning `WouldBlock` for `timeout`, the stream
+    /// becomes readable and the next read fails with `TimedOut`, letting the
+    /// application give up on an unresponsive peer. The time is counted from
+    /// the first read that would block, and restarts once a read returns
+    /// data. `None`, the default, lets reads block indefinitely.
+    ///
+    /// Returns `InvalidInput` if `timeout` is zero, as `TcpStream` does.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+
+        let mut inner = self.inner.borrow_mut();
+        inner.connections[self.token].read_timeout.set(timeout);
+
+        Ok(())
+    }
+
+    /// Returns the read timeout.
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        let inner = self.inner.borrow();
+        Ok(inner.connections[self.token].read_timeout.get())
+    }
+
+    /// Sets the write timeout.
+    ///
+    /// Writes fail with `TimedOut` once they have been returning `WouldBlock`
+    /// for `timeout`, such as when the peer stopped acking data, see
+    /// `set_read_timeout`. Writes block while connecting, so this bounds the
+    /// handshake as well.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+
+        let mut inner = self.inner.borrow_mut();
+        inner.connections[self.token].write_timeout.set(timeout);
+
+        Ok(())
+    }
+
+    /// Returns the write timeout.
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        let inner = self.inner.borrow();
+        Ok(inner.connections[self.token].write_timeout.get())
+    }
+
+    /// Sets the priority reported to the `TransmitGate` with each packet of
+    /// the connection. The meaning of the value is up to the gate. Defaults
+    /// to 0.
+    pub fn set_priority(&self, priority: u8) {
+        let mut inner = self.inner.borrow_mut();
+        inner.connections[self.token].priority = priority;
+    }
+
+    /// Returns the priority reported to the `TransmitGate`.
+    pub fn priority(&self) -> u8 {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].priority
+    }
+
+    /// Returns `Ok(())` once the handshake completed, `WouldBlock` while
+    /// connecting, and the error that ended the connection if it failed.
+    pub(crate) fn connect_result(&self) -> io::Result<()> {
+        let inner = self.inner.borrow();
+        let conn = &inner.connections[self.token];
+
+        match conn.state {
+            State::SynSent => Err(io::ErrorKind::WouldBlock.into()),
+            State::Reset => Err(conn.reset_error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Registers a task to wake once the stream becomes readable.
+    pub(crate) fn register_read_waker(&self, waker: &Waker) {
+        let mut inner = self.inner.borrow_mut();
+        register_waker(&mut inner.connections[self.token].read_waker, waker);
+    }
+
+    /// Registers a task to wake once the stream becomes writable.
+    pub(crate) fn register_write_waker(&self, waker: &Waker) {
+        let mut inner = self.inner.borrow_mut();
+        register_waker(&mut inner.connections[self.token].write_waker, waker);
+    }
+
+    /// Returns a snapshot of the connection's statistics.
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].stats(Instant::now())
+    }
+
+    /// Returns a copy of the data written to the stream that the peer has not
+    /// acked yet.
+    ///
+    /// Once the connection failed, e.g. it was reset or timed out, this is
+    /// what must be written to a new connection to pick up where this one
+    /// stopped. Everything written before it was acked by the peer, see
+    /// `ack_offset`. Data the peer selectively acked is included, as
+    /// it may not have been delivered before the connection failed.
+    pub fn unacked_data(&self) -> Vec<u8> {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].out_queue.unacked_data()
+    }
+
+    /// Returns the number of bytes written to the stream that the peer has
+    /// acked in order.
+    ///
+    /// The peer received at least this many bytes from the start of the
+    /// stream. This remains available after the connection failed, so resume
+    /// logic can tell exactly where to pick up. Selectively acked data past
+    /// a gap is not counted.
+    pub fn ack_offset(&self) -> u64 {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].out_queue.ack_offset()
+    }
+
+    /// Returns the smoothed round trip time to the peer.
+    ///
+    /// Only packets that were sent once are sampled. This is zero until the
+    /// first sample is taken.
+    pub fn rtt(&self) -> Duration {
+        let inner = self.inner.borrow();
+        Duration::from_millis(inner.connections[self.token].out_queue.rtt())
+    }
+
+    /// Returns the mean deviation of the round trip time to the peer.
+    pub fn rtt_variance(&self) -> Duration {
+        let inner = self.inner.borrow();
+        Duration::from_millis(inner.connections[self.token].out_queue.rtt_variance())
+    }
+
+    /// Returns `true` if the configured `PeerPolicy` reported the peer as slow
+    /// during its last check.
+    pub fn is_slow_peer(&self) -> bool {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].slow_peer
+    }
+
+    /// Returns `true` if the watchdog flagged the connection as stalled and
+    /// no packet was sent or received since, see `UtpConfig::set_watchdog`.
+    pub fn is_stalled(&self) -> bool {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].stalled
     }
 }
 
 #[cfg(test)]
 impl UtpStream {
+    /// Number of received bytes waiting to be read
+    pub fn bytes_buffered(&self) -> usize {
+        let inner = self.inner.borrow();
+        inner.connections[self.token].in_queue.bytes_pending()
+    }
+
     pub fn is_readable(&self) -> bool {
         let inner = self.inner.borrow();
         let connection = &inner.connections[self.token];
@@ -330,6 +1074,54 @@ impl UtpStream {
     }
 }
 
+impl io::Read for UtpStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        UtpStream::read(self, dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        UtpStream::read_vectored(self, dsts)
+    }
+}
+
+impl io::Read for &UtpStream {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        UtpStream::read(self, dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        UtpStream::read_vectored(self, dsts)
+    }
+}
+
+impl io::Write for UtpStream {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        UtpStream::write(self, src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        UtpStream::write_vectored(self, srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        UtpStream::flush(self)
+    }
+}
+
+impl io::Write for &UtpStream {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        UtpStream::write(self, src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        UtpStream::write_vectored(self, srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        UtpStream::flush(self)
+    }
+}
+
 impl Drop for UtpStream {
     fn drop(&mut self) {
         self.inner.borrow_mut().close(self.token);
@@ -369,39 +1161,69 @@ impl Inner {
                     unreachable!();
                 }
 
-                try!(conn.update_readiness());
+                conn.update_readiness()?;
 
                 Ok(socket)
             }
             None => {
                 // Unset readiness
-                try!(self.listener.set_readiness(Ready::empty()));
+                self.listener.set_readiness(Ready::empty())?;
 
                 Err(io::ErrorKind::WouldBlock.into())
             }
         }
     }
 
-    fn write(&mut self, token: usize, src: &[u8]) -> io::Result<usize> {
+    /// Queues data on the connection with `write`, which is passed the
+    /// connection's `OutQueue`
+    fn write<F>(&mut self, token: usize, write: F) -> io::Result<usize>
+        where F: FnOnce(&mut OutQueue) -> io::Result<usize>,
+    {
         let conn = &mut self.connections[token];
 
+        if conn.state == State::SynSent {
+            if conn.write_timeout.would_block(Instant::now()) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            // The stream becomes writable once connected
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        if conn.state == State::Reset {
+            return Err(conn.reset_error.into());
+        }
+
         if conn.state != State::Connected {
             assert!(conn.state.is_closed(),
                     "expected closed state; actual={:?}", conn.state);
 
+            if conn.expired {
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            }
+
             return Err(io::ErrorKind::BrokenPipe.into());
         }
 
-        match conn.out_queue.write(src) {
+        match write(&mut conn.out_queue) {
             Ok(n) => {
+                conn.write_timeout.clear();
                 conn.flush(&mut self.shared);
-                try!(conn.update_readiness());
+                conn.update_readiness()?;
                 Ok(n)
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                conn.last_maxed_out_window = Instant::now();
-                try!(conn.update_readiness());
-                Err(io::ErrorKind::WouldBlock.into())
+                let now = Instant::now();
+                conn.last_maxed_out_window = now;
+
+                let timed_out = conn.write_timeout.would_block(now);
+                conn.update_readiness()?;
+
+                if timed_out {
+                    Err(io::ErrorKind::TimedOut.into())
+                } else {
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
             }
             Err(e) => {
                 Err(e)
@@ -411,18 +1233,18 @@ impl Inner {
 
     /// Connect a new `UtpSocket` to the given remote socket address
     fn connect(&mut self, addr: &SocketAddr, inner: &InnerCell) -> io::Result<UtpStream> {
-        if self.connections.len() == MAX_CONNECTIONS_PER_SOCKET {
-            return Err(io::Error::new(io::ErrorKind::Other, "socket has max connections"));
+        if self.connections.len() == self.config.max_connections() {
+            return Err(io::Error::other("socket has max connections"));
         }
 
-        debug_assert!(self.connections.len() < MAX_CONNECTIONS_PER_SOCKET);
+        debug_assert!(self.connections.len() < self.config.max_connections());
 
         // The peer establishing the connection picks the identifiers uses for
         // the stream.
         let (receive_id, mut send_id) = util::generate_sequential_identifiers();
 
         let mut key = Key {
-            receive_id: receive_id,
+            receive_id,
             addr: addr.clone()
         };
 
@@ -434,47 +1256,99 @@ impl Inner {
             send_id += 1;
         }
 
-        // SYN packet has seq_nr of 1
-        let mut out_queue = OutQueue::new(send_id, 0, None);
+        let now = Instant::now();
+
+        // The seq_nr is incremented before the SYN is sent
+        let seq_nr = util::initial_seq_nr().wrapping_sub(1);
+
+        let mut out_queue = OutQueue::new(
+            send_id, seq_nr, None, self.config.new_congestion_control(), now);
+        out_queue.set_max_packet_size(self.config.max_packet_size());
+        out_queue.set_min_packet_size(self.config.min_packet_size());
+        out_queue.set_max_packets_in_flight(self.config.max_packets_in_flight());
+        out_queue.set_max_window_size(self.config.max_window_size());
+        out_queue.set_initial_timeout(self.config.initial_timeout());
+        out_queue.set_min_timeout(self.config.min_timeout());
+        out_queue.set_pacing(self.config.pacing());
+        out_queue.set_max_ack_delay(self.config.max_ack_delay());
+        out_queue.set_delayed_ack(self.config.delayed_ack());
+        out_queue.set_keepalive(self.config.keepalive());
+        out_queue.set_mtu_probing(self.config.mtu_discovery());
+        out_queue.set_timestamp_source(self.config.new_timestamp_source(now));
+
+        if let Some(info) = self.path_cache.get(&addr.ip(), now) {
+            out_queue.set_path_info(info);
+        }
+
+        let mut in_queue = InQueue::new(None);
+        in_queue.set_max_held(self.config.reorder_buffer_size());
+        in_queue.set_capacity(cmp::min(self.config.recv_buffer_size(),
+                                       self.config.max_window_size()));
+        out_queue.set_local_window(in_queue.local_window());
 
         let mut packet = Packet::syn();
         packet.set_connection_id(key.receive_id);
 
+        if self.config.advertise_extensions() {
+            // No extension beyond selective ACKs is supported, all bits are
+            // cleared as in libutp.
+            packet.set_extension_bits(&[0; packet::EXTENSION_BITS_LEN]);
+        }
+
         // Queue the syn packet
         out_queue.push(packet);
 
         let (registration, set_readiness) = Registration::new2();
-        let now = Instant::now();
 
         let token = self.connections.insert(Connection {
             state: State::SynSent,
             key: key.clone(),
-            set_readiness: set_readiness,
-            out_queue: out_queue,
-            in_queue: InQueue::new(None),
+            set_readiness,
+            out_queue,
+            in_queue,
             our_delays: Delays::new(),
             their_delays: Delays::new(),
             released: false,
-            deadline: Some(now + Duration::from_millis(DEFAULT_TIMEOUT_MS)),
+            deadline: Some(now + self.config.initial_timeout()),
             last_maxed_out_window: now,
-            average_delay: 0,
-            current_delay_sum: 0,
-            current_delay_samples: 0,
-            average_delay_base: 0,
-            average_sample_time: now,
-            clock_drift: 0,
-            slow_start: true,
+            last_recv_at: now,
+            idle_timeout: self.config.idle_timeout(),
+            max_retransmits: self.config.max_retransmits(),
+            max_burst: self.config.max_burst(),
+            burst_limited: false,
+            priority: 0,
+            gated_until: None,
+            expires_at: self.config.max_lifetime().map(|max| now + max),
+            expired: false,
+            reset_error: io::ErrorKind::ConnectionReset,
+            clock_drift: ClockDrift::new(now),
+            jitter: Jitter::new(),
+            peer_policy: self.config.new_peer_policy(),
+            slow_peer: false,
+            watchdog: self.config.watchdog(),
+            stalled: false,
+            read_closed: false,
+            read_shutdown: false,
+            reset_on_read_shutdown: self.config.reset_on_read_shutdown(),
+            quality: QualityMeter::new(),
+            read_waker: None,
+            write_waker: None,
+            read_timeout: IoTimeout::default(),
+            write_timeout: IoTimeout::default(),
         });
 
         // Track the connection in the lookup
         self.connection_lookup.insert(key, token);
 
+        self.shared.driver.max_connections =
+            cmp::max(self.shared.driver.max_connections, self.connections.len());
+
         self.flush();
 
         Ok(UtpStream {
             inner: inner.clone(),
-            token: token,
-            registration: registration,
+            token,
+            registration,
         })
     }
 
@@ -498,10 +1372,20 @@ impl Inner {
         // Update readiness
         self.shared.update_ready(ready);
 
+        let mut received = 0;
+
         loop {
-            // Try to receive a packet
-            let (packet, addr) = match self.recv_from() {
-                Ok(v) => v,
+            // The packet is parsed in place, the buffer is moved out of the
+            // way so that it can be borrowed while the packet is processed.
+            let mut in_buf = mem::replace(&mut self.in_buf, BytesMut::new());
+            let res = self.recv_packet(&mut in_buf, inner);
+
+            // Unless the packet was queued, its space is reused
+            in_buf.clear();
+            self.in_buf = in_buf;
+
+            match res {
+                Ok(()) => received += 1,
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     trace!("ready -> would block");
                     break;
@@ -510,34 +1394,133 @@ impl Inner {
                     trace!("recv_from; error={:?}", e);
                     return Err(e);
                 }
-            };
-
-            trace!("recv_from; addr={:?}; packet={:?}", addr, packet);
-
-            match self.process(packet, addr, inner) {
-                Ok(_) => {}
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    panic!("NOPE");
-                }
-                Err(e) => return Err(e),
             }
         }
 
+        self.update_driver_stats(received);
+
         self.flush();
         Ok(())
     }
 
+    fn update_driver_stats(&mut self, received: u64) {
+        let driver = &mut self.shared.driver;
+
+        driver.wakeups += 1;
+        driver.packets_received += received;
+        driver.max_packets_per_wakeup = cmp::max(driver.max_packets_per_wakeup, received);
+        driver.max_connections = cmp::max(driver.max_connections, self.connections.len());
+        driver.max_accept_backlog = cmp::max(driver.max_accept_backlog, self.accept_buf.len());
+    }
+
     fn tick(&mut self) -> io::Result<()> {
         trace!("Socket::tick");
+        let start = Instant::now();
+        let mut finalized = vec![];
+
         for &idx in self.connection_lookup.values() {
-            try!(self.connections[idx].tick(&mut self.shared));
+            let conn = &mut self.connections[idx];
+
+            if conn.tick(&mut self.shared)? {
+                finalized.push(idx);
+                continue;
+            }
+
+            if let Some(stall) = conn.check_stall(Instant::now()) {
+                debug!("connection stalled; {:?}", stall);
+                self.config.notify_stall(&stall);
+
+                if self.config.watchdog_reset() {
+                    conn.reset_error = io::ErrorKind::TimedOut;
+                    conn.reset(&mut self.shared)?;
+
+                    if conn.is_finalized() {
+                        finalized.push(idx);
+                    }
+                }
+            }
         }
 
+        for idx in finalized {
+            self.remove_connection(idx);
+        }
+
+        self.shared.driver.ticks += 1;
+        self.shared.driver.tick_time += start.elapsed();
+
         Ok(())
     }
 
+    fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.connection_lookup.values()
+            .flat_map(|&idx| {
+                let conn = &self.connections[idx];
+                let out_queue = &conn.out_queue;
+
+                // Packets held back by the burst limit are sent right away
+                let burst_at = if conn.burst_limited { Some(now) } else { None };
+
+                burst_at.into_iter()
+                    .chain(conn.deadline)
+                    .chain(conn.gated_until)
+                    .chain(out_queue.next_send_at())
+                    .chain(out_queue.ack_due_at())
+                    .chain(out_queue.keepalive_at())
+                    .chain(out_queue.window_probe_at())
+                    .chain(conn.read_timeout.deadline())
+                    .chain(conn.write_timeout.deadline())
+            })
+            .min()
+            .map(|at| if at > now { at - now } else { Duration::from_secs(0) })
+    }
+
+    /// Receives a single packet into `in_buf` and processes it
+    fn recv_packet(&mut self, in_buf: &mut BytesMut, inner: &InnerCell) -> io::Result<()> {
+        let addr = self.recv_from(in_buf)?;
+
+        let packet = match PacketRef::parse(in_buf) {
+            Ok(packet) => packet,
+            Err(e) => {
+                trace!("malformed packet; addr={:?}; err={}", addr, e);
+                self.shared.dropped(&addr, DropReason::Malformed);
+                return Ok(());
+            }
+        };
+
+        trace!("recv_from; addr={:?}; packet={}", addr, packet);
+
+        if packet.payload().len() > self.config.max_recv_payload_size() {
+            trace!("oversized packet; len={}", packet.payload().len());
+            self.shared.dropped(&addr, DropReason::Oversized);
+            return Ok(());
+        }
+
+        if self.config.strict_validation() {
+            if let Err(e) = packet.validate_strict() {
+                trace!("invalid packet; err={}", e);
+                self.shared.dropped(&addr, DropReason::Invalid);
+                return Ok(());
+            }
+        }
+
+        if self.config.strict_extensions() {
+            if let Some(ty) = packet.unknown_extension() {
+                trace!("unknown extension; ty={}", ty);
+                self.shared.dropped(&addr, DropReason::UnknownExtension);
+                return Ok(());
+            }
+        }
+
+        match self.process(packet, addr, inner) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                panic!("NOPE");
+            }
+            res => res,
+        }
+    }
+
     fn process(&mut self,
-               packet: Packet,
+               packet: PacketRef,
                addr: SocketAddr,
                inner: &InnerCell) -> io::Result<()>
     {
@@ -556,7 +1539,7 @@ impl Inner {
                     Some(&token) => {
                         let finalized = {
                             let conn = &mut self.connections[token];
-                            try!(conn.process(packet, &mut self.shared))
+                            conn.process(packet, &mut self.shared)?
                         };
 
                         if finalized {
@@ -566,13 +1549,32 @@ impl Inner {
                         Ok(())
                     }
                     None => {
-                        trace!("no connection associated with ID; dropping packet");
+                        trace!("no connection associated with ID; id={}", packet.connection_id());
+                        self.shared.dropped(&addr, DropReason::UnknownConnection);
+
+                        // A peer with other connections on the socket most
+                        // likely restarted.
+                        let known_peer = self.connection_lookup.keys()
+                            .any(|key| key.addr == addr);
+
+                        if known_peer {
+                            match self.config.unknown_connection() {
+                                UnknownConnection::Ignore => return Ok(()),
+                                UnknownConnection::Notify => {
+                                    self.config.notify_unknown_connection(&addr, packet.connection_id());
+                                    return Ok(());
+                                }
+                                UnknownConnection::Reset => {}
+                            }
+                        }
 
-                        // Send the RESET packet, ignoring errors...
-                        let mut p = Packet::reset();
-                        p.set_connection_id(packet.connection_id());
+                        // Answering a RESET with a RESET would bounce packets
+                        // between two peers that both forgot the connection.
+                        if packet.ty() == packet::Type::Reset {
+                            return Ok(());
+                        }
 
-                        let _ = self.shared.socket.send_to(p.as_slice(), &addr);
+                        self.reset_unknown(packet.connection_id(), &addr);
 
                         return Ok(());
                     }
@@ -581,17 +1583,32 @@ impl Inner {
         }
     }
 
+    /// Answers a packet that matches no connection with a RESET, unless the
+    /// socket is configured to drop it silently.
+    fn reset_unknown(&self, connection_id: u16, addr: &SocketAddr) {
+        if self.config.silent_drop() {
+            trace!("silently dropping packet; id={}", connection_id);
+            return;
+        }
+
+        // Send the RESET packet, ignoring errors...
+        let mut p = Packet::reset();
+        p.set_connection_id(connection_id);
+
+        let mut buf = [0; HEADER_LEN];
+        let n = p.encode_into(&mut buf);
+
+        let _ = self.shared.socket.send_to(&buf[..n], addr);
+    }
+
     fn process_syn(&mut self,
-                   packet: Packet,
+                   packet: PacketRef,
                    addr: SocketAddr,
                    inner: &InnerCell) -> io::Result<()>
     {
         if !self.listener_open {
-            // Send the RESET packet, ignoring errors...
-            let mut p = Packet::reset();
-            p.set_connection_id(packet.connection_id());
-
-            let _ = self.shared.socket.send_to(p.as_slice(), &addr);
+            self.shared.dropped(&addr, DropReason::NotListening);
+            self.reset_unknown(packet.connection_id(), &addr);
 
             return Ok(());
         }
@@ -601,15 +1618,34 @@ impl Inner {
         let send_id = packet.connection_id();
         let receive_id = send_id + 1;
 
-        // TODO: If accept buffer is full, reset the connection
-
         let key = Key {
-            receive_id: receive_id,
-            addr: addr,
+            receive_id,
+            addr,
         };
 
-        if self.connection_lookup.contains_key(&key) {
-            // Just ignore the packet...
+        if let Some(&token) = self.connection_lookup.get(&key) {
+            // The peer retransmitted the SYN, our STATE must have been lost
+            self.shared.dropped(&addr, DropReason::Duplicate);
+
+            let conn = &mut self.connections[token];
+            conn.out_queue.resend_ack();
+            conn.flush(&mut self.shared);
+
+            return Ok(());
+        }
+
+        let full = if self.accept_buf.len() >= self.config.accept_backlog() {
+            Some(DropReason::AcceptBacklogFull)
+        } else if self.connections.len() >= self.config.max_connections() {
+            Some(DropReason::MaxConnections)
+        } else {
+            None
+        };
+
+        if let Some(reason) = full {
+            self.shared.dropped(&addr, reason);
+            self.reset_unknown(send_id, &addr);
+
             return Ok(());
         }
 
@@ -617,24 +1653,66 @@ impl Inner {
 
         let now = Instant::now();
 
+        let mut out_queue = OutQueue::new(
+            send_id, seq_nr, Some(ack_nr), self.config.new_congestion_control(), now);
+        out_queue.set_max_packet_size(self.config.max_packet_size());
+        out_queue.set_min_packet_size(self.config.min_packet_size());
+        out_queue.set_max_packets_in_flight(self.config.max_packets_in_flight());
+        out_queue.set_max_window_size(self.config.max_window_size());
+        out_queue.set_initial_timeout(self.config.initial_timeout());
+        out_queue.set_min_timeout(self.config.min_timeout());
+        out_queue.set_pacing(self.config.pacing());
+        out_queue.set_max_ack_delay(self.config.max_ack_delay());
+        out_queue.set_delayed_ack(self.config.delayed_ack());
+        out_queue.set_keepalive(self.config.keepalive());
+        out_queue.set_mtu_probing(self.config.mtu_discovery());
+        out_queue.set_timestamp_source(self.config.new_timestamp_source(now));
+
+        if let Some(info) = self.path_cache.get(&addr.ip(), now) {
+            out_queue.set_path_info(info);
+        }
+
+        let mut in_queue = InQueue::new(Some(ack_nr));
+        in_queue.set_max_held(self.config.reorder_buffer_size());
+        in_queue.set_capacity(cmp::min(self.config.recv_buffer_size(),
+                                       self.config.max_window_size()));
+        out_queue.set_local_window(in_queue.local_window());
+
         let mut connection = Connection {
             state: State::SynRecv,
             key: key.clone(),
-            set_readiness: set_readiness,
-            out_queue: OutQueue::new(send_id, seq_nr, Some(ack_nr)),
-            in_queue: InQueue::new(Some(ack_nr)),
+            set_readiness,
+            out_queue,
+            in_queue,
             released: false,
             our_delays: Delays::new(),
             their_delays: Delays::new(),
             deadline: None,
             last_maxed_out_window: now,
-            average_delay: 0,
-            current_delay_sum: 0,
-            current_delay_samples: 0,
-            average_delay_base: 0,
-            average_sample_time: now,
-            clock_drift: 0,
-            slow_start: true,
+            last_recv_at: now,
+            idle_timeout: self.config.idle_timeout(),
+            max_retransmits: self.config.max_retransmits(),
+            max_burst: self.config.max_burst(),
+            burst_limited: false,
+            priority: 0,
+            gated_until: None,
+            expires_at: self.config.max_lifetime().map(|max| now + max),
+            expired: false,
+            reset_error: io::ErrorKind::ConnectionReset,
+            clock_drift: ClockDrift::new(now),
+            jitter: Jitter::new(),
+            peer_policy: self.config.new_peer_policy(),
+            slow_peer: false,
+            watchdog: self.config.watchdog(),
+            stalled: false,
+            read_closed: false,
+            read_shutdown: false,
+            reset_on_read_shutdown: self.config.reset_on_read_shutdown(),
+            quality: QualityMeter::new(),
+            read_waker: None,
+            write_waker: None,
+            read_timeout: IoTimeout::default(),
+            write_timeout: IoTimeout::default(),
         };
 
         // This will handle the state packet being sent
@@ -646,31 +1724,29 @@ impl Inner {
         // Store the connection in the accept buffer
         self.accept_buf.push_back(UtpStream {
             inner: inner.clone(),
-            token: token,
-            registration: registration,
+            token,
+            registration,
         });
 
         // Notify the listener
-        try!(self.listener.set_readiness(Ready::readable()));
+        self.listener.set_readiness(Ready::readable())?;
+        wake(&mut self.accept_waker);
 
         return Ok(());
     }
 
-    fn recv_from(&mut self) -> io::Result<(Packet, SocketAddr)> {
+    /// Reads a packet into `in_buf`, which must be empty
+    fn recv_from(&mut self, in_buf: &mut BytesMut) -> io::Result<SocketAddr> {
         // Ensure the buffer has at least 4kb of available space.
-        self.in_buf.reserve(MIN_BUFFER_SIZE);
+        allocs::grow_buffer(in_buf, MIN_BUFFER_SIZE);
+        in_buf.reserve(MIN_BUFFER_SIZE);
 
         // Read in the bytes
-        let addr = unsafe {
-            let (n, addr) = try!(self.shared.socket.recv_from(self.in_buf.bytes_mut()));
-            self.in_buf.advance_mut(n);
-            addr
-        };
-
-        // Try loading the header
-        let packet = try!(Packet::parse(self.in_buf.take()));
-
-        Ok((packet, addr))
+        unsafe {
+            let (n, addr) = self.shared.socket.recv_from(in_buf.bytes_mut())?;
+            in_buf.advance_mut(n);
+            Ok(addr)
+        }
     }
 
     fn flush(&mut self) {
@@ -689,6 +1765,11 @@ impl Inner {
     fn remove_connection(&mut self, token: usize) {
         let connection = self.connections.remove(token);
         self.connection_lookup.remove(&connection.key);
+
+        if let Some(info) = connection.out_queue.path_info() {
+            self.path_cache.insert(connection.key.addr.ip(), info, Instant::now());
+        }
+
         trace!("removing connection state; token={:?}, addr={:?}; id={:?}",
                token, connection.key.addr, connection.key.receive_id);
     }
@@ -706,6 +1787,37 @@ impl Shared {
     fn need_writable(&mut self) {
         self.ready.remove(Ready::writable());
     }
+
+    /// Encodes the packet into the out buffer and sends it
+    fn send_to(&mut self, packet: &Packet, addr: &SocketAddr) -> io::Result<usize> {
+        self.out_buf.clear();
+        packet.encode_to(&mut self.out_buf);
+
+        self.socket.send_to(&self.out_buf, addr)
+    }
+
+    /// Sets the "don't fragment" bit for the packets sent next. Probes that
+    /// got through as fragments would otherwise raise the MTU past the path's.
+    fn set_dont_fragment(&mut self, val: bool) {
+        if self.dont_fragment == val {
+            return;
+        }
+
+        match sockopt::set_dont_fragment(&self.socket, val) {
+            Ok(()) => self.dont_fragment = val,
+            Err(e) => trace!("failed to set don't fragment; err={:?}", e),
+        }
+    }
+
+    /// Records that a packet received from `addr` was discarded
+    fn dropped(&mut self, addr: &SocketAddr, reason: DropReason) {
+        trace!("dropping packet; addr={:?}; reason={:?}", addr, reason);
+        self.driver.drops[reason as usize] += 1;
+
+        if let Some(ref f) = self.drop_hook {
+            f(addr, reason);
+        }
+    }
 }
 
 impl Connection {
@@ -714,46 +1826,87 @@ impl Connection {
     }
 
     /// Process an inbound packet for the connection
-    fn process(&mut self, packet: Packet, shared: &mut Shared) -> io::Result<bool> {
+    fn process(&mut self, packet: PacketRef, shared: &mut Shared) -> io::Result<bool> {
         let now = Instant::now();
+        let action = state::on_packet(self.state, packet.ty());
 
-        if self.state == State::Reset {
-            return Ok(self.is_finalized());
+        match action {
+            Action::Ignore => {
+                shared.dropped(&self.key.addr, DropReason::ConnectionReset);
+                return Ok(self.is_finalized());
+            }
+            Action::Reject => {
+                trace!("packet not valid in state; state={:?}; packet={}", self.state, packet);
+                shared.dropped(&self.key.addr, DropReason::InvalidState);
+                return Ok(false);
+            }
+            _ => {}
+        }
+
+        // A spoofed or stale ACK would drop packets that the peer never
+        // received from the queue
+        if action != Action::Reset && !self.out_queue.is_valid_ack(packet.ack_nr()) {
+            trace!("invalid ack_nr; packet={}", packet);
+            shared.dropped(&self.key.addr, DropReason::InvalidAck);
+            return Ok(false);
         }
 
-        if packet.ty() == packet::Type::Reset {
+        self.last_recv_at = now;
+
+        if action == Action::Reset {
             self.state = State::Reset;
 
             // Update readiness
-            try!(self.update_readiness());
+            self.update_readiness()?;
 
             return Ok(self.is_finalized());
         }
 
-        // TODO: Invalid packets should be discarded here.
-
+        let start = Instant::now();
         self.update_delays(now, &packet);
+        shared.driver.congestion_time += start.elapsed();
+
+        // Every packet carries the peer's current receive window. Without
+        // this, the window would only be learned from the handshake and a
+        // slow reader could not throttle the sender.
+        self.out_queue.set_peer_window(packet.wnd_size());
+
+        // STATE packets do not have an associated sequence number, thus do not
+        // require ordering. They are only used to ACK packets, which is handled
+        // above, and to complete the handshake.
+        if action == Action::Establish {
+            self.in_queue.set_initial_ack_nr(packet.seq_nr());
+            self.out_queue.set_local_ack(packet.seq_nr());
+
+            self.state = State::Connected;
+        } else if action == Action::Sequence {
+            if self.read_shutdown && packet.ty() == packet::Type::Data {
+                trace!("data after read shutdown; seq_nr={}", packet.seq_nr());
+                shared.dropped(&self.key.addr, DropReason::ReadShutdown);
+
+                if self.reset_on_read_shutdown {
+                    self.reset(shared)?;
+                    return Ok(self.is_finalized());
+                }
 
-        if packet.ty() == packet::Type::State {
-            // State packets are special, they do not have an associated
-            // sequence number, thus do not require ordering. They are only used
-            // to ACK packets, which is handled above, and to transition a
-            // connection into the connected state.
-            if self.state == State::SynSent {
-                self.in_queue.set_initial_ack_nr(packet.seq_nr());
-                self.out_queue.set_local_ack(packet.seq_nr());
-                self.out_queue.set_peer_window(packet.wnd_size());
-
-                self.state = State::Connected;
+                return Ok(false);
             }
-        } else {
-            // TODO: validate the packet's ack_nr
+
+            let seq_nr = packet.seq_nr();
 
             // Add the packet to the inbound queue. This handles ordering
             trace!("inqueue -- push packet");
-            if !self.in_queue.push(packet) {
-                // Invalid packet, avoid any further processing
-                trace!("invalid packet");
+            if let Err(reason) = self.in_queue.push(packet.into_packet()) {
+                shared.dropped(&self.key.addr, reason);
+
+                if self.in_queue.is_consumed(seq_nr) {
+                    // Our STATE was lost. Unless it is sent again, the peer
+                    // keeps retransmitting the packet.
+                    trace!("duplicate packet; seq_nr={}", seq_nr);
+                    self.out_queue.resend_ack();
+                    self.flush(shared);
+                }
+
                 return Ok(false);
             }
         }
@@ -763,21 +1916,18 @@ impl Connection {
         trace!("polling from in_queue");
 
         while let Some(packet) = self.in_queue.poll() {
-            trace!("process; packet={:?}; state={:?}", packet, self.state);
-
-            // Update the peer window size
-            self.out_queue.set_peer_window(packet.wnd_size());
+            trace!("process; packet={}; state={:?}", packet, self.state);
 
             // At this point, we only receive CTL frames. Data is held in the
             // queue
             match packet.ty() {
-                packet::Type::Reset => {
-                    self.state = State::Reset;
-                }
                 packet::Type::Fin => {
-                    self.send_fin(true, shared);
+                    // The peer closed its write half, ours stays open until
+                    // the stream is shut down or dropped.
+                    self.read_closed = true;
                 }
                 packet::Type::Data |
+                    packet::Type::Reset |
                     packet::Type::Syn |
                     packet::Type::State => unreachable!(),
             }
@@ -797,70 +1947,272 @@ impl Connection {
         self.flush(shared);
 
         // Update readiness
-        try!(self.update_readiness());
+        self.update_readiness()?;
 
         Ok(self.is_finalized())
     }
 
     fn flush(&mut self, shared: &mut Shared) {
-        let mut sent = false;
+        let mut sent = 0;
+
+        self.burst_limited = false;
 
         if self.state == State::Reset {
             return;
         }
 
-        while let Some(next) = self.out_queue.next() {
+        match self.gated_until {
+            Some(until) if Instant::now() < until => return,
+            _ => self.gated_until = None,
+        }
+
+        while let Some(next) = self.out_queue.next(Instant::now()) {
+            if sent == self.max_burst {
+                // Give the other connections a turn
+                trace!("burst limited; id={}", self.out_queue.connection_id());
+                self.burst_limited = true;
+                break;
+            }
+
             if !shared.is_writable() {
                 return;
             }
 
-            trace!("send_to; addr={:?}; packet={:?}", self.key.addr, next.packet());
+            if let Some(ref mut gate) = shared.gate {
+                let transmit = Transmit {
+                    addr: self.key.addr,
+                    connection_id: next.packet().connection_id(),
+                    ty: next.packet().ty(),
+                    size: next.packet().encoded_len(),
+                    priority: self.priority,
+                    retransmit: next.is_retransmit(),
+                };
+
+                match gate.check(&transmit) {
+                    Admission::Allow => {}
+                    Admission::Delay(delay) => {
+                        trace!("transmit delayed; {:?}; delay={:?}", transmit, delay);
+                        self.gated_until = Some(Instant::now() + delay);
+                        break;
+                    }
+                    Admission::Deny => {
+                        trace!("transmit denied; {:?}", transmit);
+                        next.sent();
+                        sent += 1;
+                        continue;
+                    }
+                }
+            }
 
-            match shared.socket.send_to(next.packet().as_slice(), &self.key.addr) {
+            trace!("send_to; addr={:?}; packet={}", self.key.addr, next.packet());
+
+            shared.set_dont_fragment(next.is_mtu_probe());
+
+            match shared.send_to(next.packet(), &self.key.addr) {
                 Ok(n) => {
-                    assert_eq!(n, next.packet().as_slice().len());
+                    assert_eq!(n, next.packet().encoded_len());
                     next.sent();
 
+                    shared.driver.packets_sent += 1;
+
                     // Reset the connection timeout
-                    sent = true;
+                    sent += 1;
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     shared.need_writable();
                     return;
                 }
+                Err(ref e) if next.is_mtu_probe() && sockopt::is_message_too_long(e) => {
+                    trace!("mtu probe refused; id={}", self.out_queue.connection_id());
+                    self.out_queue.mtu_probe_refused();
+                }
                 Err(e) => {
                     panic!("TODO: implement error handling {:?}", e);
                 }
             }
         }
 
-        if sent {
+        if sent > 0 {
             self.reset_timeout();
         }
     }
 
-    fn tick(&mut self, shared: &mut Shared) -> io::Result<()> {
+    fn tick(&mut self, shared: &mut Shared) -> io::Result<bool> {
         if self.state == State::Reset {
-            return Ok(());
+            return Ok(self.is_finalized());
+        }
+
+        let now = Instant::now();
+
+        if let Some(idle_timeout) = self.idle_timeout {
+            if now >= self.last_recv_at + idle_timeout {
+                trace!("connection idle; id={}", self.out_queue.connection_id());
+
+                // The RESET lets the peer know, should it still be around
+                self.reset_error = io::ErrorKind::TimedOut;
+                self.reset(shared)?;
+
+                return Ok(self.is_finalized());
+            }
+        }
+
+        if let Some(expires_at) = self.expires_at {
+            if now >= expires_at && !self.expired {
+                trace!("connection lifetime expired; id={}", self.out_queue.connection_id());
+                self.expired = true;
+
+                if self.state == State::SynSent {
+                    // The FIN can only be sent once connected
+                    self.reset_error = io::ErrorKind::ConnectionAborted;
+                    self.reset(shared)?;
+
+                    return Ok(self.is_finalized());
+                }
+
+                // Pending data is still delivered ahead of the FIN, and
+                // buffered data can still be read.
+                self.read_closed = true;
+                self.shutdown(Shutdown::Write, shared)?;
+            }
         }
 
         if let Some(deadline) = self.deadline {
-            if Instant::now() >= deadline {
+            if now >= deadline {
                 trace!("connection timed out; id={}", self.out_queue.connection_id());
+
+                if self.out_queue.consecutive_timeouts() >= self.max_retransmits {
+                    trace!("max retransmits reached; id={}", self.out_queue.connection_id());
+
+                    self.reset_error = io::ErrorKind::TimedOut;
+                    self.reset(shared)?;
+
+                    return Ok(self.is_finalized());
+                }
+
                 self.out_queue.timed_out();
-                self.flush(shared);
+            }
+        }
+
+        // Wake the tasks blocked past their read or write timeout
+        let read_expired = self.read_timeout.poll_expired(now);
+
+        if self.write_timeout.poll_expired(now) || read_expired {
+            self.update_readiness()?;
+        }
+
+        // Send timed out and paced packets
+        self.flush(shared);
+
+        let mut stats = self.out_queue.stats(now);
+        self.quality.refresh(&stats);
+        stats.quality = self.quality.get();
+
+        self.check_peer(&stats, shared)?;
+
+        Ok(self.is_finalized())
+    }
+
+    fn stats(&self, now: Instant) -> Stats {
+        let (ranges, gap) = self.in_queue.gaps();
+
+        let mut stats = self.out_queue.stats(now);
+        stats.quality = self.quality.get();
+        stats.recv_offset = self.in_queue.offset();
+        stats.recv_ranges = ranges;
+        stats.recv_gap = gap;
+        stats.jitter = self.jitter.get();
+        stats
+    }
+
+    /// Returns a snapshot of the connection if the watchdog just found it
+    /// stalled: data is unacked, yet no packet was sent or received for the
+    /// configured number of retransmission timeouts.
+    fn check_stall(&mut self, now: Instant) -> Option<Stall> {
+        let watched = !self.out_queue.is_empty() &&
+            (self.state == State::Connected || self.state == State::FinSent);
+
+        let watchdog = match self.watchdog {
+            Some(watchdog) if watched => watchdog,
+            _ => {
+                self.stalled = false;
+                return None;
+            }
+        };
+
+        let last_active = match self.out_queue.last_sent_at() {
+            Some(at) => cmp::max(at, self.last_recv_at),
+            None => self.last_recv_at,
+        };
+
+        let idle = now.duration_since(last_active);
+        let timeout = self.out_queue.retransmit_timeout();
+
+        if idle < timeout * watchdog {
+            self.stalled = false;
+            return None;
+        }
+
+        if self.stalled {
+            // Already reported
+            return None;
+        }
+
+        self.stalled = true;
+
+        Some(Stall {
+            addr: self.key.addr,
+            connection_id: self.out_queue.connection_id(),
+            idle,
+            timeout,
+            stats: self.stats(now),
+        })
+    }
+
+    /// Consult the peer policy, if any
+    fn check_peer(&mut self, stats: &Stats, shared: &mut Shared) -> io::Result<()> {
+        if self.state != State::Connected {
+            return Ok(());
+        }
+
+        let verdict = match self.peer_policy {
+            Some(ref mut policy) => policy.check(stats),
+            None => return Ok(()),
+        };
+
+        match verdict {
+            Verdict::Healthy => self.slow_peer = false,
+            Verdict::Slow => self.slow_peer = true,
+            Verdict::Disconnect => {
+                trace!("disconnecting slow peer; id={}", self.out_queue.connection_id());
+                self.slow_peer = true;
+                self.reset(shared)?;
             }
         }
 
         Ok(())
     }
 
-    fn update_delays(&mut self, now: Instant, packet: &Packet) {
+    /// Reset the connection, notifying the peer
+    fn reset(&mut self, shared: &mut Shared) -> io::Result<()> {
+        // Send the RESET packet, ignoring errors...
+        let mut p = Packet::reset();
+        p.set_connection_id(self.out_queue.connection_id());
+        p.set_ack_nr(self.in_queue.ack_nr());
+
+        let _ = shared.send_to(&p, &self.key.addr);
+
+        self.state = State::Reset;
+        self.update_readiness()
+    }
+
+    fn update_delays(&mut self, now: Instant, packet: &PacketRef) {
         let mut actual_delay = u32::MAX;
 
         if packet.timestamp() > 0 {
+            self.jitter.add_sample(packet.timestamp(), now);
+
             // Use the packet to update the delay value
-            let their_delay = self.out_queue.update_their_delay(packet.timestamp());
+            let their_delay = self.out_queue.update_their_delay(packet.timestamp(), now);
             let prev_base_delay = self.their_delays.base_delay();
 
             // Track the delay
@@ -885,56 +2237,22 @@ impl Connection {
             if actual_delay != u32::MAX {
                 self.our_delays.add_sample(actual_delay, now);
 
-                if self.average_delay_base == 0 {
-                    self.average_delay_base = actual_delay;
-                }
-
-                let average_delay_sample;
-                let dist_down = self.average_delay_base.wrapping_sub(actual_delay);
-                let dist_up = actual_delay.wrapping_sub(self.average_delay_base);
-
-                if dist_down > dist_up {
-                    average_delay_sample = dist_up as i64;
-                } else {
-                    average_delay_sample = -(dist_down as i64);
-                }
-
-                self.current_delay_sum = self.current_delay_sum.wrapping_add(average_delay_sample);
-                self.current_delay_samples += 1;
-
-                if now > self.average_sample_time {
-                    let mut prev_average_delay = self.average_delay;
-                    self.average_delay = (self.current_delay_sum / self.current_delay_samples) as i32;
-                    self.average_sample_time = now + Duration::from_secs(5);
-
-                    self.current_delay_sum = 0;
-                    self.current_delay_samples = 0;
-
-                    let min_sample = cmp::min(prev_average_delay, self.average_delay);
-                    let max_sample = cmp::max(prev_average_delay, self.average_delay);
-
-                    if min_sample > 0 {
-                        self.average_delay_base += min_sample as u32;
-                        self.average_delay -= min_sample;
-                        prev_average_delay -= min_sample;
-                    } else if max_sample < 0 {
-                        let adjust = -max_sample;
-
-                        self.average_delay_base -= adjust as u32;
-                        self.average_delay += adjust;
-                        prev_average_delay += adjust;
+                if let Some(drift) = self.clock_drift.add_sample(actual_delay, now) {
+                    // The base delay is the minimum over several minutes. When
+                    // the peer's clock runs faster, the samples grow steadily
+                    // and the stale base makes it look like queuing delay.
+                    // Drift within the bounds of clock skew is compensated
+                    // for, anything larger is left to congestion control.
+                    if drift > 0 && drift <= MAX_CLOCK_DRIFT_MICROS {
+                        trace!("compensating clock drift; drift={}", drift);
+                        self.our_delays.shift(drift as u32);
                     }
-
-                    // Update the clock drive estimate
-                    let drift = self.average_delay as i64 - prev_average_delay as i64;
-
-                    self.clock_drift = ((self.clock_drift as i64 * 7 + drift) / 8) as i32;
                 }
             }
         }
 
         // Ack all packets
-        if let Some((acked_bytes, min_rtt)) = self.out_queue.set_their_ack(packet.ack_nr(), now) {
+        if let Some((acked_bytes, min_rtt)) = self.out_queue.set_their_ack(packet.ack_nr(), packet.selective_ack(), now) {
             let min_rtt = util::as_wrapping_micros(min_rtt);
 
             if let Some(delay) = self.our_delays.get() {
@@ -943,7 +2261,7 @@ impl Connection {
                 }
             }
 
-            if actual_delay != u32::MAX && acked_bytes >= 1 {
+            if acked_bytes >= 1 {
                 self.apply_congestion_control(acked_bytes, actual_delay, min_rtt, now);
             }
         }
@@ -955,62 +2273,33 @@ impl Connection {
                                 min_rtt: u32,
                                 now: Instant)
     {
-        trace!("applying congenstion control; bytes_acked={}; actual_delay={}; min_rtt={}",
-               bytes_acked, actual_delay, min_rtt);
+        let delay = if actual_delay != u32::MAX {
+            let mut our_delay = cmp::min(self.our_delays.get().unwrap(), min_rtt) as i64;
 
-        let target = TARGET_DELAY;
+            let clock_drift = self.clock_drift.get();
 
-        let mut our_delay = cmp::min(self.our_delays.get().unwrap(), min_rtt);
-        let max_window = self.out_queue.max_window() as usize;
-
-        if self.clock_drift < -200_000 {
-            let penalty = (-self.clock_drift - 200_000) / 7;
-
-            if penalty > 0 {
-                our_delay += penalty as u32;
-            } else {
-                our_delay -= (-penalty) as u32;
+            if clock_drift < -200_000 {
+                // The peer's clock is running slower than ours, penalize our
+                // delay measurement.
+                let penalty = (-clock_drift - 200_000) / 7;
+                our_delay += penalty as i64;
             }
-        }
-
-        let off_target = (target - our_delay) as f64;
-        let window_factor =
-            cmp::min(bytes_acked, max_window) as f64 /
-            cmp::max(max_window, bytes_acked) as f64;
 
-        let delay_factor = off_target / target as f64;
-        let mut scaled_gain = MAX_CWND_INCREASE_BYTES_PER_RTT as f64 *
-            window_factor * delay_factor;
-
-        if scaled_gain > 0.0 && now - self.last_maxed_out_window > Duration::from_secs(1) {
-            // if it was more than 1 second since we tried to send a packet and
-            // stopped because we hit the max window, we're most likely rate
-            // limited (which prevents us from ever hitting the window size) if
-            // this is the case, we cannot let the max_window grow indefinitely
-            scaled_gain = 0.0;
-        }
-
-        let ledbat_cwnd = if max_window + (scaled_gain as usize) < MIN_WINDOW_SIZE {
-            MIN_WINDOW_SIZE
+            Some(util::from_micros(our_delay as u64))
         } else {
-            max_window + scaled_gain as usize
+            None
         };
 
-        if self.slow_start {
-            let ss_cwnd = max_window + window_factor as usize * MAX_DATA_SIZE;
-
-            if ss_cwnd > SLOW_START_THRESHOLD {
-                self.slow_start = false;
-            } else if our_delay > (target as f64 * 0.9) as u32 {
-                // Even if we're a little under the target delay, we
-                // conservatively discontinue the slow start phase
-                self.slow_start = false;
-            } else {
-                self.out_queue.set_max_window(cmp::max(ss_cwnd, ledbat_cwnd) as u32);
-            }
-        } else {
-            self.out_queue.set_max_window(ledbat_cwnd as u32);
-        }
+        // If it was more than 1 second since we tried to send a packet and
+        // stopped because we hit the max window, we're most likely rate
+        // limited. The same goes when the peer's window, rather than the
+        // congestion window, is what holds data back.
+        let app_limited = now - self.last_maxed_out_window > Duration::from_secs(1) ||
+            self.out_queue.is_peer_window_limited();
+
+        let ack = Ack::new(bytes_acked, delay, util::from_micros(min_rtt as u64),
+                           self.jitter.get(), app_limited, now);
+        self.out_queue.on_ack(&ack);
     }
 
     fn reset_timeout(&mut self) {
@@ -1021,6 +2310,28 @@ impl Connection {
             });
     }
 
+    fn shutdown(&mut self, how: Shutdown, shared: &mut Shared) -> io::Result<()> {
+        if self.state == State::Reset {
+            return Err(self.reset_error.into());
+        }
+
+        if how != Shutdown::Read {
+            self.send_fin(false, shared);
+            self.flush(shared);
+        }
+
+        if how != Shutdown::Write && !self.read_shutdown {
+            self.read_closed = true;
+            self.read_shutdown = true;
+
+            // Nobody reads the buffered data anymore
+            self.in_queue.discard();
+            self.update_local_window();
+        }
+
+        self.update_readiness()
+    }
+
     fn send_fin(&mut self, _: bool, shared: &mut Shared) {
         if self.state.is_closed() {
             return;
@@ -1036,24 +2347,85 @@ impl Connection {
              self.state == State::Reset)
     }
 
+    /// Returns the result of reading when no data is queued for the
+    /// application
+    fn read_unavailable(&mut self) -> io::Result<usize> {
+        if self.state == State::Reset {
+            Err(self.reset_error.into())
+        } else if self.expired {
+            Err(io::ErrorKind::ConnectionAborted.into())
+        } else if self.read_closed {
+            self.read_timeout.clear();
+            Ok(0)
+        } else if self.read_timeout.would_block(Instant::now()) {
+            self.update_readiness()?;
+            Err(io::ErrorKind::TimedOut.into())
+        } else if self.state == State::Connected ||
+            self.state == State::FinSent
+        {
+            // The write half may be closed while the peer is still
+            // sending.
+            self.update_readiness()?;
+            Err(io::ErrorKind::WouldBlock.into())
+        } else {
+            // Still connecting. Layered protocols, such as TLS, may
+            // try to read before the handshake completes.
+            assert!(self.state == State::SynSent,
+                    "unexpected state; actual={:?}", self.state);
+
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
     /// Update the UtpStream's readiness
-    fn update_readiness(&self) -> io::Result<()> {
+    fn update_readiness(&mut self) -> io::Result<()> {
         let mut ready = Ready::empty();
 
-        if self.state == State::Connected {
-            if self.is_readable() {
-                ready.insert(Ready::readable());
-            }
+        match self.state {
+            State::Connected | State::FinSent => {
+                if self.is_readable() || self.read_closed {
+                    ready.insert(Ready::readable());
+                }
+
+                // Once the write half is closed, the stream becomes writable
+                // when the FIN is acked, signaling that the close completed.
+                let writable = if self.state == State::Connected {
+                    self.is_writable()
+                } else {
+                    self.out_queue.is_empty()
+                };
 
-            if self.is_writable() {
-                ready.insert(Ready::writable());
+                if writable {
+                    ready.insert(Ready::writable());
+                }
             }
-        } else if self.state.is_closed() {
-            ready = Ready::readable();
+            State::Reset => {
+                // Fail pending reads and writes
+                ready = Ready::readable() | Ready::writable();
+            }
+            State::SynSent | State::SynRecv => {}
+        }
+
+        // The next read or write fails with `TimedOut`
+        if self.read_timeout.is_expired() {
+            ready.insert(Ready::readable());
+        }
+
+        if self.write_timeout.is_expired() {
+            ready.insert(Ready::writable());
         }
 
         trace!("updating socket readiness; ready={:?}", ready);
 
+        // Once closed, pending writes must observe the error as well
+        if ready.is_readable() {
+            wake(&mut self.read_waker);
+        }
+
+        if ready.is_writable() || self.state.is_closed() {
+            wake(&mut self.write_waker);
+        }
+
         self.set_readiness.set_readiness(ready)
     }
 
@@ -1068,6 +2440,30 @@ impl Connection {
     }
 }
 
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::from_secs(0)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "cannot set a 0 duration timeout"));
+    }
+
+    Ok(())
+}
+
+fn register_waker(slot: &mut Option<Waker>, waker: &Waker) {
+    match *slot {
+        Some(ref curr) if curr.will_wake(waker) => return,
+        _ => {}
+    }
+
+    *slot = Some(waker.clone());
+}
+
+fn wake(slot: &mut Option<Waker>) {
+    if let Some(waker) = slot.take() {
+        waker.wake();
+    }
+}
+
 impl State {
     fn is_closed(&self) -> bool {
         match *self {
@@ -1080,8 +2476,8 @@ impl State {
 impl Key {
     fn new(receive_id: u16, addr: SocketAddr) -> Key {
         Key {
-            receive_id: receive_id,
-            addr: addr,
+            receive_id,
+            addr,
         }
     }
 }
@@ -0,0 +1,159 @@
+//! Socket options that neither std nor mio expose
+
+use mio::net::UdpSocket;
+
+use std::io;
+
+/// Sets the TOS byte of IPv4 packets, or the traffic class of IPv6 packets,
+/// sent by `socket`
+#[cfg(unix)]
+pub fn set_tos(socket: &UdpSocket, tos: u8) -> io::Result<()> {
+    use libc::{c_int, c_void, socklen_t};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = tos_option(socket)?;
+    let val = tos as c_int;
+
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name,
+                         &val as *const c_int as *const c_void,
+                         mem::size_of::<c_int>() as socklen_t)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns the TOS byte, or the traffic class, of packets sent by `socket`
+#[cfg(unix)]
+pub fn tos(socket: &UdpSocket) -> io::Result<u8> {
+    use libc::{c_int, c_void, socklen_t};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = tos_option(socket)?;
+    let mut val: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(socket.as_raw_fd(), level, name,
+                         &mut val as *mut c_int as *mut c_void,
+                         &mut len)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(val as u8)
+}
+
+/// Returns the level and name of the option holding the TOS byte, which
+/// depend on the address family of the socket
+#[cfg(unix)]
+fn tos_option(socket: &UdpSocket) -> io::Result<(::libc::c_int, ::libc::c_int)> {
+    use libc;
+
+    if socket.local_addr()?.is_ipv4() {
+        Ok((libc::IPPROTO_IP, libc::IP_TOS))
+    } else {
+        Ok((libc::IPPROTO_IPV6, libc::IPV6_TCLASS))
+    }
+}
+
+/// True if `set_dont_fragment` is supported on this platform
+pub const DONT_FRAGMENT: bool = cfg!(any(target_os = "linux", target_os = "android",
+                                         target_vendor = "apple", target_os = "freebsd"));
+
+/// Sets whether packets sent by `socket` carry the "don't fragment" bit.
+///
+/// Packets without it may be fragmented by routers along the path, packets
+/// with it are dropped instead when they exceed the path MTU.
+#[cfg(any(target_os = "linux", target_os = "android",
+          target_vendor = "apple", target_os = "freebsd"))]
+pub fn set_dont_fragment(socket: &UdpSocket, val: bool) -> io::Result<()> {
+    use libc::{c_int, c_void, socklen_t};
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name, val) = dont_fragment_option(socket, val)?;
+
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name,
+                         &val as *const c_int as *const c_void,
+                         mem::size_of::<c_int>() as socklen_t)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns the level, name and value of the option setting the "don't
+/// fragment" bit. Linux sets the bit through path MTU discovery.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn dont_fragment_option(socket: &UdpSocket, val: bool)
+    -> io::Result<(::libc::c_int, ::libc::c_int, ::libc::c_int)>
+{
+    use libc;
+
+    if socket.local_addr()?.is_ipv4() {
+        let val = if val { libc::IP_PMTUDISC_DO } else { libc::IP_PMTUDISC_DONT };
+        Ok((libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, val))
+    } else {
+        let val = if val { libc::IPV6_PMTUDISC_DO } else { libc::IPV6_PMTUDISC_DONT };
+        Ok((libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, val))
+    }
+}
+
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+fn dont_fragment_option(socket: &UdpSocket, val: bool)
+    -> io::Result<(::libc::c_int, ::libc::c_int, ::libc::c_int)>
+{
+    use libc;
+
+    if socket.local_addr()?.is_ipv4() {
+        Ok((libc::IPPROTO_IP, libc::IP_DONTFRAG, val as libc::c_int))
+    } else {
+        Ok((libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, val as libc::c_int))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android",
+              target_vendor = "apple", target_os = "freebsd")))]
+pub fn set_dont_fragment(_: &UdpSocket, _: bool) -> io::Result<()> {
+    Err(io::Error::other("the don't fragment bit is not supported on this platform"))
+}
+
+/// Returns true if a send failed because the datagram is larger than the path
+/// MTU known locally, which is only reported with the "don't fragment" bit set
+#[cfg(unix)]
+pub fn is_message_too_long(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(::libc::EMSGSIZE)
+}
+
+#[cfg(not(unix))]
+pub fn is_message_too_long(_: &io::Error) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+pub fn set_tos(_: &UdpSocket, _: u8) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+pub fn tos(_: &UdpSocket) -> io::Result<u8> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::other("TOS is not supported on this platform")
+}
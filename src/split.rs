@@ -0,0 +1,263 @@
+//! Splitting a stream into read and write halves
+//!
+//! Each half only gives access to one direction of the connection, so one part
+//! of an application can pump outbound data while another consumes inbound
+//! data. `UtpStream::split` borrows the stream, `UtpStream::into_split` moves
+//! it into halves that are used independently. The connection is closed once
+//! both owned halves are dropped, as when the stream itself is dropped.
+//!
+//! Like the stream, the halves are not `Send`; they are used from the thread
+//! driving the socket.
+
+use socket::UtpStream;
+
+use bytes::{Buf, BufMut, Bytes};
+
+use std::io::{self, IoSlice, IoSliceMut};
+use std::mem::MaybeUninit;
+use std::net::Shutdown;
+use std::rc::Rc;
+
+/// The read half of a borrowed `UtpStream`, see `UtpStream::split`.
+pub struct ReadHalf<'a> {
+    stream: &'a UtpStream,
+}
+
+/// The write half of a borrowed `UtpStream`, see `UtpStream::split`.
+pub struct WriteHalf<'a> {
+    stream: &'a UtpStream,
+}
+
+/// The read half of a `UtpStream`, see `UtpStream::into_split`.
+pub struct OwnedReadHalf {
+    stream: Rc<UtpStream>,
+}
+
+/// The write half of a `UtpStream`, see `UtpStream::into_split`.
+pub struct OwnedWriteHalf {
+    stream: Rc<UtpStream>,
+}
+
+/// Returns the halves of a borrowed stream
+pub fn split(stream: &UtpStream) -> (ReadHalf<'_>, WriteHalf<'_>) {
+    (ReadHalf { stream }, WriteHalf { stream })
+}
+
+/// Returns the owned halves of `stream`
+pub fn into_split(stream: UtpStream) -> (OwnedReadHalf, OwnedWriteHalf) {
+    let stream = Rc::new(stream);
+    (OwnedReadHalf { stream: stream.clone() }, OwnedWriteHalf { stream })
+}
+
+impl<'a> ReadHalf<'a> {
+    /// Reads data from the stream, see `UtpStream::read`.
+    pub fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(dst)
+    }
+
+    /// Reads data into several buffers, see `UtpStream::read_vectored`.
+    pub fn read_vectored(&self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(dsts)
+    }
+
+    /// Reads data into a `BufMut`, see `UtpStream::read_buf`.
+    pub fn read_buf<B: BufMut>(&self, dst: &mut B) -> io::Result<usize> {
+        self.stream.read_buf(dst)
+    }
+
+    /// Reads data into uninitialized memory, see `UtpStream::read_uninit`.
+    pub fn read_uninit(&self, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.stream.read_uninit(dst)
+    }
+
+    /// Copies received data without consuming it, see `UtpStream::peek`.
+    pub fn peek(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.peek(dst)
+    }
+
+    pub(crate) fn stream(&self) -> &UtpStream {
+        self.stream
+    }
+}
+
+impl<'a> WriteHalf<'a> {
+    /// Writes data to the stream, see `UtpStream::write`.
+    pub fn write(&self, src: &[u8]) -> io::Result<usize> {
+        self.stream.write(src)
+    }
+
+    /// Writes data from several buffers, see `UtpStream::write_vectored`.
+    pub fn write_vectored(&self, srcs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(srcs)
+    }
+
+    /// Writes data from a `Buf`, see `UtpStream::write_buf`.
+    pub fn write_buf<B: Buf>(&self, src: &mut B) -> io::Result<usize> {
+        self.stream.write_buf(src)
+    }
+
+    /// Writes data to the stream without copying it, see
+    /// `UtpStream::write_bytes`.
+    pub fn write_bytes(&self, src: &Bytes) -> io::Result<usize> {
+        self.stream.write_bytes(src)
+    }
+
+    /// Sends any data held back by Nagle's algorithm, see `UtpStream::flush`.
+    pub fn flush(&self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    /// Shuts down the write half of the stream, queuing a FIN after any
+    /// pending data. The read half keeps receiving data until the peer
+    /// closes its own write half.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    pub(crate) fn stream(&self) -> &UtpStream {
+        self.stream
+    }
+}
+
+impl OwnedReadHalf {
+    /// Reads data from the stream, see `UtpStream::read`.
+    pub fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(dst)
+    }
+
+    /// Reads data into several buffers, see `UtpStream::read_vectored`.
+    pub fn read_vectored(&self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(dsts)
+    }
+
+    /// Reads data into a `BufMut`, see `UtpStream::read_buf`.
+    pub fn read_buf<B: BufMut>(&self, dst: &mut B) -> io::Result<usize> {
+        self.stream.read_buf(dst)
+    }
+
+    /// Reads data into uninitialized memory, see `UtpStream::read_uninit`.
+    pub fn read_uninit(&self, dst: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.stream.read_uninit(dst)
+    }
+
+    /// Copies received data without consuming it, see `UtpStream::peek`.
+    pub fn peek(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.peek(dst)
+    }
+
+    /// Puts the stream back together.
+    ///
+    /// Returns both halves if `other` was not split from the same stream.
+    pub fn reunite(self, other: OwnedWriteHalf)
+        -> Result<UtpStream, (OwnedReadHalf, OwnedWriteHalf)>
+    {
+        if !Rc::ptr_eq(&self.stream, &other.stream) {
+            return Err((self, other));
+        }
+
+        drop(other);
+
+        match Rc::try_unwrap(self.stream) {
+            Ok(stream) => Ok(stream),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    pub(crate) fn stream(&self) -> &UtpStream {
+        &self.stream
+    }
+}
+
+impl OwnedWriteHalf {
+    /// Writes data to the stream, see `UtpStream::write`.
+    pub fn write(&self, src: &[u8]) -> io::Result<usize> {
+        self.stream.write(src)
+    }
+
+    /// Writes data from several buffers, see `UtpStream::write_vectored`.
+    pub fn write_vectored(&self, srcs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(srcs)
+    }
+
+    /// Writes data from a `Buf`, see `UtpStream::write_buf`.
+    pub fn write_buf<B: Buf>(&self, src: &mut B) -> io::Result<usize> {
+        self.stream.write_buf(src)
+    }
+
+    /// Writes data to the stream without copying it, see
+    /// `UtpStream::write_bytes`.
+    pub fn write_bytes(&self, src: &Bytes) -> io::Result<usize> {
+        self.stream.write_bytes(src)
+    }
+
+    /// Sends any data held back by Nagle's algorithm, see `UtpStream::flush`.
+    pub fn flush(&self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    /// Shuts down the write half of the stream, queuing a FIN after any
+    /// pending data. The read half keeps receiving data until the peer
+    /// closes its own write half.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    /// Puts the stream back together, see `OwnedReadHalf::reunite`.
+    pub fn reunite(self, other: OwnedReadHalf)
+        -> Result<UtpStream, (OwnedReadHalf, OwnedWriteHalf)>
+    {
+        other.reunite(self)
+    }
+
+    pub(crate) fn stream(&self) -> &UtpStream {
+        &self.stream
+    }
+}
+
+impl<'a> io::Read for ReadHalf<'a> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(dsts)
+    }
+}
+
+impl io::Read for OwnedReadHalf {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(dst)
+    }
+
+    fn read_vectored(&mut self, dsts: &mut [IoSliceMut]) -> io::Result<usize> {
+        self.stream.read_vectored(dsts)
+    }
+}
+
+impl<'a> io::Write for WriteHalf<'a> {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.stream.write(src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl io::Write for OwnedWriteHalf {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.stream.write(src)
+    }
+
+    fn write_vectored(&mut self, srcs: &[IoSlice]) -> io::Result<usize> {
+        self.stream.write_vectored(srcs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,102 @@
+//! Connection state machine
+//!
+//! A connection is created in `SynSent` when connecting, and in `SynRecv` when
+//! a SYN is received. It moves to `Connected` once the handshake completes,
+//! to `FinSent` once the stream is shut down, and to `Reset` when either side
+//! resets it. It is closed, i.e. removed from the socket, once finalized.
+//! Packets that match no connection are handled by the socket, which accepts
+//! SYNs and answers anything else with a RESET.
+//!
+//! Whether the peer sent its FIN is tracked separately from the state, as the
+//! two halves of a connection are closed independently.
+//!
+//! Each packet received by a connection is dispatched on its state and type,
+//! using the table below.
+//!
+//! ```text
+//!             | ST_DATA  | ST_FIN   | ST_STATE    | ST_RESET | ST_SYN
+//! ------------+----------+----------+-------------+----------+--------
+//! SynSent     | sequence | sequence | establish   | reset    | reject
+//! SynRecv     | sequence | sequence | acknowledge | reset    | reject
+//! Connected   | sequence | sequence | acknowledge | reset    | reject
+//! FinSent     | sequence | sequence | acknowledge | reset    | reject
+//! Reset       | ignore   | ignore   | ignore      | ignore   | ignore
+//! ```
+//!
+//! Data that overtakes the STATE completing the handshake is held until the
+//! peer's initial sequence number is known. A retransmitted SYN is answered by
+//! the socket and never reaches the connection.
+
+use packet::Type;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum State {
+    // Establishing a new connection, waiting for the peer to respond with a
+    // STATE.
+    SynSent,
+    // Received Syn, the state packet is sent immediately, but the connection is
+    // not transitioned to `Connected` until it has been accepted.
+    SynRecv,
+    // Fully connected state
+    Connected,
+    // A FIN has been sent and we are currently waiting for an ACK before
+    // closing the connection.
+    FinSent,
+    // The connection has been reset by the remote.
+    Reset,
+}
+
+/// What a connection does with a received packet
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// The packet completes the handshake, the connection moves to `Connected`
+    Establish,
+    /// The packet only acknowledges data, the state is unchanged
+    Acknowledge,
+    /// The packet is queued and processed in sequence number order
+    Sequence,
+    /// The peer reset the connection, which moves to `Reset`
+    Reset,
+    /// The packet is not valid in the current state and is dropped
+    Reject,
+    /// The connection is over, the packet is dropped
+    Ignore,
+}
+
+/// Returns the action taken when a packet of type `ty` is received in
+/// `state`.
+pub fn on_packet(state: State, ty: Type) -> Action {
+    use self::Action::*;
+
+    match (state, ty) {
+        (State::SynSent, Type::Data) => Sequence,
+        (State::SynSent, Type::Fin) => Sequence,
+        (State::SynSent, Type::State) => Establish,
+        (State::SynSent, Type::Reset) => Reset,
+        (State::SynSent, Type::Syn) => Reject,
+
+        (State::SynRecv, Type::Data) => Sequence,
+        (State::SynRecv, Type::Fin) => Sequence,
+        (State::SynRecv, Type::State) => Acknowledge,
+        (State::SynRecv, Type::Reset) => Reset,
+        (State::SynRecv, Type::Syn) => Reject,
+
+        (State::Connected, Type::Data) => Sequence,
+        (State::Connected, Type::Fin) => Sequence,
+        (State::Connected, Type::State) => Acknowledge,
+        (State::Connected, Type::Reset) => Reset,
+        (State::Connected, Type::Syn) => Reject,
+
+        (State::FinSent, Type::Data) => Sequence,
+        (State::FinSent, Type::Fin) => Sequence,
+        (State::FinSent, Type::State) => Acknowledge,
+        (State::FinSent, Type::Reset) => Reset,
+        (State::FinSent, Type::Syn) => Reject,
+
+        (State::Reset, Type::Data) => Ignore,
+        (State::Reset, Type::Fin) => Ignore,
+        (State::Reset, Type::State) => Ignore,
+        (State::Reset, Type::Reset) => Ignore,
+        (State::Reset, Type::Syn) => Ignore,
+    }
+}
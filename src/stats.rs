@@ -0,0 +1,531 @@
+//! Connection statistics
+
+use util;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A snapshot of a connection's statistics.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub(crate) elapsed: Duration,
+    pub(crate) rtt: Duration,
+    pub(crate) rtt_variance: Duration,
+    pub(crate) cwnd: usize,
+    pub(crate) bytes_pending: usize,
+    pub(crate) bytes_acked: u64,
+    pub(crate) packets_sent: u64,
+    pub(crate) packets_resent: u64,
+    pub(crate) acks_sent: u64,
+    pub(crate) acks_piggybacked: u64,
+    pub(crate) packets_lost: u64,
+    pub(crate) timeouts: u64,
+    pub(crate) quality: u8,
+    pub(crate) recv_offset: u64,
+    pub(crate) recv_ranges: usize,
+    pub(crate) recv_gap: usize,
+    pub(crate) jitter: Duration,
+}
+
+/// Delivery summary of a connection, returned by `UtpStream::finish` once the
+/// peer has acknowledged all of the data.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub(crate) elapsed: Duration,
+    pub(crate) bytes_acked: u64,
+    pub(crate) packets_sent: u64,
+    pub(crate) packets_resent: u64,
+    pub(crate) timeouts: u64,
+}
+
+/// A snapshot of a connection flagged by the watchdog, see
+/// `UtpConfig::set_watchdog`.
+#[derive(Debug, Clone)]
+pub struct Stall {
+    pub(crate) addr: SocketAddr,
+    pub(crate) connection_id: u16,
+    pub(crate) idle: Duration,
+    pub(crate) timeout: Duration,
+    pub(crate) stats: Stats,
+}
+
+/// Why a received packet was discarded, see `DriverStats::dropped`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DropReason {
+    /// The datagram is not a valid packet
+    Malformed,
+    /// The payload exceeds `UtpConfig::max_recv_payload_size`
+    Oversized,
+    /// The packet failed `UtpConfig::set_strict_validation`
+    Invalid,
+    /// The packet carries an extension that is not understood, and
+    /// `UtpConfig::set_strict_extensions` is set
+    UnknownExtension,
+    /// The packet matches no connection on the socket
+    UnknownConnection,
+    /// A SYN was received after the listener was dropped
+    NotListening,
+    /// The packet is not valid in the state of its connection, see the
+    /// `state` module
+    InvalidState,
+    /// The connection was reset
+    ConnectionReset,
+    /// The packet was already received
+    Duplicate,
+    /// The connection's receive buffer is full
+    WindowFull,
+    /// The packet is too far ahead of the last packet received in order
+    OutOfWindow,
+    /// Too many packets are held ahead of a gap, see
+    /// `UtpConfig::set_reorder_buffer_size`
+    ReorderBufferFull,
+    /// Data was received after the read half was shut down, see
+    /// `UtpStream::shutdown`
+    ReadShutdown,
+    /// A SYN was received while the accept backlog was full, see
+    /// `UtpConfig::set_accept_backlog`
+    AcceptBacklogFull,
+    /// A SYN was received while the socket managed its max number of
+    /// connections, see `UtpConfig::set_max_connections`
+    MaxConnections,
+    /// The packet acks a packet that was not sent, or is too far behind the
+    /// last ACK received, see `tuning::ACK_NR_ALLOWED_WINDOW`
+    InvalidAck,
+}
+
+/// Number of `DropReason` variants
+const DROP_REASONS: usize = 16;
+
+/// A snapshot of the statistics of the driver of a `UtpSocket`, shared by
+/// all of its connections.
+///
+/// These are meant for capacity planning. A socket that spends most of its
+/// time processing, or that handles many packets per wakeup, is a candidate
+/// for sharding across several sockets.
+#[derive(Debug, Clone, Default)]
+pub struct DriverStats {
+    pub(crate) elapsed: Duration,
+    pub(crate) wakeups: u64,
+    pub(crate) packets_received: u64,
+    pub(crate) drops: [u64; DROP_REASONS],
+    pub(crate) packets_sent: u64,
+    pub(crate) max_packets_per_wakeup: u64,
+    pub(crate) ticks: u64,
+    pub(crate) tick_time: Duration,
+    pub(crate) congestion_time: Duration,
+    pub(crate) max_connections: usize,
+    pub(crate) max_accept_backlog: usize,
+    pub(crate) packet_allocations: u64,
+    pub(crate) queue_allocations: u64,
+}
+
+/// Tracks a connection's quality score, refreshed on each socket tick.
+#[derive(Debug)]
+pub(crate) struct QualityMeter {
+    score: Option<u32>,
+
+    // Stats at the previous refresh, used to compute the recent throughput
+    last_elapsed: Duration,
+    last_bytes_acked: u64,
+}
+
+// Weights of the quality score components, in percent
+const STABILITY_WEIGHT: f64 = 30.0;
+const DELIVERY_WEIGHT: f64 = 40.0;
+const EFFICIENCY_WEIGHT: f64 = 30.0;
+
+impl Stats {
+    /// Time elapsed since the connection was created
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Smoothed round trip time
+    pub fn rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    /// Round trip time variance
+    pub fn rtt_variance(&self) -> Duration {
+        self.rtt_variance
+    }
+
+    /// Current size of the congestion window, in bytes
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Number of bytes written to the connection but not yet acked by the
+    /// peer, including headers
+    pub fn bytes_pending(&self) -> usize {
+        self.bytes_pending
+    }
+
+    /// Total number of payload bytes acked by the peer
+    pub fn bytes_acked(&self) -> u64 {
+        self.bytes_acked
+    }
+
+    /// Total number of SYN, DATA and FIN packets sent, including
+    /// retransmissions
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// Number of retransmissions included in `packets_sent`
+    pub fn packets_resent(&self) -> u64 {
+        self.packets_resent
+    }
+
+    /// Number of STATE packets sent, including keep-alives
+    pub fn acks_sent(&self) -> u64 {
+        self.acks_sent
+    }
+
+    /// Number of ACKs carried by outbound DATA or FIN packets instead of a
+    /// STATE packet. During a bidirectional transfer, most ACKs should be.
+    pub fn acks_piggybacked(&self) -> u64 {
+        self.acks_piggybacked
+    }
+
+    /// Number of packets detected as lost by selective ACKs
+    pub fn packets_lost(&self) -> u64 {
+        self.packets_lost
+    }
+
+    /// Number of times the connection timed out waiting for an ACK
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts
+    }
+
+    /// Connection quality score between 0 (unusable) and 100 (perfect).
+    ///
+    /// The score combines the stability of the round trip time, the loss rate
+    /// and the throughput relative to what the congestion window allows. It is
+    /// smoothed and refreshed on every socket tick, which makes it suitable
+    /// for comparing peers.
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    /// Number of bytes received from the peer in order, counted from the start
+    /// of the stream. This includes data not read yet.
+    pub fn recv_offset(&self) -> u64 {
+        self.recv_offset
+    }
+
+    /// Number of contiguous ranges of packets received past a gap, held until
+    /// the gap fills
+    pub fn recv_ranges(&self) -> usize {
+        self.recv_ranges
+    }
+
+    /// Number of packets missing between `recv_offset` and the first range
+    /// received past it. Zero when there is no gap.
+    ///
+    /// A gap that does not fill while the peer keeps sending points at
+    /// packets the peer does not retransmit.
+    pub fn recv_gap(&self) -> usize {
+        self.recv_gap
+    }
+
+    /// Jitter of the arrival of the peer's packets, i.e. the mean variation
+    /// of the one way delay between two packets, as defined by RFC 3550.
+    ///
+    /// A high jitter relative to the target delay means the delay signal used
+    /// by congestion control is noisy, see `UtpConfig::set_jitter_filter`.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Average number of payload bytes acked per second over the lifetime of
+    /// the connection
+    pub fn throughput(&self) -> u64 {
+        let micros = util::as_micros(self.elapsed);
+
+        if micros == 0 {
+            return 0;
+        }
+
+        self.bytes_acked * 1_000_000 / micros
+    }
+
+    /// Fraction of sent packets that had to be retransmitted
+    pub fn loss_rate(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+
+        self.packets_resent as f64 / self.packets_sent as f64
+    }
+}
+
+impl Stall {
+    /// Address of the peer
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// ID of the packets sent on the connection
+    pub fn connection_id(&self) -> u16 {
+        self.connection_id
+    }
+
+    /// Time since the last packet was sent or received
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    /// Retransmission timeout, before backing off
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Statistics of the connection at the time it was flagged
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Summary {
+    pub(crate) fn new(stats: &Stats) -> Summary {
+        Summary {
+            elapsed: stats.elapsed,
+            bytes_acked: stats.bytes_acked,
+            packets_sent: stats.packets_sent,
+            packets_resent: stats.packets_resent,
+            timeouts: stats.timeouts,
+        }
+    }
+
+    /// Time from the creation of the connection until it was closed
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Total number of payload bytes acked by the peer
+    pub fn bytes_acked(&self) -> u64 {
+        self.bytes_acked
+    }
+
+    /// Total number of SYN, DATA and FIN packets sent, including
+    /// retransmissions
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// Number of retransmissions included in `packets_sent`
+    pub fn packets_resent(&self) -> u64 {
+        self.packets_resent
+    }
+
+    /// Number of times the connection timed out waiting for an ACK
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts
+    }
+
+    /// Average number of payload bytes acked per second
+    pub fn throughput(&self) -> u64 {
+        let micros = util::as_micros(self.elapsed);
+
+        if micros == 0 {
+            return 0;
+        }
+
+        self.bytes_acked * 1_000_000 / micros
+    }
+}
+
+impl DriverStats {
+    /// Time elapsed since the socket was created
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Number of times `UtpSocket::ready` was called
+    pub fn wakeups(&self) -> u64 {
+        self.wakeups
+    }
+
+    /// Average number of `UtpSocket::ready` calls per second
+    pub fn wakeups_per_sec(&self) -> f64 {
+        per_sec(self.wakeups, self.elapsed)
+    }
+
+    /// Total number of packets received
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    /// Number of datagrams received that were not valid packets. These are
+    /// dropped and included in `packets_received`.
+    pub fn malformed_packets(&self) -> u64 {
+        self.dropped(DropReason::Malformed)
+    }
+
+    /// Number of well formed packets dropped by strict validation, see
+    /// `UtpConfig::set_strict_validation`. These are included in
+    /// `packets_received`.
+    pub fn rejected_packets(&self) -> u64 {
+        self.dropped(DropReason::Invalid)
+    }
+
+    /// Number of packets dropped because their payload exceeds
+    /// `UtpConfig::max_recv_payload_size`. These are included in
+    /// `packets_received`.
+    pub fn oversized_packets(&self) -> u64 {
+        self.dropped(DropReason::Oversized)
+    }
+
+    /// Number of received packets discarded for `reason`. These are included
+    /// in `packets_received`.
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.drops[reason as usize]
+    }
+
+    /// Total number of received packets that were discarded, for any reason
+    pub fn packets_dropped(&self) -> u64 {
+        self.drops.iter().sum()
+    }
+
+    /// Average number of packets received per wakeup
+    pub fn packets_per_wakeup(&self) -> f64 {
+        if self.wakeups == 0 {
+            return 0.0;
+        }
+
+        self.packets_received as f64 / self.wakeups as f64
+    }
+
+    /// Total number of packets sent by connections
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    /// Largest number of packets received in a single wakeup
+    pub fn max_packets_per_wakeup(&self) -> u64 {
+        self.max_packets_per_wakeup
+    }
+
+    /// Number of times `UtpSocket::tick` was called
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Total time spent processing timers in `UtpSocket::tick`
+    pub fn tick_time(&self) -> Duration {
+        self.tick_time
+    }
+
+    /// Total time spent processing delay samples and ACKs for congestion
+    /// control
+    pub fn congestion_time(&self) -> Duration {
+        self.congestion_time
+    }
+
+    /// Largest number of connections open at once
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Largest number of inbound connections waiting to be accepted
+    pub fn max_accept_backlog(&self) -> usize {
+        self.max_accept_backlog
+    }
+
+    /// Number of packet buffers allocated or grown. Sockets driven by the
+    /// same thread share this count.
+    #[cfg(feature = "alloc-stats")]
+    pub fn packet_allocations(&self) -> u64 {
+        self.packet_allocations
+    }
+
+    /// Number of times a packet queue grew. Sockets driven by the same thread
+    /// share this count.
+    #[cfg(feature = "alloc-stats")]
+    pub fn queue_allocations(&self) -> u64 {
+        self.queue_allocations
+    }
+
+    /// Average number of allocations per packet sent or received
+    #[cfg(feature = "alloc-stats")]
+    pub fn allocations_per_packet(&self) -> f64 {
+        let packets = self.packets_sent + self.packets_received;
+
+        if packets == 0 {
+            return 0.0;
+        }
+
+        (self.packet_allocations + self.queue_allocations) as f64 / packets as f64
+    }
+}
+
+fn per_sec(n: u64, elapsed: Duration) -> f64 {
+    let micros = util::as_micros(elapsed);
+
+    if micros == 0 {
+        return 0.0;
+    }
+
+    n as f64 * 1_000_000.0 / micros as f64
+}
+
+impl QualityMeter {
+    pub fn new() -> QualityMeter {
+        QualityMeter {
+            score: None,
+            last_elapsed: Duration::from_secs(0),
+            last_bytes_acked: 0,
+        }
+    }
+
+    /// Returns the current score. Connections without any samples are
+    /// assumed to be perfect.
+    pub fn get(&self) -> u8 {
+        self.score.unwrap_or(100) as u8
+    }
+
+    /// Refresh the score using the latest statistics
+    pub fn refresh(&mut self, stats: &Stats) {
+        let elapsed = stats.elapsed - self.last_elapsed;
+        let acked = stats.bytes_acked - self.last_bytes_acked;
+
+        self.last_elapsed = stats.elapsed;
+        self.last_bytes_acked = stats.bytes_acked;
+
+        let rtt = util::as_micros(stats.rtt) as f64;
+
+        // A round trip time that varies a lot relative to its average makes
+        // for an unpredictable connection.
+        let stability = if rtt > 0.0 {
+            1.0 - (util::as_micros(stats.rtt_variance) as f64 / rtt).min(1.0)
+        } else {
+            1.0
+        };
+
+        // 25% loss or more is considered unusable
+        let delivery = 1.0 - (stats.loss_rate() * 4.0).min(1.0);
+
+        // Compare the recent throughput to one congestion window per round
+        // trip. This is only meaningful when there is data to send.
+        let micros = util::as_micros(elapsed) as f64;
+
+        let efficiency = if stats.bytes_pending > 0 && rtt > 0.0 && micros > 0.0 && stats.cwnd > 0 {
+            let throughput = acked as f64 / micros;
+            let max = stats.cwnd as f64 / rtt;
+
+            (throughput / max).min(1.0)
+        } else {
+            1.0
+        };
+
+        let sample = (STABILITY_WEIGHT * stability +
+                      DELIVERY_WEIGHT * delivery +
+                      EFFICIENCY_WEIGHT * efficiency).round() as u32;
+
+        self.score = Some(match self.score {
+            Some(score) => (score * 3 + sample + 2) / 4,
+            None => sample,
+        });
+    }
+}
@@ -1,7 +1,8 @@
-use {UtpSocket, UtpListener, UtpStream};
+use {UtpSocket, UtpListener, UtpStream, UtpConfig, DriverStats, PathInfo};
+use state::State;
 use mio::*;
-use std::{cmp, io};
-use std::net::SocketAddr;
+use std::{cmp, io, thread};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::{Duration, Instant};
 
 pub struct Harness {
@@ -11,7 +12,16 @@ pub struct Harness {
 
 impl Harness {
     pub fn new() -> (Harness, UtpListener) {
-        let (socket, listener) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        Harness::with_config(UtpConfig::new())
+    }
+
+    pub fn with_config(config: UtpConfig) -> (Harness, UtpListener) {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let (socket, listener) = UtpSocket::bind_with_config(&addr, config).unwrap();
+        Harness::from_socket(socket, listener)
+    }
+
+    pub fn from_socket(socket: UtpSocket, listener: UtpListener) -> (Harness, UtpListener) {
         let poll = Poll::new().unwrap();
 
         // Register the sockets
@@ -24,8 +34,8 @@ impl Harness {
                      PollOpt::edge()).unwrap();
 
         let harness = Harness {
-            socket: socket,
-            poll: poll,
+            socket,
+            poll,
         };
 
         (harness, listener)
@@ -35,8 +45,24 @@ impl Harness {
         self.socket.local_addr().unwrap()
     }
 
+    pub fn driver_stats(&self) -> DriverStats {
+        self.socket.driver_stats()
+    }
+
+    pub fn next_timeout(&self) -> Option<Duration> {
+        self.socket.next_timeout()
+    }
+
+    pub fn path_info(&self, addr: &SocketAddr) -> Option<PathInfo> {
+        self.socket.path_info(addr)
+    }
+
+    pub fn connection_states(&self) -> Vec<State> {
+        self.socket.connection_states()
+    }
+
     pub fn connect(&self, remote: SocketAddr) -> UtpStream {
-        let stream = self.socket.connect(&remote).unwrap();
+        let stream = self.socket.connect(remote).unwrap();
 
         self.poll.register(&stream, Token(2),
                            Ready::readable() | Ready::writable(),
@@ -45,6 +71,26 @@ impl Harness {
         stream
     }
 
+    pub fn connect_to<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UtpStream> {
+        let stream = self.socket.connect(addr)?;
+
+        self.poll.register(&stream, Token(2),
+                           Ready::readable() | Ready::writable(),
+                           PollOpt::edge()).unwrap();
+
+        Ok(stream)
+    }
+
+    pub fn reconnect(&self, stream: UtpStream) -> io::Result<UtpStream> {
+        let stream = self.socket.reconnect(stream)?;
+
+        self.poll.register(&stream, Token(2),
+                           Ready::readable() | Ready::writable(),
+                           PollOpt::edge()).unwrap();
+
+        Ok(stream)
+    }
+
     pub fn wait<F, T>(&self, f: F) -> io::Result<T>
         where F: FnMut() -> io::Result<T>,
     {
@@ -83,7 +129,7 @@ impl Harness {
     pub fn tick(&self) {
         let mut events = Events::with_capacity(4);
 
-        self.poll.poll(&mut events, Some(Duration::from_millis(500))).unwrap();
+        self.poll.poll(&mut events, Some(self.poll_timeout(Duration::from_millis(500)))).unwrap();
 
         for e in events.iter() {
             if e.token() == Token(0) {
@@ -106,7 +152,7 @@ impl Harness {
                 return;
             }
 
-            let wait = cmp::min(dur - elapsed, Duration::from_millis(500));
+            let wait = self.poll_timeout(cmp::min(dur - elapsed, Duration::from_millis(500)));
 
             self.poll.poll(&mut events, Some(wait)).unwrap();
 
@@ -119,4 +165,22 @@ impl Harness {
             self.socket.tick().unwrap();
         }
     }
+
+    /// Wait at most `max`, waking up earlier if paced packets are due
+    fn poll_timeout(&self, max: Duration) -> Duration {
+        self.socket.next_timeout()
+            .map(|timeout| cmp::min(timeout, max))
+            .unwrap_or(max)
+    }
+}
+
+/// Every test using the harness doubles as a leak check. Streams and the
+/// listener are dropped before the harness, so at this point only connections
+/// that are still closing may be left.
+impl Drop for Harness {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.socket.assert_no_leaks();
+        }
+    }
 }
@@ -38,8 +38,8 @@ impl Mock {
                       PollOpt::edge()).unwrap();
 
         Mock {
-            socket: socket,
-            poll: poll,
+            socket,
+            poll,
             recv: HashMap::new(),
         }
     }
@@ -123,8 +123,13 @@ impl Mock {
     }
 
     pub fn send_to(&self, packet: Packet, target: &SocketAddr) {
+        self.send_raw(&packet.to_vec(), target);
+    }
+
+    /// Send a datagram that is not necessarily a valid packet
+    pub fn send_raw(&self, data: &[u8], target: &SocketAddr) {
         loop {
-            match self.socket.send_to(packet.as_slice(), target) {
+            match self.socket.send_to(data, target) {
                 Ok(_) => return,
                 Err(e) => {
                     if e.kind() != io::ErrorKind::WouldBlock {
@@ -170,3 +175,19 @@ impl Mock {
         }
     }
 }
+
+/// Packets the socket sent must all have been handled by the test
+impl Drop for Mock {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+
+        for (remote, buf) in &self.recv {
+            if let Some(packet) = buf.front() {
+                panic!("packets not handled; remote={:?}; count={}; packet={:?}",
+                       remote, buf.len(), packet);
+            }
+        }
+    }
+}
@@ -4,11 +4,33 @@
 mod mock;
 mod harness;
 
+#[cfg(feature = "futures-io")]
+mod test_async_io;
+mod test_blocking;
+mod test_congestion;
+mod test_delays;
+mod test_driver;
 mod test_err;
 mod test_flow;
+mod test_gate;
+mod test_in_queue;
 mod test_listener;
+mod test_loss;
+mod test_out_queue;
+mod test_packet;
+mod test_path_cache;
+mod test_peer_policy;
+mod test_selftest;
+mod test_seq;
+#[cfg(feature = "serde")]
+mod test_serde;
+mod test_sockopt;
+mod test_state;
+mod test_stats;
 mod test_stream;
 mod test_timeout;
+#[cfg(all(feature = "tokio", unix))]
+mod test_tokio;
 
 /// Types that are imported in test modules
 mod prelude {
@@ -16,9 +38,10 @@ mod prelude {
     pub use super::mock::{Mock};
 
     pub use packet::Packet;
+    pub use {UtpConfig, FixedWindow};
 
     pub mod packet {
-        pub use packet::Type;
+        pub use packet::{Type, HEADER_LEN};
     }
 
     use std::time::{Instant, Duration};
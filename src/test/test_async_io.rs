@@ -0,0 +1,469 @@
+use super::prelude::*;
+
+use UtpStream;
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::StreamExt;
+
+use std::cell::RefCell;
+use std::io;
+use std::future::Future;
+use std::net::UdpSocket;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Records whether the task was woken
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn poll_read_wakes_task_on_data() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // Receive the data
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"ping");
+
+        sleep(100);
+
+        // Send a reply
+        let mut p = Packet::data(b"pong");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+    });
+
+    let mut stream = socket.connect(server);
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    match Pin::new(&mut stream).poll_write(&mut cx, b"ping") {
+        Poll::Ready(Ok(4)) => {}
+        ret => panic!("unexpected; {:?}", ret),
+    }
+
+    let mut buf = [0; 16];
+
+    match Pin::new(&mut stream).poll_read(&mut cx, &mut buf) {
+        Poll::Pending => {}
+        ret => panic!("unexpected; {:?}", ret),
+    }
+
+    // The task is woken once the reply arrives
+    socket.wait_until(|| flag.0.load(Ordering::SeqCst));
+
+    match Pin::new(&mut stream).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(4)) => assert_eq!(&buf[..4], b"pong"),
+        ret => panic!("unexpected; {:?}", ret),
+    }
+
+    th.join().unwrap();
+}
+
+#[test]
+fn poll_accept_and_connect_wake_tasks() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+
+    let accept_flag = Arc::new(Flag(AtomicBool::new(false)));
+    let accept_waker = Waker::from(accept_flag.clone());
+    let mut accept_cx = Context::from_waker(&accept_waker);
+
+    let connect_flag = Arc::new(Flag(AtomicBool::new(false)));
+    let connect_waker = Waker::from(connect_flag.clone());
+    let mut connect_cx = Context::from_waker(&connect_waker);
+
+    assert!(listener.poll_accept(&mut accept_cx).is_pending());
+
+    // Both ends of the stream live on the same socket
+    let stream = socket.connect(socket.local_addr());
+    assert!(stream.poll_connect(&mut connect_cx).is_pending());
+
+    socket.wait_until(|| accept_flag.0.load(Ordering::SeqCst));
+
+    match listener.poll_accept(&mut accept_cx) {
+        Poll::Ready(Ok(_)) => {}
+        ret => panic!("unexpected; {:?}", ret.map(|r| r.map(|_| ()))),
+    }
+
+    socket.wait_until(|| connect_flag.0.load(Ordering::SeqCst));
+
+    match stream.poll_connect(&mut connect_cx) {
+        Poll::Ready(Ok(())) => {}
+        ret => panic!("unexpected; {:?}", ret),
+    }
+}
+
+#[test]
+fn incoming_poll_next_wakes_task() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut incoming = listener.incoming();
+    assert!(Pin::new(&mut incoming).poll_next(&mut cx).is_pending());
+
+    let _stream = socket.connect(socket.local_addr());
+    socket.wait_until(|| flag.0.load(Ordering::SeqCst));
+
+    let stream = match Pin::new(&mut incoming).poll_next(&mut cx) {
+        Poll::Ready(Some(Ok(stream))) => stream,
+        _ => panic!("no connection"),
+    };
+
+    drop(stream);
+}
+
+#[test]
+fn incoming_stream_next_accepts_connection() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut incoming = listener.incoming();
+    let mut next = StreamExt::next(&mut incoming);
+    assert!(Pin::new(&mut next).poll(&mut cx).is_pending());
+
+    let _stream = socket.connect(socket.local_addr());
+    socket.wait_until(|| flag.0.load(Ordering::SeqCst));
+
+    let stream = match Pin::new(&mut next).poll(&mut cx) {
+        Poll::Ready(Some(Ok(stream))) => stream,
+        _ => panic!("no connection"),
+    };
+
+    drop(stream);
+}
+
+#[test]
+fn poll_connect_reports_reset() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::reset();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+    });
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let stream = socket.connect(server);
+    assert!(stream.poll_connect(&mut cx).is_pending());
+
+    socket.wait_until(|| flag.0.load(Ordering::SeqCst));
+
+    match stream.poll_connect(&mut cx) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {}
+        ret => panic!("unexpected; {:?}", ret),
+    }
+
+    th.join().unwrap();
+}
+
+#[test]
+fn poll_connect_reports_expired_lifetime() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_max_lifetime(Some(Duration::from_millis(100)));
+
+    let (socket, _) = Harness::with_config(config);
+    let mut mock = Mock::new();
+    let server = mock.local_addr();
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    // The peer never answers the SYN
+    let stream = socket.connect(server);
+    assert!(stream.poll_connect(&mut cx).is_pending());
+
+    socket.wait_until(|| flag.0.load(Ordering::SeqCst));
+
+    match stream.poll_connect(&mut cx) {
+        Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionAborted => {}
+        ret => panic!("unexpected; {:?}", ret),
+    }
+
+    let addr = socket.local_addr();
+    assert_eq!(packet::Type::Syn, mock.recv_from(&addr).ty());
+    assert_eq!(packet::Type::Reset, mock.recv_from(&addr).ty());
+}
+
+#[test]
+fn stream_is_unpin() {
+    // Required by TLS adapters, such as `futures-rustls`
+    fn assert_unpin<T: Unpin + AsyncRead + AsyncWrite>() {}
+    assert_unpin::<::UtpStream>();
+}
+
+#[test]
+fn halves_are_async() {
+    fn assert_read<T: Unpin + AsyncRead>() {}
+    fn assert_write<T: Unpin + AsyncWrite>() {}
+
+    assert_read::<::ReadHalf<'static>>();
+    assert_read::<::OwnedReadHalf>();
+    assert_write::<::WriteHalf<'static>>();
+    assert_write::<::OwnedWriteHalf>();
+}
+
+#[test]
+fn many_tasks_share_socket_under_loss() {
+    const STREAMS: usize = 24;
+    const LEN: usize = 16 * 1_024;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+
+    // Both ends of every stream live on the same socket. Packets are
+    // reflected back to it, dropping one in twenty.
+    let proxy = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let reflector = {
+        let target = socket.local_addr();
+        let done = done.clone();
+
+        proxy.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 2_048];
+            let mut n = 0;
+
+            while !done.load(Ordering::SeqCst) {
+                let len = match proxy.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(_) => continue,
+                };
+
+                n += 1;
+
+                if n % 20 != 0 {
+                    proxy.send_to(&buf[..len], target).unwrap();
+                }
+            }
+
+            // Number of dropped packets
+            n / 20
+        })
+    };
+
+    let received = Rc::new(RefCell::new(vec![]));
+    let mut tasks: Vec<Task> = vec![];
+
+    for i in 0..STREAMS {
+        let stream = socket.connect(proxy_addr);
+        tasks.push(Task::new(WriteAll::new(stream, payload(i, LEN))));
+    }
+
+    let start = Instant::now();
+    let mut accepted = 0;
+
+    while accepted < STREAMS || !tasks.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(30),
+                "stalled; accepted={}; tasks={}; received={}",
+                accepted, tasks.len(), received.borrow().len());
+
+        socket.tick();
+
+        while let Ok(stream) = listener.accept() {
+            tasks.push(Task::new(ReadAll::new(stream, received.clone())));
+            accepted += 1;
+        }
+
+        // Tasks are only polled once woken, a missed wake up stalls the test
+        tasks.retain_mut(|task| !task.poll());
+    }
+
+    done.store(true, Ordering::SeqCst);
+    assert!(reflector.join().unwrap() > 0);
+
+    let mut received = received.borrow_mut();
+    received.sort();
+
+    let expect: Vec<_> = (0..STREAMS).map(|i| payload(i, LEN)).collect();
+    assert!(*received == expect);
+}
+
+/// Data written on the `i`th stream
+fn payload(i: usize, len: usize) -> Vec<u8> {
+    let mut data: Vec<u8> = (0..len).map(|j| (i * 7 + j) as u8).collect();
+    data[0] = i as u8;
+    data
+}
+
+/// A task of the executor driving `many_tasks_share_socket_under_loss`
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    woken: Arc<Flag>,
+}
+
+impl Task {
+    fn new<F: Future<Output = ()> + 'static>(future: F) -> Task {
+        Task {
+            future: Box::pin(future),
+            woken: Arc::new(Flag(AtomicBool::new(true))),
+        }
+    }
+
+    /// Polls the future if the task was woken, returns true once it completed
+    fn poll(&mut self) -> bool {
+        if !self.woken.0.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+
+        let waker = Waker::from(self.woken.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        self.future.as_mut().poll(&mut cx).is_ready()
+    }
+}
+
+/// Writes all of the data, then closes the stream
+struct WriteAll {
+    stream: UtpStream,
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl WriteAll {
+    fn new(stream: UtpStream, data: Vec<u8>) -> WriteAll {
+        WriteAll {
+            stream,
+            data,
+            pos: 0,
+        }
+    }
+}
+
+impl Future for WriteAll {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let me = self.get_mut();
+
+        while me.pos < me.data.len() {
+            match Pin::new(&mut me.stream).poll_write(cx, &me.data[me.pos..]) {
+                Poll::Ready(Ok(n)) => me.pos += n,
+                Poll::Ready(Err(e)) => panic!("write failed; {:?}", e),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match Pin::new(&mut me.stream).poll_close(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(()),
+            Poll::Ready(Err(e)) => panic!("close failed; {:?}", e),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Reads until EOF, then stores the data
+struct ReadAll {
+    stream: UtpStream,
+    data: Vec<u8>,
+    received: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl ReadAll {
+    fn new(stream: UtpStream, received: Rc<RefCell<Vec<Vec<u8>>>>) -> ReadAll {
+        ReadAll {
+            stream,
+            data: vec![],
+            received,
+        }
+    }
+}
+
+impl Future for ReadAll {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let me = self.get_mut();
+        let mut buf = [0; 4_096];
+
+        loop {
+            match Pin::new(&mut me.stream).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(n)) => me.data.extend_from_slice(&buf[..n]),
+                Poll::Ready(Err(e)) => panic!("read failed; {:?}", e),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let data = ::std::mem::take(&mut me.data);
+        me.received.borrow_mut().push(data);
+
+        Poll::Ready(())
+    }
+}
@@ -0,0 +1,157 @@
+use blocking::{UtpListener, UtpStream};
+
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::net::Shutdown;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn echo_over_blocking_streams() {
+    let _ = ::env_logger::init();
+
+    let listener = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        assert_eq!(addr, stream.local_addr().unwrap());
+
+        let mut data = vec![];
+        stream.read_to_end(&mut data).unwrap();
+        stream.write_all(&data).unwrap();
+    });
+
+    let data: Vec<u8> = (0..64 * 1_024).map(|i| i as u8).collect();
+
+    let mut stream = UtpStream::connect(addr).unwrap();
+    stream.write_all(&data).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut echo = vec![];
+    stream.read_to_end(&mut echo).unwrap();
+    assert!(echo == data);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn connect_from_listener_shares_port() {
+    let _ = ::env_logger::init();
+
+    let a = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let b = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let b_addr = b.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let mut stream = b.accept().unwrap();
+
+        // Peeking blocks until data arrives, without consuming it
+        let mut buf = [0; 1];
+        assert_eq!(1, stream.peek(&mut buf).unwrap());
+        assert_eq!(&buf, b"h");
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        stream.connection_id()
+    });
+
+    let mut stream = a.connect(b_addr).unwrap();
+    assert_eq!(a.local_addr().unwrap(), stream.local_addr().unwrap());
+    assert_eq!(b_addr, stream.peer_addr().unwrap());
+
+    stream.write_all(b"hello").unwrap();
+
+    // Each side identifies the connection by the ID of the packets it receives
+    assert_eq!(stream.connection_id() + 1, server.join().unwrap());
+}
+
+#[test]
+fn connect_resolves_addresses() {
+    let _ = ::env_logger::init();
+
+    let listener = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        listener.accept().unwrap();
+    });
+
+    let stream = UtpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(stream.local_addr().unwrap().is_ipv4());
+
+    server.join().unwrap();
+
+    let err = UtpStream::connect(&[][..]).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn read_times_out() {
+    let _ = ::env_logger::init();
+
+    let listener = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (tx, rx) = mpsc::channel();
+
+    let server = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+
+        // Reply once the client's read timed out
+        rx.recv().unwrap();
+        stream.write_all(b"hello").unwrap();
+    });
+
+    let mut stream = UtpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert_eq!(Some(Duration::from_millis(100)), stream.read_timeout().unwrap());
+    assert_eq!(None, stream.write_timeout().unwrap());
+
+    let start = Instant::now();
+    let mut buf = [0; 5];
+    assert_eq!(io::ErrorKind::TimedOut, stream.read(&mut buf).unwrap_err().kind());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    tx.send(()).unwrap();
+
+    stream.set_read_timeout(None).unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    server.join().unwrap();
+}
+
+#[test]
+fn vectored_read_and_write() {
+    let _ = ::env_logger::init();
+
+    let listener = UtpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let stream = listener.accept().unwrap();
+
+        // The buffers are written as a single packet, which is scattered
+        // across the read buffers
+        let mut a = [0; 6];
+        let mut b = [0; 16];
+
+        let n = {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+            stream.read_vectored(&mut bufs).unwrap()
+        };
+
+        assert_eq!(15, n);
+        assert_eq!(&a, b"header");
+        assert_eq!(&b[..9], b" and body");
+    });
+
+    let stream = UtpStream::connect(addr).unwrap();
+    let bufs = [IoSlice::new(b"head"), IoSlice::new(b""), IoSlice::new(b"er and body")];
+    assert_eq!(15, stream.write_vectored(&bufs).unwrap());
+
+    server.join().unwrap();
+}
@@ -0,0 +1,228 @@
+use congestion::{CongestionControl, Ack, Ledbat};
+use tuning::{MAX_PACKET_SIZE, TARGET_DELAY_MICROS};
+use UtpConfig;
+
+use std::time::{Duration, Instant};
+
+fn ack(bytes_acked: usize, delay_ms: u64) -> Ack {
+    Ack::new(bytes_acked,
+             Some(Duration::from_millis(delay_ms)),
+             Duration::from_millis(10),
+             Duration::from_millis(0),
+             false,
+             Instant::now())
+}
+
+/// Ack a full window, one packet at a time
+fn ack_window(cc: &mut Ledbat, delay_ms: u64) {
+    let mut remaining = cc.cwnd();
+
+    while remaining > 0 {
+        let n = ::std::cmp::min(remaining, MAX_PACKET_SIZE);
+        cc.on_ack(&ack(n, delay_ms));
+        remaining -= n;
+    }
+}
+
+#[test]
+fn slow_start_doubles_window_each_rtt() {
+    let mut cc = Ledbat::new();
+    assert_eq!(cc.cwnd(), MAX_PACKET_SIZE);
+
+    // Run until the window is well past the point where LEDBAT's own gain
+    // would dominate.
+    for _ in 0..3 {
+        let prev = cc.cwnd();
+        ack_window(&mut cc, 0);
+        assert!(cc.cwnd() >= 2 * prev, "prev={}; cwnd={}", prev, cc.cwnd());
+    }
+
+    let prev = cc.cwnd();
+    ack_window(&mut cc, 0);
+    assert_eq!(cc.cwnd(), 2 * prev);
+}
+
+#[test]
+fn initial_window_skips_ramp_up() {
+    let mut cc = Ledbat::new();
+    cc.set_initial_window(10 * MAX_PACKET_SIZE);
+    assert_eq!(cc.cwnd(), 10 * MAX_PACKET_SIZE);
+
+    // Slow start continues from the larger window
+    let prev = cc.cwnd();
+    ack_window(&mut cc, 0);
+    assert_eq!(cc.cwnd(), 2 * prev);
+
+    // The hint never shrinks the window
+    cc.set_initial_window(MAX_PACKET_SIZE);
+    assert_eq!(cc.cwnd(), 2 * prev);
+}
+
+#[test]
+fn slow_start_ends_on_delay() {
+    let mut cc = Ledbat::new();
+    ack_window(&mut cc, 0);
+
+    // A delay close to the target ends slow start
+    let delay_ms = (TARGET_DELAY_MICROS / 1_000) as u64 * 95 / 100;
+    ack_window(&mut cc, delay_ms);
+    let cwnd = cc.cwnd();
+    assert!(cwnd < 2 * MAX_PACKET_SIZE + 3_000, "cwnd={}", cwnd);
+
+    // Congestion avoidance only grows the window additively, even once the
+    // delay drops again.
+    ack_window(&mut cc, 0);
+    assert!(cc.cwnd() < 2 * cwnd, "cwnd={}", cc.cwnd());
+}
+
+#[test]
+fn slow_start_ends_on_loss() {
+    let mut cc = Ledbat::new();
+    ack_window(&mut cc, 0);
+    ack_window(&mut cc, 0);
+    let prev = cc.cwnd();
+
+    cc.on_loss();
+    assert_eq!(cc.cwnd(), prev / 2);
+
+    // Slow start does not resume after the loss
+    ack_window(&mut cc, 0);
+    assert!(cc.cwnd() < prev, "cwnd={}", cc.cwnd());
+}
+
+#[test]
+fn lower_target_delay_shrinks_window() {
+    let mut default = Ledbat::new();
+    let mut low = Ledbat::new();
+    low.set_target_delay(Duration::from_millis(25));
+
+    // Leave slow start
+    default.on_loss();
+    low.on_loss();
+
+    // 50ms of queuing delay is below the default target but above 25ms
+    ack_window(&mut default, 50);
+    ack_window(&mut low, 50);
+
+    assert!(default.cwnd() > MAX_PACKET_SIZE / 2, "cwnd={}", default.cwnd());
+    assert!(low.cwnd() < MAX_PACKET_SIZE / 2, "cwnd={}", low.cwnd());
+}
+
+#[test]
+fn configured_gain_limits_growth() {
+    let mut config = UtpConfig::new();
+    config.set_max_cwnd_increase_bytes_per_rtt(100);
+
+    let mut cc = config.new_congestion_control();
+    cc.on_loss();
+    let prev = cc.cwnd();
+
+    cc.on_ack(&ack(prev, 0));
+    assert_eq!(cc.cwnd(), prev + 100);
+}
+
+#[test]
+fn app_limited_slow_start_does_not_grow() {
+    let mut cc = Ledbat::new();
+    let prev = cc.cwnd();
+
+    let limited = Ack::new(prev, Some(Duration::from_millis(0)),
+                           Duration::from_millis(10), Duration::from_millis(0), true,
+                           Instant::now());
+    cc.on_ack(&limited);
+    assert_eq!(cc.cwnd(), prev);
+
+    // Growth resumes once the window is filled
+    ack_window(&mut cc, 0);
+    assert!(cc.cwnd() >= 2 * prev, "prev={}; cwnd={}", prev, cc.cwnd());
+}
+
+#[test]
+fn idle_decays_window() {
+    let mut cc = Ledbat::new();
+    cc.set_initial_window(16 * MAX_PACKET_SIZE);
+
+    cc.on_idle(1);
+    assert_eq!(cc.cwnd(), 8 * MAX_PACKET_SIZE);
+
+    cc.on_idle(2);
+    assert_eq!(cc.cwnd(), 2 * MAX_PACKET_SIZE);
+
+    // Down to the initial window
+    cc.on_idle(10);
+    assert_eq!(cc.cwnd(), MAX_PACKET_SIZE);
+}
+
+#[test]
+fn configured_max_send_window_caps_growth() {
+    let mut config = UtpConfig::new();
+    config.set_max_send_window(Some(4 * MAX_PACKET_SIZE));
+
+    let mut cc = config.new_congestion_control();
+
+    for _ in 0..5 {
+        let cwnd = cc.cwnd();
+        cc.on_ack(&ack(cwnd, 0));
+    }
+
+    assert_eq!(cc.cwnd(), 4 * MAX_PACKET_SIZE);
+
+    // Including the initial window hint
+    cc.set_initial_window(10 * MAX_PACKET_SIZE);
+    assert_eq!(cc.cwnd(), 4 * MAX_PACKET_SIZE);
+}
+
+#[test]
+fn configured_packet_size_seeds_window() {
+    let mut config = UtpConfig::new();
+    config.set_max_packet_size(500);
+
+    let mut cc = config.new_congestion_control();
+    assert_eq!(cc.cwnd(), 500);
+
+    let cwnd = cc.cwnd();
+    cc.on_ack(&ack(cwnd, 0));
+    assert!(cc.cwnd() >= 1_000, "cwnd={}", cc.cwnd());
+
+    // The window decays down to a single packet
+    cc.on_idle(10);
+    assert_eq!(cc.cwnd(), 500);
+}
+
+#[test]
+#[should_panic(expected = "invalid min packet size")]
+fn min_packet_size_above_max_panics() {
+    let mut config = UtpConfig::new();
+    config.set_max_packet_size(500);
+    config.set_min_packet_size(501);
+}
+
+#[test]
+fn jitter_filter_ignores_noise() {
+    let mut config = UtpConfig::new();
+    let mut plain = config.new_congestion_control();
+
+    config.set_jitter_filter(true);
+    let mut filtered = config.new_congestion_control();
+
+    // Leave slow start
+    plain.on_loss();
+    filtered.on_loss();
+
+    let prev = plain.cwnd();
+    assert_eq!(prev, filtered.cwnd());
+
+    // 10ms over the target, on a path with 20ms of jitter
+    let noisy = Ack::new(prev,
+                         Some(Duration::from_millis(TARGET_DELAY_MICROS as u64 / 1_000 + 10)),
+                         Duration::from_millis(10),
+                         Duration::from_millis(20),
+                         false,
+                         Instant::now());
+
+    plain.on_ack(&noisy);
+    filtered.on_ack(&noisy);
+
+    assert!(plain.cwnd() < prev, "cwnd={}", plain.cwnd());
+    assert!(filtered.cwnd() > prev, "cwnd={}", filtered.cwnd());
+}
@@ -0,0 +1,125 @@
+use delays::{Delays, ClockDrift, Jitter};
+
+use std::time::{Duration, Instant};
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn delay_is_relative_to_base() {
+    let now = Instant::now();
+    let mut delays = Delays::new();
+    assert_eq!(None, delays.get());
+
+    delays.add_sample(10_000, now);
+    assert_eq!(Some(10_000), delays.base_delay());
+    assert_eq!(Some(0), delays.get());
+
+    // The smallest of the recent samples
+    delays.add_sample(14_000, now);
+    delays.add_sample(12_000, now);
+    delays.add_sample(13_000, now);
+    assert_eq!(Some(2_000), delays.get());
+
+    // A lower sample becomes the base
+    delays.add_sample(9_000, now);
+    assert_eq!(Some(9_000), delays.base_delay());
+}
+
+#[test]
+fn base_delay_history_rolls_over() {
+    let now = Instant::now();
+    let mut delays = Delays::new();
+
+    delays.add_sample(10_000, now);
+
+    // Later samples are higher, the old minimum is kept for the length of
+    // the history
+    for minute in 1..13 {
+        delays.add_sample(20_000, now + secs(60 * minute));
+        assert_eq!(Some(10_000), delays.base_delay(), "minute={}", minute);
+    }
+
+    // Then it expires
+    delays.add_sample(20_000, now + secs(60 * 13));
+    assert_eq!(Some(20_000), delays.base_delay());
+}
+
+#[test]
+fn base_delay_wraps() {
+    let now = Instant::now();
+    let mut delays = Delays::new();
+
+    delays.add_sample(5, now);
+    delays.add_sample(0xFFFF_FFF0, now);
+
+    // 0xFFFF_FFF0 is 21 microseconds before 5
+    assert_eq!(Some(0xFFFF_FFF0), delays.base_delay());
+
+    delays.shift(0x20);
+    assert_eq!(Some(0x10), delays.base_delay());
+}
+
+#[test]
+fn clock_drift_tracks_steadily_growing_delays() {
+    let now = Instant::now();
+    let mut drift = ClockDrift::new(now);
+
+    // One sample per second, the delay grows by 80us every second
+    for i in 0..200 {
+        let at = now + secs(i) + Duration::from_millis(1);
+        drift.add_sample(50_000 + 80 * i as u32, at);
+    }
+
+    // A period closes with the first sample past its end, six seconds
+    let estimate = drift.get();
+    assert!(estimate > 450 && estimate <= 480, "estimate={}", estimate);
+}
+
+#[test]
+fn clock_drift_is_zero_for_stable_delays() {
+    let now = Instant::now();
+    let mut drift = ClockDrift::new(now);
+
+    // Jitter around a stable delay
+    for i in 0..200 {
+        let at = now + secs(i) + Duration::from_millis(1);
+        let sample = if i % 2 == 0 { 50_000 } else { 52_000 };
+
+        drift.add_sample(sample, at);
+    }
+
+    assert!(drift.get().abs() <= 5, "drift={}", drift.get());
+}
+
+#[test]
+fn jitter_is_zero_for_steady_arrivals() {
+    let now = Instant::now();
+    let mut jitter = Jitter::new();
+
+    // Sent every 10ms, arriving 30ms later
+    for i in 0..100 {
+        let at = now + Duration::from_millis(30 + 10 * i);
+        jitter.add_sample(1_000_000 + 10_000 * i as u32, at);
+    }
+
+    assert_eq!(Duration::from_millis(0), jitter.get());
+}
+
+#[test]
+fn jitter_tracks_varying_delay() {
+    let now = Instant::now();
+    let mut jitter = Jitter::new();
+
+    // The delay alternates between 30ms and 34ms, each arrival is 4ms off
+    // the previous one. The timestamps wrap along the way.
+    for i in 0..200 {
+        let delay = if i % 2 == 0 { 30 } else { 34 };
+        let at = now + Duration::from_millis(delay + 10 * i);
+        jitter.add_sample(0xFFF0_0000u32.wrapping_add(10_000 * i as u32), at);
+    }
+
+    let micros = jitter.get().subsec_micros();
+    assert!(micros > 3_900 && micros <= 4_000, "jitter={:?}", jitter.get());
+}
@@ -0,0 +1,97 @@
+use {UtpDriver, UtpSocket};
+
+use mio::{Events, Ready, PollOpt, Token};
+
+use std::io;
+use std::time::{Duration, Instant};
+
+const SERVER_SOCKET: Token = Token(0);
+const CLIENT_SOCKET: Token = Token(1);
+const LISTENER: Token = Token(2);
+const SERVER: Token = Token(3);
+
+#[test]
+fn drives_several_sockets() {
+    let _ = ::env_logger::init();
+
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let (server_socket, listener) = UtpSocket::bind(&addr).unwrap();
+    let (client_socket, _) = UtpSocket::bind(&addr).unwrap();
+
+    let server_addr = server_socket.local_addr().unwrap();
+    let client = client_socket.connect(server_addr).unwrap();
+
+    let mut driver = UtpDriver::new().unwrap();
+    driver.add_socket(server_socket, SERVER_SOCKET).unwrap();
+    driver.add_socket(client_socket, CLIENT_SOCKET).unwrap();
+    driver.poll().register(&listener, LISTENER, Ready::readable(), PollOpt::edge()).unwrap();
+
+    assert!(driver.socket(SERVER_SOCKET).is_some());
+    assert!(driver.socket(LISTENER).is_none());
+
+    let mut events = Events::with_capacity(64);
+    let mut server = None;
+    let mut received = vec![];
+    let mut written = false;
+    let mut buf = [0; 64];
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while received != b"hello world" {
+        assert!(Instant::now() < deadline, "transfer did not complete");
+
+        driver.turn(&mut events, Some(Duration::from_millis(100))).unwrap();
+
+        if !written && client.is_writable() {
+            assert_eq!(11, client.write(b"hello world").unwrap());
+            written = true;
+        }
+
+        for event in &events {
+            match event.token() {
+                SERVER_SOCKET | CLIENT_SOCKET => {}
+                LISTENER => {
+                    let stream = listener.accept().unwrap();
+                    driver.poll().register(&stream, SERVER, Ready::readable(), PollOpt::edge()).unwrap();
+                    server = Some(stream);
+                }
+                SERVER => {
+                    let stream = server.as_ref().unwrap();
+
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(n) => received.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => panic!("read failed; err={:?}", e),
+                        }
+                    }
+                }
+                token => panic!("unexpected token; token={:?}", token),
+            }
+        }
+    }
+
+    // The client acks the data within the delayed ACK timeout, without
+    // waiting for the periodic tick.
+    let start = Instant::now();
+
+    while client.stats().bytes_pending() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "data never acked");
+        driver.turn(&mut events, None).unwrap();
+    }
+
+    let socket = driver.remove_socket(CLIENT_SOCKET).unwrap();
+    assert!(socket.is_some());
+    assert!(driver.socket(CLIENT_SOCKET).is_none());
+    assert!(driver.remove_socket(CLIENT_SOCKET).unwrap().is_none());
+}
+
+#[test]
+fn waits_for_the_earliest_timer() {
+    let driver = UtpDriver::new().unwrap();
+
+    // Without sockets, only the periodic tick is pending
+    let timeout = driver.next_timeout();
+    assert!(timeout <= Duration::from_millis(::tuning::TICK_INTERVAL_MS), "timeout={:?}", timeout);
+    assert!(timeout > Duration::from_millis(::tuning::TICK_INTERVAL_MS - 100), "timeout={:?}", timeout);
+}
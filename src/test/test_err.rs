@@ -1,4 +1,12 @@
 use super::prelude::*;
+use UnknownConnection;
+use stats::DropReason;
+use vectors;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::mpsc;
 
 #[test]
 fn remote_reset() {
@@ -43,6 +51,178 @@ fn remote_reset() {
     let mut buf = [0; 128];
     assert!(stream.read(&mut buf).is_err());
 
+    // Pending writes fail as well
+    assert!(stream.is_writable());
+    assert_eq!(io::ErrorKind::ConnectionReset, stream.write(b"hello").unwrap_err().kind());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn unacked_data_can_be_replayed_after_reset() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        for i in 0..3 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            assert_eq!(p.seq_nr(), 2 + i);
+        }
+
+        // Only the first packet is acked before the connection is reset
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::reset();
+        p.set_connection_id(CONNECTION_ID);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    stream.set_nodelay(true).unwrap();
+
+    for data in &[&b"one"[..], b"two", b"three"] {
+        stream.write(data).unwrap();
+    }
+
+    // The stream becomes readable once reset
+    socket.wait_until(|| stream.is_readable());
+    th.join().unwrap();
+
+    assert_eq!(3, stream.stats().bytes_acked());
+    assert_eq!(&stream.unacked_data()[..], b"twothree");
+}
+
+#[test]
+fn ack_offset_excludes_selectively_acked_data() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        for i in 0..3 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            assert_eq!(p.seq_nr(), 2 + i);
+        }
+
+        // The first packet is acked, the third one selectively acked
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        p.set_selective_ack(&[0b0000_0001, 0, 0, 0]);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::reset();
+        p.set_connection_id(CONNECTION_ID);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    stream.set_nodelay(true).unwrap();
+
+    for data in &[&b"one"[..], b"two", b"three"] {
+        stream.write(data).unwrap();
+    }
+
+    socket.wait_until(|| stream.is_readable());
+    th.join().unwrap();
+
+    // Only the data before the gap was received in order
+    assert_eq!(8, stream.stats().bytes_acked());
+    assert_eq!(3, stream.ack_offset());
+}
+
+#[test]
+fn abort_sends_reset() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+
+        // The abort is signaled with a RESET
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), CONNECTION_ID + 1);
+
+        // Pending data is not retransmitted
+        m.assert_quiescence(1_000);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+    stream.abort().unwrap();
+
+    let mut buf = [0; 128];
+    assert_eq!(io::ErrorKind::ConnectionReset, stream.read(&mut buf).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::ConnectionReset, stream.write(b"world").unwrap_err().kind());
+
+    socket.tick_for(1_000);
+
     th.join().unwrap();
 }
 
@@ -91,3 +271,389 @@ fn remote_sends_invalid_packet() {
 
     th.join().unwrap();
 }
+
+#[test]
+fn malformed_datagrams_are_dropped() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        for &(_, data) in &vectors::MALFORMED {
+            m.send_raw(data, &addr);
+        }
+
+        // The connection is unaffected
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 16];
+    assert_eq!(5, stream.read(&mut buf).unwrap());
+    assert_eq!(&buf[..5], b"hello");
+
+    th.join().unwrap();
+
+    let stats = socket.driver_stats();
+    assert_eq!(vectors::MALFORMED.len() as u64, stats.malformed_packets());
+}
+
+#[test]
+fn strict_validation_drops_invalid_packets() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_strict_validation(true);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // A STATE packet never carries data
+        let mut p = Packet::state();
+        p.extend_payload(b"bogus");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The selective ACK is not a multiple of 4 bytes
+        let mut p = Packet::data(b"bogus");
+        p.set_selective_ack(&[1, 0, 0]);
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 16];
+    assert_eq!(5, stream.read(&mut buf).unwrap());
+    assert_eq!(&buf[..5], b"hello");
+
+    th.join().unwrap();
+
+    let stats = socket.driver_stats();
+    assert_eq!(2, stats.rejected_packets());
+    assert_eq!(0, stats.malformed_packets());
+}
+
+#[test]
+fn oversized_packets_are_dropped() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_max_recv_payload_size(16);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::data(&[0; 17]);
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::data(b"sixteen bytes...");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 32];
+    assert_eq!(16, stream.read(&mut buf).unwrap());
+    assert_eq!(&buf[..16], b"sixteen bytes...");
+
+    th.join().unwrap();
+
+    let stats = socket.driver_stats();
+    assert_eq!(1, stats.oversized_packets());
+}
+
+#[test]
+fn dropped_packets_notify_hook() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let mut config = UtpConfig::new();
+    config.set_drop_hook(move |addr, reason| {
+        tx.lock().unwrap().send((*addr, reason)).unwrap();
+    });
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        m.send_raw(vectors::MALFORMED[0].1, &addr);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p.clone(), &addr);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+    socket.tick_for(200);
+
+    th.join().unwrap();
+
+    assert_eq!((server, DropReason::Malformed), rx.try_recv().unwrap());
+    assert_eq!((server, DropReason::Duplicate), rx.try_recv().unwrap());
+    assert!(rx.try_recv().is_err());
+
+    let stats = socket.driver_stats();
+    assert_eq!(1, stats.dropped(DropReason::Malformed));
+    assert_eq!(1, stats.dropped(DropReason::Duplicate));
+    assert_eq!(2, stats.packets_dropped());
+}
+
+#[test]
+fn unknown_connection_from_known_peer_is_ignored() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_unknown_connection(UnknownConnection::Ignore);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        connect(m, &addr);
+
+        // The peer restarted and lost track of the connection
+        let mut p = Packet::state();
+        p.set_connection_id(12345);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(200);
+    });
+
+    let stream = socket.connect(server);
+    socket.tick_for(300);
+
+    th.join().unwrap();
+    assert!(stream.is_writable());
+}
+
+#[test]
+fn unknown_connection_from_known_peer_notifies() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let mut config = UtpConfig::new();
+    config.set_unknown_connection(UnknownConnection::Notify);
+    config.set_unknown_connection_hook(move |addr, connection_id| {
+        tx.lock().unwrap().send((*addr, connection_id)).unwrap();
+    });
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        connect(m, &addr);
+
+        // The restarted peer resets the packets it does not know about
+        let mut p = Packet::reset();
+        p.set_connection_id(12345);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(200);
+    });
+
+    let stream = socket.connect(server);
+    socket.tick_for(300);
+
+    th.join().unwrap();
+    assert_eq!((server, 12345), rx.try_recv().unwrap());
+
+    // Unknown peers are still reset
+    let other = Mock::new();
+    let th = other.background(move |m| {
+        let mut p = Packet::state();
+        p.set_connection_id(12345);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+    });
+
+    socket.tick_for(100);
+    th.join().unwrap();
+
+    assert!(rx.try_recv().is_err());
+    drop(stream);
+}
+
+#[test]
+fn reconnect_after_peer_restart() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let mut config = UtpConfig::new();
+    config.set_unknown_connection(UnknownConnection::Notify);
+    config.set_unknown_connection_hook(move |addr, _| {
+        tx.lock().unwrap().send(*addr).unwrap();
+    });
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        connect(m, &addr);
+
+        // The peer restarts and resets the packets it does not know about
+        let mut p = Packet::reset();
+        p.set_connection_id(54321);
+        m.send_to(p, &addr);
+
+        // The stale connection is reset
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), CONNECTION_ID + 1);
+
+        // And a new one established
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert!(p.connection_id() != CONNECTION_ID);
+
+        let mut state = Packet::state();
+        state.set_connection_id(p.connection_id());
+        state.set_seq_nr(456);
+        state.set_ack_nr(1);
+        m.send_to(state, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    let peer = socket.wait(|| rx.try_recv().map_err(|_| io::ErrorKind::WouldBlock.into()));
+    assert_eq!(server, peer.unwrap());
+
+    let stream = socket.reconnect(stream).unwrap();
+    socket.wait_until(|| stream.is_writable());
+    stream.write(b"hello").unwrap();
+
+    socket.tick_for(200);
+    th.join().unwrap();
+
+    // Streams of another socket are rejected
+    let (other, _) = Harness::new();
+    match socket.reconnect(other.connect(server)) {
+        Err(e) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+        Ok(_) => panic!("reconnected a stream of another socket"),
+    }
+}
+
+/// Accepts the connection on the mock's side
+fn connect(m: &mut Mock, addr: &SocketAddr) {
+    const CONNECTION_ID: u16 = 25103;
+
+    let p = m.recv_from(addr);
+    assert_eq!(p.ty(), packet::Type::Syn);
+
+    let mut p = Packet::state();
+    p.set_connection_id(CONNECTION_ID);
+    p.set_seq_nr(123);
+    p.set_ack_nr(1);
+    m.send_to(p, addr);
+}
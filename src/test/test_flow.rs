@@ -1,4 +1,11 @@
 use super::prelude::*;
+use tuning::MAX_WINDOW_SIZE;
+
+use std::{cmp, io, thread};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 #[test]
 fn ramp_up() {
@@ -7,7 +14,11 @@ fn ramp_up() {
     let _ = ::env_logger::init();
     ::util::reset_rand();
 
-    let (socket, _) = Harness::new();
+    // Packet sizes are fixed for the window math below
+    let mut config = UtpConfig::new();
+    config.set_mtu_discovery(false);
+
+    let (socket, _) = Harness::with_config(config);
     let mock = Mock::new();
     let server = mock.local_addr();
 
@@ -49,109 +60,793 @@ fn ramp_up() {
         m.send_to(p, &addr);
 
         let mut total = 1380;
+        let mut data = 1380;
         let mut ts1 = 0;
 
-        for i in 0..4 {
+        // The tail of the written data is held back by Nagle
+        for i in 0..3 {
             let p = m.recv_from(&addr);
             ts1 = p.timestamp();
             assert_eq!(p.ty(), packet::Type::Data);
-            total += p.len();
+            total += p.encoded_len();
+            data += p.payload().len();
+        }
+        assert_eq!(total, 5580);
+
+        // Slow start doubles the window every round trip. Ack each flight and
+        // check that the next one is about twice as large.
+        let mut ack_nr = 5;
+        let mut flight = total - 1380;
+
+        for _ in 0..2 {
+            let ts2 = t.timestamp();
+
+            let mut p = Packet::state();
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(123);
+            p.set_ack_nr(ack_nr);
+            p.set_timestamp(ts2);
+            p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+            m.send_to(p, &addr);
+
+            let mut next = 0;
+
+            while let Some(p) = m.recv_from_ms(&addr, 200) {
+                ts1 = p.timestamp();
+                assert_eq!(p.ty(), packet::Type::Data);
+                assert_eq!(p.seq_nr(), ack_nr + 1);
+                ack_nr = p.seq_nr();
+                next += p.encoded_len();
+                data += p.payload().len();
+            }
+
+            assert!(next > flight * 3 / 2, "flight={}; next={}", flight, next);
+            flight = next;
         }
-        assert_eq!(total, 5737);
 
-        let ts2 = t.timestamp();
+        // Ack the rest of the data until the socket has nothing left to send
+        loop {
+            let ts2 = t.timestamp();
+
+            let mut p = Packet::state();
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(123);
+            p.set_ack_nr(ack_nr);
+            p.set_timestamp(ts2);
+            p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+            m.send_to(p, &addr);
+
+            let prev = ack_nr;
+
+            while let Some(p) = m.recv_from_ms(&addr, 200) {
+                ts1 = p.timestamp();
+                assert_eq!(p.ty(), packet::Type::Data);
+                ack_nr = p.seq_nr();
+                data += p.payload().len();
+            }
+
+            if ack_nr == prev {
+                break;
+            }
+        }
+
+        assert_eq!(data, 36069);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let mut buf = vec![1; 36069];
+
+    while !buf.is_empty() {
+        assert!(stream.is_writable());
+        // Write some data
+        let n = stream.write(&buf).unwrap();
+        buf.drain(..n);
+        socket.wait_until(|| stream.is_writable());
+        // assert_eq!(n, 10_000);
+    }
+
+    // Tick a bunch
+    socket.tick_for(1500);
+
+    th.join().unwrap();
+
+    drop(stream);
+}
+
+#[test]
+fn shrinks_window_above_target_delay() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
 
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let t = Time::new();
+
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK, this
+        // establishes the base delay.
         let mut p = Packet::state();
         p.set_connection_id(CONNECTION_ID);
         p.set_seq_nr(123);
-        p.set_ack_nr(6);
-        p.set_timestamp(ts2);
-        p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+        p.set_ack_nr(1);
+        p.set_timestamp(t.timestamp());
+        p.set_timestamp_diff(1_000);
         m.send_to(p, &addr);
 
-        for i in 0..6 {
-            let p = m.recv_from(&addr);
-            ts1 = p.timestamp();
-            assert_eq!(p.ty(), packet::Type::Data);
-            total += p.len();
+        // Receive the data
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload().len(), 1380);
+
+        // Delay the ACK well past the target delay
+        sleep(300);
+
+        for ack_nr in &[1, 1, 2] {
+            let mut p = Packet::state();
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(123);
+            p.set_ack_nr(*ack_nr);
+            p.set_timestamp(t.timestamp());
+            p.set_timestamp_diff(301_000);
+            m.send_to(p, &addr);
         }
-        assert_eq!(total, 13038);
 
-        let ts2 = t.timestamp();
+        // Only a single packet is sent
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 3);
+
+        m.assert_quiescence(200);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let buf = vec![0; 4_000];
+
+    // The initial window is a single packet
+    assert_eq!(1380, stream.write(&buf).unwrap());
+
+    socket.wait_until(|| stream.is_writable());
+
+    // The window has shrunk to its minimum, only a single packet fits
+    assert_eq!(1380, stream.write(&buf).unwrap());
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn fixed_window_congestion_control() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_congestion_control(|| Box::new(FixedWindow::new(3_000)));
+    config.set_mtu_discovery(false);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
 
+        // Send the state packet representing the connection ACK
         let mut p = Packet::state();
         p.set_connection_id(CONNECTION_ID);
         p.set_seq_nr(123);
-        p.set_ack_nr(12);
-        p.set_timestamp(ts2);
-        p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+        p.set_ack_nr(1);
         m.send_to(p, &addr);
 
-        for i in 0..8 {
-            let p = m.recv_from(&addr);
-            ts1 = p.timestamp();
-            assert_eq!(p.ty(), packet::Type::Data);
-            total += p.len();
+        for &ack_nr in &[4, 7] {
+            // The window is filled
+            for _ in 0..3 {
+                let p = m.recv_from(&addr);
+                assert_eq!(p.ty(), packet::Type::Data);
+            }
+
+            m.assert_quiescence(100);
+
+            let mut p = Packet::state();
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(123);
+            p.set_ack_nr(ack_nr);
+            m.send_to(p, &addr);
         }
-        assert_eq!(total, 23289);
+    });
 
-        sleep(200);
-        let ts2 = t.timestamp();
+    let stream = socket.connect(server);
+
+    // The last packet of each write is not full
+    stream.set_nodelay(true).unwrap();
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let buf = vec![0; 4_000];
 
+    for _ in 0..2 {
+        // The window does not change
+        assert_eq!(2_940, stream.write(&buf).unwrap());
+        assert!(!stream.is_writable());
+
+        socket.wait_until(|| stream.is_writable());
+    }
+
+    th.join().unwrap();
+}
+
+#[test]
+fn nagle_coalesces_small_writes() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
         let mut p = Packet::state();
         p.set_connection_id(CONNECTION_ID);
         p.set_seq_nr(123);
-        p.set_ack_nr(20);
-        p.set_timestamp(ts2);
-        p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // Nothing is in-flight, the first write is sent right away
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+
+        // The following writes are held back until the ACK
+        m.assert_quiescence(200);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 3);
+        assert_eq!(p.payload(), b" world!");
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    assert!(!stream.nodelay().unwrap());
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    for chunk in &[&b"hello"[..], b" world", b"!"] {
+        assert_eq!(chunk.len(), stream.write(chunk).unwrap());
+    }
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn flush_sends_held_back_data() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+
+        // The flushed write is sent without waiting for the ACK
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 3);
+        assert_eq!(p.payload(), b" world");
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+    assert_eq!(6, stream.write(b" world").unwrap());
+    stream.flush().unwrap();
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn nodelay_sends_small_writes() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
 
-        sleep(200);
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
 
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
         m.send_to(p, &addr);
 
-        for i in 0..9 {
+        // Each write is sent in its own packet without waiting for an ACK
+        for (i, chunk) in [&b"hello"[..], b" world", b"!"].iter().enumerate() {
             let p = m.recv_from(&addr);
-            ts1 = p.timestamp();
             assert_eq!(p.ty(), packet::Type::Data);
-            total += p.len();
+            assert_eq!(p.seq_nr(), 2 + i as u16);
+            assert_eq!(p.payload(), *chunk);
         }
-        assert_eq!(total, 36069);
 
-        sleep(200);
-        let ts2 = t.timestamp();
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(4);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    stream.set_nodelay(true).unwrap();
+    assert!(stream.nodelay().unwrap());
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    for chunk in &[&b"hello"[..], b" world", b"!"] {
+        assert_eq!(chunk.len(), stream.write(chunk).unwrap());
+    }
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn slow_reader_throttles_sender() {
+    // The reader consumes 8kb every 50ms, or 160kb/s
+    const CHUNK: usize = 8 * 1_024;
+    const INTERVAL_MS: u64 = 50;
+    const TOTAL: usize = 320 * 1_024;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let read = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let reader = {
+        let read = read.clone();
+
+        thread::spawn(move || {
+            let (socket, listener) = Harness::new();
+            tx.send(socket.local_addr()).unwrap();
+
+            let stream = socket.wait(|| listener.accept()).unwrap();
+            let mut buf = vec![0; CHUNK];
+            let mut max_buffered = 0;
+
+            'outer: loop {
+                socket.tick_for(INTERVAL_MS);
+
+                max_buffered = cmp::max(max_buffered, stream.bytes_buffered());
+
+                // Each read returns at most one packet worth of data
+                let mut pos = 0;
+
+                while pos < CHUNK {
+                    match stream.read(&mut buf[pos..]) {
+                        Ok(0) => break 'outer,
+                        Ok(n) => pos += n,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => panic!("unexpected error; {:?}", e),
+                    }
+                }
+
+                read.fetch_add(pos, Ordering::SeqCst);
+            }
+
+            max_buffered
+        })
+    };
+
+    let (socket, _) = Harness::new();
+    let stream = socket.connect(rx.recv().unwrap());
+
+    let start = Instant::now();
+    let data = vec![0; TOTAL];
+    let mut written = 0;
+    let mut halfway = None;
+
+    while written < TOTAL {
+        written += socket.wait(|| stream.write(&data[written..])).unwrap();
+
+        // The sender can't get further ahead of the reader than the window
+        let acked = stream.stats().bytes_acked() as usize;
+        let read = read.load(Ordering::SeqCst);
+        assert!(acked <= read + MAX_WINDOW_SIZE, "acked={}; read={}", acked, read);
+
+        if halfway.is_none() && written >= TOTAL / 2 {
+            halfway = Some((Instant::now(), acked));
+        }
+    }
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+    stream.close().ok();
+    socket.tick_for(200);
+
+    let max_buffered = reader.join().unwrap();
+
+    // Memory stays bounded by the advertised window
+    assert!(max_buffered <= MAX_WINDOW_SIZE, "max_buffered={}", max_buffered);
+
+    // Over the second half of the transfer, the throughput matches the read
+    // rate.
+    let (at, acked) = halfway.unwrap();
+    let elapsed = at.elapsed();
+    let rate = (TOTAL - acked) as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9);
+    let read_rate = CHUNK as f64 * 1_000.0 / INTERVAL_MS as f64;
+
+    assert!(rate < read_rate * 1.5, "rate={}; read_rate={}", rate, read_rate);
+    assert!(rate > read_rate * 0.5, "rate={}; read_rate={}", rate, read_rate);
+
+    assert!(start.elapsed() >= Duration::from_millis(1_000));
+}
+
+#[test]
+fn advertised_window_tracks_receive_buffer() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_recv_buffer_size(4_000);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // The SYN advertises the whole buffer
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(p.wnd_size(), 4_000);
 
         let mut p = Packet::state();
         p.set_connection_id(CONNECTION_ID);
         p.set_seq_nr(123);
-        p.set_ack_nr(29);
-        p.set_timestamp(ts2);
-        p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let data = |seq_nr, len| {
+            let mut p = Packet::data(&vec![0; len]);
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(seq_nr);
+            p.set_ack_nr(1);
+            p
+        };
+
+        // Unread data shrinks the window
+        m.send_to(data(124, 1_000), &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+        assert_eq!(p.wnd_size(), 3_000);
+
+        // So does data held until a gap fills. The buffer is then full, only
+        // the packet filling the gap is accepted.
+        m.send_to(data(126, 3_000), &addr);
+        m.send_to(data(127, 10), &addr);
+        m.send_to(data(125, 1), &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 126);
+        assert_eq!(p.wnd_size(), 0);
+
+        // Once the application reads, the window reopens
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 126);
+        assert_eq!(p.wnd_size(), 4_000);
+    });
+
+    let stream = socket.connect(server);
+
+    socket.wait_until(|| stream.bytes_buffered() == 4_001);
+
+    let mut buf = [0; 4_096];
+    let mut read = 0;
+
+    while let Ok(n) = stream.read(&mut buf) {
+        read += n;
+    }
+
+    assert_eq!(read, 4_001);
+
+    socket.tick_for(200);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn initial_window_is_sent_in_first_flight() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
 
-        sleep(200);
+    let mut config = UtpConfig::new();
+    config.set_mtu_discovery(false);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
 
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
         m.send_to(p, &addr);
+
+        // The whole initial window is sent without waiting for an ACK
+        let mut total = 0;
+
+        while let Some(p) = m.recv_from_ms(&addr, 200) {
+            assert_eq!(p.ty(), packet::Type::Data);
+            total += p.encoded_len();
+        }
+
+        assert_eq!(total, 10 * 1_400);
     });
 
     let stream = socket.connect(server);
 
+    // Requests are capped by the config
+    stream.set_initial_window(1_000_000).unwrap();
+
     // The socket becomes writable
     socket.wait_until(|| stream.is_writable());
+    assert_eq!(stream.stats().cwnd(), 10 * 1_400);
 
-    let mut buf = vec![1; 36069];
+    let data = vec![0; 64 * 1_024];
+    let mut written = 0;
 
-    while !buf.is_empty() {
-        assert!(stream.is_writable());
-        // Write some data
-        let n = stream.write(&buf).unwrap();
-        buf.drain(..n);
-        socket.wait_until(|| stream.is_writable());
-        // assert_eq!(n, 10_000);
+    while let Ok(n) = stream.write(&data[written..]) {
+        written += n;
     }
 
-    // Tick a bunch
-    socket.tick_for(1500);
+    socket.tick_for(300);
+
+    // Too late once data has been sent
+    let err = stream.set_initial_window(20_000).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
 
     th.join().unwrap();
+}
 
-    drop(stream);
+#[test]
+fn zero_window_is_probed() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // ACK the connection, without room to receive anything
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        p.set_wnd_size(0);
+        m.send_to(p, &addr);
+
+        let mut data = vec![];
+        let mut last = Instant::now();
+
+        // Single byte probes are sent, backing off while the window stays
+        // closed.
+        for i in 0..2 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            assert_eq!(p.payload().len(), 1);
+            assert!(last.elapsed() >= Duration::from_millis(400 << i));
+            data.extend_from_slice(p.payload());
+            last = Instant::now();
+
+            // The last probe finds the window open again
+            let mut ack = Packet::state();
+            ack.set_connection_id(CONNECTION_ID);
+            ack.set_seq_nr(123);
+            ack.set_ack_nr(p.seq_nr());
+            ack.set_wnd_size(if i == 0 { 0 } else { MAX_WINDOW_SIZE as u32 });
+            m.send_to(ack, &addr);
+        }
+
+        // The rest of the data follows right away
+        while data.len() < 11 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            data.extend_from_slice(p.payload());
+
+            let mut ack = Packet::state();
+            ack.set_connection_id(CONNECTION_ID);
+            ack.set_seq_nr(123);
+            ack.set_ack_nr(p.seq_nr());
+            m.send_to(ack, &addr);
+        }
+
+        assert!(last.elapsed() < Duration::from_millis(200));
+        assert_eq!(&data[..], b"hello world");
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    let data = b"hello world";
+    let mut written = 0;
+
+    while written < data.len() {
+        written += socket.wait(|| stream.write(&data[written..])).unwrap();
+    }
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+    th.join().unwrap();
+
+    // Probes held back by the window are not timeouts
+    assert_eq!(stream.stats().timeouts(), 0);
+}
+
+#[test]
+fn burst_limit_spreads_packets_over_ticks() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_congestion_control(|| Box::new(FixedWindow::new(64 * 1024)));
+    config.set_mtu_discovery(false);
+    config.set_max_burst(2);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // All of the data arrives, over several ticks
+        let mut received = 0;
+        let mut last = 0;
+
+        while received < 4_000 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+
+            received += p.payload().len();
+            last = p.seq_nr();
+        }
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(last);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    stream.set_nodelay(true).unwrap();
+
+    socket.wait_until(|| stream.is_writable());
+    assert_eq!(None, socket.next_timeout());
+
+    // The write needs more than two packets, the rest are held back
+    assert_eq!(4_000, stream.write(&[0; 4_000]).unwrap());
+    assert_eq!(Some(Duration::from_millis(0)), socket.next_timeout());
+
+    socket.wait_until(|| stream.stats().bytes_pending == 0);
+    th.join().unwrap();
+
+    assert_eq!(None, socket.next_timeout());
 }
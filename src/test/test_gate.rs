@@ -0,0 +1,128 @@
+use super::prelude::*;
+
+use {TransmitGate, Transmit, Admission};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CONNECTION_ID: u16 = 25103;
+
+type Checks = Arc<Mutex<Vec<(Instant, Transmit)>>>;
+
+/// Records the packets it is consulted on, and applies `admission` to the
+/// first DATA packet
+#[derive(Debug)]
+struct Gate {
+    checks: Checks,
+    admission: Admission,
+}
+
+impl TransmitGate for Gate {
+    fn check(&mut self, transmit: &Transmit) -> Admission {
+        let mut checks = self.checks.lock().unwrap();
+        let first = !checks.iter().any(|(_, t)| t.ty() == packet::Type::Data);
+
+        checks.push((Instant::now(), transmit.clone()));
+
+        if first && transmit.ty() == packet::Type::Data {
+            self.admission
+        } else {
+            Admission::Allow
+        }
+    }
+}
+
+/// Connects to a mock peer through a socket gated by `admission`, writes
+/// "hello" and returns the gate's checks once the peer received it
+fn gated_write(admission: Admission) -> Vec<(Instant, Transmit)> {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let checks = Checks::default();
+    let gate_checks = checks.clone();
+
+    let mut config = UtpConfig::new();
+    config.set_transmit_gate(move || {
+        Box::new(Gate { checks: gate_checks.clone(), admission })
+    });
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    stream.set_priority(7);
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    socket.wait_until(|| stream.stats().bytes_acked() == 5);
+    th.join().unwrap();
+
+    let checks = checks.lock().unwrap();
+    checks.clone()
+}
+
+#[test]
+fn gate_sees_every_packet() {
+    let checks = gated_write(Admission::Allow);
+
+    let tys: Vec<_> = checks.iter().map(|(_, t)| t.ty()).collect();
+    assert_eq!(tys, [packet::Type::Syn, packet::Type::Data]);
+
+    let data = &checks[1].1;
+    assert_eq!(CONNECTION_ID + 1, data.connection_id());
+    assert_eq!(packet::HEADER_LEN + 5, data.size());
+    assert_eq!(7, data.priority());
+    assert!(!data.is_retransmit());
+}
+
+#[test]
+fn gate_delays_transmission() {
+    let checks = gated_write(Admission::Delay(Duration::from_millis(200)));
+
+    // The DATA packet is offered again once the delay elapsed
+    let data: Vec<_> = checks.iter()
+        .filter(|&(_, t)| t.ty() == packet::Type::Data)
+        .collect();
+
+    assert_eq!(2, data.len());
+    assert!(data[1].0 - data[0].0 >= Duration::from_millis(200));
+    assert!(!data[1].1.is_retransmit());
+}
+
+#[test]
+fn gate_denies_transmission() {
+    let checks = gated_write(Admission::Deny);
+
+    // The denied DATA packet is retransmitted after timing out
+    let data: Vec<_> = checks.iter()
+        .filter(|&(_, t)| t.ty() == packet::Type::Data)
+        .collect();
+
+    assert_eq!(2, data.len());
+    assert!(data[1].0 - data[0].0 >= Duration::from_millis(400));
+    assert!(data[1].1.is_retransmit());
+}
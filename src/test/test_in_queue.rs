@@ -0,0 +1,395 @@
+use super::prelude::*;
+use in_queue::InQueue;
+use stats::DropReason;
+
+use bytes::BufMut;
+
+use std::io::{self, IoSliceMut};
+use std::mem::MaybeUninit;
+
+const CONNECTION_ID: u16 = 25103;
+
+fn data(seq_nr: u16, payload: &[u8]) -> Packet {
+    let mut p = Packet::data(payload);
+    p.set_connection_id(CONNECTION_ID);
+    p.set_seq_nr(seq_nr);
+    p
+}
+
+fn fin(seq_nr: u16) -> Packet {
+    let mut p = Packet::fin();
+    p.set_connection_id(CONNECTION_ID);
+    p.set_seq_nr(seq_nr);
+    p
+}
+
+fn read_all(q: &mut InQueue) -> Vec<u8> {
+    let mut ret = vec![];
+    let mut buf = [0; 128];
+
+    loop {
+        match q.read(&mut buf) {
+            Ok(n) => ret.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return ret,
+            Err(e) => panic!("unexpected error; {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn in_order_data() {
+    let mut q = InQueue::new(Some(1));
+
+    assert!(q.push(data(2, b"hello ")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 2);
+
+    assert!(q.push(data(3, b"world")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 3);
+
+    assert!(q.is_readable());
+    assert_eq!(read_all(&mut q), b"hello world");
+    assert!(!q.is_readable());
+}
+
+#[test]
+fn fills_gaps() {
+    let mut q = InQueue::new(Some(1));
+
+    // Packets arriving after a gap are held
+    assert!(q.push(data(4, b"three")).is_ok());
+    assert!(q.push(data(3, b"two")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 1);
+    assert!(!q.is_readable());
+
+    // Filling the gap releases all the held packets
+    assert!(q.push(data(2, b"one")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 4);
+    assert_eq!(read_all(&mut q), b"onetwothree");
+}
+
+#[test]
+fn peek_does_not_consume() {
+    let mut q = InQueue::new(Some(1));
+    let mut buf = [0; 8];
+
+    assert_eq!(io::ErrorKind::WouldBlock, q.peek(&mut buf).unwrap_err().kind());
+
+    assert!(q.push(data(2, b"hello ")).is_ok());
+    assert!(q.push(data(3, b"world")).is_ok());
+    assert!(q.poll().is_none());
+
+    // Peeking spans packets
+    assert_eq!(8, q.peek(&mut buf).unwrap());
+    assert_eq!(&buf, b"hello wo");
+
+    // Partially read packets are peeked from the read position
+    let mut buf = [0; 3];
+    assert_eq!(3, q.read(&mut buf).unwrap());
+
+    let mut buf = [0; 16];
+    assert_eq!(8, q.peek(&mut buf).unwrap());
+    assert_eq!(&buf[..8], b"lo world");
+    assert_eq!(read_all(&mut q), b"lo world");
+}
+
+#[test]
+fn read_vectored_fills_each_buffer() {
+    let mut q = InQueue::new(Some(1));
+
+    assert!(q.push(data(2, b"hello ")).is_ok());
+    assert!(q.push(data(3, b"world")).is_ok());
+    assert!(q.poll().is_none());
+
+    let mut a = [0; 4];
+    let mut b = [0; 16];
+
+    {
+        let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+        assert_eq!(11, q.read_vectored(&mut bufs).unwrap());
+    }
+
+    assert_eq!(&a, b"hell");
+    assert_eq!(&b[..7], b"o world");
+
+    let mut bufs = [IoSliceMut::new(&mut a)];
+    assert_eq!(io::ErrorKind::WouldBlock, q.read_vectored(&mut bufs).unwrap_err().kind());
+}
+
+#[test]
+fn read_buf_fills_spare_capacity() {
+    let mut q = InQueue::new(Some(1));
+
+    assert!(q.push(data(2, b"hello ")).is_ok());
+    assert!(q.push(data(3, b"world")).is_ok());
+    assert!(q.poll().is_none());
+
+    // Reads stop once the buffer is full
+    let mut dst = io::Cursor::new([0; 8]);
+    dst.put_slice(b">");
+    assert_eq!(7, q.read_buf(&mut dst).unwrap());
+    assert_eq!(dst.get_ref(), b">hello w");
+
+    // Growable buffers take everything
+    let mut dst = vec![];
+    assert_eq!(4, q.read_buf(&mut dst).unwrap());
+    assert_eq!(&dst[..], b"orld");
+
+    assert_eq!(io::ErrorKind::WouldBlock, q.read_buf(&mut dst).unwrap_err().kind());
+}
+
+#[test]
+fn read_uninit_spans_packets() {
+    let mut q = InQueue::new(Some(1));
+    let mut buf = [MaybeUninit::<u8>::uninit(); 8];
+
+    assert_eq!(io::ErrorKind::WouldBlock, q.read_uninit(&mut buf).unwrap_err().kind());
+
+    assert!(q.push(data(2, b"hello ")).is_ok());
+    assert!(q.push(data(3, b"world")).is_ok());
+    assert!(q.poll().is_none());
+
+    assert_eq!(8, q.read_uninit(&mut buf).unwrap());
+    let read: Vec<u8> = buf.iter().map(|b| unsafe { b.assume_init() }).collect();
+    assert_eq!(&read[..], b"hello wo");
+
+    // The rest of the partially read packet
+    assert_eq!(3, q.read_uninit(&mut buf).unwrap());
+    let read: Vec<u8> = buf[..3].iter().map(|b| unsafe { b.assume_init() }).collect();
+    assert_eq!(&read[..], b"rld");
+    assert!(!q.is_readable());
+}
+
+#[test]
+fn discard_drops_buffered_data() {
+    let mut q = InQueue::new(Some(1));
+
+    assert!(q.push(data(2, b"one")).is_ok());
+    assert!(q.push(data(4, b"three")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(8, q.bytes_buffered());
+
+    q.discard();
+
+    assert!(!q.is_readable());
+    assert_eq!(0, q.bytes_buffered());
+    assert_eq!(2, q.ack_nr());
+}
+
+#[test]
+fn reports_offset_and_gaps() {
+    let mut q = InQueue::new(Some(1));
+    assert_eq!(q.gaps(), (0, 0));
+
+    assert!(q.push(data(2, b"one")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.offset(), 3);
+    assert_eq!(q.gaps(), (0, 0));
+
+    // 3 and 4 are missing, 5-6 and 8 are held
+    assert!(q.push(data(5, b"four")).is_ok());
+    assert!(q.push(data(6, b"five")).is_ok());
+    assert!(q.push(data(8, b"seven")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.offset(), 3);
+    assert_eq!(q.gaps(), (2, 2));
+
+    // Reading does not move the offset
+    assert_eq!(read_all(&mut q), b"one");
+    assert_eq!(q.offset(), 3);
+
+    assert!(q.push(data(3, b"two")).is_ok());
+    assert!(q.push(data(4, b"three")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.offset(), 19);
+    assert_eq!(q.gaps(), (1, 1));
+}
+
+#[test]
+fn bounds_held_packets() {
+    let mut q = InQueue::new(Some(1));
+    q.set_max_held(2);
+
+    assert!(q.push(data(3, b"two")).is_ok());
+    assert!(q.push(data(4, b"three")).is_ok());
+
+    // The reorder buffer is full
+    assert_eq!(Err(DropReason::ReorderBufferFull), q.push(data(5, b"four")));
+
+    // The packet filling the gap is always accepted
+    assert!(q.push(data(2, b"one")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 4);
+
+    // Draining the buffer makes room again
+    assert!(q.push(data(6, b"five")).is_ok());
+    assert!(q.push(data(5, b"four")).is_ok());
+    assert!(q.poll().is_none());
+
+    assert_eq!(q.ack_nr(), 6);
+    assert_eq!(read_all(&mut q), b"onetwothreefourfive");
+}
+
+#[test]
+fn suppresses_duplicates() {
+    let mut q = InQueue::new(Some(1));
+
+    // Duplicate of a held packet
+    assert!(q.push(data(3, b"two")).is_ok());
+    assert_eq!(Err(DropReason::Duplicate), q.push(data(3, b"two")));
+
+    assert!(q.push(data(2, b"one")).is_ok());
+    assert!(q.poll().is_none());
+
+    // Duplicate of an already consumed packet
+    assert_eq!(Err(DropReason::Duplicate), q.push(data(2, b"one")));
+    assert_eq!(Err(DropReason::Duplicate), q.push(data(3, b"two")));
+    assert!(q.poll().is_none());
+
+    assert_eq!(q.ack_nr(), 3);
+    assert_eq!(read_all(&mut q), b"onetwo");
+
+    // The stale packets don't show up once the sequence space moves on
+    for seq_nr in 4..40 {
+        assert!(q.push(data(seq_nr, b"x")).is_ok());
+        assert!(q.poll().is_none());
+    }
+
+    assert_eq!(q.ack_nr(), 39);
+    assert_eq!(read_all(&mut q), vec![b'x'; 36]);
+}
+
+#[test]
+fn rejects_packets_beyond_window() {
+    let mut q = InQueue::new(Some(1));
+
+    assert_eq!(Err(DropReason::OutOfWindow), q.push(data(2 + 32, b"too far")));
+    assert!(q.push(data(2 + 31, b"ok")).is_ok());
+}
+
+#[test]
+fn wraps_sequence_numbers() {
+    let mut q = InQueue::new(Some(65_534));
+
+    assert!(q.push(data(0, b"two")).is_ok());
+    assert!(q.push(data(65_535, b"one")).is_ok());
+    assert!(q.poll().is_none());
+
+    assert_eq!(q.ack_nr(), 0);
+
+    assert!(q.push(data(1, b"three")).is_ok());
+    assert!(q.poll().is_none());
+
+    assert_eq!(q.ack_nr(), 1);
+    assert_eq!(read_all(&mut q), b"onetwothree");
+}
+
+#[test]
+fn window_accounting() {
+    let mut q = InQueue::new(Some(1));
+
+    assert_eq!(q.local_window(), 64 * 1024);
+
+    assert!(q.push(data(2, &[0; 1_000])).is_ok());
+    assert!(q.push(data(3, &[0; 500])).is_ok());
+    q.poll();
+
+    assert_eq!(q.bytes_pending(), 1_500);
+    assert_eq!(q.local_window(), 64 * 1024 - 1_500);
+
+    // Partially reading a packet frees up its space
+    let mut buf = [0; 400];
+    assert_eq!(400, q.read(&mut buf).unwrap());
+    assert_eq!(q.bytes_pending(), 1_100);
+    assert_eq!(q.local_window(), 64 * 1024 - 1_100);
+
+    read_all(&mut q);
+    assert_eq!(q.bytes_pending(), 0);
+    assert_eq!(q.local_window(), 64 * 1024);
+}
+
+#[test]
+fn drops_data_when_window_full() {
+    let mut q = InQueue::new(Some(1));
+
+    for i in 0..46 {
+        assert!(q.push(data(2 + i, &[0; 1_400])).is_ok());
+        q.poll();
+    }
+
+    assert_eq!(q.local_window(), 64 * 1024 - 46 * 1_400);
+
+    // The last packet is accepted even if it does not entirely fit
+    assert!(q.push(data(48, &[0; 1_400])).is_ok());
+    q.poll();
+
+    // The window is full
+    assert_eq!(q.local_window(), 0);
+    assert_eq!(Err(DropReason::WindowFull), q.push(data(49, b"hello")));
+}
+
+#[test]
+fn held_packets_count_against_window() {
+    let mut q = InQueue::new(Some(1));
+    q.set_capacity(4_000);
+
+    assert_eq!(q.local_window(), 4_000);
+
+    assert!(q.push(data(3, &[0; 1_500])).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.bytes_pending(), 0);
+    assert_eq!(q.bytes_buffered(), 1_500);
+    assert_eq!(q.local_window(), 2_500);
+
+    assert!(q.push(data(4, &[0; 2_500])).is_ok());
+    assert_eq!(q.local_window(), 0);
+
+    // Only the packet filling the gap fits
+    assert_eq!(Err(DropReason::WindowFull), q.push(data(5, b"hello")));
+    assert!(q.push(data(2, b"hello")).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 4);
+
+    read_all(&mut q);
+    assert_eq!(q.local_window(), 4_000);
+}
+
+#[test]
+fn fin_sequenced_after_data() {
+    let mut q = InQueue::new(Some(1));
+
+    // The FIN arrives before the data preceding it
+    assert!(q.push(fin(3)).is_ok());
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 1);
+
+    assert!(q.push(data(2, b"hello")).is_ok());
+
+    // The FIN is yielded once the data is queued
+    let p = q.poll().unwrap();
+    assert_eq!(p.ty(), packet::Type::Fin);
+    assert_eq!(q.ack_nr(), 3);
+    assert!(q.poll().is_none());
+
+    assert_eq!(read_all(&mut q), b"hello");
+}
+
+#[test]
+fn orders_packets_before_initial_ack() {
+    // When connecting, the initial seq_nr of the peer is not known until the
+    // STATE packet arrives.
+    let mut q = InQueue::new(None);
+
+    assert!(q.push(data(124, b"hello")).is_ok());
+    assert!(q.poll().is_none());
+    assert!(!q.is_readable());
+
+    q.set_initial_ack_nr(123);
+
+    assert!(q.poll().is_none());
+    assert_eq!(q.ack_nr(), 124);
+    assert_eq!(read_all(&mut q), b"hello");
+}
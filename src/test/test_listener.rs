@@ -1,5 +1,8 @@
 use super::prelude::*;
 
+use std::io;
+use DropReason;
+
 #[test]
 fn accept_stream() {
     const CONNECTION_ID: u16 = 25103;
@@ -41,6 +44,9 @@ fn accept_stream() {
     socket.wait_until(|| listener.is_readable());
     let stream = listener.accept().unwrap();
 
+    assert_eq!(server, stream.peer_addr().unwrap());
+    assert_eq!(124, stream.connection_id());
+
     socket.wait_until(|| stream.is_readable());
 
     // Read the data out of the stream buffer
@@ -51,6 +57,48 @@ fn accept_stream() {
     th.join().unwrap();
 }
 
+#[test]
+fn incoming_yields_each_connection() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        for &id in &[123, 456] {
+            let mut p = Packet::syn();
+            p.set_seq_nr(1);
+            p.set_connection_id(id);
+            m.send_to(p, &addr);
+
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::State);
+            assert_eq!(p.connection_id(), id);
+        }
+    });
+
+    let mut incoming = listener.incoming();
+
+    // No connection yet
+    match incoming.next() {
+        Some(Err(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+        _ => panic!("unexpected connection"),
+    }
+
+    socket.tick_for(200);
+    th.join().unwrap();
+
+    assert!(incoming.next().unwrap().is_ok());
+    assert!(incoming.next().unwrap().is_ok());
+
+    match incoming.next() {
+        Some(Err(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+        _ => panic!("unexpected connection"),
+    }
+}
+
 #[test]
 fn dropping_listener() {
     const CONNECTION_ID: u16 = 25103;
@@ -78,3 +126,212 @@ fn dropping_listener() {
 
     th.join().unwrap();
 }
+
+#[test]
+fn full_accept_backlog_resets() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_accept_backlog(1);
+
+    let (socket, listener) = Harness::with_config(config);
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(123);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.connection_id(), 123);
+
+        // The first connection is not accepted yet
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(456);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), 456);
+    });
+
+    socket.tick_for(200);
+    th.join().unwrap();
+
+    assert_eq!(1, socket.driver_stats().dropped(DropReason::AcceptBacklogFull));
+    assert_eq!(0, socket.driver_stats().dropped(DropReason::MaxConnections));
+
+    let stream = listener.accept().unwrap();
+    assert_eq!(124, stream.connection_id());
+    assert!(listener.accept().is_err());
+}
+
+#[test]
+fn max_connections_resets() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_max_connections(1);
+
+    let (socket, listener) = Harness::with_config(config);
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(123);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.connection_id(), 123);
+
+        // The backlog has room, but the socket has one connection
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(456);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), 456);
+    });
+
+    socket.tick_for(200);
+    th.join().unwrap();
+
+    assert_eq!(1, socket.driver_stats().dropped(DropReason::MaxConnections));
+    assert_eq!(0, socket.driver_stats().dropped(DropReason::AcceptBacklogFull));
+
+    let stream = listener.accept().unwrap();
+    assert_eq!(124, stream.connection_id());
+    assert!(listener.accept().is_err());
+}
+
+#[test]
+fn silent_drop_does_not_reset() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_silent_drop(true);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // The listener is closed
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(123);
+        m.send_to(p, &addr);
+
+        // No connection has this ID
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(456);
+        p.set_seq_nr(2);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(300);
+    });
+
+    socket.tick_for(400);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn accepts_libutp_syn() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_strict_extensions(true);
+    config.set_strict_validation(true);
+
+    let (socket, listener) = Harness::with_config(config);
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let mut p = Packet::syn();
+        p.set_extension_bits(&[0; 8]);
+        p.set_seq_nr(1);
+        p.set_connection_id(123);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 1);
+    });
+
+    socket.wait_until(|| listener.is_readable());
+    let stream = listener.accept();
+
+    th.join().unwrap();
+    assert!(stream.is_ok());
+}
+
+#[test]
+fn strict_extensions_drops_unknown() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_strict_extensions(true);
+
+    let (socket, listener) = Harness::with_config(config);
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let mut p = Packet::syn();
+        p.set_seq_nr(1);
+        p.set_connection_id(123);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        let ack_nr = p.seq_nr();
+
+        // Dropped, without being acked
+        let mut p = Packet::data(b"dropped");
+        p.add_extension(9, b"xyz");
+        p.set_connection_id(124);
+        p.set_seq_nr(2);
+        p.set_ack_nr(ack_nr);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(300);
+
+        let mut p = Packet::data(b"accepted");
+        p.set_connection_id(124);
+        p.set_seq_nr(2);
+        p.set_ack_nr(ack_nr);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 2);
+    });
+
+    socket.wait_until(|| listener.is_readable());
+    let stream = listener.accept().unwrap();
+
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 128];
+    assert_eq!(8, stream.read(&mut buf).unwrap());
+    assert_eq!(&buf[..8], b"accepted");
+
+    th.join().unwrap();
+}
@@ -0,0 +1,479 @@
+use super::prelude::*;
+use UtpStream;
+
+use std::{cmp, io, thread};
+use std::collections::{BTreeMap, HashSet};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn selective_ack_resends_missing_packet() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let mock = th.join().unwrap();
+
+    // Write 5 packets of data
+    stream.set_nodelay(true).unwrap();
+
+    for i in 0..5 {
+        let n = stream.write(&[i]).unwrap();
+        assert_eq!(n, 1);
+    }
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        for i in 0..5 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            assert_eq!(p.seq_nr(), 2 + i as u16);
+            assert_eq!(p.payload(), &[i]);
+        }
+
+        // ACK the first packet and selectively ACK the last three, seq_nr 3 is
+        // missing.
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        p.set_selective_ack(&[0b0000_0111, 0, 0, 0]);
+        m.send_to(p, &addr);
+
+        // Only the missing packet is sent again
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 3);
+        assert_eq!(p.payload(), &[1]);
+
+        m.assert_quiescence(200);
+
+        // ACK everything
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(6);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(200);
+    });
+
+    socket.tick_for(1_000);
+
+    th.join().unwrap();
+
+    drop(stream);
+}
+
+#[test]
+fn duplicate_packets_are_acked_again() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+    let mock = Mock::new();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Connect
+        let mut p = Packet::syn();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        let seq_nr = p.seq_nr();
+
+        // The STATE is lost and the SYN retransmitted
+        let mut p = Packet::syn();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from_ms(&addr, 100).unwrap();
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 1);
+
+        let data = || {
+            let mut p = Packet::data(b"hello");
+            p.set_connection_id(CONNECTION_ID + 1);
+            p.set_seq_nr(2);
+            p.set_ack_nr(seq_nr);
+            p
+        };
+
+        m.send_to(data(), &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 2);
+
+        // Same for data, the packet is acked again right away
+        m.send_to(data(), &addr);
+
+        let p = m.recv_from_ms(&addr, 100).unwrap();
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 2);
+    });
+
+    let stream = socket.wait(|| listener.accept()).unwrap();
+
+    let mut buf = [0; 16];
+    let n = socket.wait(|| stream.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+
+    socket.tick_for(500);
+    th.join().unwrap();
+
+    // The payload is only delivered once
+    assert_eq!(io::ErrorKind::WouldBlock, stream.read(&mut buf).unwrap_err().kind());
+}
+
+#[test]
+fn recovers_from_burst_of_10_lost_packets() {
+    // Packets sent after the burst are selectively acked
+    burst_loss(100, 10, 0);
+}
+
+#[test]
+fn recovers_from_burst_of_25_lost_packets() {
+    burst_loss(100, 25, 0);
+}
+
+#[test]
+fn recovers_from_burst_of_50_lost_packets() {
+    // The whole window is lost, only a timeout recovers
+    burst_loss(100, 50, 1);
+}
+
+/// Transfers data to a mock peer that drops the first transmission of `count`
+/// consecutive DATA packets, starting with the `first` one sent. The stream
+/// must arrive intact within a bounded time and number of timeouts.
+fn burst_loss(first: u16, count: u16, max_timeouts: u64) {
+    const CONNECTION_ID: u16 = 25103;
+    const LEN: usize = 256 * 1_024;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let expect = data.clone();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let t = Time::new();
+
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        let ts1 = p.timestamp();
+        let ts2 = t.timestamp();
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        p.set_timestamp(ts2);
+        p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+
+        m.send_to(p, &addr);
+
+        // DATA packets start at seq_nr 2
+        let lost = (2 + first)..(2 + first + count);
+        let mut dropped = HashSet::new();
+        let mut received = BTreeMap::new();
+        let mut ack_nr = 1;
+        let mut len = 0;
+
+        while len < LEN {
+            let p = m.recv_from(&addr);
+
+            if p.ty() != packet::Type::Data {
+                continue;
+            }
+
+            let seq_nr = p.seq_nr();
+            let ts1 = p.timestamp();
+            let ts2 = t.timestamp();
+
+            if seq_nr >= lost.start && seq_nr < lost.end && dropped.insert(seq_nr) {
+                continue;
+            }
+
+            if seq_nr > ack_nr && !received.contains_key(&seq_nr) {
+                received.insert(seq_nr, p.payload().to_vec());
+            }
+
+            // Deliver in-order data
+            while let Some(payload) = received.remove(&(ack_nr + 1)) {
+                assert_eq!(&expect[len..len + payload.len()], &payload[..]);
+
+                ack_nr += 1;
+                len += payload.len();
+            }
+
+            // ACK, selectively acking the packets received past the gap
+            let mut p = Packet::state();
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(123);
+            p.set_ack_nr(ack_nr);
+            p.set_timestamp(ts2);
+            p.set_timestamp_diff(ts2.wrapping_sub(ts1));
+
+            if let Some(&last) = received.keys().next_back() {
+                let bits = (last - ack_nr - 1) as usize;
+                let mut bitfield = vec![0; cmp::min(32, (bits / 32 + 1) * 4)];
+
+                for &seq_nr in received.keys() {
+                    let i = (seq_nr - ack_nr - 2) as usize;
+
+                    if i < bitfield.len() * 8 {
+                        bitfield[i / 8] |= 1 << (i % 8);
+                    }
+                }
+
+                p.set_selective_ack(&bitfield);
+            }
+
+            m.send_to(p, &addr);
+        }
+
+        assert_eq!(dropped.len(), count as usize);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let start = Instant::now();
+    let mut written = 0;
+
+    while written < LEN {
+        written += socket.wait(|| stream.write(&data[written..])).unwrap();
+    }
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(5), "elapsed={:?}", elapsed);
+
+    th.join().unwrap();
+
+    // The lost packets were recovered by resending them
+    let stats = stream.stats();
+    assert!(stats.packets_resent() >= count as u64, "stats={:?}", stats);
+    assert!(stats.timeouts() <= max_timeouts, "stats={:?}", stats);
+}
+
+#[test]
+fn bidirectional_transfer_with_one_way_loss() {
+    // The client sends more than the server, and its packets are lost
+    const CLIENT_LEN: usize = 512 * 1_024;
+    const SERVER_LEN: usize = 96 * 1_024;
+
+    let _ = ::env_logger::init();
+
+    let client_data: Vec<u8> = (0..CLIENT_LEN).map(|i| (i % 251) as u8).collect();
+    let server_data: Vec<u8> = (0..SERVER_LEN).map(|i| (i % 241) as u8).collect();
+
+    let (addr_tx, addr_rx) = mpsc::channel();
+
+    let server = {
+        let (client_data, server_data) = (client_data.clone(), server_data.clone());
+
+        thread::spawn(move || {
+            let (socket, listener) = Harness::new();
+            addr_tx.send(socket.local_addr()).unwrap();
+
+            socket.wait_until(|| listener.is_readable());
+            let stream = listener.accept().unwrap();
+
+            let received = exchange(&socket, &stream, &server_data, CLIENT_LEN);
+            assert!(received == client_data, "client data corrupted");
+
+            stream.stats()
+        })
+    };
+
+    // Sockets do not send selective ACKs, each loss is recovered by a timeout.
+    // Keep them few enough for the test to complete quickly.
+    let relay = Relay::new(addr_rx.recv().unwrap(), 50);
+    let relay_addr = relay.local_addr();
+
+    let client = thread::spawn(move || {
+        let (socket, _) = Harness::new();
+        let stream = socket.connect(relay_addr);
+
+        socket.wait_until(|| stream.is_writable());
+
+        let received = exchange(&socket, &stream, &client_data, SERVER_LEN);
+        assert!(received == server_data, "server data corrupted");
+
+        stream.stats()
+    });
+
+    let start = Instant::now();
+    let client_stats = client.join().unwrap();
+    let server_stats = server.join().unwrap();
+    let dropped = relay.stop();
+
+    assert!(start.elapsed() < Duration::from_secs(10), "elapsed={:?}", start.elapsed());
+
+    // The client recovered the lost packets
+    assert!(dropped > 0);
+    assert!(client_stats.packets_resent() > 0, "stats={:?}", client_stats);
+
+    // The server's ACKs kept flowing while the client's data was lost, and
+    // its own data was acked without waiting for timeouts.
+    assert_eq!(0, server_stats.timeouts(), "stats={:?}", server_stats);
+}
+
+/// Writes `data` to the stream while reading `len` bytes from it, then waits
+/// for the peer to ack everything. Returns the bytes read.
+fn exchange(socket: &Harness, stream: &UtpStream, data: &[u8], len: usize) -> Vec<u8> {
+    let mut written = 0;
+    let mut received = vec![];
+    let mut buf = [0; 4_096];
+
+    socket.wait(|| {
+        while written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        while received.len() < len {
+            match stream.read(&mut buf) {
+                Ok(0) => panic!("unexpected EOF"),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if written == data.len() && received.len() == len {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }).unwrap();
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+
+    received
+}
+
+/// Forwards packets between a client and a server. Every `nth` DATA packet
+/// from the client is dropped, the server's packets are all forwarded.
+struct Relay {
+    addr: SocketAddr,
+    done: Arc<AtomicBool>,
+    thread: thread::JoinHandle<usize>,
+}
+
+impl Relay {
+    fn new(server: SocketAddr, nth: usize) -> Relay {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        let addr = socket.local_addr().unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let done = done.clone();
+
+            thread::spawn(move || {
+                let mut client = None;
+                let mut data_packets = 0;
+                let mut dropped = 0;
+                let mut buf = [0; 2_048];
+
+                while !done.load(Ordering::SeqCst) {
+                    let (n, src) = match socket.recv_from(&mut buf) {
+                        Ok(v) => v,
+                        Err(_) => continue,
+                    };
+
+                    if src == server {
+                        if let Some(client) = client {
+                            socket.send_to(&buf[..n], client).unwrap();
+                        }
+
+                        continue;
+                    }
+
+                    client = Some(src);
+
+                    // DATA packets have a type of zero
+                    if buf[0] >> 4 == 0 {
+                        data_packets += 1;
+
+                        if data_packets % nth == 0 {
+                            dropped += 1;
+                            continue;
+                        }
+                    }
+
+                    socket.send_to(&buf[..n], server).unwrap();
+                }
+
+                dropped
+            })
+        };
+
+        Relay {
+            addr,
+            done,
+            thread,
+        }
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the relay, returning the number of packets dropped
+    fn stop(self) -> usize {
+        self.done.store(true, Ordering::SeqCst);
+        self.thread.join().unwrap()
+    }
+}
@@ -0,0 +1,1046 @@
+use super::prelude::*;
+use congestion::{Ack, CongestionControl, Ledbat};
+use out_queue::OutQueue;
+use timestamp::TimestampSource;
+use tuning::MAX_PACKET_SIZE;
+
+use bytes::{Buf, Bytes};
+
+use std::io::{self, IoSlice};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const CONNECTION_ID: u16 = 25103;
+
+/// Congestion control with a window that is set by the test. The window is
+/// halved on loss and reset to a single packet on timeout.
+#[derive(Debug)]
+struct Window(Rc<Cell<usize>>);
+
+impl CongestionControl for Window {
+    fn on_ack(&mut self, _: &Ack) {
+    }
+
+    fn on_loss(&mut self) {
+        self.0.set(self.0.get() / 2);
+    }
+
+    fn on_timeout(&mut self) {
+        self.0.set(1_400);
+    }
+
+    fn cwnd(&self) -> usize {
+        self.0.get()
+    }
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+/// Returns an out queue for a connected socket along with a handle to set the
+/// max window. The initial window is a single packet and Nagle is disabled.
+fn connected(seq_nr: u16, now: Instant) -> (OutQueue, Rc<Cell<usize>>) {
+    let window = Rc::new(Cell::new(1_400));
+    let congestion = Box::new(Window(window.clone()));
+
+    let mut q = OutQueue::new(CONNECTION_ID, seq_nr, Some(123), congestion, now);
+    q.set_peer_window(64 * 1024);
+
+    // Each write is its own packet
+    q.set_nodelay(true);
+
+    (q, window)
+}
+
+/// Sends all packets that the queue is willing to send, returning them
+fn flush(q: &mut OutQueue, now: Instant) -> Vec<Packet> {
+    let mut ret = vec![];
+
+    while let Some(next) = q.next(now) {
+        ret.push(next.packet().clone());
+        next.sent();
+    }
+
+    ret
+}
+
+/// Returns a state packet including the selective ACK bitfield
+fn selective_ack(bitfield: &[u8]) -> Packet {
+    let mut p = Packet::state();
+    p.set_selective_ack(bitfield);
+    p
+}
+
+#[test]
+fn write_splits_at_window_boundary() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+
+    let buf = vec![0; 4_000];
+
+    // The initial window is a single packet
+    assert_eq!(1_380, q.write(&buf).unwrap());
+    assert_eq!(1, q.len());
+    assert!(!q.is_writable());
+    assert_eq!(io::ErrorKind::WouldBlock, q.write(&buf).unwrap_err().kind());
+
+    // Growing the window allows more data, split in max sized packets with
+    // the remainder in a smaller one.
+    window.set(5_000);
+
+    assert_eq!(3_540, q.write(&buf).unwrap());
+    assert_eq!(4, q.len());
+
+    let packets = flush(&mut q, now);
+    let lens: Vec<_> = packets.iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lens, [1_380, 1_380, 1_380, 780]);
+
+    // The packets are sequenced
+    let seq_nrs: Vec<_> = packets.iter().map(|p| p.seq_nr()).collect();
+    assert_eq!(seq_nrs, [2, 3, 4, 5]);
+}
+
+#[test]
+fn write_bytes_shares_payloads() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    let buf = Bytes::from((0..4_000).map(|i| i as u8).collect::<Vec<_>>());
+    assert_eq!(4_000, q.write_bytes(&buf).unwrap());
+
+    // Each packet references its own segment of the written buffer
+    let packets = flush(&mut q, now);
+    let mut offset = 0;
+
+    for p in &packets {
+        assert_eq!(buf[offset..].as_ptr(), p.payload().as_ptr());
+        offset += p.payload().len();
+    }
+
+    assert_eq!(3, packets.len());
+    assert_eq!(4_000, offset);
+}
+
+#[test]
+fn write_vectored_spans_buffers() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+
+    let header = [1; 8];
+    let body = vec![2; 2_000];
+    let bufs = [IoSlice::new(&header), IoSlice::new(&[]), IoSlice::new(&body)];
+
+    // The window is a single packet, the write stops within the body
+    assert_eq!(1_380, q.write_vectored(&bufs).unwrap());
+    assert_eq!(1, q.len());
+    assert_eq!(io::ErrorKind::WouldBlock, q.write_vectored(&bufs).unwrap_err().kind());
+
+    window.set(64 * 1024);
+    assert_eq!(628, q.write_vectored(&[IoSlice::new(&body[1_372..])]).unwrap());
+
+    // Packets are filled across buffers, even though Nagle is disabled
+    let packets = flush(&mut q, now);
+    let lens: Vec<_> = packets.iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lens, [1_380, 628]);
+    assert_eq!(&packets[0].payload()[..9], &[1, 1, 1, 1, 1, 1, 1, 1, 2]);
+}
+
+#[test]
+fn write_buf_advances_source() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+
+    let header = io::Cursor::new(vec![1; 8]);
+    let body = io::Cursor::new(vec![2; 2_000]);
+    let mut src = header.chain(body);
+
+    // The window is a single packet
+    assert_eq!(1_380, q.write_buf(&mut src).unwrap());
+    assert_eq!(628, src.remaining());
+
+    window.set(64 * 1024);
+    assert_eq!(628, q.write_buf(&mut src).unwrap());
+    assert!(!src.has_remaining());
+
+    // Chunks share packets
+    let packets = flush(&mut q, now);
+    let lens: Vec<_> = packets.iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lens, [1_380, 628]);
+    assert_eq!(&packets[0].payload()[..9], &[1, 1, 1, 1, 1, 1, 1, 1, 2]);
+}
+
+#[test]
+fn chatty_writes_are_coalesced() {
+    const RTT_MS: u64 = 100;
+
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_nodelay(false);
+
+    let mut in_flight = VecDeque::new();
+    let mut written = 0;
+    let mut packets = 0;
+    let mut payload = 0;
+
+    // 200 writes of 10 to 100 bytes per second, for two seconds, with the
+    // peer acking each packet a round trip after it was sent.
+    for i in 0..400 {
+        let now = now + ms(5 * i);
+
+        let mut acked = None;
+
+        while in_flight.front().map(|&(_, at)| at + ms(RTT_MS) <= now).unwrap_or(false) {
+            acked = in_flight.pop_front().map(|(seq_nr, _)| seq_nr);
+        }
+
+        if let Some(seq_nr) = acked {
+            q.set_their_ack(seq_nr, None, now);
+        }
+
+        let len = 10 + (i as usize * 37) % 91;
+        assert_eq!(len, q.write(&vec![0; len]).unwrap());
+        written += len;
+
+        for p in flush(&mut q, now) {
+            packets += 1;
+            payload += p.payload().len();
+            in_flight.push_back((p.seq_nr(), now));
+        }
+    }
+
+    q.push_pending();
+
+    for p in flush(&mut q, now + ms(2_000)) {
+        packets += 1;
+        payload += p.payload().len();
+    }
+
+    assert_eq!(written, payload);
+
+    // While a packet is in flight, writes are coalesced into the next one:
+    // about one packet per round trip instead of one per write.
+    assert!(packets <= 2_000 / RTT_MS as usize + 5, "packets={}", packets);
+}
+
+#[test]
+fn write_uses_configured_packet_size() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    // Jumbo frames
+    q.set_max_packet_size(9_000);
+    assert_eq!(20_000, q.write(&vec![0; 20_000]).unwrap());
+
+    let lens: Vec<_> = flush(&mut q, now).iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lens, [8_980, 8_980, 2_040]);
+
+    // Constrained tunnel
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    q.set_max_packet_size(520);
+    assert_eq!(1_200, q.write(&vec![0; 1_200]).unwrap());
+
+    let lens: Vec<_> = flush(&mut q, now).iter().map(|p| p.payload().len()).collect();
+    assert_eq!(lens, [500, 500, 200]);
+}
+
+#[test]
+fn write_waits_for_min_packet_size() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(1_500);
+
+    assert_eq!(1_380, q.write(&vec![0; 4_000]).unwrap());
+
+    // The 100 bytes left in the window are below the min packet size
+    assert!(!q.is_writable());
+    assert_eq!(io::ErrorKind::WouldBlock, q.write(&vec![0; 4_000]).unwrap_err().kind());
+
+    q.set_min_packet_size(50);
+    assert_eq!(80, q.write(&vec![0; 4_000]).unwrap());
+}
+
+#[test]
+fn write_waits_for_max_packets_in_flight() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    q.set_max_packets_in_flight(4);
+
+    for _ in 0..4 {
+        assert_eq!(1, q.write(b"a").unwrap());
+    }
+
+    // The window has plenty of room, but the packet limit is reached
+    assert!(!q.is_writable());
+    assert_eq!(io::ErrorKind::WouldBlock, q.write(b"a").unwrap_err().kind());
+    assert_eq!(4, flush(&mut q, now).len());
+
+    // Acking a packet frees a slot
+    q.set_their_ack(2, None, now + ms(10));
+    assert!(q.is_writable());
+    assert_eq!(1, q.write(b"a").unwrap());
+    assert!(!q.is_writable());
+
+    // Large writes stop at the limit as well
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    q.set_max_packets_in_flight(2);
+    assert_eq!(2 * 1_380, q.write(&vec![0; 10_000]).unwrap());
+}
+
+#[test]
+fn next_respects_window() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(5_000);
+
+    q.write(&vec![0; 4_000]).unwrap();
+
+    // Shrinking the window limits the number of packets sent, but the first
+    // packet is always sent.
+    window.set(10);
+
+    assert_eq!(1, flush(&mut q, now).len());
+    assert_eq!(0, flush(&mut q, now).len());
+
+    // Acking the packet allows the next one
+    q.set_their_ack(2, None, now + ms(10));
+    assert_eq!(1, flush(&mut q, now + ms(10)).len());
+}
+
+#[test]
+fn ack_pops_packets_across_wrap() {
+    let now = Instant::now();
+    let (mut q, window) = connected(65_533, now);
+    window.set(64 * 1024);
+
+    for _ in 0..4 {
+        q.write(b"hello").unwrap();
+    }
+
+    let seq_nrs: Vec<_> = flush(&mut q, now).iter().map(|p| p.seq_nr()).collect();
+    assert_eq!(seq_nrs, [65_534, 65_535, 0, 1]);
+
+    // A stale ack does nothing
+    assert!(q.set_their_ack(65_533, None, now + ms(10)).is_none());
+    assert_eq!(4, q.len());
+
+    // Ack across the wrap
+    let (acked, _) = q.set_their_ack(0, None, now + ms(10)).unwrap();
+    assert_eq!(acked, 15);
+    assert_eq!(1, q.len());
+
+    let (acked, _) = q.set_their_ack(1, None, now + ms(10)).unwrap();
+    assert_eq!(acked, 5);
+    assert_eq!(0, q.len());
+}
+
+#[test]
+fn ack_must_be_for_sent_packet() {
+    let now = Instant::now();
+    let (mut q, window) = connected(65_533, now);
+    window.set(5_000);
+
+    // Nothing sent yet, only ACKs of the last packet acked pass
+    assert!(q.is_valid_ack(65_533));
+    assert!(!q.is_valid_ack(65_534));
+
+    q.write(&vec![0; 4_000]).unwrap();
+
+    // Only the first packet is sent
+    window.set(10);
+    assert_eq!(1, flush(&mut q, now).len());
+
+    assert!(q.is_valid_ack(65_534));
+    assert!(!q.is_valid_ack(65_535));
+    assert!(!q.is_valid_ack(0));
+
+    q.set_their_ack(65_534, None, now + ms(10));
+    assert_eq!(1, flush(&mut q, now + ms(10)).len());
+
+    assert!(q.is_valid_ack(65_535));
+    assert!(!q.is_valid_ack(0));
+
+    // ACKs may lag a few packets behind the last one, such as keep-alives
+    assert!(q.is_valid_ack(65_534 - 3));
+    assert!(!q.is_valid_ack(65_534 - 4));
+    assert!(!q.is_valid_ack(32_000));
+}
+
+#[test]
+fn rtt_update() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    q.write(b"one").unwrap();
+    q.write(b"two").unwrap();
+    flush(&mut q, now);
+
+    let (_, min_rtt) = q.set_their_ack(2, None, now + ms(800)).unwrap();
+    assert_eq!(min_rtt, ms(800));
+
+    // rtt += (800 - 0) / 8, rtt_var += (800 - 0) / 4
+    assert_eq!(q.rtt(), 100);
+    assert_eq!(q.rtt_variance(), 200);
+    assert_eq!(q.socket_timeout(), Some(ms(500)));
+
+    let (_, min_rtt) = q.set_their_ack(3, None, now + ms(4_000)).unwrap();
+    assert_eq!(min_rtt, ms(4_000));
+
+    // rtt += (4000 - 100) / 8, rtt_var += (3900 - 200) / 4
+    assert_eq!(q.rtt(), 587);
+    assert_eq!(q.rtt_variance(), 1_125);
+
+    // Retransmitted packets are not used to update the rtt
+    q.write(b"three").unwrap();
+    flush(&mut q, now + ms(4_000));
+    assert_eq!(q.socket_timeout(), Some(ms(1_712)));
+
+    q.timed_out();
+    flush(&mut q, now + ms(5_000));
+
+    q.set_their_ack(4, None, now + ms(5_100)).unwrap();
+    assert_eq!(q.rtt(), 587);
+    assert_eq!(q.rtt_variance(), 1_125);
+}
+
+#[test]
+fn configured_timeouts() {
+    let now = Instant::now();
+    let mut q = OutQueue::new(CONNECTION_ID, 1, None, Box::new(Ledbat::new()), now);
+    q.set_initial_timeout(ms(3_000));
+    q.set_min_timeout(ms(200));
+
+    q.push(Packet::syn());
+    flush(&mut q, now);
+
+    // Nothing was received from the peer yet
+    assert_eq!(q.socket_timeout(), Some(ms(3_000)));
+
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_min_timeout(ms(200));
+
+    q.write(b"one").unwrap();
+    q.write(b"two").unwrap();
+    flush(&mut q, now);
+    q.set_their_ack(2, None, now + ms(80)).unwrap();
+
+    // rtt = 10, rtt_var = 20, below the floor
+    assert_eq!(q.socket_timeout(), Some(ms(200)));
+}
+
+#[test]
+fn write_waits_for_max_window_size() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_max_window_size(2_000);
+
+    // Two packets fill the limit
+    assert_eq!(1_960, q.write(&vec![0; 4_000]).unwrap());
+    assert!(!q.is_writable());
+}
+
+#[test]
+fn state_packet_generation() {
+    let now = Instant::now();
+
+    // No state packet until a packet has been received from the peer
+    let mut q = OutQueue::new(CONNECTION_ID, 1, None, Box::new(Ledbat::new()), now);
+    assert!(q.next(now).is_none());
+
+    // The first ack is implied by the connection handshake
+    q.set_local_ack(123);
+    assert!(q.next(now).is_none());
+    assert!(q.is_empty());
+
+    // Receiving a packet requires a state packet to be sent
+    q.set_local_ack(124);
+    assert!(!q.is_empty());
+
+    let p = flush(&mut q, now);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::State);
+    assert_eq!(p[0].connection_id(), CONNECTION_ID);
+    assert_eq!(p[0].seq_nr(), 1);
+    assert_eq!(p[0].ack_nr(), 124);
+
+    // Once sent, no further state packets are generated
+    assert!(q.next(now).is_none());
+    assert!(q.is_empty());
+
+    // Data packets carry the ack, no state packet is needed
+    q.set_local_ack(125);
+    q.write(b"hello").unwrap();
+
+    let p = flush(&mut q, now);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::Data);
+    assert_eq!(p[0].ack_nr(), 125);
+}
+
+#[test]
+fn timestamp_uses_provided_clock() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+
+    q.write(b"hello").unwrap();
+
+    let p = flush(&mut q, now + ms(1_500));
+    assert_eq!(p[0].timestamp(), 1_500_000);
+
+    // Their delay is relative to the same clock
+    assert_eq!(q.update_their_delay(1_000, now + ms(2_000)), 1_999_000);
+}
+
+#[test]
+fn congestion_control_notified_of_loss_and_timeout() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    for i in 0..5 {
+        q.write(b"hello").unwrap();
+        assert_eq!(1, flush(&mut q, now + ms(i)).len());
+    }
+
+    // Not enough packets were selectively acked to consider seq_nr 3 lost
+    let sack = selective_ack(&[0b0000_0011, 0, 0, 0]);
+    q.set_their_ack(2, sack.selective_ack(), now + ms(10));
+    assert_eq!(window.get(), 64 * 1024);
+    assert_eq!(0, flush(&mut q, now + ms(10)).len());
+
+    // seq_nr 3 is lost
+    let sack = selective_ack(&[0b0000_0111, 0, 0, 0]);
+    q.set_their_ack(2, sack.selective_ack(), now + ms(10));
+    assert_eq!(window.get(), 32 * 1024);
+
+    let p = flush(&mut q, now + ms(10));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].seq_nr(), 3);
+
+    q.timed_out();
+    assert_eq!(window.get(), 1_400);
+}
+
+#[test]
+fn resent_packet_is_not_lost_again() {
+    let now = Instant::now();
+    let (mut q, window) = connected(65_533, now);
+    window.set(64 * 1024);
+
+    // seq_nr 65_534 through 2
+    for i in 0..5 {
+        q.write(b"hello").unwrap();
+        assert_eq!(1, flush(&mut q, now + ms(i)).len());
+    }
+
+    // The bitfield starts past the wrap, seq_nr 65_535 is lost
+    let sack = selective_ack(&[0b0000_0111, 0, 0, 0]);
+    q.set_their_ack(65_534, sack.selective_ack(), now + ms(10));
+    assert_eq!(window.get(), 32 * 1024);
+
+    let p = flush(&mut q, now + ms(10));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].seq_nr(), 65_535);
+
+    // The packets acked before were sent before the retransmission
+    q.set_their_ack(65_534, sack.selective_ack(), now + ms(20));
+    assert_eq!(window.get(), 32 * 1024);
+    assert_eq!(0, flush(&mut q, now + ms(20)).len());
+    assert_eq!(5 + 20, q.in_flight());
+}
+
+#[test]
+fn in_flight_counts_bytes() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+
+    q.write(b"hello").unwrap();
+    assert_eq!(0, q.in_flight());
+
+    // Packets of different sizes, seq_nr 2 through 6
+    assert_eq!(1, flush(&mut q, now).len());
+
+    for i in 2..6 {
+        q.write(&vec![0; i * 100]).unwrap();
+        assert_eq!(1, flush(&mut q, now + ms(i as u64)).len());
+    }
+
+    assert_eq!(1_405 + 5 * 20, q.in_flight());
+
+    q.set_their_ack(2, None, now + ms(10));
+    assert_eq!(1_400 + 4 * 20, q.in_flight());
+
+    // Selectively acked packets leave, as does seq_nr 3 once lost
+    let sack = selective_ack(&[0b0000_0111, 0, 0, 0]);
+    q.set_their_ack(2, sack.selective_ack(), now + ms(20));
+    assert_eq!(0, q.in_flight());
+
+    let p = flush(&mut q, now + ms(20));
+    assert_eq!(1, p.len());
+    assert_eq!(200 + 20, q.in_flight());
+
+    // Nothing is in-flight after a timeout, until it is resent
+    q.timed_out();
+    assert_eq!(0, q.in_flight());
+
+    assert_eq!(1, flush(&mut q, now + ms(30)).len());
+    assert_eq!(200 + 20, q.in_flight());
+
+    q.set_their_ack(6, None, now + ms(40));
+    assert_eq!(0, q.in_flight());
+}
+
+#[test]
+fn timeout_backs_off_until_acked() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+
+    q.write(b"hello").unwrap();
+    assert_eq!(1, flush(&mut q, now).len());
+    assert_eq!(Some(ms(500)), q.socket_timeout());
+
+    // The timeout doubles each time it expires
+    for i in 1..4 {
+        q.timed_out();
+        assert_eq!(1, flush(&mut q, now).len());
+        assert_eq!(i, q.consecutive_timeouts());
+        assert_eq!(Some(ms(500 << i)), q.socket_timeout());
+    }
+
+    // Up to a limit
+    for _ in 0..10 {
+        q.timed_out();
+        assert_eq!(1, flush(&mut q, now).len());
+    }
+
+    assert_eq!(Some(ms(60_000)), q.socket_timeout());
+
+    // An ACK for a retransmitted packet resets the count, but the backoff is
+    // kept as the ACK gives no RTT sample
+    q.write(b"world").unwrap();
+    q.set_their_ack(2, None, now + ms(10));
+    assert_eq!(0, q.consecutive_timeouts());
+    assert_eq!(Some(ms(60_000)), q.socket_timeout());
+
+    // Until a packet sent once is acked
+    assert_eq!(1, flush(&mut q, now + ms(10)).len());
+    q.set_their_ack(3, None, now + ms(20));
+    q.write(b"!").unwrap();
+    assert_eq!(Some(ms(500)), q.socket_timeout());
+}
+
+#[test]
+fn pacing_spreads_window_over_rtt() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(14_000);
+    q.set_pacing(true);
+
+    // Establish a 100ms round trip time
+    q.write(b"one").unwrap();
+    flush(&mut q, now);
+    q.set_their_ack(2, None, now + ms(800)).unwrap();
+    assert_eq!(q.rtt(), 100);
+
+    let now = now + ms(800);
+
+    q.write(&[0; 5 * 1_380]).unwrap();
+
+    // A full packet takes a tenth of the window, packets are sent every 10ms
+    assert_eq!(1, flush(&mut q, now).len());
+    assert_eq!(0, flush(&mut q, now + ms(9)).len());
+    assert_eq!(Some(now + ms(10)), q.next_send_at());
+
+    // ACKs are not held back by pacing
+    q.set_max_ack_delay(ms(0));
+    q.set_local_ack(124);
+    let p = flush(&mut q, now + ms(9));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::State);
+
+    assert_eq!(1, flush(&mut q, now + ms(10)).len());
+    assert_eq!(1, flush(&mut q, now + ms(20)).len());
+
+    // Without pacing, the rest of the window is sent at once
+    q.set_pacing(false);
+    assert_eq!(None, q.next_send_at());
+    assert_eq!(2, flush(&mut q, now + ms(20)).len());
+}
+
+#[test]
+fn mtu_probe_raises_packet_size() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_mtu_probing(true);
+
+    // The first full packet probes halfway between 1400 and 1472 bytes
+    q.write(&[0; 4_000]).unwrap();
+
+    let lens: Vec<_> = flush(&mut q, now).iter().map(|p| p.encoded_len()).collect();
+    assert_eq!(lens, [1_436, 1_400, 1_224]);
+
+    // Acking the probe raises the regular packet size
+    q.set_their_ack(2, None, now + ms(10)).unwrap();
+    assert_eq!(q.max_data_size(), 1_416);
+
+    // The next probe continues the search
+    q.write(&[0; 4_000]).unwrap();
+
+    let lens: Vec<_> = flush(&mut q, now + ms(10)).iter().map(|p| p.encoded_len()).collect();
+    assert_eq!(lens, [1_454, 1_436, 1_170]);
+}
+
+#[test]
+fn mtu_probe_loss_is_not_congestion() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_mtu_probing(true);
+
+    q.write(&[0; 1_416]).unwrap();
+    assert_eq!(1_436, flush(&mut q, now)[0].encoded_len());
+
+    for i in 1..4 {
+        q.write(b"hello").unwrap();
+        assert_eq!(1, flush(&mut q, now + ms(i)).len());
+    }
+
+    // The probe is lost, the window is left alone
+    let sack = selective_ack(&[0b0000_0111, 0, 0, 0]);
+    q.set_their_ack(1, sack.selective_ack(), now + ms(10));
+    assert_eq!(window.get(), 64 * 1024);
+    assert_eq!(q.max_data_size(), 1_380);
+
+    // The probe is retransmitted as is, and may be fragmented
+    assert!(!q.next(now + ms(10)).unwrap().is_mtu_probe());
+
+    let p = flush(&mut q, now + ms(10));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].seq_nr(), 2);
+    assert_eq!(p[0].encoded_len(), 1_436);
+
+    // The next probe searches below the lost one
+    q.set_their_ack(5, None, now + ms(20));
+    q.write(&[0; 4_000]).unwrap();
+    assert_eq!(1_418, flush(&mut q, now + ms(20))[0].encoded_len());
+}
+
+#[test]
+fn mtu_probe_refused_is_split() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(64 * 1024);
+    q.set_mtu_probing(true);
+
+    q.write(&[0; 4_000]).unwrap();
+
+    // The OS refuses to send the probe without fragmenting it
+    {
+        let next = q.next(now).unwrap();
+        assert!(next.is_mtu_probe());
+        assert_eq!(next.packet().encoded_len(), 1_436);
+    }
+
+    q.mtu_probe_refused();
+
+    // The probe's payload is split at the regular packet size and the packets
+    // queued after it are renumbered
+    let p = flush(&mut q, now);
+    let lens: Vec<_> = p.iter().map(|p| p.encoded_len()).collect();
+    let seq_nrs: Vec<_> = p.iter().map(|p| p.seq_nr()).collect();
+    assert_eq!(lens, [1_400, 56, 1_400, 1_224]);
+    assert_eq!(seq_nrs, [2, 3, 4, 5]);
+
+    // The next probe searches below the refused one
+    q.set_their_ack(5, None, now + ms(10));
+    q.write(&[0; 4_000]).unwrap();
+
+    let next = q.next(now + ms(10)).unwrap();
+    assert!(next.is_mtu_probe());
+    assert_eq!(next.packet().seq_nr(), 6);
+    assert_eq!(next.packet().encoded_len(), 1_418);
+}
+
+#[test]
+fn mtu_drops_after_repeated_timeouts() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+    q.set_mtu_probing(true);
+
+    q.write(b"hello").unwrap();
+    flush(&mut q, now);
+
+    // A single timeout is not enough
+    q.timed_out();
+    assert_eq!(q.max_data_size(), 1_380);
+
+    flush(&mut q, now + ms(500));
+
+    q.timed_out();
+    assert_eq!(q.max_data_size(), 528);
+}
+
+#[test]
+fn ack_delay_scales_with_rtt() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+    q.set_local_ack(123);
+
+    // Without a round trip time, ACKs are sent right away
+    q.set_local_ack(124);
+    assert_eq!(packet::Type::State, flush(&mut q, now)[0].ty());
+
+    // Establish a 100ms round trip time
+    q.write(b"one").unwrap();
+    flush(&mut q, now);
+    q.set_their_ack(2, None, now + ms(800)).unwrap();
+    assert_eq!(q.rtt(), 100);
+
+    let now = now + ms(800);
+
+    // The ACK is delayed by a quarter of the round trip time
+    q.set_local_ack(125);
+    assert_eq!(0, flush(&mut q, now).len());
+    assert_eq!(Some(now + ms(25)), q.ack_due_at());
+    assert_eq!(0, flush(&mut q, now + ms(24)).len());
+
+    let p = flush(&mut q, now + ms(25));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::State);
+    assert_eq!(p[0].ack_nr(), 125);
+    assert_eq!(None, q.ack_due_at());
+
+    // Outbound data carries the ACK instead
+    q.set_local_ack(126);
+    assert_eq!(0, flush(&mut q, now + ms(30)).len());
+    q.write(b"two").unwrap();
+
+    let p = flush(&mut q, now + ms(31));
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::Data);
+    assert_eq!(p[0].ack_nr(), 126);
+    assert_eq!(None, q.ack_due_at());
+
+    // The delay is capped
+    q.set_max_ack_delay(ms(10));
+    q.set_local_ack(127);
+    assert_eq!(0, flush(&mut q, now + ms(40)).len());
+    assert_eq!(1, flush(&mut q, now + ms(50)).len());
+}
+
+#[test]
+fn ack_every_second_packet() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+    q.set_local_ack(123);
+    flush(&mut q, now);
+
+    // Establish a 100ms round trip time
+    q.write(b"one").unwrap();
+    flush(&mut q, now);
+    q.set_their_ack(2, None, now + ms(800)).unwrap();
+
+    let now = now + ms(800);
+
+    // A single packet is acked after the delay
+    q.set_local_ack(124);
+    assert_eq!(0, flush(&mut q, now).len());
+
+    // The second one is acked right away
+    q.set_local_ack(125);
+    let p = flush(&mut q, now);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::State);
+    assert_eq!(p[0].ack_nr(), 125);
+    assert_eq!(None, q.ack_due_at());
+
+    // Without delayed ACKs, every packet is acked right away
+    q.set_delayed_ack(false);
+    q.set_local_ack(126);
+    let p = flush(&mut q, now);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ack_nr(), 126);
+}
+
+#[test]
+fn peer_window_shrinks_below_in_flight() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(8_400);
+
+    assert_eq!(6 * 1_380, q.write(&vec![0; 6 * 1_380]).unwrap());
+
+    window.set(5_600);
+    assert_eq!(4, flush(&mut q, now).len());
+    assert!(!q.is_peer_window_limited());
+
+    // The peer shrinks its window below the data in-flight
+    q.set_peer_window(1_000);
+    assert!(!q.is_writable());
+    assert_eq!(io::ErrorKind::WouldBlock, q.write(b"hello").unwrap_err().kind());
+
+    // Acks drain the in-flight data, but nothing new is sent. This is not
+    // treated as loss.
+    q.set_their_ack(3, None, now + ms(10)).unwrap();
+    assert_eq!(0, flush(&mut q, now + ms(10)).len());
+    assert!(q.is_peer_window_limited());
+    assert_eq!(window.get(), 5_600);
+
+    q.set_their_ack(5, None, now + ms(20)).unwrap();
+    assert_eq!(0, flush(&mut q, now + ms(20)).len());
+    assert!(q.is_peer_window_limited());
+
+    // Sending resumes once the window recovers
+    q.set_peer_window(64 * 1024);
+    assert_eq!(2, flush(&mut q, now + ms(30)).len());
+    assert!(!q.is_peer_window_limited());
+    assert!(q.is_writable());
+}
+
+#[test]
+fn window_decays_while_idle() {
+    let now = Instant::now();
+
+    let mut q = OutQueue::new(CONNECTION_ID, 1, Some(123), Box::new(Ledbat::new()), now);
+    q.set_peer_window(64 * 1024);
+    q.set_nodelay(true);
+    assert!(q.set_initial_window(8 * MAX_PACKET_SIZE));
+
+    q.write(b"hello").unwrap();
+    let p = flush(&mut q, now);
+    q.set_their_ack(p[0].seq_nr(), None, now + ms(10));
+
+    // Sending again before a timeout elapsed keeps the window
+    q.write(b"world").unwrap();
+    let p = flush(&mut q, now + ms(400));
+    q.set_their_ack(p[0].seq_nr(), None, now + ms(410));
+    assert_eq!(8 * MAX_PACKET_SIZE, q.max_window());
+
+    // The window halves for each timeout that elapses without sending
+    q.write(b"again").unwrap();
+    assert_eq!(1, flush(&mut q, now + ms(1_500)).len());
+    assert_eq!(2 * MAX_PACKET_SIZE, q.max_window());
+}
+
+/// Timestamps that are set by the test
+#[derive(Debug)]
+struct ManualTimestamps(Rc<Cell<u32>>);
+
+impl TimestampSource for ManualTimestamps {
+    fn timestamp(&mut self, _: Instant) -> u32 {
+        self.0.get()
+    }
+}
+
+#[test]
+fn timestamps_come_from_source() {
+    let now = Instant::now();
+    let (mut q, _) = connected(1, now);
+
+    let clock = Rc::new(Cell::new(0xFFFF_FF00));
+    q.set_timestamp_source(Box::new(ManualTimestamps(clock.clone())));
+
+    q.write(b"hello").unwrap();
+    let p = flush(&mut q, now);
+    assert_eq!(0xFFFF_FF00, p[0].timestamp());
+
+    // The peer's delay is measured with the same clock, across the wrap
+    clock.set(0x100);
+    assert_eq!(0x200, q.update_their_delay(0xFFFF_FF00, now));
+
+    q.write(b"world").unwrap();
+    let p = flush(&mut q, now);
+    assert_eq!(0x100, p[0].timestamp());
+    assert_eq!(0x200, p[0].timestamp_diff());
+}
+
+#[test]
+fn acks_ride_on_outbound_data() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(14_000);
+    q.set_local_ack(123);
+    flush(&mut q, now);
+
+    // Establish a 100ms round trip time
+    q.write(b"one").unwrap();
+    flush(&mut q, now);
+    q.set_their_ack(2, None, now + ms(800)).unwrap();
+
+    let mut now = now + ms(800);
+    let acks_sent = q.stats(now).acks_sent();
+
+    // Both sides exchange data, each packet received is acked by the next
+    // packet sent
+    for i in 0..20 {
+        q.set_local_ack(124 + i);
+        q.write(&[0; 1_000]).unwrap();
+
+        let p = flush(&mut q, now);
+        assert_eq!(1, p.len());
+        assert_eq!(p[0].ty(), packet::Type::Data);
+        assert_eq!(p[0].ack_nr(), 124 + i);
+
+        q.set_their_ack(3 + i, None, now).unwrap();
+        now += ms(10);
+    }
+
+    let stats = q.stats(now);
+    assert_eq!(acks_sent, stats.acks_sent());
+    assert_eq!(20, stats.acks_piggybacked());
+
+    // Data held back by pacing carries an ACK that is due right away
+    q.set_pacing(true);
+    q.write(&[0; 2 * 1_380]).unwrap();
+    assert_eq!(1, flush(&mut q, now).len());
+
+    let at = q.next_send_at().unwrap();
+    q.set_local_ack(144);
+    q.set_local_ack(145);
+    assert_eq!(0, flush(&mut q, now).len());
+
+    let p = flush(&mut q, at);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::Data);
+    assert_eq!(p[0].ack_nr(), 145);
+    assert_eq!(acks_sent, q.stats(at).acks_sent());
+}
+
+#[test]
+fn window_limited_queue_still_acks() {
+    let now = Instant::now();
+    let (mut q, window) = connected(1, now);
+    window.set(5_000);
+    q.set_delayed_ack(false);
+    flush(&mut q, now);
+    let acks_sent = q.stats(now).acks_sent();
+
+    // Shrinking the window allows a single packet in-flight
+    q.write(&[0; 4_000]).unwrap();
+    window.set(10);
+    assert_eq!(1, flush(&mut q, now).len());
+
+    // Data received while the window is full is acked by a STATE
+    q.set_local_ack(124);
+    let p = flush(&mut q, now);
+    assert_eq!(1, p.len());
+    assert_eq!(p[0].ty(), packet::Type::State);
+    assert_eq!(p[0].ack_nr(), 124);
+    assert_eq!(acks_sent + 1, q.stats(now).acks_sent());
+}
@@ -0,0 +1,351 @@
+use packet::{self, Packet, PacketRef, ParseError, HEADER_LEN};
+use vectors;
+use util;
+
+use bytes::BytesMut;
+
+use std::io;
+use std::time::Duration;
+
+fn parse(data: &[u8]) -> Packet {
+    Packet::parse(BytesMut::from(data)).unwrap()
+}
+
+#[test]
+fn default_header() {
+    assert_eq!(&Packet::syn().to_vec()[..], &vectors::SYN[..]);
+
+    let p = parse(&vectors::SYN);
+    assert_eq!(p.ty(), packet::Type::Syn);
+    assert_eq!(p.version(), 1);
+    assert_eq!(p.timestamp_diff(), 0xFFFF_FFFF);
+    assert_eq!(p.wnd_size(), 64 * 1024);
+}
+
+#[test]
+fn encode_header_fields() {
+    let mut p = Packet::state();
+    p.set_connection_id(0x1234);
+    p.set_timestamp(0x01020304);
+    p.set_timestamp_diff(0x05060708);
+    p.set_wnd_size(0x090A0B0C);
+    p.set_seq_nr(0x0D0E);
+    p.set_ack_nr(0x0F10);
+
+    assert_eq!(&p.to_vec()[..], &vectors::STATE[..]);
+}
+
+#[test]
+fn each_field_is_big_endian() {
+    let mut p = Packet::state();
+
+    // Type in the high nibble, version in the low one
+    p.set_ty(packet::Type::Reset);
+    assert_eq!(p.to_vec()[0], 0x31);
+
+    p.set_connection_id(0xABCD);
+    assert_eq!(&p.to_vec()[2..4], &[0xAB, 0xCD]);
+
+    p.set_timestamp(0xDEADBEEF);
+    assert_eq!(&p.to_vec()[4..8], &[0xDE, 0xAD, 0xBE, 0xEF]);
+
+    p.set_timestamp_diff(1);
+    assert_eq!(&p.to_vec()[8..12], &[0, 0, 0, 1]);
+
+    p.set_wnd_size(0x80000000);
+    assert_eq!(&p.to_vec()[12..16], &[0x80, 0, 0, 0]);
+
+    p.set_seq_nr(0xFF00);
+    assert_eq!(&p.to_vec()[16..18], &[0xFF, 0x00]);
+
+    p.set_ack_nr(0x00FF);
+    assert_eq!(&p.to_vec()[18..20], &[0x00, 0xFF]);
+
+    p.set_version(2);
+    assert_eq!(p.to_vec()[0], 0x32);
+    assert_eq!(packet::Type::Reset, p.ty());
+
+    p.set_extension(7);
+    assert_eq!(p.to_vec()[1], 7);
+    assert_eq!(7, p.extension());
+}
+
+#[test]
+fn decode_header_fields() {
+    let p = parse(&vectors::DATA);
+
+    assert_eq!(p.ty(), packet::Type::Data);
+    assert_eq!(p.version(), 1);
+    assert_eq!(p.extension(), 0);
+    assert_eq!(p.connection_id(), 0x1234);
+    assert_eq!(p.timestamp(), 0x01020304);
+    assert_eq!(p.timestamp_diff(), 0x05060708);
+    assert_eq!(p.wnd_size(), 0x090A0B0C);
+    assert_eq!(p.seq_nr(), 0x0D0E);
+    assert_eq!(p.ack_nr(), 0x0F10);
+    assert_eq!(p.payload(), b"utp");
+}
+
+#[test]
+fn field_extremes_round_trip() {
+    for &(u16_val, u32_val) in &[(0, 0), (1, 1), (0x7FFF, 0x7FFF_FFFF), (0xFFFF, 0xFFFF_FFFF)] {
+        let mut p = Packet::data(b"x");
+        p.set_connection_id(u16_val);
+        p.set_timestamp(u32_val);
+        p.set_timestamp_diff(u32_val);
+        p.set_wnd_size(u32_val);
+        p.set_seq_nr(u16_val);
+        p.set_ack_nr(u16_val);
+
+        let p = parse(&p.to_vec());
+        assert_eq!(p.connection_id(), u16_val);
+        assert_eq!(p.timestamp(), u32_val);
+        assert_eq!(p.timestamp_diff(), u32_val);
+        assert_eq!(p.wnd_size(), u32_val);
+        assert_eq!(p.seq_nr(), u16_val);
+        assert_eq!(p.ack_nr(), u16_val);
+        assert_eq!(p.payload(), b"x");
+    }
+}
+
+#[test]
+fn selective_ack_encoding() {
+    let mut p = Packet::data(b"utp");
+    p.set_selective_ack(&[0b1000_0001, 0, 0, 0b0100_0000]);
+    assert_eq!(&p.to_vec()[..], &vectors::SELECTIVE_ACK[..]);
+
+    let p = parse(&vectors::SELECTIVE_ACK);
+    let sack = p.selective_ack().unwrap();
+    let acked: Vec<usize> = (0..sack.len()).filter(|&i| sack.is_acked(i)).collect();
+
+    assert_eq!(acked, vec![0, 7, 30]);
+    assert_eq!(p.payload(), b"utp");
+    assert_eq!(p.encoded_len(), HEADER_LEN + 6 + 3);
+}
+
+#[test]
+fn malformed_packets_are_rejected() {
+    for &(name, data) in &vectors::MALFORMED {
+        assert!(Packet::parse(BytesMut::from(data)).is_err(), "{} was accepted", name);
+    }
+}
+
+#[test]
+fn strict_validation_rejects_impossible_headers() {
+    let validate = |p: Packet| {
+        let mut buf = BytesMut::from(p.to_vec());
+        let res = PacketRef::parse(&mut buf).unwrap().validate_strict();
+        res
+    };
+
+    assert_eq!(Ok(()), validate(Packet::data(b"hello")));
+    assert_eq!(Ok(()), validate(Packet::syn()));
+
+    let mut p = Packet::syn();
+    p.extend_payload(b"hello");
+    assert_eq!(Err(ParseError::UnexpectedPayload(packet::Type::Syn)), validate(p));
+
+    let mut p = Packet::state();
+    p.extend_payload(b"hello");
+    assert_eq!(Err(ParseError::UnexpectedPayload(packet::Type::State)), validate(p));
+
+    let mut p = Packet::state();
+    p.set_selective_ack(&[1, 0, 0, 0]);
+    assert_eq!(Ok(()), validate(p));
+
+    let mut p = Packet::state();
+    p.set_selective_ack(&[1, 0, 0]);
+    assert_eq!(Err(ParseError::BadSelectiveAck(3)), validate(p));
+
+    let mut p = Packet::state();
+    p.set_selective_ack(&[]);
+    assert_eq!(Err(ParseError::BadSelectiveAck(0)), validate(p));
+
+    let mut p = Packet::syn();
+    p.add_extension(2, &[0; 4]);
+    assert_eq!(Err(ParseError::BadExtensionBits(4)), validate(p));
+}
+
+#[test]
+fn extension_bits() {
+    let mut p = Packet::syn();
+    p.set_extension_bits(&[0; packet::EXTENSION_BITS_LEN]);
+    assert_eq!(&p.to_vec()[..], &vectors::SYN_EXTENSION_BITS[..]);
+
+    let mut buf = BytesMut::from(&vectors::SYN_EXTENSION_BITS[..]);
+    let p = PacketRef::parse(&mut buf).unwrap();
+
+    assert_eq!(Some(&[0; 8][..]), p.extension_bits());
+    assert_eq!(None, p.unknown_extension());
+    assert_eq!(Ok(()), p.validate_strict());
+
+    assert_eq!(None, parse(&vectors::SYN).extension_bits());
+}
+
+#[test]
+fn timestamps_wrap_at_32_bits() {
+    // 2^32 microseconds is about 71.6 minutes
+    let wrap = Duration::new(4_294, 967_296_000);
+    assert_eq!(util::as_wrapping_micros(wrap), 0);
+
+    let before = Duration::new(4_294, 967_295_000);
+    assert_eq!(util::as_wrapping_micros(before), 0xFFFF_FFFF);
+
+    // 3 * 2^32 + 5 microseconds
+    let after = Duration::new(12_884, 901_893_000);
+    assert_eq!(util::as_wrapping_micros(after), 5);
+}
+
+#[test]
+fn extension_chain() {
+    let mut p = Packet::data(b"utp");
+    assert_eq!(0, p.extensions().count());
+
+    p.set_selective_ack(&[0b1000_0001, 0, 0, 0b0100_0000]);
+    p.add_extension(2, b"ab");
+    p.add_extension(7, b"");
+
+    // The header links to the first extension, each extension to the next
+    assert_eq!(&p.to_vec()[HEADER_LEN..HEADER_LEN + 2], &[2, 4]);
+
+    let p = parse(&p.to_vec());
+    let exts: Vec<(u8, &[u8])> = p.extensions().collect();
+
+    assert_eq!(exts, vec![
+        (1, &[0b1000_0001, 0, 0, 0b0100_0000][..]),
+        (2, &b"ab"[..]),
+        (7, &b""[..]),
+    ]);
+
+    assert!(p.selective_ack().unwrap().is_acked(7));
+    assert_eq!(p.payload(), b"utp");
+}
+
+#[test]
+fn malformed_extension_chain_ends_iteration() {
+    let mut data = vectors::SELECTIVE_ACK.to_vec();
+
+    // The selective ACK claims to be followed by another extension whose
+    // length runs past the end of the packet
+    data[HEADER_LEN] = 2;
+    data.truncate(HEADER_LEN + 6);
+    data.extend_from_slice(&[0, 10, 1]);
+
+    let p = Packet::new(BytesMut::from(&data[..]));
+    assert_eq!(1, p.extensions().count());
+    assert!(Packet::parse(BytesMut::from(&data[..])).is_err());
+}
+
+#[test]
+fn unknown_extension_is_skipped() {
+    let mut p = Packet::data(b"utp");
+    p.add_extension(9, b"xyz");
+    p.set_selective_ack(&[0b0000_0001, 0, 0, 0]);
+
+    let p = parse(&p.to_vec());
+
+    // The unknown extension does not hide the rest of the packet
+    assert_eq!(Some(9), p.unknown_extension());
+    assert!(p.selective_ack().unwrap().is_acked(0));
+    assert_eq!(p.payload(), b"utp");
+
+    assert_eq!(None, parse(&vectors::SELECTIVE_ACK).unknown_extension());
+}
+
+#[test]
+fn packet_ref_parses_in_place() {
+    let mut buf = BytesMut::from(&vectors::DATA[..]);
+
+    {
+        let p = PacketRef::parse(&mut buf).unwrap();
+
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.connection_id(), 0x1234);
+        assert_eq!(p.timestamp(), 0x01020304);
+        assert_eq!(p.timestamp_diff(), 0x05060708);
+        assert_eq!(p.wnd_size(), 0x090A0B0C);
+        assert_eq!(p.seq_nr(), 0x0D0E);
+        assert_eq!(p.ack_nr(), 0x0F10);
+        assert_eq!(p.payload(), b"utp");
+    }
+
+    // Splitting the packet off does not copy it
+    let mut buf = BytesMut::from(&Packet::data(&[7; 1_000]).to_vec()[..]);
+    let ptr = buf[HEADER_LEN..].as_ptr();
+
+    let p = PacketRef::parse(&mut buf).unwrap().into_packet();
+    assert_eq!(ptr, p.payload().as_ptr());
+    assert_eq!(p.payload(), &[7; 1_000][..]);
+    assert!(buf.is_empty());
+
+    for &(_, data) in &vectors::MALFORMED {
+        assert!(PacketRef::parse(&mut BytesMut::from(data)).is_err());
+    }
+}
+
+#[test]
+fn parse_errors_are_typed() {
+    let errors: Vec<ParseError> = vectors::MALFORMED.iter()
+        .map(|&(_, data)| Packet::parse(BytesMut::from(data)).unwrap_err())
+        .collect();
+
+    assert_eq!(errors, vec![
+        ParseError::TooShort(19),
+        ParseError::UnsupportedVersion(2),
+        ParseError::UnknownType(5),
+        ParseError::BadExtension,
+    ]);
+
+    let err: io::Error = ParseError::UnknownType(5).into();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "unknown packet type; type=5");
+}
+
+#[test]
+fn encode_into_buffer() {
+    let mut p = Packet::data(b"utp");
+    p.set_selective_ack(&[0b1000_0001, 0, 0, 0b0100_0000]);
+
+    let mut buf = [0xFF; 64];
+    let n = p.encode_into(&mut buf);
+
+    assert_eq!(n, vectors::SELECTIVE_ACK.len());
+    assert_eq!(&buf[..n], &vectors::SELECTIVE_ACK[..]);
+    assert_eq!(buf[n], 0xFF);
+
+    // The streaming variant appends
+    let mut buf = vec![1, 2];
+    p.encode_to(&mut buf);
+    assert_eq!(&buf[..2], &[1, 2]);
+    assert_eq!(&buf[2..], &vectors::SELECTIVE_ACK[..]);
+}
+
+#[test]
+#[should_panic(expected = "buffer too short")]
+fn encode_into_short_buffer() {
+    let mut buf = [0; HEADER_LEN - 1];
+    Packet::state().encode_into(&mut buf);
+}
+
+#[test]
+fn display_on_one_line() {
+    let mut p = Packet::data(b"utp");
+    p.set_connection_id(25103);
+    p.set_seq_nr(2);
+    p.set_ack_nr(1);
+    p.set_wnd_size(65536);
+    p.set_timestamp(1000);
+    p.set_timestamp_diff(200);
+
+    assert_eq!(p.to_string(),
+               "ST_DATA id=25103 seq=2 ack=1 wnd=65536 ts=1000 ts_diff=200 len=3");
+
+    // The borrowed form reads the same, and extensions are flagged
+    p.set_selective_ack(&[0; 4]);
+
+    let mut buf = BytesMut::from(&p.to_vec()[..]);
+    let p = PacketRef::parse(&mut buf).unwrap();
+
+    assert_eq!(p.to_string(),
+               "ST_DATA id=25103 seq=2 ack=1 wnd=65536 ts=1000 ts_diff=200 ext=1 len=3");
+}
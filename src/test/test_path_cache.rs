@@ -0,0 +1,145 @@
+use super::prelude::*;
+use path_cache::{PathCache, PathInfo};
+use tuning::MAX_PACKET_SIZE;
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+fn info(rtt: u64) -> PathInfo {
+    PathInfo {
+        rtt,
+        rtt_variance: 0,
+        packet_size: MAX_PACKET_SIZE,
+        mtu_ceiling: MAX_PACKET_SIZE,
+        packets_sent: 4,
+        packets_resent: 1,
+    }
+}
+
+#[test]
+fn cache_expires_and_evicts() {
+    let now = Instant::now();
+    let mut cache = PathCache::new(2, Duration::from_secs(10));
+
+    let a = "127.0.0.1".parse().unwrap();
+    let b = "127.0.0.2".parse().unwrap();
+    let c = "127.0.0.3".parse().unwrap();
+
+    cache.insert(a, info(10), now);
+    cache.insert(b, info(20), now + Duration::from_secs(1));
+
+    assert_eq!(cache.get(&a, now).unwrap().rtt(), Duration::from_millis(10));
+    assert_eq!(cache.get(&a, now).unwrap().loss_rate(), 0.25);
+
+    // The least recently updated entry is evicted
+    cache.insert(c, info(30), now + Duration::from_secs(2));
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(&a, now).is_none());
+
+    // Entries expire
+    assert!(cache.get(&b, now + Duration::from_secs(11)).is_none());
+    assert!(cache.get(&c, now + Duration::from_secs(11)).is_some());
+
+    // A disabled cache stores nothing
+    let mut cache = PathCache::new(0, Duration::from_secs(10));
+    cache.insert(a, info(10), now);
+    assert!(cache.get(&a, now).is_none());
+}
+
+#[test]
+fn reconnect_uses_cached_path() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+    let addr = socket.local_addr();
+
+    // First connection, discovering the path MTU
+    let (tx, rx) = mpsc::channel();
+    let th = mock.background(move |m| tx.send(receive(m, &addr)).unwrap());
+
+    transfer(&socket, server, 200 * 1_024);
+    socket.wait_until(|| socket.path_info(&server).is_some());
+
+    let mock = th.join().unwrap();
+    let lens = rx.recv().unwrap();
+
+    let info = socket.path_info(&server).unwrap();
+    assert!(info.packet_size() > MAX_PACKET_SIZE, "info={:?}", info);
+    assert_eq!(lens.iter().max(), Some(&info.packet_size()));
+
+    // The port does not matter
+    let other: SocketAddr = format!("{}:1", server.ip()).parse().unwrap();
+    assert!(socket.path_info(&other).is_some());
+
+    // The next connection starts out with the discovered packet size
+    let (tx, rx) = mpsc::channel();
+    let th = mock.background(move |m| tx.send(receive(m, &addr)).unwrap());
+
+    transfer(&socket, server, 10 * 1_024);
+    th.join().unwrap();
+
+    // Packets may be cut short by the congestion window, but never probe
+    // past the cached size
+    let lens = rx.recv().unwrap();
+    assert_eq!(lens[0], info.packet_size(), "lens={:?}", lens);
+    assert!(lens.iter().all(|&len| len <= info.packet_size()), "lens={:?}", lens);
+}
+
+/// Writes `len` bytes on a new connection, then closes it
+fn transfer(socket: &Harness, server: SocketAddr, len: usize) {
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    let data = vec![0; len];
+    let mut written = 0;
+
+    while written < len {
+        written += socket.wait(|| stream.write(&data[written..])).unwrap();
+    }
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+}
+
+/// Accepts a connection, acking each packet until the FIN. Returns the size of
+/// the DATA packets.
+fn receive(m: &mut Mock, addr: &SocketAddr) -> Vec<usize> {
+    let t = Time::new();
+
+    let syn = m.recv_from(addr);
+    assert_eq!(syn.ty(), packet::Type::Syn);
+
+    let mut p = Packet::state();
+    p.set_connection_id(syn.connection_id());
+    p.set_seq_nr(123);
+    p.set_ack_nr(syn.seq_nr());
+    m.send_to(p, addr);
+
+    let mut lens = vec![];
+
+    loop {
+        let p = m.recv_from(addr);
+
+        if p.ty() == packet::Type::Data {
+            lens.push(p.encoded_len());
+        }
+
+        // Timestamps let the window grow past a single packet
+        let ts = t.timestamp();
+
+        let mut ack = Packet::state();
+        ack.set_connection_id(syn.connection_id());
+        ack.set_seq_nr(123);
+        ack.set_ack_nr(p.seq_nr());
+        ack.set_timestamp(ts);
+        ack.set_timestamp_diff(ts.wrapping_sub(p.timestamp()));
+        m.send_to(ack, addr);
+
+        if p.ty() == packet::Type::Fin {
+            return lens;
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use super::prelude::*;
+
+use {PeerPolicy, SlowPeerPolicy, Stats, Verdict};
+
+use std::io;
+use std::time::Duration;
+
+fn stats(elapsed_ms: u64, rtt_ms: u64) -> Stats {
+    Stats {
+        elapsed: Duration::from_millis(elapsed_ms),
+        rtt: Duration::from_millis(rtt_ms),
+        ..Stats::default()
+    }
+}
+
+#[test]
+fn slow_peer_flagged_after_strikes() {
+    let mut policy = SlowPeerPolicy::new();
+    policy.set_grace_period(Duration::from_secs(1))
+        .set_max_rtt(Duration::from_millis(500))
+        .set_strikes(3);
+
+    // Not judged during the grace period
+    assert_eq!(policy.check(&stats(500, 1_000)), Verdict::Healthy);
+
+    assert_eq!(policy.check(&stats(1_000, 1_000)), Verdict::Healthy);
+    assert_eq!(policy.check(&stats(1_500, 1_000)), Verdict::Healthy);
+    assert_eq!(policy.check(&stats(2_000, 1_000)), Verdict::Slow);
+
+    // A good check resets the strikes
+    assert_eq!(policy.check(&stats(2_500, 100)), Verdict::Healthy);
+    assert_eq!(policy.check(&stats(3_000, 1_000)), Verdict::Healthy);
+}
+
+#[test]
+fn slow_peer_auto_disconnect_and_veto() {
+    let mut policy = SlowPeerPolicy::new();
+    policy.set_grace_period(Duration::from_secs(0))
+        .set_max_rtt(Duration::from_millis(500))
+        .set_strikes(1)
+        .set_auto_disconnect(true);
+
+    assert_eq!(policy.check(&stats(1_000, 1_000)), Verdict::Disconnect);
+
+    // Veto disconnects of peers that have been connected for a long time
+    policy.set_veto(|stats| stats.elapsed() > Duration::from_secs(60));
+
+    assert_eq!(policy.check(&stats(2_000, 1_000)), Verdict::Disconnect);
+    assert_eq!(policy.check(&stats(61_000, 1_000)), Verdict::Slow);
+}
+
+#[test]
+fn slow_peer_throughput_requires_pending_data() {
+    let mut policy = SlowPeerPolicy::new();
+    policy.set_grace_period(Duration::from_secs(0))
+        .set_min_throughput(10_000)
+        .set_strikes(1);
+
+    // Nothing to send, the peer is not at fault
+    assert_eq!(policy.check(&stats(1_000, 10)), Verdict::Healthy);
+
+    let mut s = stats(2_000, 10);
+    s.bytes_pending = 5_000;
+    s.bytes_acked = 1_000;
+    assert_eq!(policy.check(&s), Verdict::Slow);
+
+    let mut s = stats(3_000, 10);
+    s.bytes_pending = 5_000;
+    s.bytes_acked = 21_000;
+    assert_eq!(policy.check(&s), Verdict::Healthy);
+}
+
+#[derive(Debug)]
+struct AlwaysDisconnect;
+
+impl PeerPolicy for AlwaysDisconnect {
+    fn check(&mut self, _: &Stats) -> Verdict {
+        Verdict::Disconnect
+    }
+}
+
+#[test]
+fn peer_policy_disconnect_resets_connection() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_peer_policy(|| Box::new(AlwaysDisconnect));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The socket resets the connection on the next tick
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), CONNECTION_ID + 1);
+    });
+
+    let stream = socket.connect(server);
+
+    // The connection is established, then reset by the policy
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 16];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    assert!(stream.is_slow_peer());
+
+    th.join().unwrap();
+}
@@ -0,0 +1,11 @@
+use selftest;
+
+#[test]
+fn selftest_passes() {
+    let _ = ::env_logger::init();
+
+    let report = selftest();
+
+    assert_eq!(report.checks().len(), 5);
+    assert!(report.is_ok(), "{}", report);
+}
@@ -0,0 +1,55 @@
+use seq;
+
+#[test]
+fn distance_wraps() {
+    assert_eq!(0, seq::distance(7, 7));
+    assert_eq!(3, seq::distance(7, 10));
+    assert_eq!(2, seq::distance(65_535, 1));
+    assert_eq!(65_535, seq::distance(1, 0));
+}
+
+#[test]
+fn lt_and_le_across_the_wrap() {
+    assert!(seq::wrapping_lt(1, 2));
+    assert!(!seq::wrapping_lt(2, 1));
+    assert!(!seq::wrapping_lt(5, 5));
+    assert!(seq::wrapping_le(5, 5));
+
+    assert!(seq::wrapping_lt(65_535, 0));
+    assert!(seq::wrapping_lt(65_000, 100));
+    assert!(!seq::wrapping_lt(100, 65_000));
+    assert!(seq::wrapping_le(65_535, 0));
+    assert!(!seq::wrapping_le(0, 65_535));
+}
+
+#[test]
+fn lt_splits_the_space_in_half() {
+    for &base in &[0u16, 1, 32_767, 32_768, 65_535] {
+        assert!(seq::wrapping_lt(base, base.wrapping_add(32_767)), "base={}", base);
+        assert!(!seq::wrapping_lt(base, base.wrapping_add(32_768)), "base={}", base);
+        assert!(seq::wrapping_lt(base.wrapping_sub(32_767), base), "base={}", base);
+
+        // Exactly one of two distinct numbers comes first, except half the
+        // space apart
+        for &dist in &[1u16, 100, 32_767, 32_769, 65_535] {
+            let other = base.wrapping_add(dist);
+            assert!(seq::wrapping_lt(base, other) != seq::wrapping_lt(other, base),
+                    "base={}; other={}", base, other);
+        }
+    }
+}
+
+#[test]
+fn in_window_across_the_wrap() {
+    assert!(seq::in_window(10, 5, 10));
+    assert!(seq::in_window(10, 5, 14));
+    assert!(!seq::in_window(10, 5, 15));
+    assert!(!seq::in_window(10, 5, 9));
+    assert!(!seq::in_window(10, 0, 10));
+
+    assert!(seq::in_window(65_534, 4, 65_534));
+    assert!(seq::in_window(65_534, 4, 0));
+    assert!(seq::in_window(65_534, 4, 1));
+    assert!(!seq::in_window(65_534, 4, 2));
+    assert!(!seq::in_window(65_534, 4, 65_533));
+}
@@ -0,0 +1,60 @@
+use super::prelude::*;
+use stats::Stats;
+
+use serde::Deserialize;
+use serde::de::value::{Error, MapDeserializer, StrDeserializer};
+
+#[test]
+fn deserialize_packet_fields() {
+    let fields = vec![
+        ("connection_id", 0x1234u32),
+        ("timestamp", 0x01020304),
+        ("wnd_size", 1_000),
+        ("seq_nr", 7),
+        ("ack_nr", 6),
+        // Ignored
+        ("unknown", 1),
+    ];
+
+    let p = Packet::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).unwrap();
+
+    // Missing fields keep their default
+    assert_eq!(packet::Type::Data, p.ty());
+    assert_eq!(1, p.version());
+    assert_eq!(0x1234, p.connection_id());
+    assert_eq!(0x01020304, p.timestamp());
+    assert_eq!(0xFFFFFFFF, p.timestamp_diff());
+    assert_eq!(1_000, p.wnd_size());
+    assert_eq!(7, p.seq_nr());
+    assert_eq!(6, p.ack_nr());
+    assert!(p.payload().is_empty());
+
+    // Out of range values are rejected
+    let fields = vec![("seq_nr", 70_000u32)];
+    assert!(Packet::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).is_err());
+}
+
+#[test]
+fn deserialize_packet_type() {
+    let ty = packet::Type::deserialize(StrDeserializer::<Error>::new("ST_SYN")).unwrap();
+    assert_eq!(packet::Type::Syn, ty);
+
+    assert!(packet::Type::deserialize(StrDeserializer::<Error>::new("ST_NONE")).is_err());
+}
+
+#[test]
+fn deserialize_stats() {
+    let fields = vec![
+        ("bytes_acked", 10_000u64),
+        ("packets_sent", 10),
+        ("packets_resent", 1),
+        ("added_later", 5),
+    ];
+
+    let stats = Stats::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).unwrap();
+
+    assert_eq!(10_000, stats.bytes_acked());
+    assert_eq!(10, stats.packets_sent());
+    assert_eq!(1, stats.packets_resent());
+    assert_eq!(0, stats.timeouts());
+}
@@ -0,0 +1,64 @@
+use UtpSocket;
+use sockopt;
+
+use mio::net::UdpSocket;
+
+#[test]
+fn ttl_round_trips() {
+    let (socket, _) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+    socket.set_ttl(17).unwrap();
+    assert_eq!(17, socket.ttl().unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn tos_round_trips() {
+    let (socket, _) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+    // CS1, the scavenger class
+    socket.set_tos(0x20).unwrap();
+    assert_eq!(0x20, socket.tos().unwrap());
+
+    socket.set_tos(0).unwrap();
+    assert_eq!(0, socket.tos().unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn tos_sets_ipv6_traffic_class() {
+    let (socket, _) = match UtpSocket::bind(&"[::1]:0".parse().unwrap()) {
+        Ok(ret) => ret,
+        // IPv6 is not available
+        Err(_) => return,
+    };
+
+    socket.set_tos(0x04).unwrap();
+    assert_eq!(0x04, socket.tos().unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn builder_applies_options() {
+    let (socket, _) = UtpSocket::builder()
+        .ttl(17)
+        .tos(0x20)
+        .bind(&"127.0.0.1:0".parse().unwrap())
+        .unwrap();
+
+    assert_eq!(17, socket.ttl().unwrap());
+    assert_eq!(0x20, socket.tos().unwrap());
+}
+
+#[test]
+fn dont_fragment_is_set_where_supported() {
+    let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+
+    assert_eq!(sockopt::DONT_FRAGMENT, sockopt::set_dont_fragment(&socket, true).is_ok());
+    assert_eq!(sockopt::DONT_FRAGMENT, sockopt::set_dont_fragment(&socket, false).is_ok());
+
+    // IPv6 is not always available
+    if let Ok(socket) = UdpSocket::bind(&"[::1]:0".parse().unwrap()) {
+        assert_eq!(sockopt::DONT_FRAGMENT, sockopt::set_dont_fragment(&socket, true).is_ok());
+    }
+}
@@ -0,0 +1,269 @@
+use super::prelude::*;
+use state::State;
+use {UtpListener, UtpStream, DropReason};
+
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+
+const STATES: [State; 5] = [
+    State::SynSent,
+    State::SynRecv,
+    State::Connected,
+    State::FinSent,
+    State::Reset,
+];
+
+const TYPES: [packet::Type; 5] = [
+    packet::Type::Data,
+    packet::Type::Fin,
+    packet::Type::State,
+    packet::Type::Reset,
+    packet::Type::Syn,
+];
+
+/// A connection to the mock, brought into a given state
+struct Peer {
+    stream: Option<UtpStream>,
+    listener: UtpListener,
+    socket: Harness,
+    mock: Mock,
+    addr: SocketAddr,
+    // Connection ID of the packets sent by the mock
+    id: u16,
+    // Sequence number of the mock's next packet
+    seq_nr: u16,
+    // Sequence number of the last packet sent by the socket
+    ack_nr: u16,
+}
+
+/// What the socket did with a packet
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    state: State,
+    replies: Vec<packet::Type>,
+    dropped: Option<DropReason>,
+}
+
+const DROP_REASONS: [DropReason; 4] = [
+    DropReason::InvalidState,
+    DropReason::ConnectionReset,
+    DropReason::Duplicate,
+    DropReason::UnknownConnection,
+];
+
+impl Peer {
+    fn new(state: State) -> Peer {
+        let mut config = UtpConfig::new();
+        config.set_delayed_ack(false);
+
+        let (socket, listener) = Harness::with_config(config);
+        let mut mock = Mock::new();
+        let addr = socket.local_addr();
+
+        if state == State::SynRecv {
+            let mut p = Packet::syn();
+            p.set_seq_nr(1);
+            p.set_connection_id(123);
+            mock.send_to(p, &addr);
+
+            socket.tick_for(50);
+            let p = mock.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::State);
+
+            return Peer {
+                stream: None,
+                listener,
+                socket,
+                mock,
+                addr,
+                id: 124,
+                seq_nr: 2,
+                ack_nr: p.seq_nr(),
+            };
+        }
+
+        let stream = socket.connect(mock.local_addr());
+        socket.tick_for(50);
+
+        let p = mock.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut peer = Peer {
+            stream: Some(stream),
+            listener,
+            socket,
+            mock,
+            addr,
+            id: p.connection_id(),
+            seq_nr: 124,
+            ack_nr: p.seq_nr(),
+        };
+
+        if state == State::SynSent {
+            return peer;
+        }
+
+        let mut p = Packet::state();
+        p.set_connection_id(peer.id);
+        p.set_seq_nr(123);
+        p.set_ack_nr(peer.ack_nr);
+        peer.mock.send_to(p, &addr);
+        peer.socket.tick_for(50);
+
+        match state {
+            State::Connected => {}
+            State::FinSent => {
+                peer.stream.as_ref().unwrap().shutdown(Shutdown::Write).unwrap();
+                peer.socket.tick_for(50);
+
+                let p = peer.mock.recv_from(&addr);
+                assert_eq!(p.ty(), packet::Type::Fin);
+                peer.ack_nr = p.seq_nr();
+            }
+            State::Reset => {
+                let mut p = Packet::reset();
+                p.set_connection_id(peer.id);
+                peer.mock.send_to(p, &addr);
+                peer.socket.tick_for(50);
+            }
+            _ => unreachable!(),
+        }
+
+        assert_eq!(vec![state], peer.socket.connection_states());
+        peer
+    }
+
+    /// Sends a packet of type `ty` to the connection
+    fn deliver(&mut self, ty: packet::Type) -> Outcome {
+        let mut p = match ty {
+            packet::Type::Data => Packet::data(b"hello"),
+            packet::Type::Fin => Packet::fin(),
+            packet::Type::State => Packet::state(),
+            packet::Type::Reset => Packet::reset(),
+            packet::Type::Syn => Packet::syn(),
+        };
+
+        if ty == packet::Type::Syn {
+            // The SYN that opened the connection, retransmitted
+            p.set_connection_id(self.id.wrapping_sub(1));
+            p.set_seq_nr(self.seq_nr.wrapping_sub(1));
+        } else {
+            p.set_connection_id(self.id);
+            p.set_seq_nr(self.seq_nr);
+            p.set_ack_nr(self.ack_nr);
+        }
+
+        let before = self.socket.driver_stats();
+
+        self.mock.send_to(p, &self.addr);
+        self.socket.tick_for(50);
+
+        let mut replies = vec![];
+
+        while let Some(p) = self.mock.recv_from_ms(&self.addr, 50) {
+            replies.push(p.ty());
+        }
+
+        let after = self.socket.driver_stats();
+        let dropped = DROP_REASONS.iter()
+            .find(|&&reason| after.dropped(reason) > before.dropped(reason))
+            .cloned();
+
+        let states = self.socket.connection_states();
+        assert_eq!(1, states.len(), "states={:?}", states);
+
+        Outcome {
+            state: states[0],
+            replies,
+            dropped,
+        }
+    }
+}
+
+fn outcome(state: State, acked: bool, dropped: Option<DropReason>) -> Outcome {
+    Outcome {
+        state,
+        replies: if acked { vec![packet::Type::State] } else { vec![] },
+        dropped,
+    }
+}
+
+#[test]
+fn every_packet_in_every_state() {
+    use state::State::*;
+
+    let _ = ::env_logger::init();
+
+    let acked = |st| outcome(st, true, None);
+    let quiet = |st| outcome(st, false, None);
+    let ignored = || outcome(Reset, false, Some(DropReason::ConnectionReset));
+
+    // A retransmitted SYN is answered by the socket, which resends the
+    // connection's STATE unless it was reset.
+    let syn = |st| outcome(st, st != Reset, Some(DropReason::Duplicate));
+
+    // Rows follow `STATES`, columns follow `TYPES`. Data and FIN overtaking
+    // the handshake are held, without an ACK, until it completes.
+    let expect = [
+        [quiet(SynSent), quiet(SynSent), quiet(Connected), quiet(Reset), syn(SynSent)],
+        [acked(SynRecv), acked(SynRecv), quiet(SynRecv), quiet(Reset), syn(SynRecv)],
+        [acked(Connected), acked(Connected), quiet(Connected), quiet(Reset), syn(Connected)],
+        [acked(FinSent), acked(FinSent), quiet(FinSent), quiet(Reset), syn(FinSent)],
+        [ignored(), ignored(), ignored(), ignored(), syn(Reset)],
+    ];
+
+    for (i, &st) in STATES.iter().enumerate() {
+        for (j, &ty) in TYPES.iter().enumerate() {
+            ::util::reset_rand();
+
+            let mut peer = Peer::new(st);
+            assert_eq!(expect[i][j], peer.deliver(ty), "state={:?}; type={}", st, ty);
+        }
+    }
+}
+
+#[test]
+fn packets_after_reset_are_ignored() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::reset();
+        p.set_connection_id(CONNECTION_ID);
+        m.send_to(p, &addr);
+
+        // Neither ACKed, nor answered with a RESET
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        m.assert_quiescence(200);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+    socket.tick_for(300);
+
+    let mut buf = [0; 16];
+    assert_eq!(io::ErrorKind::ConnectionReset, stream.read(&mut buf).unwrap_err().kind());
+
+    th.join().unwrap();
+}
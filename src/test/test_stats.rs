@@ -0,0 +1,223 @@
+use super::prelude::*;
+use stats::{Stats, QualityMeter};
+
+use std::time::Duration;
+
+fn stats(elapsed_ms: u64) -> Stats {
+    Stats {
+        elapsed: Duration::from_millis(elapsed_ms),
+        rtt: Duration::from_millis(100),
+        rtt_variance: Duration::from_millis(5),
+        cwnd: 10_000,
+        ..Stats::default()
+    }
+}
+
+#[test]
+fn quality_of_clean_connection() {
+    let mut meter = QualityMeter::new();
+    assert_eq!(meter.get(), 100);
+
+    let mut s = stats(500);
+    s.packets_sent = 100;
+    meter.refresh(&s);
+
+    // Only the small RTT variance costs points
+    assert_eq!(meter.get(), 99);
+}
+
+#[test]
+fn quality_penalizes_loss_and_jitter() {
+    let mut meter = QualityMeter::new();
+
+    let mut s = stats(500);
+    s.packets_sent = 100;
+    s.packets_resent = 10;
+    s.rtt_variance = Duration::from_millis(50);
+    meter.refresh(&s);
+
+    // stability = 0.5, delivery = 0.6
+    assert_eq!(meter.get(), 15 + 24 + 30);
+}
+
+#[test]
+fn quality_penalizes_low_throughput_when_data_pending() {
+    let mut meter = QualityMeter::new();
+
+    // A full window per RTT is 100,000 bytes per second. The peer only acks
+    // 25,000 over the second.
+    let mut s = stats(1_000);
+    s.bytes_pending = 10_000;
+    s.bytes_acked = 25_000;
+    meter.refresh(&s);
+
+    // stability = 0.95, efficiency = 0.25
+    assert_eq!(meter.get(), 76);
+
+    // Without pending data, the throughput is not held against the peer. The
+    // score is smoothed.
+    let mut s = stats(2_000);
+    s.bytes_acked = 25_000;
+    meter.refresh(&s);
+
+    assert_eq!(meter.get(), 82);
+}
+
+#[test]
+fn driver_stats_count_wakeups_and_packets() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // Send a couple of data packets at once
+        for (i, data) in [&b"hello"[..], b"world"].iter().enumerate() {
+            let mut p = Packet::data(data);
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(124 + i as u16);
+            p.set_ack_nr(1);
+            m.send_to(p, &addr);
+        }
+    });
+
+    let stream = socket.connect(server);
+
+    socket.wait_until(|| stream.is_readable());
+    socket.tick_for(100);
+
+    th.join().unwrap();
+
+    let stats = socket.driver_stats();
+
+    assert_eq!(stats.packets_received(), 3);
+    assert!(stats.packets_sent() >= 2);
+    assert!(stats.wakeups() >= 1);
+    assert!(stats.max_packets_per_wakeup() >= 1);
+    assert!(stats.packets_per_wakeup() > 0.0);
+    assert!(stats.ticks() >= 1);
+    assert_eq!(stats.max_connections(), 1);
+    assert_eq!(stats.max_accept_backlog(), 0);
+}
+
+#[test]
+#[cfg(feature = "alloc-stats")]
+fn driver_stats_count_allocations() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        for i in 0..50 {
+            let mut p = Packet::data(&[0; 1_000]);
+            p.set_connection_id(CONNECTION_ID);
+            p.set_seq_nr(124 + i);
+            p.set_ack_nr(1);
+            m.send_to(p, &addr);
+        }
+
+        // Acks for the received data, then the data sent back
+        while let Some(p) = m.recv_from_ms(&addr, 200) {
+            assert!(p.ty() == packet::Type::State || p.ty() == packet::Type::Data);
+        }
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    let before = socket.driver_stats();
+
+    let mut buf = [0; 1_000];
+    let mut read = 0;
+
+    while read < 50_000 {
+        read += socket.wait(|| stream.read(&mut buf)).unwrap();
+
+        while let Ok(n) = stream.read(&mut buf) {
+            read += n;
+        }
+    }
+
+    // Inbound packets are read into a shared buffer and acks fit inline
+    let stats = socket.driver_stats();
+    assert_eq!(stats.packet_allocations(), before.packet_allocations());
+    assert!(stats.allocations_per_packet() < 0.5,
+            "allocations_per_packet={}", stats.allocations_per_packet());
+
+    // Each outbound data packet has its own buffer
+    stream.write(&[0; 5_000]).unwrap();
+    assert!(socket.driver_stats().packet_allocations() > stats.packet_allocations());
+
+    socket.tick_for(100);
+    th.join().unwrap();
+}
+
+#[test]
+fn stream_rtt_is_sampled_from_acks() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        sleep(200);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    assert_eq!(Duration::from_millis(0), stream.rtt());
+
+    socket.wait_until(|| stream.is_writable());
+    th.join().unwrap();
+
+    // rtt += (200 - 0) / 8, rtt_var += (200 - 0) / 4
+    assert!(stream.rtt() >= Duration::from_millis(25));
+    assert!(stream.rtt_variance() >= Duration::from_millis(50));
+    assert_eq!(stream.rtt(), stream.stats().rtt);
+}
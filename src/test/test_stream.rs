@@ -1,5 +1,12 @@
 use super::prelude::*;
 use std::io;
+use std::net::{self, Shutdown, SocketAddr};
+use std::time::Duration;
+
+use stats::DropReason;
+use UtpSocket;
+
+use bytes::{Buf, BytesMut};
 
 #[test]
 fn connect_echo_close() {
@@ -36,6 +43,9 @@ fn connect_echo_close() {
 
     let stream = socket.connect(server);
 
+    assert_eq!(server, stream.peer_addr().unwrap());
+    assert_eq!(CONNECTION_ID, stream.connection_id());
+
     // The socket becomes writable
     socket.wait_until(|| stream.is_writable());
 
@@ -169,10 +179,16 @@ fn connect_out_of_order_recv() {
     // The socket becomes writable
     socket.wait_until(|| stream.is_readable());
 
+    // Peeking leaves the data in the stream buffer
+    let mut buf = [0; 4];
+    assert_eq!(4, stream.peek(&mut buf).unwrap());
+    assert_eq!(&buf, b"this");
+
     // Read the data out of the stream buffer
     let mut buf = [0; 128];
     assert_eq!(14, stream.read(&mut buf).unwrap());
     assert_eq!(&buf[..14], b"this is my msg");
+    assert_eq!(io::ErrorKind::WouldBlock, stream.peek(&mut buf).unwrap_err().kind());
 
     // Dropping the stream will send a FIN
     drop(stream);
@@ -228,6 +244,12 @@ fn ignores_dup_packets() {
         assert_eq!(p.seq_nr(), 1);
         assert_eq!(p.ack_nr(), 124);
 
+        // The duplicate is acked again
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.seq_nr(), 1);
+        assert_eq!(p.ack_nr(), 124);
+
         // Receive the FIN packet
         let p = m.recv_from(&addr);
         assert_eq!(p.ty(), packet::Type::Fin);
@@ -294,7 +316,12 @@ fn remote_close() {
         p.set_ack_nr(1);
         m.send_to(p, &addr);
 
-        // Receive the FIN packet
+        // The FIN is acked, but the write half stays open
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+
+        // Receive the FIN packet once the stream is dropped
         let p = m.recv_from(&addr);
         assert_eq!(p.ty(), packet::Type::Fin);
         assert_eq!(p.seq_nr(), 2);
@@ -323,3 +350,804 @@ fn remote_close() {
 
     th.join().unwrap();
 }
+
+#[test]
+fn graceful_close() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The FIN follows the pending data
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 2);
+        assert_eq!(p.payload(), b"hello");
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Fin);
+        assert_eq!(p.seq_nr(), 3);
+
+        sleep(100);
+
+        // Ack the data and the FIN
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    // The close completes once the FIN is acked
+    assert_eq!(io::ErrorKind::WouldBlock, stream.close().unwrap_err().kind());
+    assert!(!stream.is_writable());
+
+    socket.wait_until(|| stream.is_writable());
+    stream.close().unwrap();
+
+    let mut buf = [0; 16];
+    assert_eq!(0, stream.read(&mut buf).unwrap());
+    assert_eq!(io::ErrorKind::BrokenPipe, stream.write(b"world").unwrap_err().kind());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn finish_returns_summary() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Fin);
+
+        sleep(100);
+
+        // Ack the data and the FIN
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    // Resolves once the FIN is acked
+    assert_eq!(io::ErrorKind::WouldBlock, stream.finish().unwrap_err().kind());
+
+    let summary = socket.wait(|| stream.finish()).unwrap();
+
+    assert_eq!(5, summary.bytes_acked());
+    assert_eq!(3, summary.packets_sent());
+    assert_eq!(0, summary.packets_resent());
+    assert_eq!(0, summary.timeouts());
+    assert!(summary.elapsed() >= Duration::from_millis(100));
+    assert!(summary.throughput() > 0);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn shutdown_write_keeps_reading() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // Receive the request followed by the FIN
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"request");
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Fin);
+        assert_eq!(p.seq_nr(), 3);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+
+        // Send the response, which is still received and acked
+        let mut p = Packet::data(b"response");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+
+        // Close the connection
+        let mut p = Packet::fin();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(125);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 125);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(7, stream.write(b"request").unwrap());
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    assert_eq!(io::ErrorKind::BrokenPipe, stream.write(b"more").unwrap_err().kind());
+
+    let mut buf = [0; 16];
+    let n = socket.wait(|| stream.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"response");
+
+    // EOF once the peer closes
+    assert_eq!(0, socket.wait(|| stream.read(&mut buf)).unwrap());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn into_split_halves_are_independent() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // Receive the request followed by the FIN
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"request");
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Fin);
+
+        // The response is still received by the read half
+        let mut p = Packet::data(b"response");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    let (read, write) = stream.into_split();
+
+    assert_eq!(7, write.write(b"request").unwrap());
+    write.shutdown().unwrap();
+    drop(write);
+
+    let mut buf = [0; 16];
+    let n = socket.wait(|| read.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"response");
+
+    th.join().unwrap();
+}
+
+#[test]
+fn reunite_requires_halves_of_one_stream() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let (read1, write1) = socket.connect(server).into_split();
+    let (read2, write2) = socket.connect(server).into_split();
+
+    let (read1, write2) = match read1.reunite(write2) {
+        Err(halves) => halves,
+        Ok(_) => panic!("reunited halves of different streams"),
+    };
+
+    assert!(read1.reunite(write1).is_ok());
+    assert!(write2.reunite(read2).is_ok());
+}
+
+#[test]
+fn shutdown_read_returns_eof() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The write half is still open
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+    });
+
+    let stream = socket.connect(server);
+
+    // Not connected yet
+    assert_eq!(io::ErrorKind::NotConnected,
+               stream.shutdown(Shutdown::Read).unwrap_err().kind());
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    stream.shutdown(Shutdown::Read).unwrap();
+
+    let mut buf = [0; 16];
+    assert_eq!(0, stream.read(&mut buf).unwrap());
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn shutdown_read_discards_data() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+
+        // The read half is shut down in the meantime
+        m.assert_quiescence(200);
+
+        let mut p = Packet::data(b"world");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(125);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The data is not acked
+        m.assert_quiescence(500);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_readable());
+
+    // Buffered data is discarded
+    stream.shutdown(Shutdown::Read).unwrap();
+
+    let mut buf = [0; 16];
+    assert_eq!(0, stream.read(&mut buf).unwrap());
+
+    socket.tick_for(1_000);
+    assert_eq!(0, stream.read(&mut buf).unwrap());
+    assert_eq!(1, socket.driver_stats().dropped(DropReason::ReadShutdown));
+
+    th.join().unwrap();
+}
+
+#[test]
+fn ack_of_unsent_packet_is_dropped() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 2);
+
+        // Ack a packet that was never sent
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(3);
+        m.send_to(p, &addr);
+
+        // The data was not dropped from the queue, it times out
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 2);
+        assert_eq!(p.payload(), b"hello");
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    socket.tick_for(1_500);
+    th.join().unwrap();
+
+    assert_eq!(1, socket.driver_stats().dropped(DropReason::InvalidAck));
+}
+
+#[test]
+fn shutdown_read_resets_on_data() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_reset_on_read_shutdown(true);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The read half is shut down in the meantime
+        m.assert_quiescence(200);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+        assert_eq!(p.connection_id(), CONNECTION_ID + 1);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    stream.shutdown(Shutdown::Read).unwrap();
+
+    let mut buf = [0; 16];
+    let err = socket.wait(|| {
+        match stream.read(&mut buf) {
+            Ok(0) => Err(io::ErrorKind::WouldBlock.into()),
+            ret => ret,
+        }
+    }).unwrap_err();
+
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn initial_seq_nr_is_random() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+    ::util::pin_seq_nr(None);
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let first = m.recv_from(&addr);
+        let second = m.recv_from(&addr);
+
+        assert_eq!(first.ty(), packet::Type::Syn);
+        assert_eq!(second.ty(), packet::Type::Syn);
+        assert!(first.seq_nr() != second.seq_nr(), "seq_nr={}", first.seq_nr());
+    });
+
+    let _first = socket.connect(server);
+    let _second = socket.connect(server);
+
+    socket.tick_for(100);
+    th.join().unwrap();
+}
+
+#[test]
+fn connect_advertises_extension_bits() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_advertise_extensions(false);
+
+    let (socket, _) = Harness::new();
+    let (plain, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let plain_addr = plain.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(Some(&[0; 8][..]), p.extension_bits());
+
+        let p = m.recv_from(&plain_addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(None, p.extension_bits());
+    });
+
+    let _stream = socket.connect(server);
+    socket.tick_for(100);
+
+    let _stream = plain.connect(server);
+    plain.tick_for(100);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn initial_seq_nr_wraps() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+    ::util::pin_seq_nr(Some(65_535));
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(p.seq_nr(), 65_535);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(65_535);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 0);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(0);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    socket.wait_until(|| stream.stats().bytes_pending() == 0);
+    th.join().unwrap();
+}
+
+#[test]
+fn connect_skips_other_address_families() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+    });
+
+    let v6: SocketAddr = "[::1]:1234".parse().unwrap();
+
+    let err = socket.connect_to(v6).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    let _stream = socket.connect_to(&[v6, server][..]).unwrap();
+    socket.tick_for(100);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn connect_resolves_host_names() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+    });
+
+    let _stream = socket.connect_to(format!("127.0.0.1:{}", server.port())).unwrap();
+    socket.tick_for(100);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn io_before_connected_would_block() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        sleep(100);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello");
+    });
+
+    let stream = socket.connect(server);
+
+    // Layered protocols may start reading and writing right away
+    let mut buf = [0; 16];
+    assert_eq!(io::ErrorKind::WouldBlock, stream.read(&mut buf).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::WouldBlock, stream.peek(&mut buf).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::WouldBlock, stream.write(b"hello").unwrap_err().kind());
+
+    socket.wait_until(|| stream.is_writable());
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    th.join().unwrap();
+}
+
+#[test]
+fn configured_timestamp_source() {
+    use TimestampSource;
+    use std::time::Instant;
+
+    #[derive(Debug)]
+    struct Fixed;
+
+    impl TimestampSource for Fixed {
+        fn timestamp(&mut self, _: Instant) -> u32 {
+            1_234
+        }
+    }
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_timestamp_source(|| Box::new(Fixed));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(p.timestamp(), 1_234);
+    });
+
+    let _stream = socket.connect(server);
+    socket.tick_for(200);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn buf_and_uninit_io() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, listener) = Harness::new();
+
+    // Both ends of the stream live on the same socket
+    let client = socket.connect(socket.local_addr());
+    let server = socket.wait(|| listener.accept()).unwrap();
+    socket.wait_until(|| client.is_writable());
+
+    let mut src = io::Cursor::new(&b"hello "[..]).chain(io::Cursor::new(&b"world"[..]));
+    assert_eq!(11, client.write_buf(&mut src).unwrap());
+    assert!(!src.has_remaining());
+
+    let mut dst = BytesMut::with_capacity(64);
+    socket.wait(|| server.read_buf(&mut dst)).unwrap();
+    assert_eq!(&dst[..], b"hello world");
+
+    // Reading into uninitialized memory
+    assert_eq!(5, server.write(b"again").unwrap());
+
+    let mut dst = Vec::with_capacity(64);
+    let n = socket.wait(|| client.read_uninit(dst.spare_capacity_mut())).unwrap();
+    unsafe { dst.set_len(n) };
+    assert_eq!(&dst[..], b"again");
+}
+
+#[test]
+fn from_std_socket() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let std = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    std.set_ttl(17).unwrap();
+    let addr = std.local_addr().unwrap();
+
+    let (socket, listener) = UtpSocket::from_socket(std).unwrap();
+    assert_eq!(addr, socket.local_addr().unwrap());
+    assert_eq!(17, socket.ttl().unwrap());
+
+    let (socket, listener) = Harness::from_socket(socket, listener);
+
+    let client = socket.connect(addr);
+    let server = socket.wait(|| listener.accept()).unwrap();
+    socket.wait_until(|| client.is_writable());
+
+    assert_eq!(5, client.write(b"hello").unwrap());
+
+    let mut buf = [0; 16];
+    let n = socket.wait(|| server.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+}
@@ -1,4 +1,7 @@
 use super::prelude::*;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[test]
 fn resends_syn_packet_on_timeout() {
@@ -134,3 +137,603 @@ fn resends_data_packet_on_timeout() {
 
     drop(stream);
 }
+
+#[test]
+fn configured_initial_timeout_resends_syn_sooner() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_initial_timeout(Duration::from_millis(200));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let now = Instant::now();
+
+        // The default initial timeout would wait a full second
+        let p = m.recv_from_ms(&addr, 500).expect("SYN not resent");
+        assert_eq!(p.ty(), packet::Type::Syn);
+        assert_eq!(p.seq_nr(), 1);
+
+        let elapsed = now.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "elapsed={:?}", elapsed);
+    });
+
+    let stream = socket.connect(server);
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+
+    drop(stream);
+}
+
+#[test]
+fn configured_min_timeout_resends_data_sooner() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_min_timeout(Duration::from_millis(100));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.seq_nr(), 2);
+
+        // The round trip over loopback is well below the floor, which
+        // defaults to 500ms
+        let p = m.recv_from_ms(&addr, 400).expect("data not resent");
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello world");
+        assert_eq!(p.seq_nr(), 2);
+    });
+
+    let stream = socket.connect(server);
+
+    socket.wait_until(|| stream.is_writable());
+
+    let n = stream.write(b"hello world").unwrap();
+    assert_eq!(n, 11);
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+
+    drop(stream);
+}
+
+#[test]
+fn resent_data_packet_has_current_header() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let t = Time::new();
+
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+
+        // Get first data packet, but ignore it.
+        let first = m.recv_from(&addr);
+        assert_eq!(first.ty(), packet::Type::Data);
+        assert_eq!(first.seq_nr(), 2);
+        assert_eq!(first.ack_nr(), 123);
+        assert_eq!(first.wnd_size(), 64 * 1024);
+
+        // Send some data that is not read by the stream
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        p.set_timestamp(t.timestamp().wrapping_sub(50_000));
+        m.send_to(p, &addr);
+
+        // The data is acked
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+
+        // Get the packet again, the header reflects the current state
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Data);
+        assert_eq!(p.payload(), b"hello world");
+        assert_eq!(p.seq_nr(), 2);
+        assert_eq!(p.ack_nr(), 124);
+        assert_eq!(p.wnd_size(), 64 * 1024 - 5);
+        assert!(p.timestamp() > first.timestamp());
+        assert!(p.timestamp_diff() != first.timestamp_diff());
+
+        // ACK the packet
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(2);
+        m.send_to(p, &addr);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    // Write some data
+    let n = stream.write(b"hello world").unwrap();
+    assert_eq!(n, 11);
+
+    // Tick a bunch
+    socket.tick_for(1500);
+
+    th.join().unwrap();
+
+    drop(stream);
+}
+
+#[test]
+fn sends_keepalive_when_idle() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_keepalive(Some(Duration::from_millis(200)));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+
+        // Nothing is sent until the connection has been idle for the interval
+        m.assert_quiescence(100);
+
+        let mut prev = None;
+
+        for _ in 0..2 {
+            // The keep-alive acks the packet before the last one received
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::State);
+            assert_eq!(p.seq_nr(), 1);
+            assert_eq!(p.ack_nr(), 122);
+
+            // Keep-alives are spaced by the interval
+            if let Some(prev) = prev {
+                let elapsed = Instant::now() - prev;
+                assert!(elapsed >= Duration::from_millis(150), "elapsed={:?}", elapsed);
+            }
+
+            prev = Some(Instant::now());
+        }
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn idle_timeout_fails_connection() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_idle_timeout(Some(Duration::from_millis(1_000)));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+
+        // The peer goes silent. The data is retransmitted until the
+        // connection gives up, which is signaled with a RESET.
+        loop {
+            let p = m.recv_from(&addr);
+
+            if p.ty() == packet::Type::Reset {
+                break;
+            }
+
+            assert_eq!(p.ty(), packet::Type::Data);
+        }
+
+        m.assert_quiescence(500);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+
+    let start = Instant::now();
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    // The blocked read is woken with an error
+    let mut buf = [0; 128];
+    let err = socket.wait(|| stream.read(&mut buf)).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert!(start.elapsed() >= Duration::from_millis(900));
+
+    assert_eq!(io::ErrorKind::TimedOut, stream.write(b"world").unwrap_err().kind());
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn watchdog_resets_stalled_connection() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let stalls = Arc::new(Mutex::new(vec![]));
+    let hook_stalls = stalls.clone();
+
+    let mut config = UtpConfig::new();
+    config.set_watchdog(Some(3))
+        .set_watchdog_reset(true)
+        .set_stall_hook(move |stall| hook_stalls.lock().unwrap().push(stall.clone()));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+
+        // The path drops everything. Retransmissions back off until the
+        // connection goes silent long enough for the watchdog to reset it.
+        loop {
+            let p = m.recv_from(&addr);
+
+            if p.ty() == packet::Type::Reset {
+                break;
+            }
+
+            assert_eq!(p.ty(), packet::Type::Data);
+        }
+    });
+
+    let stream = socket.connect(server);
+    socket.wait_until(|| stream.is_writable());
+
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    let mut buf = [0; 128];
+    let err = socket.wait(|| stream.read(&mut buf)).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert!(stream.is_stalled());
+
+    th.join().unwrap();
+
+    // The hook got a snapshot of the stalled connection
+    let stalls = stalls.lock().unwrap();
+    assert_eq!(1, stalls.len());
+    assert_eq!(CONNECTION_ID + 1, stalls[0].connection_id());
+    assert!(stalls[0].idle() >= stalls[0].timeout() * 3);
+    assert!(stalls[0].stats().bytes_pending() > 0);
+    assert!(stalls[0].stats().timeouts() > 0);
+}
+
+#[test]
+fn gives_up_after_max_retransmits() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_max_retransmits(2);
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+
+        m.send_to(p, &addr);
+
+        // The peer goes silent. The data is sent three times, the timeout
+        // doubling each time, before the connection gives up.
+        let mut sent_at = vec![];
+
+        for _ in 0..3 {
+            let p = m.recv_from(&addr);
+            assert_eq!(p.ty(), packet::Type::Data);
+            sent_at.push(Instant::now());
+        }
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Reset);
+
+        let first = sent_at[1] - sent_at[0];
+        let second = sent_at[2] - sent_at[1];
+
+        assert!(first >= Duration::from_millis(450), "first={:?}", first);
+        assert!(second >= first + Duration::from_millis(400),
+                "first={:?}; second={:?}", first, second);
+
+        m.assert_quiescence(500);
+    });
+
+    let stream = socket.connect(server);
+
+    // The socket becomes writable
+    socket.wait_until(|| stream.is_writable());
+    assert_eq!(5, stream.write(b"hello").unwrap());
+
+    // The blocked read is woken with an error
+    let mut buf = [0; 128];
+    let err = socket.wait(|| stream.read(&mut buf)).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert_eq!(io::ErrorKind::TimedOut, stream.write(b"world").unwrap_err().kind());
+
+    socket.tick_for(500);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn max_lifetime_closes_connection() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let mut config = UtpConfig::new();
+    config.set_max_lifetime(Some(Duration::from_millis(500)));
+
+    let (socket, _) = Harness::with_config(config);
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+
+        // The connection is closed gracefully once its lifetime is up
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Fin);
+        assert_eq!(p.seq_nr(), 2);
+
+        let mut ack = Packet::state();
+        ack.set_connection_id(CONNECTION_ID);
+        ack.set_seq_nr(124);
+        ack.set_ack_nr(2);
+        m.send_to(ack, &addr);
+
+        m.assert_quiescence(200);
+    });
+
+    let start = Instant::now();
+    let stream = socket.connect(server);
+
+    // Buffered data is still read
+    socket.wait_until(|| stream.is_readable());
+
+    let mut buf = [0; 128];
+    assert_eq!(5, stream.read(&mut buf).unwrap());
+
+    // Then reads and writes fail
+    let err = socket.wait(|| stream.read(&mut buf)).unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionAborted, err.kind());
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    assert_eq!(io::ErrorKind::ConnectionAborted, stream.write(b"world").unwrap_err().kind());
+
+    socket.tick_for(300);
+
+    th.join().unwrap();
+}
+
+#[test]
+fn read_timeout_fails_blocked_read() {
+    const CONNECTION_ID: u16 = 25103;
+
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // Receive the SYN packet
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::Syn);
+
+        // Send the state packet representing the connection ACK
+        let mut p = Packet::state();
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(123);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        // The data is sent once the read timed out
+        m.assert_quiescence(500);
+
+        let mut p = Packet::data(b"hello");
+        p.set_connection_id(CONNECTION_ID);
+        p.set_seq_nr(124);
+        p.set_ack_nr(1);
+        m.send_to(p, &addr);
+
+        let p = m.recv_from(&addr);
+        assert_eq!(p.ty(), packet::Type::State);
+        assert_eq!(p.ack_nr(), 124);
+    });
+
+    let stream = socket.connect(server);
+
+    assert_eq!(io::ErrorKind::InvalidInput,
+               stream.set_read_timeout(Some(Duration::from_secs(0))).unwrap_err().kind());
+
+    stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert_eq!(Some(Duration::from_millis(200)), stream.read_timeout().unwrap());
+
+    // The blocked read is woken with an error
+    let start = Instant::now();
+    let mut buf = [0; 128];
+    let err = socket.wait(|| stream.read(&mut buf)).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // The connection is still usable
+    stream.set_read_timeout(None).unwrap();
+    assert_eq!(5, socket.wait(|| stream.read(&mut buf)).unwrap());
+    assert_eq!(&buf[..5], b"hello");
+
+    th.join().unwrap();
+}
+
+#[test]
+fn write_timeout_fails_blocked_write() {
+    let _ = ::env_logger::init();
+    ::util::reset_rand();
+
+    let (socket, _) = Harness::new();
+    let mock = Mock::new();
+    let server = mock.local_addr();
+
+    let addr = socket.local_addr();
+    let th = mock.background(move |m| {
+        // The SYN is never answered
+        loop {
+            let p = m.recv_from(&addr);
+
+            if p.ty() == packet::Type::Reset {
+                break;
+            }
+
+            assert_eq!(p.ty(), packet::Type::Syn);
+        }
+
+        m.assert_quiescence(200);
+    });
+
+    let stream = socket.connect(server);
+    stream.set_write_timeout(Some(Duration::from_millis(200))).unwrap();
+
+    // Writes block until connected
+    let start = Instant::now();
+    let err = socket.wait(|| stream.write(b"hello")).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    stream.abort().unwrap();
+    socket.tick_for(300);
+
+    th.join().unwrap();
+}
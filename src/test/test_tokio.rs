@@ -0,0 +1,106 @@
+use tokio::{UtpSocket, UtpStream};
+use tokio_rt::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rt::runtime::{self, Runtime};
+use tokio_rt::task::LocalSet;
+
+use std::future;
+use std::io;
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::task::Poll;
+
+fn runtime() -> (Runtime, LocalSet) {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    (rt, LocalSet::new())
+}
+
+fn write(rt: &Runtime, local: &LocalSet, stream: &mut UtpStream, src: &[u8]) -> usize {
+    local.block_on(rt, future::poll_fn(|cx| Pin::new(&mut *stream).poll_write(cx, src))).unwrap()
+}
+
+fn read(rt: &Runtime, local: &LocalSet, stream: &mut UtpStream) -> Vec<u8> {
+    let mut buf = [0; 64];
+
+    local.block_on(rt, future::poll_fn(|cx| {
+        let mut dst = ReadBuf::new(&mut buf);
+
+        match Pin::new(&mut *stream).poll_read(cx, &mut dst) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(dst.filled().to_vec())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    })).unwrap()
+}
+
+#[test]
+fn connect_accept_and_transfer() {
+    let _ = ::env_logger::init();
+
+    let (rt, local) = runtime();
+    let _rt = rt.enter();
+    let _local = local.enter();
+
+    let (socket, listener) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    // Both ends of the stream live on the same socket
+    let connect = socket.connect(addr);
+    let mut server = local.block_on(&rt, listener.accept()).unwrap();
+    let mut client = local.block_on(&rt, connect).unwrap();
+
+    assert_eq!(addr, client.peer_addr().unwrap());
+
+    assert_eq!(5, write(&rt, &local, &mut client, b"hello"));
+    assert_eq!(b"hello", &read(&rt, &local, &mut server)[..]);
+
+    assert_eq!(5, write(&rt, &local, &mut server, b"world"));
+    assert_eq!(b"world", &read(&rt, &local, &mut client)[..]);
+
+    // The peer reads EOF once the write half is shut down
+    local.block_on(&rt, future::poll_fn(|cx| Pin::new(&mut client).poll_shutdown(cx))).unwrap();
+    assert!(read(&rt, &local, &mut server).is_empty());
+
+    server.shutdown(Shutdown::Write).unwrap();
+    assert!(read(&rt, &local, &mut client).is_empty());
+}
+
+#[test]
+fn connect_reports_refused_connection() {
+    let _ = ::env_logger::init();
+
+    let (rt, local) = runtime();
+    let _rt = rt.enter();
+    let _local = local.enter();
+
+    let (socket, listener) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    // Without a listener, the SYN is answered with a RESET
+    drop(listener);
+
+    let err = local.block_on(&rt, socket.connect(addr)).err().unwrap();
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+}
+
+#[test]
+fn driver_exits_once_handles_are_dropped() {
+    let _ = ::env_logger::init();
+
+    let (rt, local) = runtime();
+
+    {
+        let _rt = rt.enter();
+        let _local = local.enter();
+
+        let (socket, listener) = UtpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        drop(socket);
+        drop(listener);
+    }
+
+    // Completes once the driver task has
+    rt.block_on(local);
+}
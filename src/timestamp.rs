@@ -0,0 +1,42 @@
+//! Timestamps carried by packets
+//!
+//! Each packet carries the time at which it was sent, in microseconds, which
+//! the peer uses to measure the one way delay. Only differences between
+//! timestamps are meaningful and they wrap at 32 bits, so the clock does not
+//! need to match wall time. Connections use `InstantTimestamps` by default,
+//! any implementation of `TimestampSource` can be selected with `UtpConfig`.
+
+use util;
+
+use std::fmt;
+use std::time::Instant;
+
+/// Source of the microsecond timestamps sent with each packet.
+///
+/// Implementations backed by a cheaper clock than `Instant`, such as the CPU's
+/// time stamp counter, may ignore the `now` argument.
+pub trait TimestampSource: fmt::Debug {
+    /// Returns the current time in microseconds, wrapping at 32 bits. `now`
+    /// is the instant at which the connection sends or receives the packet.
+    fn timestamp(&mut self, now: Instant) -> u32;
+}
+
+/// Timestamps derived from `Instant`, in microseconds since the connection
+/// was created.
+#[derive(Debug)]
+pub struct InstantTimestamps {
+    origin: Instant,
+}
+
+impl InstantTimestamps {
+    /// Returns a new `InstantTimestamps` counting from `origin`.
+    pub fn new(origin: Instant) -> InstantTimestamps {
+        InstantTimestamps { origin }
+    }
+}
+
+impl TimestampSource for InstantTimestamps {
+    fn timestamp(&mut self, now: Instant) -> u32 {
+        util::as_wrapping_micros(now.duration_since(self.origin))
+    }
+}
@@ -0,0 +1,383 @@
+//! Tokio integration
+//!
+//! `UtpSocket` in this module binds a socket and spawns a task that drives it
+//! from the tokio runtime, so applications neither register the socket nor
+//! call `ready` and `tick` themselves. `UtpStream` implements tokio's
+//! `AsyncRead` and `AsyncWrite`, and `connect` and `accept` return futures
+//! that resolve to streams.
+//!
+//! Sockets are not `Send`, the driver task is spawned with `spawn_local`.
+//! Sockets must be bound from within a `LocalSet`, on a runtime with the IO
+//! and time drivers enabled. The task exits once every handle of its socket
+//! has been dropped and the connections have been closed.
+//!
+//! The read and write timeouts of a stream, set through `get_ref`, apply to
+//! tasks as well. Only supported on Unix.
+
+use config::UtpConfig;
+use socket;
+
+use tokio_rt::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rt::io::unix::AsyncFd;
+use tokio_rt::task;
+use tokio_rt::time::{self, Sleep};
+
+use mio::Ready;
+
+use std::{cmp, io};
+use std::future::Future;
+use std::io::IoSlice;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// A uTP socket driven by a tokio task.
+pub struct UtpSocket {
+    socket: Rc<socket::UtpSocket>,
+}
+
+/// Accepts inbound connections of a `UtpSocket` driven by a tokio task.
+pub struct UtpListener {
+    listener: socket::UtpListener,
+    socket: Rc<socket::UtpSocket>,
+}
+
+/// A uTP connection implementing tokio's `AsyncRead` and `AsyncWrite`.
+///
+/// Shutting the stream down closes its write half once the peer has
+/// acknowledged the data. Dropping the stream closes the connection
+/// gracefully, the driver task delivers any data still queued.
+pub struct UtpStream {
+    stream: socket::UtpStream,
+    // Keeps the driver task running
+    socket: Rc<socket::UtpSocket>,
+}
+
+/// Future returned by `UtpSocket::connect`, resolving once the handshake
+/// completes.
+pub struct Connect {
+    stream: Option<io::Result<UtpStream>>,
+}
+
+/// Future returned by `UtpListener::accept`.
+pub struct Accept<'a> {
+    listener: &'a UtpListener,
+}
+
+/// Drives a socket, owned by the task spawned when binding
+struct Driver {
+    io: AsyncFd<RawFd>,
+    socket: Rc<socket::UtpSocket>,
+    sleep: Pin<Box<Sleep>>,
+    last_tick: Instant,
+}
+
+// `UtpSocket::tick` must be called at least this often
+const TICK: Duration = Duration::from_millis(500);
+
+impl UtpSocket {
+    /// Binds a new socket to `addr` and spawns its driver task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a `LocalSet`.
+    pub fn bind(addr: &SocketAddr) -> io::Result<(UtpSocket, UtpListener)> {
+        UtpSocket::bind_with_config(addr, UtpConfig::new())
+    }
+
+    /// Binds a new socket to `addr` using the provided configuration and
+    /// spawns its driver task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a `LocalSet`.
+    pub fn bind_with_config(addr: &SocketAddr, config: UtpConfig)
+        -> io::Result<(UtpSocket, UtpListener)>
+    {
+        let (socket, listener) = socket::UtpSocket::bind_with_config(addr, config)?;
+        UtpSocket::from_parts(socket, listener)
+    }
+
+    /// Spawns the driver task of a socket created by `UtpSocket::builder` or
+    /// `UtpSocket::from_socket`. The socket must not be registered with a
+    /// mio `Poll`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a `LocalSet`.
+    pub fn from_parts(socket: socket::UtpSocket, listener: socket::UtpListener)
+        -> io::Result<(UtpSocket, UtpListener)>
+    {
+        let io = AsyncFd::new(socket.as_raw_fd())?;
+        let socket = Rc::new(socket);
+
+        let driver = Driver {
+            io,
+            socket: socket.clone(),
+            sleep: Box::pin(time::sleep(TICK)),
+            last_tick: Instant::now(),
+        };
+
+        task::spawn_local(driver);
+
+        let listener = UtpListener {
+            listener,
+            socket: socket.clone(),
+        };
+
+        Ok((UtpSocket { socket }, listener))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Opens a connection to `addr`, resolved as by
+    /// `socket::UtpSocket::connect`. The future resolves once the handshake
+    /// completes, or to the error that ended the connection.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Connect {
+        let socket = self.socket.clone();
+
+        Connect {
+            stream: Some(self.socket.connect(addr).map(|stream| UtpStream::new(stream, socket))),
+        }
+    }
+
+    /// Returns the socket driven by the task, to read its statistics or set
+    /// socket options.
+    pub fn get_ref(&self) -> &socket::UtpSocket {
+        &self.socket
+    }
+}
+
+impl UtpListener {
+    /// Returns a future that resolves to the next inbound connection.
+    pub fn accept(&self) -> Accept<'_> {
+        Accept { listener: self }
+    }
+
+    /// Attempts to accept an inbound connection, registering the task to wake
+    /// once one is ready otherwise.
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<UtpStream>> {
+        match self.listener.accept() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.listener.register_accept_waker(cx.waker());
+                Poll::Pending
+            }
+            ret => Poll::Ready(ret.map(|stream| UtpStream::new(stream, self.socket.clone()))),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl UtpStream {
+    fn new(stream: socket::UtpStream, socket: Rc<socket::UtpSocket>) -> UtpStream {
+        UtpStream {
+            stream,
+            socket,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Shuts down the read, write, or both halves of the connection, see
+    /// `socket::UtpStream::shutdown`.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    /// Returns the non-blocking stream, to set its timeouts and options or to
+    /// read its statistics.
+    pub fn get_ref(&self) -> &socket::UtpStream {
+        &self.stream
+    }
+}
+
+impl Future for Connect {
+    type Output = io::Result<UtpStream>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<UtpStream>> {
+        let ret = match self.stream {
+            Some(Ok(ref stream)) => {
+                match stream.stream.connect_result() {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // The stream becomes writable once connected, and
+                        // both wakers are woken if the connection fails.
+                        stream.stream.register_write_waker(cx.waker());
+                        return Poll::Pending;
+                    }
+                    ret => ret,
+                }
+            }
+            Some(Err(_)) => Ok(()),
+            None => panic!("`Connect` polled after completion"),
+        };
+
+        let stream = self.stream.take().unwrap();
+        Poll::Ready(ret.and(stream))
+    }
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = io::Result<UtpStream>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<UtpStream>> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf)
+        -> Poll<io::Result<()>>
+    {
+        // Only initialized bytes are written to the unfilled part
+        let n = match self.stream.read_uninit(unsafe { buf.unfilled_mut() }) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.stream.register_read_waker(cx.waker());
+                return Poll::Pending;
+            }
+            Err(e) => return Poll::Ready(Err(e)),
+            Ok(n) => n,
+        };
+
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(&self.stream, cx, self.stream.write(buf))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context, bufs: &[IoSlice])
+        -> Poll<io::Result<usize>>
+    {
+        poll_write(&self.stream, cx, self.stream.write_vectored(bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        // Written data is handed to the socket immediately, flushing only
+        // needs to release data held back by Nagle's algorithm.
+        Poll::Ready(self.stream.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        poll_write(&self.stream, cx, self.stream.close_write())
+    }
+}
+
+/// Completes once `ret`, the result of writing to the stream, no longer
+/// blocks
+fn poll_write<T>(stream: &socket::UtpStream, cx: &mut Context, ret: io::Result<T>)
+    -> Poll<io::Result<T>>
+{
+    match ret {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            stream.register_write_waker(cx.waker());
+            Poll::Pending
+        }
+        ret => Poll::Ready(ret),
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.turn(cx) {
+            Ok(ready) => ready,
+            Err(e) => {
+                error!("uTP driver task failed; err={}", e);
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+impl Driver {
+    /// Processes the socket's readiness and timers until it has nothing left
+    /// to do, returning `Ready` once the socket can be dropped.
+    fn turn(&mut self, cx: &mut Context) -> io::Result<Poll<()>> {
+        loop {
+            let mut progress = false;
+
+            if let Poll::Ready(guard) = self.io.poll_read_ready(cx) {
+                let mut guard = guard?;
+
+                // Receives until the socket would block
+                self.socket.ready(Ready::readable())?;
+                guard.clear_ready();
+                progress = true;
+            }
+
+            if !self.socket.is_writable() {
+                if let Poll::Ready(guard) = self.io.poll_write_ready(cx) {
+                    let mut guard = guard?;
+
+                    self.socket.ready(Ready::writable())?;
+
+                    if !self.socket.is_writable() {
+                        guard.clear_ready();
+                    }
+
+                    progress = true;
+                }
+            }
+
+            let now = Instant::now();
+
+            if now >= self.deadline(now) {
+                self.socket.tick()?;
+                self.last_tick = now;
+                progress = true;
+            }
+
+            // Every handle is gone, the connections are closed
+            if Rc::strong_count(&self.socket) == 1 && self.socket.is_idle() {
+                return Ok(Poll::Ready(()));
+            }
+
+            if progress {
+                continue;
+            }
+
+            let deadline = self.deadline(now);
+            self.sleep.as_mut().reset(deadline.into());
+
+            if self.sleep.as_mut().poll(cx).is_pending() {
+                return Ok(Poll::Pending);
+            }
+        }
+    }
+
+    /// Returns when the socket must be ticked next
+    fn deadline(&self, now: Instant) -> Instant {
+        let tick_at = self.last_tick + TICK;
+
+        match self.socket.next_timeout() {
+            Some(timeout) => cmp::min(tick_at, now + timeout),
+            None => tick_at,
+        }
+    }
+}
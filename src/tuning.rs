@@ -0,0 +1,121 @@
+//! Protocol tuning parameters.
+//!
+//! These constants define the default operating envelope of the protocol.
+//! The values currently in effect for a socket are available from its
+//! `UtpConfig`.
+
+/// Max number of bytes buffered for a connection in each direction.
+///
+/// This is the receive window advertised to the peer, and the window assumed
+/// for the peer until it advertises its own.
+pub const MAX_WINDOW_SIZE: usize = 64 * 1_024;
+
+/// Max size of a packet, including the header, until path MTU discovery
+/// finds that larger packets fit.
+pub const MAX_PACKET_SIZE: usize = 1_400;
+
+/// Largest payload accepted in a single packet. Packets carrying more are
+/// dropped.
+///
+/// This leaves room for peers using 9000 byte jumbo frames.
+pub const MAX_RECV_PAYLOAD_SIZE: usize = 9_000;
+
+/// Largest packet size, including the header, probed by path MTU discovery.
+///
+/// This is a 1500 byte Ethernet MTU minus the IPv4 and UDP headers.
+pub const MAX_PROBE_PACKET_SIZE: usize = 1_472;
+
+/// Packet size, including the header, used once packets of `MAX_PACKET_SIZE`
+/// appear not to fit the path.
+///
+/// This is the 576 byte datagram every IPv4 host must accept minus the IPv4
+/// and UDP headers.
+pub const MIN_MTU_PACKET_SIZE: usize = 548;
+
+/// Largest initial congestion window an application may request with
+/// `UtpStream::set_initial_window`.
+///
+/// This is ten packets, the initial window allowed for TCP by RFC 6928.
+pub const MAX_INITIAL_WINDOW_SIZE: usize = 10 * MAX_PACKET_SIZE;
+
+/// Smallest packet, including the header, that writes are split into to fill
+/// the window. Once the window has less room, writes wait for it to open up
+/// instead of sending a tiny packet.
+///
+/// This is also the size of the congestion window after a connection times
+/// out.
+pub const MIN_PACKET_SIZE: usize = 150;
+
+/// Max number of packets a connection tracks for sending, whether in-flight or
+/// waiting to be sent. This bounds the cost of processing ACKs when writes are
+/// small, and matches libutp's outgoing buffer.
+pub const MAX_PACKETS_IN_FLIGHT: usize = 1_024;
+
+/// Max number of packets a connection sends at once, before giving other
+/// connections on the socket a turn.
+pub const MAX_BURST_PACKETS: usize = 64;
+
+/// Max number of packets received ahead of a gap that a connection holds until
+/// the gap fills. This is also the largest value accepted by
+/// `UtpConfig::set_reorder_buffer_size`.
+pub const REORDER_BUFFER_SIZE: usize = 32;
+
+/// Max number of peers whose path characteristics are cached by a socket.
+pub const PATH_CACHE_SIZE: usize = 256;
+
+/// Time, in seconds, that cached path characteristics are used for.
+pub const PATH_CACHE_TTL_SECS: u64 = 10 * 60;
+
+/// Max number of connections managed by a single socket.
+pub const MAX_CONNECTIONS_PER_SOCKET: usize = 2 * 1_024;
+
+/// Max number of inbound connections waiting to be accepted by a socket's
+/// listener.
+pub const ACCEPT_BACKLOG: usize = 128;
+
+/// Timeout, in milliseconds, used until a round trip time has been measured.
+/// This applies to the connection handshake.
+pub const INITIAL_TIMEOUT_MS: u64 = 1_000;
+
+/// Lower bound, in milliseconds, of the timeout computed from the round trip
+/// time.
+pub const MIN_TIMEOUT_MS: u64 = 500;
+
+/// Upper bound, in milliseconds, of the timeout. The timeout doubles each time
+/// it expires without the peer acking anything.
+pub const MAX_TIMEOUT_MS: u64 = 60_000;
+
+/// Number of times packets are retransmitted after timing out before the
+/// connection is given up on.
+pub const MAX_RETRANSMITS: u32 = 5;
+
+/// Upper bound, in milliseconds, of the interval between window probes. Probes
+/// start out at the regular timeout and back off while the peer's window stays
+/// closed.
+pub const MAX_WINDOW_PROBE_INTERVAL_MS: u64 = 30_000;
+
+/// LEDBAT target queuing delay, in microseconds.
+pub const TARGET_DELAY_MICROS: u32 = 100_000;
+
+/// Max number of bytes LEDBAT grows the congestion window by per round trip.
+pub const MAX_CWND_INCREASE_BYTES_PER_RTT: usize = 3_000;
+
+/// Max time, in milliseconds, that an ACK is delayed by. ACKs are delayed by a
+/// quarter of the round trip time, up to this value.
+pub const MAX_ACK_DELAY_MS: u64 = 100;
+
+/// Number of data packets received after which an ACK is sent right away,
+/// instead of being delayed.
+pub const ACK_EVERY_PACKETS: u16 = 2;
+
+/// Number of packets sent after a packet that must be selectively acked before
+/// the packet is considered lost.
+pub const DUPLICATE_ACKS_BEFORE_RESEND: usize = 3;
+
+/// Number of packets an inbound ACK may lag behind the last packet acked. As
+/// in libutp, packets with an older ACK, or one acking a packet that was not
+/// sent, are dropped. Keep-alives ack the packet before the last one received.
+pub const ACK_NR_ALLOWED_WINDOW: u16 = 3;
+
+/// Max time, in milliseconds, between two calls to `UtpSocket::tick`.
+pub const TICK_INTERVAL_MS: u64 = 500;
@@ -1,3 +1,5 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
 
 pub fn as_ms(duration: Duration) -> u64 {
@@ -24,9 +26,39 @@ const NANOS_PER_MICRO: u32 = 1_000;
 
 pub fn as_wrapping_micros(duration: Duration) -> u32 {
     // Wrapping is OK
-    let mut ret = duration.as_secs().wrapping_mul(MICROS_PER_SEC as u64) as u32;
-    ret += duration.subsec_nanos() / NANOS_PER_MICRO;
-    ret
+    let ret = duration.as_secs().wrapping_mul(MICROS_PER_SEC as u64) as u32;
+    ret.wrapping_add(duration.subsec_nanos() / NANOS_PER_MICRO)
+}
+
+pub fn as_micros(duration: Duration) -> u64 {
+    duration.as_secs() * MICROS_PER_SEC as u64 +
+        (duration.subsec_nanos() / NANOS_PER_MICRO) as u64
+}
+
+pub fn from_micros(micros: u64) -> Duration {
+    let secs = micros / MICROS_PER_SEC as u64;
+    let sub_micros = (micros % MICROS_PER_SEC as u64) as u32;
+    Duration::new(secs, sub_micros * NANOS_PER_MICRO)
+}
+
+/// Calls `f` with each address `addr` resolves to until it succeeds, returning
+/// the error of the last attempt otherwise, as `TcpStream::connect` does.
+pub fn each_addr<A, F, T>(addr: A, mut f: F) -> io::Result<T>
+    where A: ToSocketAddrs,
+          F: FnMut(&SocketAddr) -> io::Result<T>,
+{
+    let mut last_err = None;
+
+    for addr in addr.to_socket_addrs()? {
+        match f(&addr) {
+            Ok(ret) => return Ok(ret),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+    }))
 }
 
 /// Safely generates two sequential connection identifiers.
@@ -44,6 +76,14 @@ pub fn generate_sequential_identifiers() -> (u16, u16) {
     }
 }
 
+/// Picks the sequence number of the SYN opening a connection. As in libutp,
+/// it is random, which makes it unlikely that stray packets of a previous
+/// connection between the same peers fall within the new connection's window.
+#[cfg(not(test))]
+pub fn initial_seq_nr() -> u16 {
+    rand()
+}
+
 #[cfg(not(test))]
 pub fn rand<T: ::rand::Rand>() -> T {
     use rand::{self, Rng};
@@ -53,17 +93,30 @@ pub fn rand<T: ::rand::Rand>() -> T {
 }
 
 #[cfg(test)]
-pub use self::test::{rand, reset_rand};
+pub use self::test::{rand, reset_rand, initial_seq_nr, pin_seq_nr};
 
 #[cfg(test)]
 mod test {
     use rand::{Rand, XorShiftRng, Rng};
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
 
     thread_local!(static THREAD_RNG: RefCell<XorShiftRng> = {
         RefCell::new(XorShiftRng::new_unseeded())
     });
 
+    // Tests expect the SYN to have a seq_nr of 1, unless they unpin it
+    thread_local!(static PINNED_SEQ_NR: Cell<Option<u16>> = const { Cell::new(Some(1)) });
+
+    pub fn initial_seq_nr() -> u16 {
+        PINNED_SEQ_NR.with(|p| p.get()).unwrap_or_else(rand)
+    }
+
+    /// Sets the seq_nr of the SYN of connections opened by the current
+    /// thread, random if `None`.
+    pub fn pin_seq_nr(seq_nr: Option<u16>) {
+        PINNED_SEQ_NR.with(|p| p.set(seq_nr));
+    }
+
     pub fn rand<T: Rand>() -> T {
         THREAD_RNG.with(|t| t.borrow_mut().gen::<T>())
     }
@@ -0,0 +1,63 @@
+//! Known packet encodings.
+//!
+//! These are used by the self test and the unit tests to check that packets
+//! are encoded the same way on every target. They are plain byte arrays, so
+//! they do not depend on the endianness or the pointer width of the target.
+
+/// A STATE packet with every header field set to a distinct value.
+///
+/// connection_id = 0x1234, timestamp = 0x01020304,
+/// timestamp_diff = 0x05060708, wnd_size = 0x090A0B0C, seq_nr = 0x0D0E,
+/// ack_nr = 0x0F10
+pub const STATE: [u8; 20] = [
+    0x21, 0x00, 0x12, 0x34,
+    0x01, 0x02, 0x03, 0x04,
+    0x05, 0x06, 0x07, 0x08,
+    0x09, 0x0A, 0x0B, 0x0C,
+    0x0D, 0x0E, 0x0F, 0x10];
+
+/// The `STATE` header as a DATA packet with a payload of "utp"
+pub const DATA: [u8; 23] = [
+    0x01, 0x00, 0x12, 0x34,
+    0x01, 0x02, 0x03, 0x04,
+    0x05, 0x06, 0x07, 0x08,
+    0x09, 0x0A, 0x0B, 0x0C,
+    0x0D, 0x0E, 0x0F, 0x10,
+    b'u', b't', b'p'];
+
+/// A freshly created SYN packet, before any field is set. The timestamp
+/// difference is unknown and the window is 64kb.
+pub const SYN: [u8; 20] = [
+    0x41, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x01, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00];
+
+/// The `SYN` packet with the extension bits sent by libutp, all cleared.
+pub const SYN_EXTENSION_BITS: [u8; 30] = [
+    0x41, 0x02, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x01, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// A DATA packet with a payload of "utp" and a selective ACK extension
+/// acking the packets at offsets 0, 7 and 30.
+pub const SELECTIVE_ACK: [u8; 29] = [
+    0x01, 0x01, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0xFF,
+    0x00, 0x01, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+    0x00, 0x04, 0b1000_0001, 0x00, 0x00, 0b0100_0000,
+    b'u', b't', b'p'];
+
+/// Packets that fail to parse, with a description of what is wrong
+pub const MALFORMED: [(&str, &[u8]); 4] = [
+    ("short packet", &[0x21, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    ("version 2", &[0x22, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    ("type 5", &[0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    ("truncated extension", &[0x21, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0]),
+];